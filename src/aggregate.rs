@@ -0,0 +1,360 @@
+use super::aqi::{self, Pollutant};
+use super::measurement::*;
+use std::collections::VecDeque;
+use time::{Duration, OffsetDateTime};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Window {
+    OneMinute,
+    FifteenMinutes,
+    OneHour,
+    EightHours,
+    TwentyFourHours,
+}
+
+impl Window {
+    pub const ALL: [Window; 5] = [
+        Window::OneMinute,
+        Window::FifteenMinutes,
+        Window::OneHour,
+        Window::EightHours,
+        Window::TwentyFourHours,
+    ];
+
+    pub fn duration(&self) -> Duration {
+        match self {
+            Window::OneMinute => Duration::minutes(1),
+            Window::FifteenMinutes => Duration::minutes(15),
+            Window::OneHour => Duration::hours(1),
+            Window::EightHours => Duration::hours(8),
+            Window::TwentyFourHours => Duration::hours(24),
+        }
+    }
+}
+
+// How much history we need: the longest window, and 12 hours for NowCast.
+const RETENTION: Duration = Duration::hours(24);
+
+/// Aggregator maintains time-weighted rolling means over a stream of
+/// measurements.
+///
+/// Each sample is assumed to represent the period until the next sample, but
+/// at most one expected_interval: longer gaps (e.g. while the sensor is
+/// returning no data) are treated as missing data. Means are only reported
+/// if the fraction of the window covered by data is at least
+/// min_completeness.
+pub struct Aggregator {
+    samples: VecDeque<TimestampedMeasurement>,
+    expected_interval: Duration,
+    min_completeness: f64,
+}
+
+impl Aggregator {
+    /// expected_interval is the normal time between measurements, e.g. 5s for
+    /// the reader.
+    pub fn new(expected_interval: std::time::Duration) -> Self {
+        Aggregator {
+            samples: VecDeque::new(),
+            expected_interval: Duration::try_from(expected_interval).unwrap_or(Duration::MAX),
+            min_completeness: 0.75,
+        }
+    }
+
+    /// set_expected_interval changes the normal time between measurements,
+    /// e.g. when the sampling interval is changed at runtime.
+    pub fn set_expected_interval(&mut self, expected_interval: std::time::Duration) {
+        self.expected_interval = Duration::try_from(expected_interval).unwrap_or(Duration::MAX);
+    }
+
+    /// min_completeness sets the fraction of each window (0-1) that must be
+    /// covered by data, the default is 75% as per common regulatory practice.
+    pub fn min_completeness(mut self, min_completeness: f64) -> Self {
+        self.min_completeness = min_completeness.clamp(0.0, 1.0);
+        self
+    }
+
+    /// push adds a measurement, measurements that are older than the latest
+    /// measurement are ignored.
+    pub fn push(&mut self, measurement: &TimestampedMeasurement) {
+        if let Some(latest) = self.samples.back() {
+            if measurement.time <= latest.time {
+                return;
+            }
+        }
+        self.samples.push_back(*measurement);
+        let cutoff = measurement.time - RETENTION - self.expected_interval;
+        while self.samples.front().is_some_and(|s| s.time < cutoff) {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn latest(&self) -> Option<&TimestampedMeasurement> {
+        self.samples.back()
+    }
+
+    // Returns the weighted sum of each channel, and the total weight (i.e. the
+    // time covered by data, in seconds) for [start, end).
+    fn weighted_sum(&self, start: OffsetDateTime, end: OffsetDateTime) -> ([f64; 10], f64) {
+        let mut sums = [0.0; 10];
+        let mut total_weight = 0.0;
+        for (i, sample) in self.samples.iter().enumerate() {
+            if sample.time >= end {
+                break;
+            }
+            let mut covered_until = (sample.time + self.expected_interval).min(end);
+            if let Some(next) = self.samples.get(i + 1) {
+                covered_until = covered_until.min(next.time);
+            }
+            let covered_from = sample.time.max(start);
+            if covered_until <= covered_from {
+                continue;
+            }
+            let weight = (covered_until - covered_from).as_seconds_f64();
+            for (sum, channel) in sums.iter_mut().zip(Channel::ALL) {
+                *sum += sample.measurement.get(channel) as f64 * weight;
+            }
+            total_weight += weight;
+        }
+        (sums, total_weight)
+    }
+
+    /// period_mean returns the time-weighted mean of every channel over
+    /// [start, end), or None if there isn't enough data. Unlike mean, the
+    /// period doesn't have to be one of the standard windows.
+    pub fn period_mean(&self, start: OffsetDateTime, end: OffsetDateTime) -> Option<Measurement> {
+        let (sums, weight) = self.weighted_sum(start, end);
+        if weight <= 0.0 || weight / (end - start).as_seconds_f64() < self.min_completeness {
+            return None;
+        }
+        let mut mean = Measurement::default();
+        for (sum, channel) in sums.iter().zip(Channel::ALL) {
+            mean.set(channel, (sum / weight) as f32);
+        }
+        Some(mean)
+    }
+
+    /// completeness returns the fraction (0-1) of the window ending at now
+    /// that is covered by data.
+    pub fn completeness(&self, window: Window, now: OffsetDateTime) -> f64 {
+        let (_, weight) = self.weighted_sum(now - window.duration(), now);
+        weight / window.duration().as_seconds_f64()
+    }
+
+    /// mean returns the time-weighted mean of every channel over the window
+    /// ending at now, or None if there isn't enough data.
+    pub fn mean(&self, window: Window, now: OffsetDateTime) -> Option<Measurement> {
+        self.period_mean(now - window.duration(), now)
+    }
+
+    /// nowcast computes the EPA NowCast concentration (in ug/m3) from the
+    /// hourly means of the 12 hours ending at now. Each hour must meet
+    /// min_completeness to be used, and at least 2 of the 3 most recent hours
+    /// are required. See
+    /// <https://usepa.servicenowservices.com/airnow?id=kb_article_view&sysparm_article=KB0011856>
+    pub fn nowcast(&self, pollutant: Pollutant, now: OffsetDateTime) -> Option<f32> {
+        let channel = match pollutant {
+            Pollutant::Pm2_5 => Channel::MassPm2_5,
+            Pollutant::Pm10 => Channel::MassPm10_0,
+        };
+        // hourly[0] is the most recent hour.
+        let hourly: Vec<Option<f64>> = (0..12)
+            .map(|i| {
+                let end = now - Duration::hours(i);
+                self.period_mean(end - Duration::hours(1), end)
+                    .map(|m| m.get(channel) as f64)
+            })
+            .collect();
+        if hourly[..3].iter().filter(|h| h.is_some()).count() < 2 {
+            return None;
+        }
+        let available = hourly.iter().flatten();
+        let max = available.clone().cloned().fold(f64::MIN, f64::max);
+        let min = available.cloned().fold(f64::MAX, f64::min);
+        let weight = if max > 0.0 { (min / max).max(0.5) } else { 1.0 };
+
+        let mut sum = 0.0;
+        let mut weights = 0.0;
+        for (i, concentration) in hourly.iter().enumerate() {
+            if let Some(concentration) = concentration {
+                let w = weight.powi(i as i32);
+                sum += w * concentration;
+                weights += w;
+            }
+        }
+        Some((sum / weights) as f32)
+    }
+
+    /// nowcast_aqi computes the US EPA AQI from the NowCast concentrations,
+    /// which is what AirNow reports as the current AQI.
+    pub fn nowcast_aqi(&self, now: OffsetDateTime) -> Option<aqi::Aqi> {
+        let pm2_5 = self.nowcast(Pollutant::Pm2_5, now)?;
+        let pm10 = self.nowcast(Pollutant::Pm10, now)?;
+        aqi::compute(aqi::Standard::UsEpa, pm2_5, pm10).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurement::test_util::measurement;
+    use time::macros::datetime;
+
+    const START: OffsetDateTime = datetime!(2024-06-01 00:00 UTC);
+
+    // Pushes one sample per interval in [from, to), with values from f.
+    fn fill<F: Fn(OffsetDateTime) -> f32>(
+        aggregator: &mut Aggregator,
+        from: OffsetDateTime,
+        to: OffsetDateTime,
+        interval: Duration,
+        f: F,
+    ) {
+        let mut time = from;
+        while time < to {
+            aggregator.push(&measurement(time, f(time)));
+            time += interval;
+        }
+    }
+
+    #[test]
+    fn test_means() {
+        let mut aggregator = Aggregator::new(std::time::Duration::from_secs(5));
+        // 10 for the first hour, 20 for the second hour.
+        let end = START + Duration::hours(2);
+        fill(&mut aggregator, START, end, Duration::seconds(5), |t| {
+            if t < START + Duration::hours(1) {
+                10.0
+            } else {
+                20.0
+            }
+        });
+
+        assert_eq!(aggregator.completeness(Window::OneHour, end), 1.0);
+        assert_eq!(
+            aggregator
+                .mean(Window::OneMinute, end)
+                .unwrap()
+                .mass_concentration_pm_2_5,
+            20.0
+        );
+        assert_eq!(
+            aggregator
+                .mean(Window::OneHour, end - Duration::minutes(30))
+                .unwrap()
+                .number_concentration_pm_0_5,
+            15.0
+        );
+        // Only 2 of 8 hours have data.
+        assert_eq!(aggregator.completeness(Window::EightHours, end), 0.25);
+        assert_eq!(aggregator.mean(Window::EightHours, end), None);
+        // The sensor stopped sending data at end.
+        assert_eq!(
+            aggregator.mean(Window::FifteenMinutes, end + Duration::minutes(5)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_gaps() {
+        let mut aggregator =
+            Aggregator::new(std::time::Duration::from_secs(5)).min_completeness(0.5);
+        let end = START + Duration::minutes(15);
+        // No data between minutes 5 and 10.
+        fill(&mut aggregator, START, end, Duration::seconds(5), |t| {
+            if t < START + Duration::minutes(5) {
+                10.0
+            } else {
+                40.0
+            }
+        });
+        aggregator.samples.retain(|s| {
+            s.time < START + Duration::minutes(5) || s.time >= START + Duration::minutes(10)
+        });
+
+        let completeness = aggregator.completeness(Window::FifteenMinutes, end);
+        assert!((completeness - 2.0 / 3.0).abs() < 1e-9, "{}", completeness);
+        // The gap doesn't count towards either value.
+        assert_eq!(
+            aggregator
+                .mean(Window::FifteenMinutes, end)
+                .unwrap()
+                .mass_concentration_pm_1_0,
+            25.0
+        );
+    }
+
+    #[test]
+    fn test_set_expected_interval() {
+        let mut aggregator = Aggregator::new(std::time::Duration::from_secs(5));
+        let end = START + Duration::minutes(15);
+        fill(&mut aggregator, START, end, Duration::minutes(1), |_| 10.0);
+        // Each sample only covers 5 seconds of every minute...
+        let completeness = aggregator.completeness(Window::FifteenMinutes, end);
+        assert!((completeness - 1.0 / 12.0).abs() < 1e-9, "{}", completeness);
+        assert_eq!(aggregator.mean(Window::FifteenMinutes, end), None);
+
+        // ... until the interval is known to be a minute.
+        aggregator.set_expected_interval(std::time::Duration::from_secs(60));
+        assert_eq!(aggregator.completeness(Window::FifteenMinutes, end), 1.0);
+        assert!(aggregator.mean(Window::FifteenMinutes, end).is_some());
+    }
+
+    #[test]
+    fn test_nowcast() {
+        let mut aggregator = Aggregator::new(std::time::Duration::from_secs(60));
+        let now = START + Duration::hours(12);
+        assert_eq!(aggregator.nowcast(Pollutant::Pm2_5, now), None);
+
+        // Steady concentrations.
+        fill(&mut aggregator, START, now, Duration::minutes(1), |_| 10.0);
+        assert_eq!(aggregator.nowcast(Pollutant::Pm2_5, now), Some(10.0));
+        assert_eq!(aggregator.nowcast_aqi(now).unwrap().index, 53);
+
+        // Only the last 2 hours, with a sharp increase: the weight factor is
+        // clamped to 0.5, so the result is (40 + 0.5 * 10) / 1.5.
+        let mut aggregator = Aggregator::new(std::time::Duration::from_secs(60));
+        fill(
+            &mut aggregator,
+            now - Duration::hours(2),
+            now,
+            Duration::minutes(1),
+            |t| {
+                if t < now - Duration::hours(1) {
+                    10.0
+                } else {
+                    40.0
+                }
+            },
+        );
+        assert_eq!(aggregator.nowcast(Pollutant::Pm10, now), Some(30.0));
+
+        // Only 1 of the 3 most recent hours has data.
+        assert_eq!(
+            aggregator.nowcast(Pollutant::Pm10, now + Duration::hours(2)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_retention() {
+        let mut aggregator = Aggregator::new(std::time::Duration::from_secs(60));
+        fill(
+            &mut aggregator,
+            START,
+            START + Duration::hours(48),
+            Duration::minutes(1),
+            |_| 1.0,
+        );
+        assert_eq!(aggregator.samples.len(), 24 * 60 + 2);
+        // Out of order samples are ignored.
+        aggregator.push(&measurement(START, 5.0));
+        assert_eq!(
+            aggregator
+                .latest()
+                .unwrap()
+                .measurement
+                .mass_concentration_pm_1_0,
+            1.0
+        );
+    }
+}
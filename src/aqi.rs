@@ -0,0 +1,469 @@
+use super::measurement::*;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Standard {
+    /// US EPA Air Quality Index, using the PM2.5 breakpoints as revised in
+    /// 2024. See <https://www.airnow.gov/publications/air-quality-index/technical-assistance-document-for-reporting-the-daily-aqi/>
+    UsEpa,
+    /// European Common Air Quality Index (CAQI), hourly background grid. See
+    /// <https://www.airqualitynow.eu/about_indices_definition.php>
+    EuCaqi,
+    /// UK Daily Air Quality Index. See
+    /// <https://uk-air.defra.gov.uk/air-pollution/daqi>
+    UkDaqi,
+}
+
+impl Standard {
+    /// averaging_period is the period over which concentrations must be
+    /// averaged for the index to be meaningful: computing an index from
+    /// instantaneous values is only ever an approximation.
+    pub fn averaging_period(&self) -> Duration {
+        match self {
+            Standard::UsEpa | Standard::UkDaqi => Duration::from_secs(24 * 60 * 60),
+            Standard::EuCaqi => Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pollutant {
+    Pm2_5,
+    Pm10,
+}
+
+impl fmt::Display for Pollutant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pollutant::Pm2_5 => write!(f, "PM2.5"),
+            Pollutant::Pm10 => write!(f, "PM10"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aqi {
+    pub standard: Standard,
+    pub index: u32,
+    pub category: &'static str,
+    // e.g. #00E400
+    pub colour: &'static str,
+    // The pollutant with the highest sub-index, which determines the index.
+    pub dominant_pollutant: Pollutant,
+    pub pm2_5_index: u32,
+    pub pm10_index: u32,
+    pub averaging_period: Duration,
+}
+
+// Concentrations are in ug/m3. For the EPA and CAQI, the index is linearly
+// interpolated within a band. DAQI bands map onto a single index.
+struct Band {
+    concentration_low: f32,
+    concentration_high: f32,
+    index_low: u32,
+    index_high: u32,
+    category: &'static str,
+    colour: &'static str,
+}
+
+const fn band(
+    concentration_low: f32,
+    concentration_high: f32,
+    index_low: u32,
+    index_high: u32,
+    category: &'static str,
+    colour: &'static str,
+) -> Band {
+    Band {
+        concentration_low,
+        concentration_high,
+        index_low,
+        index_high,
+        category,
+        colour,
+    }
+}
+
+const EPA_PM2_5: [Band; 6] = [
+    band(0.0, 9.0, 0, 50, "Good", "#00E400"),
+    band(9.1, 35.4, 51, 100, "Moderate", "#FFFF00"),
+    band(
+        35.5,
+        55.4,
+        101,
+        150,
+        "Unhealthy for Sensitive Groups",
+        "#FF7E00",
+    ),
+    band(55.5, 125.4, 151, 200, "Unhealthy", "#FF0000"),
+    band(125.5, 225.4, 201, 300, "Very Unhealthy", "#8F3F97"),
+    band(225.5, 325.4, 301, 500, "Hazardous", "#7E0023"),
+];
+
+const EPA_PM10: [Band; 6] = [
+    band(0.0, 54.0, 0, 50, "Good", "#00E400"),
+    band(55.0, 154.0, 51, 100, "Moderate", "#FFFF00"),
+    band(
+        155.0,
+        254.0,
+        101,
+        150,
+        "Unhealthy for Sensitive Groups",
+        "#FF7E00",
+    ),
+    band(255.0, 354.0, 151, 200, "Unhealthy", "#FF0000"),
+    band(355.0, 424.0, 201, 300, "Very Unhealthy", "#8F3F97"),
+    band(425.0, 604.0, 301, 500, "Hazardous", "#7E0023"),
+];
+
+const CAQI_PM2_5: [Band; 5] = [
+    band(0.0, 15.0, 0, 25, "Very low", "#79BC6A"),
+    band(15.0, 30.0, 25, 50, "Low", "#BBCF4C"),
+    band(30.0, 55.0, 50, 75, "Medium", "#EEC20B"),
+    band(55.0, 110.0, 75, 100, "High", "#F29305"),
+    band(110.0, f32::INFINITY, 100, 100, "Very high", "#E8416F"),
+];
+
+const CAQI_PM10: [Band; 5] = [
+    band(0.0, 25.0, 0, 25, "Very low", "#79BC6A"),
+    band(25.0, 50.0, 25, 50, "Low", "#BBCF4C"),
+    band(50.0, 90.0, 50, 75, "Medium", "#EEC20B"),
+    band(90.0, 180.0, 75, 100, "High", "#F29305"),
+    band(180.0, f32::INFINITY, 100, 100, "Very high", "#E8416F"),
+];
+
+const DAQI_PM2_5: [Band; 10] = [
+    band(0.0, 11.0, 1, 1, "Low", "#9CFF9C"),
+    band(12.0, 23.0, 2, 2, "Low", "#31FF00"),
+    band(24.0, 35.0, 3, 3, "Low", "#31CF00"),
+    band(36.0, 41.0, 4, 4, "Moderate", "#FFFF00"),
+    band(42.0, 47.0, 5, 5, "Moderate", "#FFCF00"),
+    band(48.0, 53.0, 6, 6, "Moderate", "#FF9A00"),
+    band(54.0, 58.0, 7, 7, "High", "#FF6464"),
+    band(59.0, 64.0, 8, 8, "High", "#FF0000"),
+    band(65.0, 70.0, 9, 9, "High", "#990000"),
+    band(71.0, f32::INFINITY, 10, 10, "Very High", "#CE30FF"),
+];
+
+const DAQI_PM10: [Band; 10] = [
+    band(0.0, 16.0, 1, 1, "Low", "#9CFF9C"),
+    band(17.0, 33.0, 2, 2, "Low", "#31FF00"),
+    band(34.0, 50.0, 3, 3, "Low", "#31CF00"),
+    band(51.0, 58.0, 4, 4, "Moderate", "#FFFF00"),
+    band(59.0, 66.0, 5, 5, "Moderate", "#FFCF00"),
+    band(67.0, 75.0, 6, 6, "Moderate", "#FF9A00"),
+    band(76.0, 83.0, 7, 7, "High", "#FF6464"),
+    band(84.0, 91.0, 8, 8, "High", "#FF0000"),
+    band(92.0, 100.0, 9, 9, "High", "#990000"),
+    band(101.0, f32::INFINITY, 10, 10, "Very High", "#CE30FF"),
+];
+
+fn tables(standard: Standard, pollutant: Pollutant) -> &'static [Band] {
+    match (standard, pollutant) {
+        (Standard::UsEpa, Pollutant::Pm2_5) => &EPA_PM2_5,
+        (Standard::UsEpa, Pollutant::Pm10) => &EPA_PM10,
+        (Standard::EuCaqi, Pollutant::Pm2_5) => &CAQI_PM2_5,
+        (Standard::EuCaqi, Pollutant::Pm10) => &CAQI_PM10,
+        (Standard::UkDaqi, Pollutant::Pm2_5) => &DAQI_PM2_5,
+        (Standard::UkDaqi, Pollutant::Pm10) => &DAQI_PM10,
+    }
+}
+
+/// ConcentrationBand is the range of concentrations (in ug/m3) that fall into
+/// one category of an index, e.g. for colouring the background of a chart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConcentrationBand {
+    pub lower: f32,
+    pub upper: f32,
+    pub category: &'static str,
+    pub colour: &'static str,
+}
+
+/// concentration_bands returns the bands of an index for one pollutant. Unlike
+/// the breakpoint tables, the bands are contiguous (each starts where the
+/// previous one ends), and the last one is unbounded.
+pub fn concentration_bands(standard: Standard, pollutant: Pollutant) -> Vec<ConcentrationBand> {
+    let bands = tables(standard, pollutant);
+    bands
+        .iter()
+        .enumerate()
+        .map(|(i, band)| ConcentrationBand {
+            lower: if i == 0 {
+                0.0
+            } else {
+                bands[i - 1].concentration_high
+            },
+            upper: if i == bands.len() - 1 {
+                f32::INFINITY
+            } else {
+                band.concentration_high
+            },
+            category: band.category,
+            colour: band.colour,
+        })
+        .collect()
+}
+
+// Each standard specifies its own precision, and bands are defined such that
+// there are no gaps after applying it.
+fn normalise(standard: Standard, pollutant: Pollutant, concentration: f32) -> f32 {
+    match (standard, pollutant) {
+        (Standard::UsEpa, Pollutant::Pm2_5) => (concentration * 10.0).trunc() / 10.0,
+        (Standard::UsEpa, Pollutant::Pm10) => concentration.trunc(),
+        (Standard::EuCaqi, _) => concentration,
+        (Standard::UkDaqi, _) => concentration.round(),
+    }
+}
+
+fn sub_index(
+    standard: Standard,
+    pollutant: Pollutant,
+    concentration: f32,
+) -> Result<(u32, &'static Band), String> {
+    if concentration.is_nan() || concentration < 0.0 {
+        return Result::Err(format!(
+            "invalid {} concentration {}",
+            pollutant, concentration
+        ));
+    }
+    let concentration = normalise(standard, pollutant, concentration);
+    let bands = tables(standard, pollutant);
+    // Values beyond the last band are capped, e.g. the EPA AQI stops at 500.
+    let band = bands
+        .iter()
+        .find(|b| concentration <= b.concentration_high)
+        .unwrap_or(&bands[bands.len() - 1]);
+    let concentration = concentration.min(band.concentration_high);
+    let index = if band.index_low == band.index_high {
+        band.index_low as f32
+    } else {
+        (band.index_high - band.index_low) as f32
+            / (band.concentration_high - band.concentration_low)
+            * (concentration - band.concentration_low)
+            + band.index_low as f32
+    };
+    Result::Ok((index.round() as u32, band))
+}
+
+/// compute computes an index from PM2.5 and PM10 mass concentrations (in
+/// ug/m3), which should be averaged over Standard::averaging_period.
+pub fn compute(standard: Standard, pm2_5: f32, pm10: f32) -> Result<Aqi, String> {
+    let (pm2_5_index, pm2_5_band) = sub_index(standard, Pollutant::Pm2_5, pm2_5)?;
+    let (pm10_index, pm10_band) = sub_index(standard, Pollutant::Pm10, pm10)?;
+    let (index, band, dominant_pollutant) = if pm10_index > pm2_5_index {
+        (pm10_index, pm10_band, Pollutant::Pm10)
+    } else {
+        (pm2_5_index, pm2_5_band, Pollutant::Pm2_5)
+    };
+    Result::Ok(Aqi {
+        standard,
+        index,
+        category: band.category,
+        colour: band.colour,
+        dominant_pollutant,
+        pm2_5_index,
+        pm10_index,
+        averaging_period: standard.averaging_period(),
+    })
+}
+
+/// from_measurement computes an index from a single (instantaneous)
+/// measurement, which is only an approximation (see
+/// Standard::averaging_period).
+pub fn from_measurement(standard: Standard, measurement: &Measurement) -> Result<Aqi, String> {
+    compute(
+        standard,
+        measurement.mass_concentration_pm_2_5,
+        measurement.mass_concentration_pm_10_0,
+    )
+}
+
+/// from_series computes an index from the mean of a series of measurements.
+/// It's up to the caller to supply a series that covers the standard's
+/// averaging period.
+pub fn from_series(
+    standard: Standard,
+    measurements: &[TimestampedMeasurement],
+) -> Result<Aqi, String> {
+    if measurements.is_empty() {
+        return Result::Err(String::from("no measurements"));
+    }
+    let mean = |channel: Channel| {
+        measurements
+            .iter()
+            .map(|m| m.measurement.get(channel) as f64)
+            .sum::<f64>()
+            / measurements.len() as f64
+    };
+    compute(
+        standard,
+        mean(Channel::MassPm2_5) as f32,
+        mean(Channel::MassPm10_0) as f32,
+    )
+}
+
+/// rgb parses a category colour (e.g. #00E400) into its components.
+pub fn rgb(colour: &str) -> Option<(u8, u8, u8)> {
+    let hex = colour.strip_prefix('#').filter(|hex| hex.len() == 6)?;
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute() {
+        struct TestCase {
+            standard: Standard,
+            pm2_5: f32,
+            pm10: f32,
+            expected_index: u32,
+            expected_category: &'static str,
+            expected_dominant_pollutant: Pollutant,
+        }
+        let tests = [
+            TestCase {
+                standard: Standard::UsEpa,
+                pm2_5: 0.0,
+                pm10: 0.0,
+                expected_index: 0,
+                expected_category: "Good",
+                expected_dominant_pollutant: Pollutant::Pm2_5,
+            },
+            TestCase {
+                // 9.05 is truncated to 9.0, which is still Good.
+                standard: Standard::UsEpa,
+                pm2_5: 9.05,
+                pm10: 10.0,
+                expected_index: 50,
+                expected_category: "Good",
+                expected_dominant_pollutant: Pollutant::Pm2_5,
+            },
+            TestCase {
+                standard: Standard::UsEpa,
+                pm2_5: 35.9,
+                pm10: 40.0,
+                expected_index: 102,
+                expected_category: "Unhealthy for Sensitive Groups",
+                expected_dominant_pollutant: Pollutant::Pm2_5,
+            },
+            TestCase {
+                standard: Standard::UsEpa,
+                pm2_5: 5.0,
+                pm10: 160.9,
+                expected_index: 103,
+                expected_category: "Unhealthy for Sensitive Groups",
+                expected_dominant_pollutant: Pollutant::Pm10,
+            },
+            TestCase {
+                // Beyond the AQI.
+                standard: Standard::UsEpa,
+                pm2_5: 1000.0,
+                pm10: 0.0,
+                expected_index: 500,
+                expected_category: "Hazardous",
+                expected_dominant_pollutant: Pollutant::Pm2_5,
+            },
+            TestCase {
+                standard: Standard::EuCaqi,
+                pm2_5: 10.0,
+                pm10: 70.0,
+                expected_index: 63,
+                expected_category: "Medium",
+                expected_dominant_pollutant: Pollutant::Pm10,
+            },
+            TestCase {
+                standard: Standard::EuCaqi,
+                pm2_5: 200.0,
+                pm10: 70.0,
+                expected_index: 100,
+                expected_category: "Very high",
+                expected_dominant_pollutant: Pollutant::Pm2_5,
+            },
+            TestCase {
+                standard: Standard::UkDaqi,
+                pm2_5: 23.4,
+                pm10: 10.0,
+                expected_index: 2,
+                expected_category: "Low",
+                expected_dominant_pollutant: Pollutant::Pm2_5,
+            },
+            TestCase {
+                // 23.5 rounds up into band 3.
+                standard: Standard::UkDaqi,
+                pm2_5: 23.5,
+                pm10: 10.0,
+                expected_index: 3,
+                expected_category: "Low",
+                expected_dominant_pollutant: Pollutant::Pm2_5,
+            },
+            TestCase {
+                standard: Standard::UkDaqi,
+                pm2_5: 23.5,
+                pm10: 55.0,
+                expected_index: 4,
+                expected_category: "Moderate",
+                expected_dominant_pollutant: Pollutant::Pm10,
+            },
+            TestCase {
+                standard: Standard::UkDaqi,
+                pm2_5: 80.0,
+                pm10: 55.0,
+                expected_index: 10,
+                expected_category: "Very High",
+                expected_dominant_pollutant: Pollutant::Pm2_5,
+            },
+        ];
+        for case in tests {
+            let aqi = compute(case.standard, case.pm2_5, case.pm10).unwrap();
+            assert_eq!(
+                (aqi.index, aqi.category, aqi.dominant_pollutant),
+                (
+                    case.expected_index,
+                    case.expected_category,
+                    case.expected_dominant_pollutant
+                ),
+                "{:?} pm2.5={} pm10={}",
+                case.standard,
+                case.pm2_5,
+                case.pm10
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(compute(Standard::UsEpa, -1.0, 0.0).is_err());
+        assert!(compute(Standard::UsEpa, 0.0, f32::NAN).is_err());
+        assert!(from_series(Standard::UsEpa, &[]).is_err());
+    }
+
+    #[test]
+    fn test_from_series() {
+        let measurement = |pm2_5: f32| TimestampedMeasurement {
+            time: time::OffsetDateTime::UNIX_EPOCH,
+            measurement: Measurement {
+                mass_concentration_pm_2_5: pm2_5,
+                mass_concentration_pm_10_0: pm2_5,
+                ..Default::default()
+            },
+        };
+        let aqi = from_series(Standard::UsEpa, &[measurement(10.0), measurement(30.0)]).unwrap();
+        assert_eq!(aqi.index, 71);
+        assert_eq!(aqi.colour, "#FFFF00");
+        assert_eq!(aqi.averaging_period, Duration::from_secs(86400));
+    }
+
+    #[test]
+    fn test_concentration_bands() {
+        let bands = concentration_bands(Standard::UsEpa, Pollutant::Pm2_5);
+        assert_eq!(bands.len(), 6);
+        assert_eq!((bands[0].lower, bands[0].upper), (0.0, 9.0));
+        assert_eq!((bands[1].lower, bands[1].upper), (9.0, 35.4));
+        assert_eq!(bands[5].upper, f32::INFINITY);
+        assert_eq!(bands[5].category, "Hazardous");
+    }
+}
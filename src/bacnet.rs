@@ -0,0 +1,1751 @@
+use super::device::{DeviceStatus, VersionInfo};
+use super::measurement::*;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The standard BACnet/IP UDP port (0xBAC0).
+pub const DEFAULT_PORT: u16 = 47808;
+
+/// The highest device instance, 4194303 is reserved as a wildcard.
+pub const MAX_INSTANCE: u32 = 4194302;
+const WILDCARD_INSTANCE: u32 = 4194303;
+
+// sps30rs doesn't have a registered vendor ID, 0 is ASHRAE's.
+const VENDOR_ID: u32 = 0;
+
+// BACnet Virtual Link Control, which wraps every BACnet/IP packet (Annex J).
+const BVLC_TYPE: u8 = 0x81;
+const BVLC_RESULT: u8 = 0x00;
+const BVLC_FORWARDED_NPDU: u8 = 0x04;
+const BVLC_ORIGINAL_UNICAST_NPDU: u8 = 0x0A;
+const BVLC_ORIGINAL_BROADCAST_NPDU: u8 = 0x0B;
+
+const NPDU_VERSION: u8 = 0x01;
+const NPDU_NETWORK_MESSAGE: u8 = 0x80;
+const NPDU_DESTINATION: u8 = 0x20;
+const NPDU_SOURCE: u8 = 0x08;
+const NPDU_EXPECTING_REPLY: u8 = 0x04;
+const GLOBAL_BROADCAST_NETWORK: u16 = 0xFFFF;
+
+// APDU types, in the high nibble of the first byte.
+const CONFIRMED_REQUEST: u8 = 0x00;
+const UNCONFIRMED_REQUEST: u8 = 0x10;
+const SIMPLE_ACK: u8 = 0x20;
+const COMPLEX_ACK: u8 = 0x30;
+const ERROR: u8 = 0x50;
+const REJECT: u8 = 0x60;
+const ABORT: u8 = 0x70;
+const SEGMENTED_MESSAGE: u8 = 0x08;
+const ABORT_FROM_SERVER: u8 = 0x01;
+
+// Confirmed services.
+const CONFIRMED_COV_NOTIFICATION: u8 = 1;
+const SUBSCRIBE_COV: u8 = 5;
+const READ_PROPERTY: u8 = 12;
+
+// Unconfirmed services.
+const I_AM: u8 = 0;
+const UNCONFIRMED_COV_NOTIFICATION: u8 = 2;
+const WHO_IS: u8 = 8;
+
+const REJECT_INVALID_TAG: u8 = 4;
+const REJECT_MISSING_REQUIRED_PARAMETER: u8 = 5;
+const REJECT_TOO_MANY_ARGUMENTS: u8 = 7;
+const REJECT_UNRECOGNIZED_SERVICE: u8 = 9;
+const ABORT_SEGMENTATION_NOT_SUPPORTED: u8 = 4;
+
+// The largest APDU that fits in an Ethernet frame, and how it's encoded in
+// confirmed requests.
+const MAX_APDU: usize = 1476;
+const MAX_APDU_CODE: u8 = 5;
+const NO_SEGMENTATION: u32 = 3;
+
+const MAX_SUBSCRIPTIONS: usize = 64;
+
+const ANALOG_INPUT: u16 = 0;
+const BINARY_INPUT: u16 = 3;
+const DEVICE: u16 = 8;
+
+const PROP_ACTIVE_TEXT: u32 = 4;
+const PROP_APDU_TIMEOUT: u32 = 11;
+const PROP_APPLICATION_SOFTWARE_VERSION: u32 = 12;
+const PROP_COV_INCREMENT: u32 = 22;
+const PROP_DESCRIPTION: u32 = 28;
+const PROP_DEVICE_ADDRESS_BINDING: u32 = 30;
+const PROP_EVENT_STATE: u32 = 36;
+const PROP_FIRMWARE_REVISION: u32 = 44;
+const PROP_INACTIVE_TEXT: u32 = 46;
+const PROP_MAX_APDU_LENGTH_ACCEPTED: u32 = 62;
+const PROP_MODEL_NAME: u32 = 70;
+const PROP_NUMBER_OF_APDU_RETRIES: u32 = 73;
+const PROP_OBJECT_IDENTIFIER: u32 = 75;
+const PROP_OBJECT_LIST: u32 = 76;
+const PROP_OBJECT_NAME: u32 = 77;
+const PROP_OBJECT_TYPE: u32 = 79;
+const PROP_OUT_OF_SERVICE: u32 = 81;
+const PROP_POLARITY: u32 = 84;
+const PROP_PRESENT_VALUE: u32 = 85;
+const PROP_PROTOCOL_OBJECT_TYPES_SUPPORTED: u32 = 96;
+const PROP_PROTOCOL_SERVICES_SUPPORTED: u32 = 97;
+const PROP_PROTOCOL_VERSION: u32 = 98;
+const PROP_RELIABILITY: u32 = 103;
+const PROP_SEGMENTATION_SUPPORTED: u32 = 107;
+const PROP_STATUS_FLAGS: u32 = 111;
+const PROP_SYSTEM_STATUS: u32 = 112;
+const PROP_UNITS: u32 = 117;
+const PROP_VENDOR_IDENTIFIER: u32 = 120;
+const PROP_VENDOR_NAME: u32 = 121;
+const PROP_PROTOCOL_REVISION: u32 = 139;
+const PROP_DATABASE_REVISION: u32 = 155;
+const PROP_PROPERTY_LIST: u32 = 371;
+
+// The properties of each object type, Property_List omits the first three
+// and itself.
+const DEVICE_PROPERTIES: [u32; 22] = [
+    PROP_OBJECT_IDENTIFIER,
+    PROP_OBJECT_NAME,
+    PROP_OBJECT_TYPE,
+    PROP_SYSTEM_STATUS,
+    PROP_VENDOR_NAME,
+    PROP_VENDOR_IDENTIFIER,
+    PROP_MODEL_NAME,
+    PROP_FIRMWARE_REVISION,
+    PROP_APPLICATION_SOFTWARE_VERSION,
+    PROP_DESCRIPTION,
+    PROP_PROTOCOL_VERSION,
+    PROP_PROTOCOL_REVISION,
+    PROP_PROTOCOL_SERVICES_SUPPORTED,
+    PROP_PROTOCOL_OBJECT_TYPES_SUPPORTED,
+    PROP_OBJECT_LIST,
+    PROP_MAX_APDU_LENGTH_ACCEPTED,
+    PROP_SEGMENTATION_SUPPORTED,
+    PROP_APDU_TIMEOUT,
+    PROP_NUMBER_OF_APDU_RETRIES,
+    PROP_DEVICE_ADDRESS_BINDING,
+    PROP_DATABASE_REVISION,
+    PROP_PROPERTY_LIST,
+];
+const ANALOG_INPUT_PROPERTIES: [u32; 12] = [
+    PROP_OBJECT_IDENTIFIER,
+    PROP_OBJECT_NAME,
+    PROP_OBJECT_TYPE,
+    PROP_PRESENT_VALUE,
+    PROP_DESCRIPTION,
+    PROP_STATUS_FLAGS,
+    PROP_EVENT_STATE,
+    PROP_RELIABILITY,
+    PROP_OUT_OF_SERVICE,
+    PROP_UNITS,
+    PROP_COV_INCREMENT,
+    PROP_PROPERTY_LIST,
+];
+const BINARY_INPUT_PROPERTIES: [u32; 13] = [
+    PROP_OBJECT_IDENTIFIER,
+    PROP_OBJECT_NAME,
+    PROP_OBJECT_TYPE,
+    PROP_PRESENT_VALUE,
+    PROP_DESCRIPTION,
+    PROP_STATUS_FLAGS,
+    PROP_EVENT_STATE,
+    PROP_RELIABILITY,
+    PROP_OUT_OF_SERVICE,
+    PROP_POLARITY,
+    PROP_ACTIVE_TEXT,
+    PROP_INACTIVE_TEXT,
+    PROP_PROPERTY_LIST,
+];
+
+// Bits of Protocol_Services_Supported, for the services that are executed.
+const SERVICES_SUPPORTED_LENGTH: usize = 41;
+const SERVICE_BIT_SUBSCRIBE_COV: usize = 5;
+const SERVICE_BIT_READ_PROPERTY: usize = 12;
+const SERVICE_BIT_WHO_IS: usize = 34;
+
+// Engineering units. There is no unit for number concentrations, so those
+// are no-units (the unit is in the description).
+const UNITS_NO_UNITS: u32 = 95;
+const UNITS_MICROMETERS: u32 = 194;
+const UNITS_MICROGRAMS_PER_CUBIC_METER: u32 = 219;
+
+const RELIABILITY_NO_FAULT_DETECTED: u32 = 0;
+const RELIABILITY_UNRELIABLE_OTHER: u32 = 7;
+
+// The binary inputs, by instance: object name, description and the flag.
+type StatusFlag = fn(&DeviceStatus) -> bool;
+const BINARY_INPUTS: [(&str, &str, StatusFlag); 3] = [
+    (
+        "fan_speed_warning",
+        "Fan speed out of range, measurements may be inaccurate",
+        |status| status.fan_speed_warning,
+    ),
+    (
+        "laser_error",
+        "Laser current out of range, measurements are unreliable",
+        |status| status.laser_error,
+    ),
+    ("fan_error", "Fan switched on but not turning", |status| {
+        status.fan_error
+    }),
+];
+
+/// ServiceError is an error class and code, as returned in Error PDUs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ServiceError {
+    class: u32,
+    code: u32,
+}
+
+const UNKNOWN_OBJECT: ServiceError = ServiceError { class: 1, code: 31 };
+const OPTIONAL_FUNCTIONALITY_NOT_SUPPORTED: ServiceError = ServiceError { class: 1, code: 45 };
+const UNKNOWN_PROPERTY: ServiceError = ServiceError { class: 2, code: 32 };
+const INVALID_ARRAY_INDEX: ServiceError = ServiceError { class: 2, code: 42 };
+const PROPERTY_IS_NOT_AN_ARRAY: ServiceError = ServiceError { class: 2, code: 50 };
+const COV_SUBSCRIPTION_FAILED: ServiceError = ServiceError { class: 5, code: 43 };
+
+// Why a confirmed request failed.
+#[derive(Debug, PartialEq)]
+enum Failure {
+    Reject(u8),
+    Error(ServiceError),
+}
+
+const INVALID_TAG: Failure = Failure::Reject(REJECT_INVALID_TAG);
+
+impl From<ServiceError> for Failure {
+    fn from(error: ServiceError) -> Self {
+        Failure::Error(error)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ObjectId {
+    object_type: u16,
+    instance: u32,
+}
+
+impl ObjectId {
+    fn new(object_type: u16, instance: u32) -> Self {
+        ObjectId {
+            object_type,
+            instance,
+        }
+    }
+
+    fn encode(&self) -> [u8; 4] {
+        ((self.object_type as u32) << 22 | self.instance).to_be_bytes()
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let value = u32::from_be_bytes(data.try_into().ok()?);
+        Some(ObjectId::new((value >> 22) as u16, value & 0x3F_FFFF))
+    }
+}
+
+/// Value is an application tagged value.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Boolean(bool),
+    Unsigned(u32),
+    Real(f32),
+    CharacterString(String),
+    BitString(Vec<bool>),
+    Enumerated(u32),
+    ObjectIdentifier(ObjectId),
+}
+
+impl Value {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            // The value is in the length field.
+            Value::Boolean(value) => buf.push(0x10 | *value as u8),
+            Value::Unsigned(value) => encode_tag(buf, 2, false, &unsigned_bytes(*value)),
+            Value::Real(value) => encode_tag(buf, 4, false, &value.to_be_bytes()),
+            Value::CharacterString(value) => {
+                // Character set 0 is UTF-8.
+                let mut data = vec![0];
+                data.extend_from_slice(value.as_bytes());
+                encode_tag(buf, 7, false, &data);
+            }
+            Value::BitString(bits) => {
+                // The first byte is the number of unused bits in the last.
+                let mut data = vec![((8 - bits.len() % 8) % 8) as u8];
+                for chunk in bits.chunks(8) {
+                    data.push(
+                        chunk
+                            .iter()
+                            .enumerate()
+                            .fold(0, |byte, (i, bit)| byte | (*bit as u8) << (7 - i)),
+                    );
+                }
+                encode_tag(buf, 8, false, &data);
+            }
+            Value::Enumerated(value) => encode_tag(buf, 9, false, &unsigned_bytes(*value)),
+            Value::ObjectIdentifier(id) => encode_tag(buf, 12, false, &id.encode()),
+        }
+    }
+}
+
+// A property's value: most are a single value, arrays can also be read by
+// index, lists can't.
+enum Property {
+    Single(Value),
+    Array(Vec<Value>),
+    List(Vec<Value>),
+}
+
+// Unsigned integers are encoded in as few bytes as possible.
+fn unsigned_bytes(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let zeros = bytes[..3].iter().take_while(|b| **b == 0).count();
+    bytes[zeros..].to_vec()
+}
+
+fn decode_unsigned(data: &[u8]) -> Option<u32> {
+    match data.len() {
+        1..=4 => Some(data.iter().fold(0, |value, b| value << 8 | *b as u32)),
+        _ => None,
+    }
+}
+
+// Encodes a tag and its data. Tag numbers above 14 and lengths above 4 use
+// the extended forms.
+fn encode_tag(buf: &mut Vec<u8>, number: u8, context: bool, data: &[u8]) {
+    let class = if context { 0x08 } else { 0x00 };
+    let number_bits = if number <= 14 { number << 4 } else { 0xF0 };
+    let length_bits = if data.len() <= 4 { data.len() as u8 } else { 5 };
+    buf.push(number_bits | class | length_bits);
+    if number > 14 {
+        buf.push(number);
+    }
+    match data.len() {
+        0..=4 => {}
+        5..=253 => buf.push(data.len() as u8),
+        254..=65535 => {
+            buf.push(254);
+            buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(255);
+            buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        }
+    }
+    buf.extend_from_slice(data);
+}
+
+fn encode_context(buf: &mut Vec<u8>, number: u8, data: &[u8]) {
+    encode_tag(buf, number, true, data);
+}
+
+fn encode_opening(buf: &mut Vec<u8>, number: u8) {
+    buf.push(number << 4 | 0x0E);
+}
+
+fn encode_closing(buf: &mut Vec<u8>, number: u8) {
+    buf.push(number << 4 | 0x0F);
+}
+
+// Decodes the context tagged parameters of a request, in order.
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Decoder { data, pos: 0 }
+    }
+
+    // Returns the n bytes at pos, advancing it.
+    fn take(&self, pos: &mut usize, n: usize) -> Result<&'a [u8], Failure> {
+        let bytes = self.data.get(*pos..*pos + n).ok_or(INVALID_TAG)?;
+        *pos += n;
+        Ok(bytes)
+    }
+
+    // Returns the content of the next tag if it is context tag number
+    // (consuming it), or None if it isn't, e.g. if it's an optional
+    // parameter that was left out.
+    fn context(&mut self, number: u8) -> Result<Option<&'a [u8]>, Failure> {
+        let Some(&header) = self.data.get(self.pos) else {
+            return Ok(None);
+        };
+        let mut pos = self.pos + 1;
+        let tag_number = match header >> 4 {
+            15 => self.take(&mut pos, 1)?[0],
+            tag_number => tag_number,
+        };
+        if header & 0x08 == 0 || tag_number != number {
+            return Ok(None);
+        }
+        let length = match header & 0x07 {
+            length @ 0..=4 => length as usize,
+            5 => match self.take(&mut pos, 1)?[0] {
+                254 => decode_unsigned(self.take(&mut pos, 2)?).ok_or(INVALID_TAG)? as usize,
+                255 => decode_unsigned(self.take(&mut pos, 4)?).ok_or(INVALID_TAG)? as usize,
+                length => length as usize,
+            },
+            // Opening and closing tags, which none of the supported
+            // requests have.
+            _ => return Err(INVALID_TAG),
+        };
+        let content = self.take(&mut pos, length)?;
+        self.pos = pos;
+        Ok(Some(content))
+    }
+
+    fn unsigned(&mut self, number: u8) -> Result<Option<u32>, Failure> {
+        self.context(number)?
+            .map(|data| decode_unsigned(data).ok_or(INVALID_TAG))
+            .transpose()
+    }
+
+    fn object_id(&mut self, number: u8) -> Result<Option<ObjectId>, Failure> {
+        self.context(number)?
+            .map(|data| ObjectId::decode(data).ok_or(INVALID_TAG))
+            .transpose()
+    }
+
+    fn boolean(&mut self, number: u8) -> Result<Option<bool>, Failure> {
+        self.context(number)?
+            .map(|data| match data {
+                [value] => Ok(*value != 0),
+                _ => Err(INVALID_TAG),
+            })
+            .transpose()
+    }
+
+    fn finish(&self) -> Result<(), Failure> {
+        if self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(Failure::Reject(REJECT_TOO_MANY_ARGUMENTS))
+        }
+    }
+}
+
+fn required<T>(value: Option<T>) -> Result<T, Failure> {
+    value.ok_or(Failure::Reject(REJECT_MISSING_REQUIRED_PARAMETER))
+}
+
+// The largest APDU a client accepts, from the low nibble of the second byte
+// of its confirmed requests.
+fn max_apdu(code: u8) -> usize {
+    match code & 0x0F {
+        0 => 50,
+        1 => 128,
+        2 => 206,
+        3 => 480,
+        4 => 1024,
+        _ => MAX_APDU,
+    }
+}
+
+// The network number and MAC address of a peer behind a router.
+type Route = (u16, Vec<u8>);
+
+/// Peer is where a request came from, and so where to send responses (and
+/// notifications) to.
+#[derive(Clone, Debug, PartialEq)]
+struct Peer {
+    addr: SocketAddr,
+    /// Set for peers behind a router, addr is then the router's.
+    route: Option<Route>,
+}
+
+// Decodes an NPDU, returning the source network and MAC address (for
+// requests from behind a router) and the APDU. Network layer messages and
+// messages for other networks are None, as this isn't a router.
+fn decode_npdu(npdu: &[u8]) -> Option<(Option<Route>, &[u8])> {
+    let (&version, &control) = (npdu.first()?, npdu.get(1)?);
+    if version != NPDU_VERSION || control & NPDU_NETWORK_MESSAGE != 0 {
+        return None;
+    }
+    let address = |pos: usize| -> Option<(u16, &[u8])> {
+        let network = u16::from_be_bytes(npdu.get(pos..pos + 2)?.try_into().ok()?);
+        let length = *npdu.get(pos + 2)? as usize;
+        Some((network, npdu.get(pos + 3..pos + 3 + length)?))
+    };
+    let mut pos = 2;
+    let destination = control & NPDU_DESTINATION != 0;
+    if destination {
+        let (network, mac) = address(pos)?;
+        if network != GLOBAL_BROADCAST_NETWORK {
+            return None;
+        }
+        pos += 3 + mac.len();
+    }
+    let mut route = None;
+    if control & NPDU_SOURCE != 0 {
+        let (network, mac) = address(pos)?;
+        pos += 3 + mac.len();
+        route = Some((network, mac.to_vec()));
+    }
+    if destination {
+        // The hop count.
+        pos += 1;
+    }
+    Some((route, npdu.get(pos..)?))
+}
+
+// Wraps an APDU in an NPDU (routed if the peer is behind a router) and an
+// Original-Unicast-NPDU.
+fn encode_packet(peer: &Peer, apdu: &[u8]) -> Vec<u8> {
+    let mut control = 0;
+    if apdu[0] & 0xF0 == CONFIRMED_REQUEST {
+        control |= NPDU_EXPECTING_REPLY;
+    }
+    let mut npdu = vec![NPDU_VERSION, control];
+    if let Some((network, mac)) = &peer.route {
+        npdu[1] |= NPDU_DESTINATION;
+        npdu.extend_from_slice(&network.to_be_bytes());
+        npdu.push(mac.len() as u8);
+        npdu.extend_from_slice(mac);
+        // The hop count.
+        npdu.push(255);
+    }
+    npdu.extend_from_slice(apdu);
+
+    let mut packet = vec![BVLC_TYPE, BVLC_ORIGINAL_UNICAST_NPDU];
+    packet.extend_from_slice(&(npdu.len() as u16 + 4).to_be_bytes());
+    packet.extend_from_slice(&npdu);
+    packet
+}
+
+/// default_instance derives a device instance from a serial number, so that
+/// it's stable across restarts and most likely different for each sensor on a
+/// site.
+pub fn default_instance(serial: &str) -> u32 {
+    // FNV-1a.
+    let hash = serial.bytes().fold(0x811C_9DC5_u32, |hash, b| {
+        (hash ^ b as u32).wrapping_mul(0x0100_0193)
+    });
+    hash % (MAX_INSTANCE + 1)
+}
+
+struct Subscription {
+    peer: Peer,
+    process_id: u32,
+    object: ObjectId,
+    confirmed: bool,
+    // None if the subscription doesn't expire.
+    expires: Option<Instant>,
+    // What was last notified.
+    present_value: Value,
+    status_flags: Value,
+}
+
+struct State {
+    latest: Option<Measurement>,
+    status: Option<DeviceStatus>,
+    subscriptions: Vec<Subscription>,
+    invoke_id: u8,
+    socket: Option<Arc<UdpSocket>>,
+}
+
+/// BacnetServer is a BACnet/IP device, for building automation systems.
+///
+/// Besides the Device object, each channel is an Analog Input (instance 0-9,
+/// in Channel::ALL order, with engineering units where BACnet has them), and
+/// the device status register flags are Binary Inputs (instance 0 fan speed
+/// warning, 1 laser error, 2 fan error). Analog Inputs are in fault until the
+/// first measurement and while the device status has a laser or fan error,
+/// Binary Inputs are in fault while the status is unknown (e.g. the firmware
+/// doesn't support the status register).
+///
+/// Who-Is, ReadProperty and SubscribeCOV are supported, without
+/// segmentation. I-Am responses are sent to the requester rather than
+/// broadcast. COV notifications are sent when the present value changes by at
+/// least COV_Increment (1 for concentrations, 0.05um for the typical particle
+/// size) or the status flags change. Confirmed notifications aren't
+/// retransmitted if they aren't acknowledged.
+#[derive(Clone)]
+pub struct BacnetServer {
+    instance: u32,
+    serial: Arc<String>,
+    version: VersionInfo,
+    state: Arc<Mutex<State>>,
+}
+
+impl BacnetServer {
+    /// instance is the device instance, which must be unique on the BACnet
+    /// network and at most MAX_INSTANCE (see default_instance).
+    pub fn new(instance: u32, serial: &str, version: VersionInfo) -> Self {
+        BacnetServer {
+            instance,
+            serial: Arc::new(String::from(serial)),
+            version,
+            state: Arc::new(Mutex::new(State {
+                latest: None,
+                status: None,
+                subscriptions: vec![],
+                invoke_id: 0,
+                socket: None,
+            })),
+        }
+    }
+
+    fn device(&self) -> ObjectId {
+        ObjectId::new(DEVICE, self.instance)
+    }
+
+    fn objects(&self) -> Vec<ObjectId> {
+        let mut objects = vec![self.device()];
+        objects.extend((0..Channel::ALL.len()).map(|i| ObjectId::new(ANALOG_INPUT, i as u32)));
+        objects.extend((0..BINARY_INPUTS.len()).map(|i| ObjectId::new(BINARY_INPUT, i as u32)));
+        objects
+    }
+
+    fn properties(&self, object: ObjectId) -> Option<&'static [u32]> {
+        if !self.objects().contains(&object) {
+            return None;
+        }
+        match object.object_type {
+            DEVICE => Some(&DEVICE_PROPERTIES),
+            ANALOG_INPUT => Some(&ANALOG_INPUT_PROPERTIES),
+            _ => Some(&BINARY_INPUT_PROPERTIES),
+        }
+    }
+
+    /// update records the device status (None if unknown), and the
+    /// measurement if the last read was successful, and sends COV
+    /// notifications.
+    pub fn update(
+        &self,
+        measurement: Option<&TimestampedMeasurement>,
+        status: Option<DeviceStatus>,
+    ) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if let Some(measurement) = measurement {
+            state.latest = Some(measurement.measurement);
+        }
+        state.status = status;
+        state
+            .subscriptions
+            .retain(|subscription| subscription.expires.is_none_or(|expires| expires > now));
+
+        let mut packets = vec![];
+        for i in 0..state.subscriptions.len() {
+            let object = state.subscriptions[i].object;
+            let present_value = self.present_value(&state, object);
+            let status_flags = self.status_flags(&state, object);
+            let subscription = &mut state.subscriptions[i];
+            let changed = match (&subscription.present_value, &present_value) {
+                (Value::Real(old), Value::Real(new)) => {
+                    (new - old).abs() >= cov_increment(Channel::ALL[object.instance as usize])
+                }
+                (old, new) => old != new,
+            };
+            if changed || subscription.status_flags != status_flags {
+                subscription.present_value = present_value;
+                subscription.status_flags = status_flags;
+                let peer = subscription.peer.clone();
+                packets.push((
+                    peer.addr,
+                    encode_packet(&peer, &notification(&mut state, self.device(), i, now)),
+                ));
+            }
+        }
+        let socket = state.socket.clone();
+        drop(state);
+        if let Some(socket) = socket {
+            for (addr, packet) in packets {
+                if let Err(e) = socket.send_to(&packet, addr) {
+                    eprintln!("failed to send COV notification to {}: {}", addr, e);
+                }
+            }
+        }
+    }
+
+    fn present_value(&self, state: &State, object: ObjectId) -> Value {
+        match object.object_type {
+            ANALOG_INPUT => Value::Real(
+                state
+                    .latest
+                    .map_or(0.0, |m| m.get(Channel::ALL[object.instance as usize])),
+            ),
+            _ => {
+                let flag = BINARY_INPUTS[object.instance as usize].2;
+                Value::Enumerated(state.status.is_some_and(|status| flag(&status)) as u32)
+            }
+        }
+    }
+
+    fn fault(&self, state: &State, object: ObjectId) -> bool {
+        match object.object_type {
+            ANALOG_INPUT => {
+                state.latest.is_none()
+                    || state
+                        .status
+                        .is_some_and(|status| status.laser_error || status.fan_error)
+            }
+            BINARY_INPUT => state.status.is_none(),
+            _ => false,
+        }
+    }
+
+    // In alarm, fault, overridden and out of service.
+    fn status_flags(&self, state: &State, object: ObjectId) -> Value {
+        Value::BitString(vec![false, self.fault(state, object), false, false])
+    }
+
+    fn property(&self, object: ObjectId, property: u32) -> Result<Property, ServiceError> {
+        let properties = self.properties(object).ok_or(UNKNOWN_OBJECT)?;
+        if !properties.contains(&property) {
+            return Err(UNKNOWN_PROPERTY);
+        }
+        let state = self.state.lock().unwrap();
+        let instance = object.instance as usize;
+        let string = |s: &str| Value::CharacterString(String::from(s));
+        let value = match (object.object_type, property) {
+            (_, PROP_OBJECT_IDENTIFIER) => Value::ObjectIdentifier(object),
+            (_, PROP_OBJECT_TYPE) => Value::Enumerated(object.object_type as u32),
+            (_, PROP_PROPERTY_LIST) => {
+                return Ok(Property::Array(
+                    properties[3..properties.len() - 1]
+                        .iter()
+                        .map(|p| Value::Enumerated(*p))
+                        .collect(),
+                ))
+            }
+            (_, PROP_PRESENT_VALUE) => self.present_value(&state, object),
+            (_, PROP_STATUS_FLAGS) => self.status_flags(&state, object),
+            // Normal, there's no intrinsic reporting.
+            (_, PROP_EVENT_STATE) => Value::Enumerated(0),
+            (_, PROP_RELIABILITY) => Value::Enumerated(if self.fault(&state, object) {
+                RELIABILITY_UNRELIABLE_OTHER
+            } else {
+                RELIABILITY_NO_FAULT_DETECTED
+            }),
+            (_, PROP_OUT_OF_SERVICE) => Value::Boolean(false),
+
+            (DEVICE, PROP_OBJECT_NAME) => string(&format!("SPS30-{}", self.serial)),
+            // Operational.
+            (DEVICE, PROP_SYSTEM_STATUS) => Value::Enumerated(0),
+            (DEVICE, PROP_VENDOR_NAME) => string(env!("CARGO_PKG_NAME")),
+            (DEVICE, PROP_VENDOR_IDENTIFIER) => Value::Unsigned(VENDOR_ID),
+            (DEVICE, PROP_MODEL_NAME) => string("SPS30"),
+            (DEVICE, PROP_FIRMWARE_REVISION) => string(&self.version.firmware_version()),
+            (DEVICE, PROP_APPLICATION_SOFTWARE_VERSION) => string(env!("CARGO_PKG_VERSION")),
+            (DEVICE, PROP_DESCRIPTION) => string(&format!(
+                "Sensirion SPS30 particulate matter sensor {}",
+                self.serial
+            )),
+            (DEVICE, PROP_PROTOCOL_VERSION) => Value::Unsigned(1),
+            (DEVICE, PROP_PROTOCOL_REVISION) => Value::Unsigned(14),
+            (DEVICE, PROP_PROTOCOL_SERVICES_SUPPORTED) => {
+                let mut bits = vec![false; SERVICES_SUPPORTED_LENGTH];
+                for bit in [
+                    SERVICE_BIT_SUBSCRIBE_COV,
+                    SERVICE_BIT_READ_PROPERTY,
+                    SERVICE_BIT_WHO_IS,
+                ] {
+                    bits[bit] = true;
+                }
+                Value::BitString(bits)
+            }
+            (DEVICE, PROP_PROTOCOL_OBJECT_TYPES_SUPPORTED) => {
+                let mut bits = vec![false; DEVICE as usize + 1];
+                for object_type in [ANALOG_INPUT, BINARY_INPUT, DEVICE] {
+                    bits[object_type as usize] = true;
+                }
+                Value::BitString(bits)
+            }
+            (DEVICE, PROP_OBJECT_LIST) => {
+                return Ok(Property::Array(
+                    self.objects()
+                        .into_iter()
+                        .map(Value::ObjectIdentifier)
+                        .collect(),
+                ))
+            }
+            (DEVICE, PROP_MAX_APDU_LENGTH_ACCEPTED) => Value::Unsigned(MAX_APDU as u32),
+            (DEVICE, PROP_SEGMENTATION_SUPPORTED) => Value::Enumerated(NO_SEGMENTATION),
+            (DEVICE, PROP_APDU_TIMEOUT) => Value::Unsigned(3000),
+            (DEVICE, PROP_NUMBER_OF_APDU_RETRIES) => Value::Unsigned(0),
+            (DEVICE, PROP_DEVICE_ADDRESS_BINDING) => return Ok(Property::List(vec![])),
+            (DEVICE, PROP_DATABASE_REVISION) => Value::Unsigned(1),
+
+            (ANALOG_INPUT, PROP_OBJECT_NAME) => string(Channel::ALL[instance].key()),
+            (ANALOG_INPUT, PROP_DESCRIPTION) => {
+                let channel = Channel::ALL[instance];
+                string(&format!("{} ({})", channel.label(), channel.unit()))
+            }
+            (ANALOG_INPUT, PROP_UNITS) => Value::Enumerated(units(Channel::ALL[instance])),
+            (ANALOG_INPUT, PROP_COV_INCREMENT) => {
+                Value::Real(cov_increment(Channel::ALL[instance]))
+            }
+
+            (BINARY_INPUT, PROP_OBJECT_NAME) => string(BINARY_INPUTS[instance].0),
+            (BINARY_INPUT, PROP_DESCRIPTION) => string(BINARY_INPUTS[instance].1),
+            // Normal, i.e. active is a set flag.
+            (BINARY_INPUT, PROP_POLARITY) => Value::Enumerated(0),
+            (BINARY_INPUT, PROP_ACTIVE_TEXT) => string("Alarm"),
+            (BINARY_INPUT, PROP_INACTIVE_TEXT) => string("Normal"),
+            _ => return Err(UNKNOWN_PROPERTY),
+        };
+        Ok(Property::Single(value))
+    }
+
+    fn read_property(&self, data: &[u8]) -> Result<Vec<u8>, Failure> {
+        let mut decoder = Decoder::new(data);
+        let mut object = required(decoder.object_id(0)?)?;
+        let property = required(decoder.unsigned(1)?)?;
+        let index = decoder.unsigned(2)?;
+        decoder.finish()?;
+        // The wildcard instance is the local device.
+        if object == ObjectId::new(DEVICE, WILDCARD_INSTANCE) {
+            object = self.device();
+        }
+
+        let values = match (self.property(object, property)?, index) {
+            (Property::Array(values), Some(0)) => vec![Value::Unsigned(values.len() as u32)],
+            (Property::Array(values), Some(index)) => {
+                vec![values
+                    .get(index as usize - 1)
+                    .cloned()
+                    .ok_or(INVALID_ARRAY_INDEX)?]
+            }
+            (_, Some(_)) => return Err(PROPERTY_IS_NOT_AN_ARRAY.into()),
+            (Property::Single(value), None) => vec![value],
+            (Property::Array(values) | Property::List(values), None) => values,
+        };
+        let mut ack = vec![];
+        encode_context(&mut ack, 0, &object.encode());
+        encode_context(&mut ack, 1, &unsigned_bytes(property));
+        if let Some(index) = index {
+            encode_context(&mut ack, 2, &unsigned_bytes(index));
+        }
+        encode_opening(&mut ack, 3);
+        for value in values {
+            value.encode(&mut ack);
+        }
+        encode_closing(&mut ack, 3);
+        Ok(ack)
+    }
+
+    // Adds, replaces or cancels a subscription, returning the initial
+    // notification (None when cancelling).
+    fn subscribe_cov(&self, peer: &Peer, data: &[u8]) -> Result<Option<Vec<u8>>, Failure> {
+        let mut decoder = Decoder::new(data);
+        let process_id = required(decoder.unsigned(0)?)?;
+        let object = required(decoder.object_id(1)?)?;
+        let confirmed = decoder.boolean(2)?;
+        let lifetime = decoder.unsigned(3)?;
+        decoder.finish()?;
+
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.subscriptions.retain(|subscription| {
+            (
+                &subscription.peer,
+                subscription.process_id,
+                subscription.object,
+            ) != (peer, process_id, object)
+        });
+        // Leaving out both issueConfirmedNotifications and lifetime cancels
+        // the subscription.
+        let confirmed = match (confirmed, lifetime) {
+            (None, None) => return Ok(None),
+            (Some(confirmed), _) => confirmed,
+            (None, Some(_)) => return Err(Failure::Reject(REJECT_MISSING_REQUIRED_PARAMETER)),
+        };
+        match self.properties(object) {
+            None => return Err(UNKNOWN_OBJECT.into()),
+            Some(_) if object.object_type == DEVICE => {
+                return Err(OPTIONAL_FUNCTIONALITY_NOT_SUPPORTED.into())
+            }
+            Some(_) => {}
+        }
+        if state.subscriptions.len() >= MAX_SUBSCRIPTIONS {
+            return Err(COV_SUBSCRIPTION_FAILED.into());
+        }
+        let subscription = Subscription {
+            peer: peer.clone(),
+            process_id,
+            object,
+            confirmed,
+            // A lifetime of 0 is indefinite.
+            expires: lifetime
+                .filter(|lifetime| *lifetime > 0)
+                .map(|lifetime| now + Duration::from_secs(lifetime as u64)),
+            present_value: self.present_value(&state, object),
+            status_flags: self.status_flags(&state, object),
+        };
+        state.subscriptions.push(subscription);
+        let i = state.subscriptions.len() - 1;
+        Ok(Some(notification(&mut state, self.device(), i, now)))
+    }
+
+    fn who_is(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut decoder = Decoder::new(data);
+        match (decoder.unsigned(0), decoder.unsigned(1)) {
+            (Ok(None), Ok(None)) => {}
+            (Ok(Some(low)), Ok(Some(high))) if (low..=high).contains(&self.instance) => {}
+            _ => return None,
+        }
+        let mut apdu = vec![UNCONFIRMED_REQUEST, I_AM];
+        for value in [
+            Value::ObjectIdentifier(self.device()),
+            Value::Unsigned(MAX_APDU as u32),
+            Value::Enumerated(NO_SEGMENTATION),
+            Value::Unsigned(VENDOR_ID),
+        ] {
+            value.encode(&mut apdu);
+        }
+        Some(apdu)
+    }
+
+    // Handles an APDU from peer, returning the APDUs to send back.
+    fn handle_apdu(&self, peer: &Peer, apdu: &[u8]) -> Vec<Vec<u8>> {
+        match apdu.first().map(|b| b & 0xF0) {
+            Some(UNCONFIRMED_REQUEST) if apdu.get(1) == Some(&WHO_IS) => {
+                self.who_is(&apdu[2..]).into_iter().collect()
+            }
+            Some(CONFIRMED_REQUEST) if apdu.len() >= 4 => {
+                let invoke_id = apdu[2];
+                if apdu[0] & SEGMENTED_MESSAGE != 0 {
+                    return vec![vec![
+                        ABORT | ABORT_FROM_SERVER,
+                        invoke_id,
+                        ABORT_SEGMENTATION_NOT_SUPPORTED,
+                    ]];
+                }
+                let service = apdu[3];
+                let data = &apdu[4..];
+                let mut notifications = vec![];
+                let result = match service {
+                    READ_PROPERTY => self.read_property(data).map(|ack| {
+                        let mut response = vec![COMPLEX_ACK, invoke_id, READ_PROPERTY];
+                        response.extend_from_slice(&ack);
+                        response
+                    }),
+                    SUBSCRIBE_COV => self.subscribe_cov(peer, data).map(|notification| {
+                        notifications.extend(notification);
+                        vec![SIMPLE_ACK, invoke_id, SUBSCRIBE_COV]
+                    }),
+                    _ => Err(Failure::Reject(REJECT_UNRECOGNIZED_SERVICE)),
+                };
+                let response = match result {
+                    // Responses that would need segmenting.
+                    Ok(response) if response.len() > max_apdu(apdu[1]) => vec![
+                        ABORT | ABORT_FROM_SERVER,
+                        invoke_id,
+                        ABORT_SEGMENTATION_NOT_SUPPORTED,
+                    ],
+                    Ok(response) => response,
+                    Err(Failure::Reject(reason)) => vec![REJECT, invoke_id, reason],
+                    Err(Failure::Error(error)) => {
+                        let mut response = vec![ERROR, invoke_id, service];
+                        Value::Enumerated(error.class).encode(&mut response);
+                        Value::Enumerated(error.code).encode(&mut response);
+                        response
+                    }
+                };
+                let mut responses = vec![response];
+                responses.extend(notifications);
+                responses
+            }
+            // Acknowledgements of confirmed notifications, and services that
+            // aren't supported.
+            _ => vec![],
+        }
+    }
+
+    // Handles a packet received from addr, returning the packets to send and
+    // where to.
+    fn handle_packet(&self, packet: &[u8], addr: SocketAddr) -> Vec<(SocketAddr, Vec<u8>)> {
+        if packet.len() < 4
+            || packet[0] != BVLC_TYPE
+            || u16::from_be_bytes([packet[2], packet[3]]) as usize != packet.len()
+        {
+            return vec![];
+        }
+        let (source, npdu) = match packet[1] {
+            BVLC_ORIGINAL_UNICAST_NPDU | BVLC_ORIGINAL_BROADCAST_NPDU => (addr, &packet[4..]),
+            // Broadcasts forwarded by a BBMD start with the original source
+            // address.
+            BVLC_FORWARDED_NPDU if packet.len() >= 10 => {
+                let ip = Ipv4Addr::new(packet[4], packet[5], packet[6], packet[7]);
+                let port = u16::from_be_bytes([packet[8], packet[9]]);
+                (SocketAddr::new(IpAddr::V4(ip), port), &packet[10..])
+            }
+            // Everything else is for BBMDs, which get a NAK.
+            function => {
+                let nak: u16 = match function {
+                    0x01 => 0x0010,
+                    0x02 => 0x0020,
+                    0x05 => 0x0030,
+                    0x06 => 0x0040,
+                    0x08 => 0x0050,
+                    0x09 => 0x0060,
+                    _ => return vec![],
+                };
+                let mut result = vec![BVLC_TYPE, BVLC_RESULT, 0, 6];
+                result.extend_from_slice(&nak.to_be_bytes());
+                return vec![(addr, result)];
+            }
+        };
+        let Some((route, apdu)) = decode_npdu(npdu) else {
+            return vec![];
+        };
+        let peer = Peer {
+            addr: source,
+            route,
+        };
+        self.handle_apdu(&peer, apdu)
+            .iter()
+            .map(|apdu| (peer.addr, encode_packet(&peer, apdu)))
+            .collect()
+    }
+
+    /// serve starts serving on a background thread, and returns the address
+    /// that is being listened on (which is useful when binding to port 0).
+    /// Binding to 0.0.0.0 is needed to receive broadcast Who-Is requests.
+    pub fn serve(&self, addr: &str) -> Result<SocketAddr, String> {
+        let socket =
+            UdpSocket::bind(addr).map_err(|e| format!("failed to bind to {}: {}", addr, e))?;
+        let local_addr = socket
+            .local_addr()
+            .map_err(|e| format!("failed to get local address: {}", e))?;
+        let socket = Arc::new(socket);
+        self.state.lock().unwrap().socket = Some(socket.clone());
+        let server = self.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0; 2048];
+            loop {
+                let (length, addr) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("failed to receive BACnet packet: {}", e);
+                        continue;
+                    }
+                };
+                for (addr, packet) in server.handle_packet(&buf[..length], addr) {
+                    if let Err(e) = socket.send_to(&packet, addr) {
+                        eprintln!("failed to send BACnet packet to {}: {}", addr, e);
+                    }
+                }
+            }
+        });
+        Result::Ok(local_addr)
+    }
+}
+
+fn units(channel: Channel) -> u32 {
+    match channel {
+        Channel::MassPm1_0 | Channel::MassPm2_5 | Channel::MassPm4_0 | Channel::MassPm10_0 => {
+            UNITS_MICROGRAMS_PER_CUBIC_METER
+        }
+        Channel::TypicalParticleSize => UNITS_MICROMETERS,
+        _ => UNITS_NO_UNITS,
+    }
+}
+
+fn cov_increment(channel: Channel) -> f32 {
+    match channel {
+        Channel::TypicalParticleSize => 0.05,
+        _ => 1.0,
+    }
+}
+
+// Encodes a COV notification for the i'th subscription, with its last
+// notified values.
+fn notification(state: &mut State, device: ObjectId, i: usize, now: Instant) -> Vec<u8> {
+    let mut apdu = if state.subscriptions[i].confirmed {
+        let invoke_id = state.invoke_id;
+        state.invoke_id = invoke_id.wrapping_add(1);
+        vec![
+            CONFIRMED_REQUEST,
+            MAX_APDU_CODE,
+            invoke_id,
+            CONFIRMED_COV_NOTIFICATION,
+        ]
+    } else {
+        vec![UNCONFIRMED_REQUEST, UNCONFIRMED_COV_NOTIFICATION]
+    };
+    let subscription = &state.subscriptions[i];
+    let time_remaining = subscription.expires.map_or(0, |expires| {
+        expires.saturating_duration_since(now).as_secs_f64().round() as u32
+    });
+    encode_context(&mut apdu, 0, &unsigned_bytes(subscription.process_id));
+    encode_context(&mut apdu, 1, &device.encode());
+    encode_context(&mut apdu, 2, &subscription.object.encode());
+    encode_context(&mut apdu, 3, &unsigned_bytes(time_remaining));
+    encode_opening(&mut apdu, 4);
+    for (property, value) in [
+        (PROP_PRESENT_VALUE, &subscription.present_value),
+        (PROP_STATUS_FLAGS, &subscription.status_flags),
+    ] {
+        encode_context(&mut apdu, 0, &unsigned_bytes(property));
+        encode_opening(&mut apdu, 2);
+        value.encode(&mut apdu);
+        encode_closing(&mut apdu, 2);
+    }
+    encode_closing(&mut apdu, 4);
+    apdu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    const INSTANCE: u32 = 1234;
+
+    // A minimal BACnet/IP client.
+    struct Client {
+        socket: UdpSocket,
+        server: SocketAddr,
+    }
+
+    impl Client {
+        fn new(server: SocketAddr) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            Client { socket, server }
+        }
+
+        fn send_packet(&self, packet: &[u8]) {
+            self.socket.send_to(packet, self.server).unwrap();
+        }
+
+        fn send(&self, apdu: &[u8]) {
+            let peer = Peer {
+                addr: self.server,
+                route: None,
+            };
+            self.send_packet(&encode_packet(&peer, apdu));
+        }
+
+        // Returns the next packet, or None if there isn't one within the
+        // timeout.
+        fn receive_packet(&self, timeout: Duration) -> Option<Vec<u8>> {
+            self.socket.set_read_timeout(Some(timeout)).unwrap();
+            let mut buf = [0; 2048];
+            let (length, addr) = self.socket.recv_from(&mut buf).ok()?;
+            assert_eq!(addr, self.server);
+            Some(buf[..length].to_vec())
+        }
+
+        // Returns the APDU of the next packet, which must be unrouted.
+        fn receive(&self) -> Vec<u8> {
+            let packet = self.receive_packet(Duration::from_secs(10)).unwrap();
+            assert_eq!(&packet[..2], &[BVLC_TYPE, BVLC_ORIGINAL_UNICAST_NPDU]);
+            assert_eq!(
+                u16::from_be_bytes([packet[2], packet[3]]) as usize,
+                packet.len()
+            );
+            assert_eq!(packet[4], NPDU_VERSION);
+            assert_eq!(packet[5] & !NPDU_EXPECTING_REPLY, 0);
+            packet[6..].to_vec()
+        }
+
+        // Returns the next COV notification, without the invoke ID if it's
+        // confirmed.
+        fn receive_notification(&self) -> Vec<u8> {
+            let mut apdu = self.receive();
+            if apdu[0] == CONFIRMED_REQUEST {
+                apdu.remove(2);
+            }
+            apdu
+        }
+
+        fn assert_nothing_received(&self) {
+            assert_eq!(self.receive_packet(Duration::from_millis(200)), None);
+        }
+
+        fn request(&self, apdu: &[u8]) -> Vec<u8> {
+            self.send(apdu);
+            self.receive()
+        }
+
+        fn read_property(
+            &self,
+            object: ObjectId,
+            property: u32,
+            index: Option<u32>,
+        ) -> Result<Vec<Value>, Vec<u8>> {
+            let mut request = vec![CONFIRMED_REQUEST, MAX_APDU_CODE, 7, READ_PROPERTY];
+            let mut parameters = vec![];
+            encode_context(&mut parameters, 0, &object.encode());
+            encode_context(&mut parameters, 1, &unsigned_bytes(property));
+            if let Some(index) = index {
+                encode_context(&mut parameters, 2, &unsigned_bytes(index));
+            }
+            request.extend_from_slice(&parameters);
+            let response = self.request(&request);
+            if response[..3] != [COMPLEX_ACK, 7, READ_PROPERTY] {
+                return Err(response);
+            }
+            // The ack echoes the parameters, then the values.
+            let mut expected = vec![COMPLEX_ACK, 7, READ_PROPERTY];
+            expected.extend_from_slice(&parameters);
+            assert_eq!(&response[..expected.len()], &expected[..]);
+            assert_eq!(response[expected.len()], 0x3E);
+            assert_eq!(response.last(), Some(&0x3F));
+            Ok(decode_values(
+                &response[expected.len() + 1..response.len() - 1],
+            ))
+        }
+
+        fn subscribe(
+            &self,
+            process_id: u32,
+            object: ObjectId,
+            confirmed: Option<bool>,
+            lifetime: Option<u32>,
+        ) -> Vec<u8> {
+            let mut request = vec![CONFIRMED_REQUEST, MAX_APDU_CODE, 8, SUBSCRIBE_COV];
+            encode_context(&mut request, 0, &unsigned_bytes(process_id));
+            encode_context(&mut request, 1, &object.encode());
+            if let Some(confirmed) = confirmed {
+                encode_context(&mut request, 2, &[confirmed as u8]);
+            }
+            if let Some(lifetime) = lifetime {
+                encode_context(&mut request, 3, &unsigned_bytes(lifetime));
+            }
+            self.request(&request)
+        }
+    }
+
+    // Decodes application tagged values, only the types and lengths used
+    // here.
+    fn decode_values(mut data: &[u8]) -> Vec<Value> {
+        let mut values = vec![];
+        while let Some(header) = data.first() {
+            let (length, rest) = match header & 0x07 {
+                5 => (data[1] as usize, &data[2..]),
+                length => (length as usize, &data[1..]),
+            };
+            // Booleans have the value in the length field.
+            if header >> 4 == 1 {
+                values.push(Value::Boolean(length == 1));
+                data = rest;
+                continue;
+            }
+            let (content, rest) = rest.split_at(length);
+            values.push(match header >> 4 {
+                2 => Value::Unsigned(decode_unsigned(content).unwrap()),
+                4 => Value::Real(f32::from_be_bytes(content.try_into().unwrap())),
+                7 => Value::CharacterString(String::from_utf8(content[1..].to_vec()).unwrap()),
+                8 => Value::BitString(
+                    (0..(content.len() - 1) * 8 - content[0] as usize)
+                        .map(|i| content[1 + i / 8] & (0x80 >> (i % 8)) != 0)
+                        .collect(),
+                ),
+                9 => Value::Enumerated(decode_unsigned(content).unwrap()),
+                12 => Value::ObjectIdentifier(ObjectId::decode(content).unwrap()),
+                tag => panic!("unexpected tag {}", tag),
+            });
+            data = rest;
+        }
+        values
+    }
+
+    fn error(invoke_id: u8, service: u8, error: ServiceError) -> Vec<u8> {
+        vec![
+            ERROR,
+            invoke_id,
+            service,
+            0x91,
+            error.class as u8,
+            0x91,
+            error.code as u8,
+        ]
+    }
+
+    fn server() -> (BacnetServer, Client) {
+        let version = VersionInfo {
+            firmware_major: 2,
+            firmware_minor: 2,
+            ..Default::default()
+        };
+        let server = BacnetServer::new(INSTANCE, "ABCDEF0123456789", version);
+        let client = Client::new(server.serve("127.0.0.1:0").unwrap());
+        (server, client)
+    }
+
+    fn measurement(pm2_5: f32) -> TimestampedMeasurement {
+        TimestampedMeasurement {
+            time: datetime!(2024-06-01 12:00 UTC),
+            measurement: Measurement {
+                mass_concentration_pm_2_5: pm2_5,
+                typical_particle_size: 0.625,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        struct TestCase {
+            value: Value,
+            expected_output: Vec<u8>,
+        }
+        let long = "x".repeat(300);
+        let mut expected_long = vec![0x75, 254, 0x01, 0x2D, 0x00];
+        expected_long.extend_from_slice(long.as_bytes());
+        let tests = [
+            TestCase {
+                value: Value::Boolean(true),
+                expected_output: vec![0x11],
+            },
+            TestCase {
+                value: Value::Unsigned(0),
+                expected_output: vec![0x21, 0x00],
+            },
+            TestCase {
+                value: Value::Unsigned(1476),
+                expected_output: vec![0x22, 0x05, 0xC4],
+            },
+            TestCase {
+                value: Value::Unsigned(70000),
+                expected_output: vec![0x23, 0x01, 0x11, 0x70],
+            },
+            TestCase {
+                value: Value::Real(1.5),
+                expected_output: vec![0x44, 0x3F, 0xC0, 0x00, 0x00],
+            },
+            TestCase {
+                value: Value::CharacterString(String::from("abc")),
+                expected_output: vec![0x74, 0x00, b'a', b'b', b'c'],
+            },
+            TestCase {
+                value: Value::CharacterString(String::from("abcd")),
+                expected_output: vec![0x75, 0x05, 0x00, b'a', b'b', b'c', b'd'],
+            },
+            TestCase {
+                value: Value::CharacterString(long),
+                expected_output: expected_long,
+            },
+            TestCase {
+                value: Value::BitString(vec![false, true, false, false]),
+                expected_output: vec![0x82, 0x04, 0x40],
+            },
+            TestCase {
+                value: Value::BitString(vec![true; 9]),
+                expected_output: vec![0x83, 0x07, 0xFF, 0x80],
+            },
+            TestCase {
+                value: Value::Enumerated(219),
+                expected_output: vec![0x91, 0xDB],
+            },
+            TestCase {
+                value: Value::ObjectIdentifier(ObjectId::new(ANALOG_INPUT, 3)),
+                expected_output: vec![0xC4, 0x00, 0x00, 0x00, 0x03],
+            },
+        ];
+        for case in tests {
+            let mut encoded = vec![];
+            case.value.encode(&mut encoded);
+            assert_eq!(encoded, case.expected_output, "{:?}", case.value);
+        }
+
+        // Context tags, and decoding them again.
+        let mut encoded = vec![];
+        encode_context(&mut encoded, 1, &unsigned_bytes(PROP_PROPERTY_LIST));
+        encode_context(&mut encoded, 2, &[0xAA; 300]);
+        encode_context(&mut encoded, 20, &[1]);
+        assert_eq!(&encoded[..5], &[0x1A, 0x01, 0x73, 0x2D, 254]);
+        assert_eq!(&encoded[encoded.len() - 3..], &[0xF9, 20, 1]);
+        let mut decoder = Decoder::new(&encoded);
+        assert_eq!(decoder.unsigned(0), Ok(None));
+        assert_eq!(decoder.unsigned(1), Ok(Some(PROP_PROPERTY_LIST)));
+        assert_eq!(decoder.context(2), Ok(Some(&[0xAA; 300][..])));
+        assert_eq!(
+            decoder.finish(),
+            Err(Failure::Reject(REJECT_TOO_MANY_ARGUMENTS))
+        );
+        assert_eq!(decoder.boolean(20), Ok(Some(true)));
+        assert_eq!(decoder.finish(), Ok(()));
+
+        // Truncated.
+        assert_eq!(Decoder::new(&[0x1A, 0x01]).unsigned(1), Err(INVALID_TAG));
+    }
+
+    #[test]
+    fn test_who_is() {
+        let (_, client) = server();
+        let i_am = vec![
+            UNCONFIRMED_REQUEST,
+            I_AM,
+            0xC4,
+            0x02,
+            0x00,
+            0x04,
+            0xD2,
+            0x22,
+            0x05,
+            0xC4,
+            0x91,
+            0x03,
+            0x21,
+            0x00,
+        ];
+        assert_eq!(client.request(&[UNCONFIRMED_REQUEST, WHO_IS]), i_am);
+
+        // Broadcast, with limits.
+        let who_is = |low: u16, high: u16| {
+            let mut packet = vec![
+                BVLC_TYPE,
+                BVLC_ORIGINAL_BROADCAST_NPDU,
+                0,
+                14,
+                NPDU_VERSION,
+                0,
+            ];
+            packet.extend_from_slice(&[UNCONFIRMED_REQUEST, WHO_IS, 0x0A]);
+            packet.extend_from_slice(&low.to_be_bytes());
+            packet.push(0x1A);
+            packet.extend_from_slice(&high.to_be_bytes());
+            packet
+        };
+        client.send_packet(&who_is(1000, 2000));
+        assert_eq!(client.receive(), i_am);
+        client.send_packet(&who_is(1235, 2000));
+        client.assert_nothing_received();
+
+        // From behind a router, the I-Am is routed back.
+        client.send_packet(&[
+            BVLC_TYPE,
+            BVLC_ORIGINAL_UNICAST_NPDU,
+            0,
+            13,
+            NPDU_VERSION,
+            NPDU_SOURCE,
+            0x00,
+            0x05,
+            2,
+            0xAB,
+            0xCD,
+            UNCONFIRMED_REQUEST,
+            WHO_IS,
+        ]);
+        let packet = client.receive_packet(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            &packet[4..13],
+            &[
+                NPDU_VERSION,
+                NPDU_DESTINATION,
+                0x00,
+                0x05,
+                2,
+                0xAB,
+                0xCD,
+                255,
+                UNCONFIRMED_REQUEST
+            ]
+        );
+        assert_eq!(&packet[12..], &i_am[..]);
+
+        // Forwarded by a BBMD, the I-Am goes to the original source.
+        let local_addr = client.socket.local_addr().unwrap();
+        let mut packet = vec![BVLC_TYPE, BVLC_FORWARDED_NPDU, 0, 14, 127, 0, 0, 1];
+        packet.extend_from_slice(&local_addr.port().to_be_bytes());
+        packet.extend_from_slice(&[NPDU_VERSION, 0, UNCONFIRMED_REQUEST, WHO_IS]);
+        let other = Client::new(client.server);
+        other.send_packet(&packet);
+        assert_eq!(client.receive(), i_am);
+        other.assert_nothing_received();
+
+        // Register-Foreign-Device gets a NAK.
+        client.send_packet(&[BVLC_TYPE, 0x05, 0, 6, 0, 60]);
+        assert_eq!(
+            client.receive_packet(Duration::from_secs(10)).unwrap(),
+            vec![BVLC_TYPE, BVLC_RESULT, 0, 6, 0x00, 0x30]
+        );
+    }
+
+    #[test]
+    fn test_read_property() {
+        let (server, client) = server();
+        let device = ObjectId::new(DEVICE, INSTANCE);
+        let pm2_5 = ObjectId::new(ANALOG_INPUT, 1);
+        let laser_error = ObjectId::new(BINARY_INPUT, 1);
+        let string = |s: &str| vec![Value::CharacterString(String::from(s))];
+
+        assert_eq!(
+            client.read_property(device, PROP_OBJECT_NAME, None),
+            Ok(string("SPS30-ABCDEF0123456789"))
+        );
+        // The wildcard instance is the local device, the ack has its actual
+        // identifier.
+        let mut request = vec![CONFIRMED_REQUEST, MAX_APDU_CODE, 9, READ_PROPERTY];
+        encode_context(
+            &mut request,
+            0,
+            &ObjectId::new(DEVICE, WILDCARD_INSTANCE).encode(),
+        );
+        encode_context(&mut request, 1, &unsigned_bytes(PROP_FIRMWARE_REVISION));
+        let mut expected = vec![COMPLEX_ACK, 9, READ_PROPERTY];
+        encode_context(&mut expected, 0, &device.encode());
+        expected.extend_from_slice(&request[9..]);
+        expected.extend_from_slice(&[0x3E, 0x74, 0x00, b'2', b'.', b'2', 0x3F]);
+        assert_eq!(client.request(&request), expected);
+        assert_eq!(
+            client.read_property(device, PROP_OBJECT_LIST, Some(0)),
+            Ok(vec![Value::Unsigned(14)])
+        );
+        assert_eq!(
+            client.read_property(device, PROP_OBJECT_LIST, Some(2)),
+            Ok(vec![Value::ObjectIdentifier(ObjectId::new(
+                ANALOG_INPUT,
+                0
+            ))])
+        );
+        let objects = client
+            .read_property(device, PROP_OBJECT_LIST, None)
+            .unwrap();
+        assert_eq!(objects.len(), 14);
+        assert_eq!(
+            objects[13],
+            Value::ObjectIdentifier(ObjectId::new(BINARY_INPUT, 2))
+        );
+
+        // Every property of every object can be read.
+        for object in objects {
+            let Value::ObjectIdentifier(object) = object else {
+                panic!("{:?}", object);
+            };
+            let properties = client
+                .read_property(object, PROP_PROPERTY_LIST, None)
+                .unwrap();
+            for property in [PROP_OBJECT_IDENTIFIER, PROP_OBJECT_NAME, PROP_OBJECT_TYPE]
+                .into_iter()
+                .chain(properties.into_iter().map(|p| match p {
+                    Value::Enumerated(p) => p,
+                    _ => panic!("{:?}", p),
+                }))
+            {
+                let values = client.read_property(object, property, None);
+                assert!(values.is_ok(), "{:?} {}: {:?}", object, property, values);
+            }
+        }
+
+        // Nothing measured yet.
+        assert_eq!(
+            client.read_property(pm2_5, PROP_PRESENT_VALUE, None),
+            Ok(vec![Value::Real(0.0)])
+        );
+        assert_eq!(
+            client.read_property(pm2_5, PROP_STATUS_FLAGS, None),
+            Ok(vec![Value::BitString(vec![false, true, false, false])])
+        );
+        assert_eq!(
+            client.read_property(laser_error, PROP_RELIABILITY, None),
+            Ok(vec![Value::Enumerated(RELIABILITY_UNRELIABLE_OTHER)])
+        );
+
+        server.update(
+            Some(&measurement(12.5)),
+            Some(DeviceStatus {
+                fan_speed_warning: true,
+                ..Default::default()
+            }),
+        );
+        assert_eq!(
+            client.read_property(pm2_5, PROP_PRESENT_VALUE, None),
+            Ok(vec![Value::Real(12.5)])
+        );
+        assert_eq!(
+            client.read_property(pm2_5, PROP_STATUS_FLAGS, None),
+            Ok(vec![Value::BitString(vec![false; 4])])
+        );
+        assert_eq!(
+            client.read_property(ObjectId::new(BINARY_INPUT, 0), PROP_PRESENT_VALUE, None),
+            Ok(vec![Value::Enumerated(1)])
+        );
+        assert_eq!(
+            client.read_property(laser_error, PROP_PRESENT_VALUE, None),
+            Ok(vec![Value::Enumerated(0)])
+        );
+        assert_eq!(
+            client.read_property(laser_error, PROP_RELIABILITY, None),
+            Ok(vec![Value::Enumerated(RELIABILITY_NO_FAULT_DETECTED)])
+        );
+        for (instance, units) in [
+            (0, UNITS_MICROGRAMS_PER_CUBIC_METER),
+            (4, UNITS_NO_UNITS),
+            (9, UNITS_MICROMETERS),
+        ] {
+            assert_eq!(
+                client.read_property(ObjectId::new(ANALOG_INPUT, instance), PROP_UNITS, None),
+                Ok(vec![Value::Enumerated(units)])
+            );
+        }
+
+        // Errors.
+        assert_eq!(
+            client.read_property(ObjectId::new(ANALOG_INPUT, 10), PROP_PRESENT_VALUE, None),
+            Err(error(7, READ_PROPERTY, UNKNOWN_OBJECT))
+        );
+        assert_eq!(
+            client.read_property(pm2_5, PROP_POLARITY, None),
+            Err(error(7, READ_PROPERTY, UNKNOWN_PROPERTY))
+        );
+        assert_eq!(
+            client.read_property(pm2_5, PROP_PRESENT_VALUE, Some(1)),
+            Err(error(7, READ_PROPERTY, PROPERTY_IS_NOT_AN_ARRAY))
+        );
+        assert_eq!(
+            client.read_property(device, PROP_OBJECT_LIST, Some(15)),
+            Err(error(7, READ_PROPERTY, INVALID_ARRAY_INDEX))
+        );
+        // A missing property identifier.
+        assert_eq!(
+            client.request(&[
+                CONFIRMED_REQUEST,
+                MAX_APDU_CODE,
+                9,
+                READ_PROPERTY,
+                0x0C,
+                0x00,
+                0x00,
+                0x00,
+                0x01
+            ]),
+            vec![REJECT, 9, REJECT_MISSING_REQUIRED_PARAMETER]
+        );
+        // WriteProperty isn't supported.
+        assert_eq!(
+            client.request(&[CONFIRMED_REQUEST, MAX_APDU_CODE, 9, 15]),
+            vec![REJECT, 9, REJECT_UNRECOGNIZED_SERVICE]
+        );
+        // Neither is segmentation, of requests or of responses (the object
+        // list doesn't fit in 50 bytes).
+        assert_eq!(
+            client.request(&[
+                CONFIRMED_REQUEST | SEGMENTED_MESSAGE,
+                MAX_APDU_CODE,
+                9,
+                0,
+                1,
+                12
+            ]),
+            vec![
+                ABORT | ABORT_FROM_SERVER,
+                9,
+                ABORT_SEGMENTATION_NOT_SUPPORTED
+            ]
+        );
+        let mut request = vec![CONFIRMED_REQUEST, 0, 9, READ_PROPERTY];
+        encode_context(&mut request, 0, &device.encode());
+        encode_context(&mut request, 1, &unsigned_bytes(PROP_OBJECT_LIST));
+        assert_eq!(
+            client.request(&request),
+            vec![
+                ABORT | ABORT_FROM_SERVER,
+                9,
+                ABORT_SEGMENTATION_NOT_SUPPORTED
+            ]
+        );
+    }
+
+    // A COV notification as the server should encode it, less the invoke ID
+    // (see Client::receive_notification).
+    fn notification(
+        confirmed: bool,
+        process_id: u8,
+        object: ObjectId,
+        time_remaining: u32,
+        present_value: Value,
+        fault: bool,
+    ) -> Vec<u8> {
+        let mut apdu = if confirmed {
+            vec![CONFIRMED_REQUEST, MAX_APDU_CODE, CONFIRMED_COV_NOTIFICATION]
+        } else {
+            vec![UNCONFIRMED_REQUEST, UNCONFIRMED_COV_NOTIFICATION]
+        };
+        apdu.extend_from_slice(&[0x09, process_id, 0x1C, 0x02, 0x00, 0x04, 0xD2]);
+        encode_context(&mut apdu, 2, &object.encode());
+        encode_context(&mut apdu, 3, &unsigned_bytes(time_remaining));
+        apdu.extend_from_slice(&[0x4E, 0x09, 0x55, 0x2E]);
+        present_value.encode(&mut apdu);
+        apdu.extend_from_slice(&[0x2F, 0x09, 0x6F, 0x2E, 0x82, 0x04, (fault as u8) << 6]);
+        apdu.extend_from_slice(&[0x2F, 0x4F]);
+        apdu
+    }
+
+    #[test]
+    fn test_cov() {
+        let (server, client) = server();
+        let pm2_5 = ObjectId::new(ANALOG_INPUT, 1);
+        let laser_error = ObjectId::new(BINARY_INPUT, 1);
+        let healthy = Some(DeviceStatus::default());
+        let ack = vec![SIMPLE_ACK, 8, SUBSCRIBE_COV];
+
+        // Subscribing sends the current values.
+        assert_eq!(client.subscribe(1, pm2_5, Some(false), Some(600)), ack);
+        assert_eq!(
+            client.receive_notification(),
+            notification(false, 1, pm2_5, 600, Value::Real(0.0), true)
+        );
+        // Subscribing again replaces the subscription, 0 is no expiry.
+        assert_eq!(client.subscribe(1, pm2_5, Some(false), Some(0)), ack);
+        assert_eq!(
+            client.receive_notification(),
+            notification(false, 1, pm2_5, 0, Value::Real(0.0), true)
+        );
+        assert_eq!(client.subscribe(2, laser_error, Some(true), None), ack);
+        assert_eq!(
+            client.receive_notification(),
+            notification(true, 2, laser_error, 0, Value::Enumerated(0), true)
+        );
+
+        // The first measurement and status clear the faults.
+        server.update(Some(&measurement(10.0)), healthy);
+        assert_eq!(
+            client.receive_notification(),
+            notification(false, 1, pm2_5, 0, Value::Real(10.0), false)
+        );
+        assert_eq!(
+            client.receive_notification(),
+            notification(true, 2, laser_error, 0, Value::Enumerated(0), false)
+        );
+
+        // Changes smaller than the COV increment aren't notified.
+        server.update(Some(&measurement(10.5)), healthy);
+        server.update(None, healthy);
+        client.assert_nothing_received();
+        server.update(Some(&measurement(9.0)), healthy);
+        assert_eq!(
+            client.receive_notification(),
+            notification(false, 1, pm2_5, 0, Value::Real(9.0), false)
+        );
+
+        // A laser error is a fault for the analog inputs.
+        let status = Some(DeviceStatus {
+            laser_error: true,
+            ..Default::default()
+        });
+        server.update(None, status);
+        assert_eq!(
+            client.receive_notification(),
+            notification(false, 1, pm2_5, 0, Value::Real(9.0), true)
+        );
+        assert_eq!(
+            client.receive_notification(),
+            notification(true, 2, laser_error, 0, Value::Enumerated(1), false)
+        );
+
+        // Cancelling.
+        assert_eq!(client.subscribe(1, pm2_5, None, None), ack);
+        server.update(Some(&measurement(100.0)), status);
+        client.assert_nothing_received();
+
+        // Errors.
+        assert_eq!(
+            client.subscribe(1, ObjectId::new(DEVICE, INSTANCE), Some(false), None),
+            error(8, SUBSCRIBE_COV, OPTIONAL_FUNCTIONALITY_NOT_SUPPORTED)
+        );
+        assert_eq!(
+            client.subscribe(1, ObjectId::new(BINARY_INPUT, 3), Some(false), None),
+            error(8, SUBSCRIBE_COV, UNKNOWN_OBJECT)
+        );
+        assert_eq!(
+            client.subscribe(1, pm2_5, None, Some(60)),
+            vec![REJECT, 8, REJECT_MISSING_REQUIRED_PARAMETER]
+        );
+    }
+}
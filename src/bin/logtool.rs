@@ -0,0 +1,590 @@
+use sps30rs::aqi::{Pollutant, Standard};
+use sps30rs::cadr::{DecayFitter, Report};
+use sps30rs::calibration::{self, CalibrationStore, ModelKind};
+use sps30rs::cleanroom::Survey;
+use sps30rs::csv::{parse_time, read_column, read_table, CsvReader, TimeFormat};
+use sps30rs::humidity::{
+    CorrectedMeasurement, Correction, HumiditySeries, KappaKohler, LinearRhCorrection,
+};
+use sps30rs::measurement::{Channel, TimestampedMeasurement};
+use sps30rs::merge::{Interpolation, Merger, OutputFormat};
+use sps30rs::plot::{Axis, Chart, ImageFormat, Quantity, Series};
+use std::io::Write;
+
+const USAGE: &str = "usage: logtool COMMAND [OPTIONS] FILE
+
+Analyses measurements logged by the reader (as CSV).
+
+commands:
+  cadr --volume M3 [--natural-decay FILE] [--channel KEY]... FILE
+    estimate the clean air delivery rate of an air cleaner from the decay
+    logged in FILE, in a room of M3 cubic metres
+
+    --volume M3            room volume, in m3
+    --natural-decay FILE   log of a decay without the air cleaner, which is subtracted
+    --channel KEY          channel to analyse, e.g. number_pm1_0 (default: all except
+                           typical_particle_size), may be repeated
+    --start-fraction F     the decay starts once the peak has dropped to F (default: 0.9)
+    --end-fraction F       the decay ends once the peak has dropped to F (default: 0.1)
+
+  calibrate --serial SERIAL --reference FILE --map KEY=COLUMN... [OPTIONS] FILE
+    fit a calibration model per channel, from a sensor log (FILE) and a log
+    from a co-located reference instrument
+
+    --serial SERIAL        serial number of the SPS30 that wrote FILE
+    --reference FILE       CSV log from the reference instrument, with a time column
+    --map KEY=COLUMN       calibrate channel KEY (e.g. mass_pm2_5) against COLUMN of the
+                           reference log, may be repeated
+    --model MODEL          linear (default), polynomial:DEGREE or piecewise:SEGMENTS
+    --tolerance SECONDS    maximum time between aligned samples (default: 30)
+    --store FILE           add the models to the calibration file FILE, which the reader
+                           loads via --calibration
+
+  cleanroom --class N [--area M2] [--flow LPM] FILE...
+    check whether a cleanroom meets ISO 14644-1 class N (at >=0.5um), with
+    one FILE per sample location
+
+    --class N              target ISO class (2-9)
+    --area M2              cleanroom area, to check the number of sample locations
+    --flow LPM             sample flow in litres per minute, to check the volume sampled at
+                           each location
+
+  correct [--kappa K | --epa] [--rh-file FILE] [--rh-column NAME] FILE
+    correct measurements for humidity, and write the raw and corrected values
+    as CSV to stdout
+
+    --kappa K              use a kappa-Kohler growth correction with hygroscopicity K
+                           (the default, with K = 0.4)
+    --epa                  use the US EPA PurpleAir PM2.5 correction instead
+    --rh-file FILE         read RH from FILE instead of from FILE being corrected
+    --rh-column NAME       name of the RH column (default: rh, relative humidity or humidity)
+    --rh-tolerance SECONDS maximum time between a measurement and its RH (default: 60)
+
+  merge [OPTIONS] NAME=FILE...
+    resample several CSV logs (e.g. from the reader and other instruments,
+    each with a time column) onto a common time grid, and write them as one
+    file, with every column prefixed by NAME
+
+    --interval SECONDS     time between grid points (default: 10)
+    --interpolation TYPE   nearest (default), linear or hold (the last sample)
+    --tolerance SECONDS    maximum time between a grid point and the samples used for it
+                           (default: the interval)
+    --format FORMAT        csv (default) or jsonl (JSON Lines)
+    --output FILE          write to FILE instead of stdout
+
+  plot --output FILE [OPTIONS] FILE
+    render the measurements in FILE as a chart, in SVG or PNG format
+    (depending on the extension of --output)
+
+    --output FILE          where to write the chart, ending in .svg or .png
+    --quantity QUANTITY    mass (default), number or size
+    --channel KEY          plot KEY instead of every channel of the quantity, may be repeated
+    --compare FILE=COLUMN  also plot COLUMN from another log (e.g. from another instrument) on
+                           the right axis, may be repeated
+    --compare-label LABEL  label of the right axis (default: the first compared column)
+    --aqi STANDARD         colour the background by PM2.5 AQI category, for mass only: epa,
+                           caqi or daqi
+    --start TIME           don't plot anything before TIME (in any format the logs use)
+    --end TIME             don't plot anything after TIME
+    --last SECONDS         only plot the last SECONDS before the end
+    --title TITLE
+    --size WIDTHxHEIGHT    size of the chart, in pixels (default: 960x540)";
+
+fn exit_with_usage(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    std::process::exit(2);
+}
+
+fn exit_on_error<T>(result: Result<T, String>, message: &str) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}: {}", message, e);
+        std::process::exit(1);
+    })
+}
+
+fn parse_value<T: std::str::FromStr>(value: &str, name: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| exit_with_usage(&format!("invalid value {} for {}", value, name)))
+}
+
+fn parse_channel(key: &str) -> Channel {
+    Channel::from_key(key).unwrap_or_else(|| exit_with_usage(&format!("unknown channel {}", key)))
+}
+
+// Reads a log, warning about (but skipping) bad lines.
+fn read_log(path: &str) -> Vec<TimestampedMeasurement> {
+    let file = exit_on_error(
+        std::fs::File::open(path).map_err(|e| e.to_string()),
+        &format!("failed to open {}", path),
+    );
+    let (measurements, errors) = CsvReader::new(std::io::BufReader::new(file), None).read_all();
+    for error in errors {
+        eprintln!("{}: {}", path, error);
+    }
+    measurements
+}
+
+fn cadr(mut args: impl Iterator<Item = String>) {
+    let mut volume = None;
+    let mut natural_decay = None;
+    let mut channels = vec![];
+    let mut fitter = DecayFitter::new();
+    let mut file = None;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| exit_with_usage(&format!("missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "--volume" => volume = Some(parse_value::<f64>(&value(), "--volume")),
+            "--natural-decay" => natural_decay = Some(value()),
+            "--channel" => channels.push(parse_channel(&value())),
+            "--start-fraction" => {
+                fitter = fitter.start_fraction(parse_value(&value(), "--start-fraction"))
+            }
+            "--end-fraction" => {
+                fitter = fitter.end_fraction(parse_value(&value(), "--end-fraction"))
+            }
+            _ if file.is_none() && !arg.starts_with("--") => file = Some(arg),
+            _ => exit_with_usage(&format!("unknown argument {}", arg)),
+        }
+    }
+    let volume = volume.unwrap_or_else(|| exit_with_usage("--volume is required"));
+    let file = file.unwrap_or_else(|| exit_with_usage("missing FILE"));
+    if channels.is_empty() {
+        channels = Channel::ALL
+            .into_iter()
+            .filter(|c| *c != Channel::TypicalParticleSize)
+            .collect();
+    }
+
+    let decay = read_log(&file);
+    let natural_decay = natural_decay.map(|path| read_log(&path));
+    print!(
+        "{}",
+        Report::analyse(&fitter, &decay, natural_decay.as_deref(), volume, &channels)
+    );
+}
+
+fn parse_model(value: &str) -> ModelKind {
+    let (name, size) = match value.split_once(':') {
+        Some((name, size)) => (name, Some(parse_value::<usize>(size, "--model"))),
+        None => (value, None),
+    };
+    match (name, size) {
+        ("linear", None) => ModelKind::Linear,
+        ("polynomial", Some(degree)) => ModelKind::Polynomial(degree),
+        ("piecewise", Some(segments)) => ModelKind::Piecewise(segments),
+        _ => exit_with_usage(&format!("invalid model {}", value)),
+    }
+}
+
+fn calibrate(mut args: impl Iterator<Item = String>) {
+    let mut serial = None;
+    let mut reference = None;
+    let mut mappings = vec![];
+    let mut kind = ModelKind::Linear;
+    let mut tolerance = 30;
+    let mut store_path = None;
+    let mut file = None;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| exit_with_usage(&format!("missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "--serial" => serial = Some(value()),
+            "--reference" => reference = Some(value()),
+            "--map" => {
+                let mapping = value();
+                let (key, column) = mapping
+                    .split_once('=')
+                    .unwrap_or_else(|| exit_with_usage(&format!("invalid mapping {}", mapping)));
+                mappings.push((parse_channel(key), String::from(column)));
+            }
+            "--model" => kind = parse_model(&value()),
+            "--tolerance" => tolerance = parse_value(&value(), "--tolerance"),
+            "--store" => store_path = Some(value()),
+            _ if file.is_none() && !arg.starts_with("--") => file = Some(arg),
+            _ => exit_with_usage(&format!("unknown argument {}", arg)),
+        }
+    }
+    let serial = serial.unwrap_or_else(|| exit_with_usage("--serial is required"));
+    let reference = reference.unwrap_or_else(|| exit_with_usage("--reference is required"));
+    let file = file.unwrap_or_else(|| exit_with_usage("missing FILE"));
+    if mappings.is_empty() {
+        exit_with_usage("at least one --map is required");
+    }
+
+    let sensor = read_log(&file);
+    let mut store = store_path
+        .as_ref()
+        .map(|path| exit_on_error(CalibrationStore::load(path), "failed to load calibrations"));
+    for (channel, column) in mappings {
+        let input = exit_on_error(
+            std::fs::File::open(&reference).map_err(|e| e.to_string()),
+            &format!("failed to open {}", reference),
+        );
+        let (mut samples, errors) = exit_on_error(
+            read_column(std::io::BufReader::new(input), ',', &[column.as_str()]),
+            &format!("failed to read {}", reference),
+        );
+        for error in errors {
+            eprintln!("{}: {}", reference, error);
+        }
+        samples.sort_by_key(|(time, _)| *time);
+        let points = calibration::align(
+            &sensor,
+            channel,
+            &samples,
+            time::Duration::seconds(tolerance),
+        );
+        match calibration::fit(kind, &points) {
+            Ok(fit) => {
+                println!(
+                    "{} vs {}: {:?} (points: {}, R2: {:.4}, RMSE: {:.3})",
+                    channel.key(),
+                    column,
+                    fit.model,
+                    fit.points,
+                    fit.r_squared,
+                    fit.rmse
+                );
+                if let Some(store) = &mut store {
+                    store.set(&serial, channel, fit.model);
+                }
+            }
+            Err(e) => eprintln!("{} vs {}: {}", channel.key(), column, e),
+        }
+    }
+    if let (Some(store), Some(path)) = (store, store_path) {
+        exit_on_error(store.save(&path), "failed to save calibrations");
+    }
+}
+
+fn cleanroom(mut args: impl Iterator<Item = String>) {
+    let mut target_class = None;
+    let mut area = None;
+    let mut flow = None;
+    let mut files = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| exit_with_usage(&format!("missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "--class" => target_class = Some(parse_value::<f64>(&value(), "--class")),
+            "--area" => area = Some(parse_value::<f64>(&value(), "--area")),
+            "--flow" => flow = Some(parse_value::<f64>(&value(), "--flow")),
+            _ if !arg.starts_with("--") => files.push(arg),
+            _ => exit_with_usage(&format!("unknown argument {}", arg)),
+        }
+    }
+    let target_class = target_class.unwrap_or_else(|| exit_with_usage("--class is required"));
+    let mut survey = Survey::new(target_class).unwrap_or_else(|e| exit_with_usage(&e));
+    if let Some(area) = area {
+        survey = survey.area(area);
+    }
+    if let Some(flow) = flow {
+        survey = survey.sample_flow(flow);
+    }
+    for file in &files {
+        survey.add_location(file, &read_log(file));
+    }
+    print!(
+        "{}",
+        exit_on_error(survey.classify(), "failed to classify cleanroom")
+    );
+}
+
+fn correct(mut args: impl Iterator<Item = String>) {
+    let mut correction = Correction::KappaKohler(KappaKohler::new(0.4));
+    let mut rh_file = None;
+    let mut rh_column = None;
+    let mut tolerance = 60;
+    let mut file = None;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| exit_with_usage(&format!("missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "--kappa" => {
+                correction =
+                    Correction::KappaKohler(KappaKohler::new(parse_value(&value(), "--kappa")))
+            }
+            "--epa" => correction = Correction::Linear(LinearRhCorrection::epa_purpleair()),
+            "--rh-file" => rh_file = Some(value()),
+            "--rh-column" => rh_column = Some(value()),
+            "--rh-tolerance" => tolerance = parse_value(&value(), "--rh-tolerance"),
+            _ if file.is_none() && !arg.starts_with("--") => file = Some(arg),
+            _ => exit_with_usage(&format!("unknown argument {}", arg)),
+        }
+    }
+    let file = file.unwrap_or_else(|| exit_with_usage("missing FILE"));
+    let rh_file = rh_file.unwrap_or_else(|| file.clone());
+    let names = match &rh_column {
+        Some(name) => vec![name.as_str()],
+        None => vec!["rh", "relative humidity", "humidity"],
+    };
+
+    let input = exit_on_error(
+        std::fs::File::open(&rh_file).map_err(|e| e.to_string()),
+        &format!("failed to open {}", rh_file),
+    );
+    let (samples, errors) = exit_on_error(
+        read_column(std::io::BufReader::new(input), ',', &names),
+        &format!("failed to read RH from {}", rh_file),
+    );
+    for error in errors {
+        eprintln!("{}: {}", rh_file, error);
+    }
+    let humidity = HumiditySeries::new(samples, time::Duration::seconds(tolerance));
+
+    println!("{}", CorrectedMeasurement::csv_header());
+    for measurement in read_log(&file) {
+        println!(
+            "{}",
+            correction
+                .apply(&measurement, humidity.at(measurement.time))
+                .csv_row()
+        );
+    }
+}
+
+fn merge(mut args: impl Iterator<Item = String>) {
+    let mut interval = 10;
+    let mut interpolation = Interpolation::Nearest;
+    let mut tolerance = None;
+    let mut format = OutputFormat::Csv;
+    let mut output = None;
+    let mut inputs = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| exit_with_usage(&format!("missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "--interval" => interval = parse_value(&value(), "--interval"),
+            "--interpolation" => {
+                let name = value();
+                interpolation = Interpolation::from_name(&name)
+                    .unwrap_or_else(|| exit_with_usage(&format!("unknown interpolation {}", name)));
+            }
+            "--tolerance" => tolerance = Some(parse_value(&value(), "--tolerance")),
+            "--format" => {
+                format = match value().as_str() {
+                    "csv" => OutputFormat::Csv,
+                    "jsonl" => OutputFormat::JsonLines,
+                    other => exit_with_usage(&format!("unknown format {}", other)),
+                }
+            }
+            "--output" => output = Some(value()),
+            _ if !arg.starts_with("--") => match arg.split_once('=') {
+                Some((name, path)) => inputs.push((String::from(name), String::from(path))),
+                None => exit_with_usage(&format!("expected NAME=FILE, got {}", arg)),
+            },
+            _ => exit_with_usage(&format!("unknown argument {}", arg)),
+        }
+    }
+    if inputs.is_empty() {
+        exit_with_usage("missing NAME=FILE");
+    }
+
+    let mut merger = Merger::new(time::Duration::seconds(interval))
+        .interpolation(interpolation)
+        .tolerance(time::Duration::seconds(tolerance.unwrap_or(interval)));
+    for (name, path) in inputs {
+        let input = exit_on_error(
+            std::fs::File::open(&path).map_err(|e| e.to_string()),
+            &format!("failed to open {}", path),
+        );
+        let (mut table, errors) = exit_on_error(
+            read_table(std::io::BufReader::new(input), ','),
+            &format!("failed to read {}", path),
+        );
+        for error in errors {
+            eprintln!("{}: {}", path, error);
+        }
+        let columns: Vec<&str> = table.columns.iter().map(|c| c.as_str()).collect();
+        let source = merger.add_source(&name, &columns);
+        table.rows.sort_by_key(|(time, _)| *time);
+        for (time, values) in &table.rows {
+            exit_on_error(merger.push(source, *time, values), "failed to merge");
+        }
+    }
+
+    let mut out: Box<dyn Write> = match &output {
+        Some(path) => Box::new(std::io::BufWriter::new(exit_on_error(
+            std::fs::File::create(path).map_err(|e| e.to_string()),
+            &format!("failed to create {}", path),
+        ))),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let rows = merger.finish();
+    let lines = merger
+        .header(format)
+        .into_iter()
+        .chain(rows.iter().map(|row| merger.format(row, format)));
+    for line in lines {
+        exit_on_error(
+            writeln!(out, "{}", line).map_err(|e| e.to_string()),
+            "failed to write output",
+        );
+    }
+    exit_on_error(
+        out.flush().map_err(|e| e.to_string()),
+        "failed to write output",
+    );
+}
+
+fn plot(mut args: impl Iterator<Item = String>) {
+    let mut output = None;
+    let mut quantity = None;
+    let mut channels = vec![];
+    let mut compare = vec![];
+    let mut compare_label = None;
+    let mut aqi = None;
+    let mut title = String::from("Particulates");
+    let mut range = (None, None, None);
+    let mut size = (960, 540);
+    let mut file = None;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| exit_with_usage(&format!("missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "--output" => output = Some(value()),
+            "--quantity" => {
+                let name = value();
+                quantity = Some(
+                    Quantity::from_name(&name)
+                        .unwrap_or_else(|| exit_with_usage(&format!("unknown quantity {}", name))),
+                );
+            }
+            "--channel" => channels.push(parse_channel(&value())),
+            "--compare" => {
+                let value = value();
+                match value.split_once('=') {
+                    Some((path, column)) => {
+                        compare.push((String::from(path), String::from(column)))
+                    }
+                    None => exit_with_usage(&format!("expected FILE=COLUMN, got {}", value)),
+                }
+            }
+            "--compare-label" => compare_label = Some(value()),
+            "--aqi" => {
+                aqi = Some(match value().as_str() {
+                    "epa" => Standard::UsEpa,
+                    "caqi" => Standard::EuCaqi,
+                    "daqi" => Standard::UkDaqi,
+                    other => exit_with_usage(&format!("unknown AQI standard {}", other)),
+                })
+            }
+            "--start" => {
+                range.0 = Some(exit_on_error(parse_time(&value(), TimeFormat::Auto), &arg))
+            }
+            "--end" => range.1 = Some(exit_on_error(parse_time(&value(), TimeFormat::Auto), &arg)),
+            "--last" => range.2 = Some(time::Duration::seconds(parse_value(&value(), "--last"))),
+            "--title" => title = value(),
+            "--size" => {
+                let value = value();
+                let (width, height) = value
+                    .split_once('x')
+                    .unwrap_or_else(|| exit_with_usage(&format!("invalid size {}", value)));
+                size = (parse_value(width, "--size"), parse_value(height, "--size"));
+            }
+            _ if file.is_none() && !arg.starts_with("--") => file = Some(arg),
+            _ => exit_with_usage(&format!("unknown argument {}", arg)),
+        }
+    }
+    let output = output.unwrap_or_else(|| exit_with_usage("--output is required"));
+    let format = ImageFormat::from_path(&output)
+        .unwrap_or_else(|| exit_with_usage("--output must end in .svg or .png"));
+    let file = file.unwrap_or_else(|| exit_with_usage("missing FILE"));
+
+    // Channels must share an axis, i.e. all belong to the same quantity.
+    let quantity = match (quantity, channels.first()) {
+        (Some(quantity), _) => quantity,
+        (None, Some(channel)) => [Quantity::Mass, Quantity::Number, Quantity::Size]
+            .into_iter()
+            .find(|q| q.channels().contains(channel))
+            .unwrap(),
+        (None, None) => Quantity::Mass,
+    };
+    if let Some(channel) = channels.iter().find(|c| !quantity.channels().contains(c)) {
+        exit_with_usage(&format!(
+            "{} is not a {:?} channel",
+            channel.key(),
+            quantity
+        ));
+    }
+
+    let mut chart = Chart::new(&title).size(size.0, size.1);
+    if let Some(start) = range.0 {
+        chart = chart.start(start);
+    }
+    if let Some(end) = range.1 {
+        chart = chart.end(end);
+    }
+    if let Some(last) = range.2 {
+        chart = chart.last(last);
+    }
+    if let Some(standard) = aqi {
+        if quantity != Quantity::Mass {
+            exit_with_usage("--aqi only applies to mass concentrations");
+        }
+        chart = chart.aqi_bands(standard, Pollutant::Pm2_5);
+    }
+
+    let measurements = read_log(&file);
+    if channels.is_empty() {
+        chart = chart.measurements(quantity, &measurements);
+    } else {
+        chart = chart.y_label(Axis::Left, quantity.axis_label());
+        for channel in channels {
+            chart = chart.series(Series::from_measurements(&measurements, channel));
+        }
+    }
+    if let Some((_, column)) = compare.first() {
+        chart = chart.y_label(Axis::Right, compare_label.as_deref().unwrap_or(column));
+    }
+    for (path, column) in compare {
+        let input = exit_on_error(
+            std::fs::File::open(&path).map_err(|e| e.to_string()),
+            &format!("failed to open {}", path),
+        );
+        let (mut samples, errors) = exit_on_error(
+            read_column(std::io::BufReader::new(input), ',', &[column.as_str()]),
+            &format!("failed to read {}", path),
+        );
+        for error in errors {
+            eprintln!("{}: {}", path, error);
+        }
+        samples.sort_by_key(|(time, _)| *time);
+        chart = chart.series(Series::new(&column, samples).axis(Axis::Right));
+    }
+
+    let image = exit_on_error(chart.render(format), "failed to render chart");
+    exit_on_error(
+        std::fs::write(&output, image).map_err(|e| e.to_string()),
+        &format!("failed to write {}", output),
+    );
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("cadr") => cadr(args),
+        Some("calibrate") => calibrate(args),
+        Some("cleanroom") => cleanroom(args),
+        Some("correct") => correct(args),
+        Some("merge") => merge(args),
+        Some("plot") => plot(args),
+        Some("--help" | "-h") => println!("{}", USAGE),
+        Some(command) => exit_with_usage(&format!("unknown command {}", command)),
+        None => exit_with_usage("missing command"),
+    }
+}
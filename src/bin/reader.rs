@@ -1,165 +1,1038 @@
 extern crate serialport;
-use std::io::BufRead;
-use std::io::BufReader;
+use sps30rs::bacnet::BacnetServer;
+use sps30rs::calibration::CalibrationStore;
+use sps30rs::device::SerialSps30;
+use sps30rs::filtration::FiltrationTest;
+use sps30rs::fittest::FitTest;
+use sps30rs::humidity::{
+    CorrectedMeasurement, Correction, HumidityInput, KappaKohler, LinearRhCorrection,
+};
+use sps30rs::influxdb::{InfluxDbSink, LineProtocolFormatter};
+use sps30rs::measurement::{Channel, Measurement, TimestampedMeasurement};
+use sps30rs::merge::{Interpolation, Merger, OutputFormat};
+use sps30rs::modbus::{Command, ModbusServer};
+use sps30rs::mqtt::{MqttConfig, PayloadMode};
+use sps30rs::pms5003::SerialPms5003Emulator;
+use sps30rs::portacount::{Sample, SerialPortaCount};
+use sps30rs::prometheus::Exporter;
+use sps30rs::purpleair::PurpleAirEndpoint;
+use sps30rs::sensorcommunity::SensorCommunitySink;
+use sps30rs::tui::Dashboard;
+use sps30rs::web::{DeviceInfo, WebDashboard};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 
 // TODO: enumerate devices dynamically
-const DEVICE: &str = "/dev/ttyUSB0";
+const DEFAULT_DEVICE: &str = "/dev/ttyUSB0";
 
-fn parse_frame(buf: &Vec<u8>) -> Result<sps30rs::shdlc::MisoFrame, String> {
-    sps30rs::shdlc::decode_miso_frame(buf)
+// How many consecutive empty measurement responses we tolerate before trying
+// to recover the sensor (i.e. 1 minute at the default interval).
+const MAX_EMPTY_RESPONSES: u32 = 12;
+
+// The SPS30 needs up to 8 seconds after starting measurement before readings
+// are stable, and produces a new measurement every second. These apply to the
+// modes using two sensors.
+const WARMUP: std::time::Duration = std::time::Duration::from_secs(10);
+const PAIRED_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+// The file that --portacount logs to by default, as expected by plot.gnu.
+const DEFAULT_PORTACOUNT_LOG: &str = "dump-8020a.csv";
+
+// The time between measurements, unless changed over Modbus.
+const DEFAULT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// How often the reader handles commands (e.g. over Modbus) and keeps
+// connections alive between measurements, which can be up to an hour apart.
+const TICK: std::time::Duration = std::time::Duration::from_secs(1);
+
+// How many intervals old the latest measurement may be before --pms5003 stops
+// writing frames, like a PMS5003 that has failed, so that the software reading
+// them notices the SPS30 is gone instead of seeing frozen values.
+const PMS5003_MAX_AGE_INTERVALS: u32 = 3;
+
+// How old the latest RH from --rh-input may be before it's considered unknown.
+const MAX_RH_AGE: std::time::Duration = std::time::Duration::from_secs(60);
+
+const USAGE: &str =
+    "usage: reader [--device PATH] [--prometheus ADDR] [--web ADDR] [--purpleair ADDR]
+              [--modbus ADDR] [--bacnet ADDR [--bacnet-instance N]] [--mqtt ADDR [MQTT OPTIONS]]
+              [--influxdb URL [INFLUXDB OPTIONS]]
+              [--sqlite PATH] [--sensor-community SENSOR_ID [--sensor-community-dry-run PATH]]
+              [--rh-input PATH [RH OPTIONS]] [--calibration PATH]
+              [--portacount PATH [PORTACOUNT OPTIONS]] [--merge PATH [MERGE OPTIONS]]
+              [--pms5003 PATH] [--tui [TUI OPTIONS]]
+       reader [--device PATH] --fit-test MASK_PATH [FIT TEST OPTIONS]
+       reader [--device PATH] --filtration DOWNSTREAM_PATH [FILTRATION OPTIONS]
+
+  --device PATH          serial port the SPS30 is connected to (default: /dev/ttyUSB0)
+  --prometheus ADDR      serve Prometheus metrics on ADDR (e.g. 0.0.0.0:9130), at /metrics
+  --web ADDR             serve a live dashboard on ADDR (e.g. localhost:8130), with history from
+                         memory (the last 24 hours) and from --sqlite, if given
+  --purpleair ADDR       serve the latest and 2 minute average measurements on ADDR, at /json,
+                         in the format of a PurpleAir sensor's local API
+  --modbus ADDR          serve the measurements and device status as Modbus TCP input registers
+                         on ADDR (e.g. 0.0.0.0:502), and accept fan cleaning and sampling
+                         interval commands as holding register writes
+  --bacnet ADDR          serve a BACnet/IP device on ADDR (e.g. 0.0.0.0:47808), with an Analog
+                         Input per channel and a Binary Input per device status flag
+  --bacnet-instance N    device instance (default: derived from the serial number)
+  --mqtt ADDR            publish measurements to the MQTT broker at ADDR (e.g. localhost:1883)
+  --mqtt-topic TOPIC     base topic (default: sps30/<serial number>)
+  --mqtt-per-channel     publish one topic per channel instead of one JSON payload
+  --mqtt-username USER
+  --mqtt-password PASSWORD
+  --no-ha-discovery      don't publish Home Assistant discovery config
+  --influxdb URL         write measurements to the InfluxDB v2 instance at URL (e.g.
+                         http://localhost:8086), in batches of 12 measurements
+  --influxdb-org ORG     organization to write to (required)
+  --influxdb-bucket BUCKET
+                         bucket to write to (required)
+  --influxdb-token TOKEN API token (default: the INFLUX_TOKEN environment variable, if set)
+  --influxdb-measurement NAME
+                         measurement name (default: sps30)
+  --influxdb-location LOCATION
+                         value of the location tag (default: no location tag)
+  --sqlite PATH          log measurements to a SQLite database (requires the sqlite feature)
+  --sensor-community SENSOR_ID
+                         upload the mean of every 145 seconds of measurements to
+                         Sensor.Community, as the sensor registered as SENSOR_ID (e.g.
+                         raspi-00000000abcdef12)
+  --sensor-community-dry-run PATH
+                         write the uploads to PATH (as JSON Lines) instead of sending them
+  --calibration PATH     apply the calibration for the connected sensor's serial number from
+                         PATH (as written by logtool calibrate), if there is one
+  --portacount PATH      also log the concentration output of a TSI PortaCount 8020A connected
+                         to the serial port PATH, using the same clock as the SPS30
+  --portacount-baud RATE baud rate the 8020A is configured for (default: 1200)
+  --portacount-log PATH  CSV file to log 8020A samples to (default: dump-8020a.csv)
+  --merge PATH           also write the SPS30 (and 8020A, with --portacount) samples to PATH,
+                         resampled onto a common time grid
+  --merge-interval SECONDS
+                         time between grid points (default: 10)
+  --merge-interpolation TYPE
+                         nearest (default), linear or hold (the last sample)
+  --merge-tolerance SECONDS
+                         maximum time between a grid point and the samples used for it
+                         (default: the interval)
+  --merge-format FORMAT  csv (default) or jsonl (JSON Lines)
+  --pms5003 PATH         also write the measurements as Plantower PMS5003 active mode frames to
+                         the serial port (or pty) PATH, for software that only supports those
+  --rh-input PATH        read relative humidity from PATH (e.g. a FIFO written to by a second
+                         sensor), one value per line, and add humidity corrected columns
+  --rh-correction TYPE   kappa (kappa-Kohler growth, default) or epa (PurpleAir PM2.5 correction)
+  --kappa K              hygroscopicity for the kappa correction (default: 0.4)
+  --tui                  show a live dashboard on the terminal instead of printing CSV, stderr
+                         is best redirected to a file as it would be drawn over
+  --tui-window MINUTES   period covered by the rolling means and sparklines (default: 15)
+  --tui-aqi STANDARD     AQI used for the category colour: epa (default), caqi or daqi
+
+  --fit-test MASK_PATH   run an OSHA respirator fit test, with --device as the ambient sensor
+                         and MASK_PATH as the serial port of the in-mask sensor
+  --fit-test-pass LEVEL  minimum overall fit factor (default: 100, use 500 for full facepieces)
+  --fit-test-channel KEY number_pm1_0 (default) or number_pm0_5
+
+  --filtration DOWNSTREAM_PATH
+                         measure filtration efficiency, with --device as the upstream sensor
+                         and DOWNSTREAM_PATH as the serial port of the downstream sensor
+  --filtration-window SECONDS
+                         length of each sampling window (default: 60)
+  --filtration-windows N number of sampling windows (default: 5)
+  --filtration-baseline SECONDS
+                         first sample unfiltered air with both sensors for SECONDS, to correct
+                         for the bias between them (default: no baseline)";
+
+#[derive(Default)]
+struct Options {
+    device: String,
+    prometheus: Option<String>,
+    web: Option<String>,
+    purpleair: Option<String>,
+    modbus: Option<String>,
+    bacnet: Option<String>,
+    bacnet_instance: Option<String>,
+    mqtt: Option<String>,
+    mqtt_topic: Option<String>,
+    mqtt_per_channel: bool,
+    mqtt_username: Option<String>,
+    mqtt_password: Option<String>,
+    no_ha_discovery: bool,
+    influxdb: Option<String>,
+    influxdb_org: Option<String>,
+    influxdb_bucket: Option<String>,
+    influxdb_token: Option<String>,
+    influxdb_measurement: Option<String>,
+    influxdb_location: Option<String>,
+    sqlite: Option<String>,
+    sensor_community: Option<String>,
+    sensor_community_dry_run: Option<String>,
+    calibration: Option<String>,
+    portacount: Option<String>,
+    portacount_baud: Option<String>,
+    portacount_log: Option<String>,
+    merge: Option<String>,
+    merge_interval: Option<String>,
+    merge_interpolation: Option<String>,
+    merge_tolerance: Option<String>,
+    merge_format: Option<String>,
+    pms5003: Option<String>,
+    rh_input: Option<String>,
+    rh_correction: Option<String>,
+    kappa: Option<String>,
+    tui: bool,
+    tui_window: Option<String>,
+    tui_aqi: Option<String>,
+    fit_test: Option<String>,
+    fit_test_pass: Option<String>,
+    fit_test_channel: Option<String>,
+    filtration: Option<String>,
+    filtration_window: Option<String>,
+    filtration_windows: Option<String>,
+    filtration_baseline: Option<String>,
+}
+
+fn exit_with_usage(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    std::process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        device: String::from(DEFAULT_DEVICE),
+        ..Default::default()
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| exit_with_usage(&format!("missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "--device" => options.device = value(),
+            "--prometheus" => options.prometheus = Some(value()),
+            "--web" => options.web = Some(value()),
+            "--purpleair" => options.purpleair = Some(value()),
+            "--modbus" => options.modbus = Some(value()),
+            "--bacnet" => options.bacnet = Some(value()),
+            "--bacnet-instance" => options.bacnet_instance = Some(value()),
+            "--mqtt" => options.mqtt = Some(value()),
+            "--mqtt-topic" => options.mqtt_topic = Some(value()),
+            "--mqtt-per-channel" => options.mqtt_per_channel = true,
+            "--mqtt-username" => options.mqtt_username = Some(value()),
+            "--mqtt-password" => options.mqtt_password = Some(value()),
+            "--no-ha-discovery" => options.no_ha_discovery = true,
+            "--influxdb" => options.influxdb = Some(value()),
+            "--influxdb-org" => options.influxdb_org = Some(value()),
+            "--influxdb-bucket" => options.influxdb_bucket = Some(value()),
+            "--influxdb-token" => options.influxdb_token = Some(value()),
+            "--influxdb-measurement" => options.influxdb_measurement = Some(value()),
+            "--influxdb-location" => options.influxdb_location = Some(value()),
+            "--sqlite" => options.sqlite = Some(value()),
+            "--sensor-community" => options.sensor_community = Some(value()),
+            "--sensor-community-dry-run" => options.sensor_community_dry_run = Some(value()),
+            "--calibration" => options.calibration = Some(value()),
+            "--portacount" => options.portacount = Some(value()),
+            "--portacount-baud" => options.portacount_baud = Some(value()),
+            "--portacount-log" => options.portacount_log = Some(value()),
+            "--merge" => options.merge = Some(value()),
+            "--merge-interval" => options.merge_interval = Some(value()),
+            "--merge-interpolation" => options.merge_interpolation = Some(value()),
+            "--merge-tolerance" => options.merge_tolerance = Some(value()),
+            "--merge-format" => options.merge_format = Some(value()),
+            "--pms5003" => options.pms5003 = Some(value()),
+            "--rh-input" => options.rh_input = Some(value()),
+            "--rh-correction" => options.rh_correction = Some(value()),
+            "--kappa" => options.kappa = Some(value()),
+            "--tui" => options.tui = true,
+            "--tui-window" => options.tui_window = Some(value()),
+            "--tui-aqi" => options.tui_aqi = Some(value()),
+            "--fit-test" => options.fit_test = Some(value()),
+            "--fit-test-pass" => options.fit_test_pass = Some(value()),
+            "--fit-test-channel" => options.fit_test_channel = Some(value()),
+            "--filtration" => options.filtration = Some(value()),
+            "--filtration-window" => options.filtration_window = Some(value()),
+            "--filtration-windows" => options.filtration_windows = Some(value()),
+            "--filtration-baseline" => options.filtration_baseline = Some(value()),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => exit_with_usage(&format!("unknown argument {}", arg)),
+        }
+    }
+    options
+}
+
+fn exit_on_error<T>(result: Result<T, String>, message: &str) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}: {}", message, e);
+        std::process::exit(1);
+    })
+}
+
+fn parse_option<T: std::str::FromStr>(value: &Option<String>, name: &str, default: T) -> T {
+    match value {
+        Some(value) => value
+            .parse()
+            .unwrap_or_else(|_| exit_with_usage(&format!("invalid value {} for {}", value, name))),
+        None => default,
+    }
+}
+
+// Opens and starts the second sensor used by the fit test and filtration
+// modes, and waits for both sensors to stabilise.
+fn open_second_sensor(path: &str, name: &str) -> SerialSps30 {
+    let mut sps30 = exit_on_error(
+        SerialSps30::open(path),
+        &format!("Unable to open {} serial port", name),
+    );
+    exit_on_error(
+        sps30.start_measurement(),
+        &format!("failed to start {} measurement", name),
+    );
+    eprintln!("Waiting for sensors to stabilise");
+    std::thread::sleep(WARMUP);
+    sps30
 }
 
-// TODO: this needs to be moved into a new SPS30 device API.
-fn write_frame(port: &mut Box<dyn serialport::SerialPort>, frame: Vec<u8>) {
-    let len_written = port.write(&frame[..]).unwrap();
-    if len_written != frame.len() {
+// Reads both sensors every PAIRED_INTERVAL for duration, showing a countdown.
+fn sample_pairs<F: FnMut(&Measurement, &Measurement)>(
+    first: &mut SerialSps30,
+    second: &mut SerialSps30,
+    duration: std::time::Duration,
+    mut record: F,
+) {
+    let end = std::time::Instant::now() + duration;
+    while let Some(remaining) = end.checked_duration_since(std::time::Instant::now()) {
+        eprint!("\r{:>3}s remaining", remaining.as_secs());
+        std::thread::sleep(PAIRED_INTERVAL.min(remaining));
+        match (first.read_measurement(), second.read_measurement()) {
+            (Ok(Some(first)), Ok(Some(second))) => record(&first, &second),
+            (Err(e), _) | (_, Err(e)) => eprintln!("\nfailed to read measurement: {}", e),
+            _ => {}
+        }
+    }
+    eprint!("\r");
+}
+
+fn run_fit_test(ambient: &mut SerialSps30, mask_device: &str, options: &Options) {
+    let mut fit_test = FitTest::new().pass_level(parse_option(
+        &options.fit_test_pass,
+        "--fit-test-pass",
+        100.0,
+    ));
+    if let Some(key) = &options.fit_test_channel {
+        let channel = Channel::from_key(key)
+            .unwrap_or_else(|| exit_with_usage(&format!("unknown channel {}", key)));
+        fit_test = fit_test
+            .channel(channel)
+            .unwrap_or_else(|e| exit_with_usage(&e));
+    }
+
+    let mut mask = open_second_sensor(mask_device, "in-mask");
+    while let Some(exercise) = fit_test.current().copied() {
+        let (index, count) = fit_test.position();
         eprintln!(
-            "Expected to write {} bytes, actually wrote {}.",
-            frame.len(),
-            len_written
+            "\nExercise {}/{}: {}\n{}",
+            index, count, exercise.name, exercise.instructions
         );
-        std::process::exit(0);
+        sample_pairs(ambient, &mut mask, exercise.duration, |ambient, mask| {
+            fit_test.record(ambient, mask)
+        });
+        // A failed exercise (e.g. no readings, or no ambient particles) stays
+        // current, so it's simply repeated.
+        match fit_test.finish_exercise() {
+            Ok(result) => eprintln!("Fit factor: {:.0}", result.fit_factor),
+            Err(e) => eprintln!("Exercise failed: {}. Repeating it (Ctrl-C to abort).", e),
+        }
     }
+    println!(
+        "{}",
+        exit_on_error(fit_test.report(), "fit test incomplete")
+    );
 }
 
-fn main() {
-    eprintln!("SPS 30 reader binary (v{})", env!("CARGO_PKG_VERSION"));
+fn run_filtration_test(upstream: &mut SerialSps30, downstream_device: &str, options: &Options) {
+    let window = std::time::Duration::from_secs(parse_option(
+        &options.filtration_window,
+        "--filtration-window",
+        60,
+    ));
+    let windows = parse_option(&options.filtration_windows, "--filtration-windows", 5);
+    if windows < 2 {
+        exit_with_usage("--filtration-windows must be at least 2");
+    }
+    let baseline = parse_option(&options.filtration_baseline, "--filtration-baseline", 0);
 
-    let mut port = serialport::new(DEVICE, /* baud_rate */ 115_200)
-        .data_bits(serialport::DataBits::Eight)
-        .parity(serialport::Parity::None)
-        .stop_bits(serialport::StopBits::One)
-        .timeout(core::time::Duration::new(5, 0))
-        .open()
-        .expect("Unable to open serial port, sorry");
-
-    // TODO: figure out a more sensible way of doing this instead of cloning the
-    // port just to be able to read and write in parallel.
-    let mut reader = BufReader::new(
-        port.try_clone()
-            .expect("splines failed to be reticulated (failed to clone the serialport)"),
+    let mut downstream = open_second_sensor(downstream_device, "downstream");
+    let mut test = FiltrationTest::new();
+    if baseline > 0 {
+        eprintln!("\nBaseline: place both sensors in the same, unfiltered, air");
+        sample_pairs(
+            upstream,
+            &mut downstream,
+            std::time::Duration::from_secs(baseline),
+            |upstream, downstream| test.record_baseline(upstream, downstream),
+        );
+        eprintln!("Place the downstream sensor behind the filter, and press enter");
+        let mut line = String::new();
+        exit_on_error(
+            std::io::stdin()
+                .read_line(&mut line)
+                .map_err(|e| e.to_string()),
+            "failed to read from stdin",
+        );
+    }
+    for i in 1..=windows {
+        eprintln!("\nWindow {}/{}", i, windows);
+        sample_pairs(upstream, &mut downstream, window, |upstream, downstream| {
+            test.record(upstream, downstream)
+        });
+        exit_on_error(test.finish_window(), "filtration test failed");
+    }
+    println!(
+        "{}",
+        exit_on_error(test.report(), "filtration test incomplete")
     );
+}
 
-    write_frame(
-        &mut port,
-        sps30rs::shdlc::mosi_frame(0, /* cmd: Device Information */ 0xD0, &[0x00]).unwrap(),
-    );
+// The time aligned log written by --merge, which the 8020A logger thread
+// pushes to as well.
+struct MergedLog {
+    merger: Merger,
+    format: OutputFormat,
+    output: std::io::LineWriter<std::fs::File>,
+}
 
-    let mut buf = vec![];
-    // TODO: replace these loops with a reusable read_frame func (or even better
-    // hide it behind the new device API).
-    loop {
-        match reader.read_until(0x7E, &mut buf) {
-            Err(e) => {
-                eprintln!("failure reading data {}", e);
-                continue;
-            }
-            Ok(1) => {
-                continue;
+impl MergedLog {
+    fn create(path: &str, options: &Options) -> Self {
+        let interval = parse_option(&options.merge_interval, "--merge-interval", 10);
+        let interpolation = match options.merge_interpolation.as_deref() {
+            None => Interpolation::Nearest,
+            Some(name) => Interpolation::from_name(name)
+                .unwrap_or_else(|| exit_with_usage(&format!("unknown interpolation {}", name))),
+        };
+        let format = match options.merge_format.as_deref() {
+            None | Some("csv") => OutputFormat::Csv,
+            Some("jsonl") => OutputFormat::JsonLines,
+            Some(other) => exit_with_usage(&format!("unknown format {}", other)),
+        };
+        let merger = Merger::new(time::Duration::seconds(interval))
+            .interpolation(interpolation)
+            .tolerance(time::Duration::seconds(parse_option(
+                &options.merge_tolerance,
+                "--merge-tolerance",
+                interval,
+            )));
+        let output = std::io::LineWriter::new(exit_on_error(
+            std::fs::File::create(path).map_err(|e| e.to_string()),
+            &format!("failed to create {}", path),
+        ));
+        MergedLog {
+            merger,
+            format,
+            output,
+        }
+    }
+
+    fn add_source(&mut self, prefix: &str, columns: &[&str]) -> usize {
+        self.merger.add_source(prefix, columns)
+    }
+
+    // Writes the header, once all sources have been added.
+    fn start(&mut self) {
+        if let Some(header) = self.merger.header(self.format) {
+            if let Err(e) = writeln!(self.output, "{}", header) {
+                eprintln!("failed to write merged log: {}", e);
             }
-            Ok(_) => (),
         }
+    }
 
-        match parse_frame(&buf) {
-            Err(e) => eprintln!("failed to parse frame {}", e),
-            Ok(frame) => {
-                if frame.cmd == 0xD0 {
-                    eprintln!(
-                        "Received device identifier: {}",
-                        std::str::from_utf8(&frame.data).unwrap()
-                    );
-                    break;
-                } else {
-                    eprintln!("Received unexpected frame {}", frame.cmd)
-                }
+    fn push(&mut self, source: usize, time: time::OffsetDateTime, values: &[f32]) {
+        if let Err(e) = self.merger.push(source, time, values) {
+            eprintln!("failed to merge sample: {}", e);
+        }
+    }
+
+    fn write_ready(&mut self) {
+        for row in self.merger.ready(time::OffsetDateTime::now_utc()) {
+            if let Err(e) = writeln!(self.output, "{}", self.merger.format(&row, self.format)) {
+                eprintln!("failed to write merged log: {}", e);
             }
         }
     }
-    buf.clear();
+}
 
-    write_frame(
-        &mut port,
-        sps30rs::shdlc::mosi_frame(
-            0,
-            /* cmd: Start measurement */ 0x00,
-            &[
-                /* subcommand, must be 0x01 */ 0x01,
-                /* output as big-endian IEEE754 float values */ 0x03,
-            ],
-        )
-        .unwrap(),
+// Logs samples from the 8020A to --portacount-log in the background, so that
+// its one second output isn't held up by the SPS30's reads.
+fn spawn_portacount_logger(
+    path: &str,
+    options: &Options,
+    merged: Option<(Arc<Mutex<MergedLog>>, usize)>,
+) {
+    let mut portacount = exit_on_error(
+        SerialPortaCount::open(
+            path,
+            parse_option(
+                &options.portacount_baud,
+                "--portacount-baud",
+                sps30rs::portacount::DEFAULT_BAUD_RATE,
+            ),
+        ),
+        "Unable to open 8020A serial port",
     );
-    loop {
-        match reader.read_until(0x7E, &mut buf) {
-            Err(e) => {
-                eprintln!("failure reading data {}", e);
-                continue;
+    let log_path = options
+        .portacount_log
+        .as_deref()
+        .unwrap_or(DEFAULT_PORTACOUNT_LOG);
+    let mut log = std::io::LineWriter::new(exit_on_error(
+        std::fs::File::create(log_path).map_err(|e| e.to_string()),
+        &format!("failed to create {}", log_path),
+    ));
+    exit_on_error(
+        writeln!(log, "{}", Sample::csv_header()).map_err(|e| e.to_string()),
+        &format!("failed to write to {}", log_path),
+    );
+    eprintln!("Logging 8020A samples to {}", log_path);
+    std::thread::spawn(move || loop {
+        match portacount.read_sample() {
+            Ok(Some(sample)) => {
+                if let Err(e) = writeln!(log, "{}", sample.csv_row()) {
+                    eprintln!("failed to log 8020A sample: {}", e);
+                }
+                if let Some((merged, source)) = &merged {
+                    merged
+                        .lock()
+                        .unwrap()
+                        .push(*source, sample.time, &[sample.concentration]);
+                }
             }
-            Ok(1) => {
-                continue;
+            Ok(None) => {
+                eprintln!("8020A serial port closed");
+                return;
             }
-            Ok(_) => (),
+            Err(e) => eprintln!("failed to read 8020A sample: {}", e),
         }
+    });
+}
 
-        match parse_frame(&buf) {
-            Err(e) => eprintln!("failed to parse frame {}", e),
-            Ok(frame) => {
-                if frame.cmd == 0x00 {
-                    eprintln!(
-                        "Received start measurement response (expected to be empty): {}",
-                        std::str::from_utf8(&frame.data).unwrap()
-                    );
-                    break;
-                } else {
-                    eprintln!("Received unexpected frame {}", frame.cmd);
+// LatestMeasurement is the latest measurement, with the interval it was read
+// at, to tell when it is stale.
+type LatestMeasurement = (TimestampedMeasurement, std::time::Duration);
+
+// Writes the latest measurement as a PMS5003 frame at the PMS5003's active mode
+// interval in the background, as software that expects a PMS5003 may treat the
+// reader's 5 second interval as a lost sensor.
+fn spawn_pms5003_emulator(path: &str) -> Arc<Mutex<Option<LatestMeasurement>>> {
+    let mut emulator = exit_on_error(
+        SerialPms5003Emulator::open(path),
+        "Unable to open PMS5003 serial port",
+    );
+    eprintln!("Writing PMS5003 frames to {}", path);
+    let latest = Arc::new(Mutex::new(None));
+    let shared = latest.clone();
+    std::thread::spawn(move || loop {
+        let latest: Option<LatestMeasurement> = *shared.lock().unwrap();
+        if let Some((measurement, interval)) = latest {
+            let age = time::OffsetDateTime::now_utc() - measurement.time;
+            if age <= interval * PMS5003_MAX_AGE_INTERVALS {
+                if let Err(e) = emulator.write(&measurement.measurement) {
+                    eprintln!("{}", e);
                 }
             }
         }
+        std::thread::sleep(sps30rs::pms5003::ACTIVE_MODE_INTERVAL);
+    });
+    latest
+}
+
+// Uploader is a sink running on a separate thread (see spawn_uploader).
+struct Uploader {
+    tx: mpsc::Sender<TimestampedMeasurement>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl Uploader {
+    fn send(&self, measurement: TimestampedMeasurement) {
+        // Sending only fails if the uploader thread has panicked.
+        let _ = self.tx.send(measurement);
     }
-    buf.clear();
 
-    println!("{}", sps30rs::measurement::Measurement::csv_header());
-    loop {
-        write_frame(
-            &mut port,
-            sps30rs::shdlc::mosi_frame(0, /* cmd: Start measurement */ 0x03, &[]).unwrap(),
-        );
+    // finish waits for the sink to push the measurements that were sent, and
+    // to flush whatever it is still holding.
+    fn finish(self) {
+        drop(self.tx);
+        let _ = self.thread.join();
+    }
+}
 
-        match reader.read_until(0x7E, &mut buf) {
-            Err(e) => {
-                eprintln!("unexpected error {}", e);
-                break;
+// spawn_uploader passes measurements to push on a separate thread, so that the
+// reader doesn't wait for (or retry) uploads, and calls flush once the
+// Uploader is finished. Errors are logged with message.
+fn spawn_uploader<S: Send + 'static>(
+    message: &'static str,
+    mut sink: S,
+    push: fn(&mut S, &TimestampedMeasurement) -> Result<(), String>,
+    flush: fn(&mut S) -> Result<(), String>,
+) -> Uploader {
+    let (tx, rx) = mpsc::channel::<TimestampedMeasurement>();
+    let thread = std::thread::spawn(move || {
+        for measurement in rx {
+            if let Err(e) = push(&mut sink, &measurement) {
+                eprintln!("{}: {}", message, e);
             }
-            // We expect nowt prior to the frame start.
-            Ok(1) => (),
-            Ok(n) => {
-                eprintln!("unexpectedly read too much data, length {}", n);
-                break;
+        }
+        if let Err(e) = flush(&mut sink) {
+            eprintln!("{}: {}", message, e);
+        }
+    });
+    Uploader { tx, thread }
+}
+
+fn main() {
+    eprintln!("SPS 30 reader binary (v{})", env!("CARGO_PKG_VERSION"));
+    let options = parse_options();
+
+    let mut sps30 = exit_on_error(
+        SerialSps30::open(&options.device),
+        "Unable to open serial port, sorry",
+    );
+    let product_type = exit_on_error(sps30.product_type(), "failed to read product type");
+    eprintln!("Received device identifier: {}", product_type);
+    let serial = exit_on_error(sps30.serial_number(), "failed to read serial number");
+    eprintln!("Received serial number: {}", serial);
+    let version = exit_on_error(sps30.read_version(), "failed to read version");
+    eprintln!("Received firmware version: {}", version.firmware_version());
+    exit_on_error(sps30.start_measurement(), "failed to start measurement");
+
+    let calibration = options.calibration.as_ref().and_then(|path| {
+        let store = exit_on_error(CalibrationStore::load(path), "failed to load calibrations");
+        match store.get(&serial) {
+            Some(calibration) => {
+                eprintln!(
+                    "Applying calibration for {} to {} channels",
+                    serial,
+                    calibration.models.len()
+                );
+                Some(calibration.clone())
+            }
+            None => {
+                eprintln!("No calibration for {} in {}", serial, path);
+                None
             }
         }
+    });
 
-        match reader.read_until(0x7E, &mut buf) {
-            Err(e) => {
-                eprintln!("unexpected error {}", e);
-                break;
+    if let Some(mask_device) = &options.fit_test {
+        run_fit_test(&mut sps30, mask_device, &options);
+        return;
+    }
+    if let Some(downstream_device) = &options.filtration {
+        run_filtration_test(&mut sps30, downstream_device, &options);
+        return;
+    }
+
+    let merged = options.merge.as_ref().map(|path| {
+        let mut merged = MergedLog::create(path, &options);
+        let keys: Vec<&str> = Channel::ALL.iter().map(|c| c.key()).collect();
+        let source = merged.add_source("sps30", &keys);
+        (Arc::new(Mutex::new(merged)), source)
+    });
+    if let Some(path) = &options.portacount {
+        let merged = merged.as_ref().map(|(merged, _)| {
+            let source = merged
+                .lock()
+                .unwrap()
+                .add_source("8020a", &["concentration"]);
+            (merged.clone(), source)
+        });
+        spawn_portacount_logger(path, &options, merged);
+    }
+    if let Some((merged, _)) = &merged {
+        merged.lock().unwrap().start();
+    }
+
+    let pms5003 = options.pms5003.as_deref().map(spawn_pms5003_emulator);
+
+    let exporter = options.prometheus.map(|addr| {
+        let exporter = Exporter::new(&serial);
+        let local_addr = exit_on_error(exporter.serve(&addr), "failed to start exporter");
+        eprintln!(
+            "Serving Prometheus metrics on http://{}/metrics",
+            local_addr
+        );
+        exporter
+    });
+
+    let web = options.web.as_ref().map(|addr| {
+        let web = WebDashboard::new(DeviceInfo {
+            serial: serial.clone(),
+            product_type: product_type.clone(),
+            firmware_version: version.firmware_version(),
+        });
+        #[cfg(feature = "sqlite")]
+        let web = match options.sqlite.clone() {
+            Some(path) => {
+                let serial = serial.clone();
+                web.archive(move |from, to| {
+                    sps30rs::sqlite::SqliteReader::open(&path)?.read_range(Some(&serial), from, to)
+                })
+            }
+            None => web,
+        };
+        let local_addr = exit_on_error(web.serve(addr), "failed to start web dashboard");
+        eprintln!("Serving web dashboard on http://{}/", local_addr);
+        web
+    });
+
+    let purpleair = options.purpleair.as_ref().map(|addr| {
+        let endpoint = PurpleAirEndpoint::new(&serial);
+        let local_addr = exit_on_error(endpoint.serve(addr), "failed to start PurpleAir endpoint");
+        eprintln!(
+            "Serving PurpleAir compatible JSON on http://{}/json",
+            local_addr
+        );
+        endpoint
+    });
+
+    let modbus = options.modbus.as_ref().map(|addr| {
+        let server = ModbusServer::new(&serial, version, DEFAULT_INTERVAL);
+        let local_addr = exit_on_error(server.serve(addr), "failed to start Modbus server");
+        eprintln!("Serving Modbus TCP on {}", local_addr);
+        server
+    });
+
+    let bacnet = options.bacnet.as_ref().map(|addr| {
+        let instance = parse_option(
+            &options.bacnet_instance,
+            "--bacnet-instance",
+            sps30rs::bacnet::default_instance(&serial),
+        );
+        if instance > sps30rs::bacnet::MAX_INSTANCE {
+            exit_with_usage(&format!(
+                "--bacnet-instance must be at most {}",
+                sps30rs::bacnet::MAX_INSTANCE
+            ));
+        }
+        let server = BacnetServer::new(instance, &serial, version);
+        let local_addr = exit_on_error(server.serve(addr), "failed to start BACnet server");
+        eprintln!("Serving BACnet/IP device {} on {}", instance, local_addr);
+        server
+    });
+
+    let mut mqtt = options.mqtt.map(|addr| {
+        let mut config = MqttConfig::new(&addr, &serial).version(version);
+        if let Some(topic) = &options.mqtt_topic {
+            config = config.base_topic(topic);
+        }
+        if options.mqtt_per_channel {
+            config = config.mode(PayloadMode::PerChannel);
+        }
+        if let (Some(username), Some(password)) = (&options.mqtt_username, &options.mqtt_password) {
+            config = config.credentials(username, password);
+        }
+        if options.no_ha_discovery {
+            config = config.discovery_prefix(None);
+        }
+        exit_on_error(config.connect(), "failed to connect to MQTT broker")
+    });
+
+    let influxdb = options.influxdb.as_ref().map(|url| {
+        let (Some(org), Some(bucket)) = (&options.influxdb_org, &options.influxdb_bucket) else {
+            exit_with_usage("--influxdb requires --influxdb-org and --influxdb-bucket");
+        };
+        let mut formatter =
+            LineProtocolFormatter::new(options.influxdb_measurement.as_deref().unwrap_or("sps30"))
+                .serial(&serial);
+        if let Some(location) = &options.influxdb_location {
+            formatter = formatter.location(location);
+        }
+        let mut sink = InfluxDbSink::new(url, org, bucket, formatter);
+        if let Some(token) = options
+            .influxdb_token
+            .clone()
+            .or_else(|| std::env::var("INFLUX_TOKEN").ok())
+        {
+            sink = sink.token(&token);
+        }
+        eprintln!("Writing measurements to InfluxDB at {}", url);
+        spawn_uploader(
+            "failed to write to InfluxDB",
+            sink,
+            InfluxDbSink::push,
+            InfluxDbSink::flush,
+        )
+    });
+
+    let sensor_community = options.sensor_community.as_ref().map(|sensor_id| {
+        let sink = SensorCommunitySink::new(sensor_id);
+        let sink = match &options.sensor_community_dry_run {
+            Some(path) => sink.dry_run(exit_on_error(
+                std::fs::File::create(path).map_err(|e| e.to_string()),
+                &format!("failed to create {}", path),
+            )),
+            None => sink,
+        };
+        spawn_uploader(
+            "failed to upload to Sensor.Community",
+            sink,
+            SensorCommunitySink::push,
+            SensorCommunitySink::flush,
+        )
+    });
+
+    #[cfg(feature = "sqlite")]
+    let mut sqlite = options.sqlite.map(|path| {
+        let sensor = sps30rs::sqlite::SensorInfo {
+            serial: serial.clone(),
+            product_type: Some(product_type.clone()),
+            firmware_version: Some(version.firmware_version()),
+            location: None,
+        };
+        exit_on_error(
+            sps30rs::sqlite::SqliteSink::open(&path, &sensor),
+            "failed to open SQLite database",
+        )
+    });
+    #[cfg(not(feature = "sqlite"))]
+    if options.sqlite.is_some() {
+        exit_with_usage("--sqlite requires the sqlite feature");
+    }
+
+    let humidity = options.rh_input.as_ref().map(|path| {
+        let file = exit_on_error(
+            std::fs::File::open(path).map_err(|e| e.to_string()),
+            "failed to open RH input",
+        );
+        let correction = match options.rh_correction.as_deref() {
+            None | Some("kappa") => Correction::KappaKohler(KappaKohler::new(parse_option(
+                &options.kappa,
+                "--kappa",
+                0.4,
+            ))),
+            Some("epa") => Correction::Linear(LinearRhCorrection::epa_purpleair()),
+            Some(other) => exit_with_usage(&format!("unknown RH correction {}", other)),
+        };
+        (
+            HumidityInput::spawn(std::io::BufReader::new(file)),
+            correction,
+        )
+    });
+
+    let mut dashboard = options.tui.then(|| {
+        let standard = match options.tui_aqi.as_deref() {
+            None | Some("epa") => sps30rs::aqi::Standard::UsEpa,
+            Some("caqi") => sps30rs::aqi::Standard::EuCaqi,
+            Some("daqi") => sps30rs::aqi::Standard::UkDaqi,
+            Some(other) => exit_with_usage(&format!("unknown AQI {}", other)),
+        };
+        let window = parse_option(&options.tui_window, "--tui-window", 15);
+        if window == 0 {
+            exit_with_usage("--tui-window must be at least 1");
+        }
+        exit_on_error(
+            sps30rs::tui::clear_screen(&mut std::io::stdout()),
+            "failed to start dashboard",
+        );
+        Dashboard::new(
+            std::time::Duration::from_secs(window * 60),
+            DEFAULT_INTERVAL,
+        )
+        .title(&format!(
+            "{} {} (firmware {})",
+            product_type,
+            serial,
+            version.firmware_version()
+        ))
+        .standard(standard)
+        .colour(std::env::var_os("NO_COLOR").is_none())
+    });
+    let mut last_error = None;
+
+    // The dashboard replaces the CSV output.
+    match (&dashboard, &humidity) {
+        (Some(_), _) => {}
+        (None, Some(_)) => println!("{}", CorrectedMeasurement::csv_header()),
+        (None, None) => println!("{}", Measurement::csv_header()),
+    }
+    // Stop reading on Ctrl-C (or SIGTERM), so that the sinks are dropped (or
+    // finished) and buffered measurements are written (e.g. to --sqlite)
+    // before exiting.
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = stop.clone();
+    exit_on_error(
+        ctrlc::set_handler(move || handler_stop.store(true, Ordering::Relaxed))
+            .map_err(|e| e.to_string()),
+        "failed to install signal handler",
+    );
+
+    let mut interval = DEFAULT_INTERVAL;
+    let mut last_read: Option<std::time::Instant> = None;
+    let mut empty_responses = 0;
+    while !stop.load(Ordering::Relaxed) {
+        for command in modbus.iter().flat_map(|modbus| modbus.commands()) {
+            match command {
+                Command::StartFanCleaning => {
+                    eprintln!("Starting fan cleaning (requested over Modbus)");
+                    if let Err(e) = sps30.start_fan_cleaning() {
+                        eprintln!("failed to start fan cleaning: {}", e);
+                        last_error = Some(format!("failed to start fan cleaning: {}", e));
+                    }
+                }
+                Command::SetInterval(new_interval) => {
+                    eprintln!(
+                        "Sampling interval set to {}s (requested over Modbus)",
+                        new_interval.as_secs()
+                    );
+                    interval = new_interval;
+                    if let Some(purpleair) = &purpleair {
+                        purpleair.set_expected_interval(interval);
+                    }
+                    if let Some(dashboard) = &mut dashboard {
+                        dashboard.set_expected_interval(interval);
+                    }
+                }
+            }
+        }
+        let until_read = last_read.map_or(std::time::Duration::ZERO, |last_read| {
+            interval.saturating_sub(last_read.elapsed())
+        });
+        if !until_read.is_zero() {
+            if let Some(mqtt) = &mut mqtt {
+                if let Err(e) = mqtt.keep_alive() {
+                    eprintln!("failed to publish to MQTT broker: {}", e);
+                }
             }
-            Ok(_) => (),
+            std::thread::sleep(until_read.min(TICK));
+            continue;
         }
+        last_read = Some(std::time::Instant::now());
 
-        match parse_frame(&buf) {
-            Err(e) => eprintln!("failed to parse frame {}", e),
-            Ok(frame) => {
-                if frame.cmd == 0x03 {
-                    match sps30rs::measurement::decode_measurement_frame(&frame) {
-                        Ok(measurement) => println!("{}", measurement.csv_row()),
-                        Err(e) => eprintln!("failed to decode measurement: {}", e),
+        let measurement = match sps30.read_measurement() {
+            Ok(Some(measurement)) => {
+                empty_responses = 0;
+                // Only show errors on the dashboard until the sensor recovers.
+                last_error = None;
+                let measurement = TimestampedMeasurement {
+                    time: time::OffsetDateTime::now_utc(),
+                    measurement: match &calibration {
+                        Some(calibration) => calibration.apply(&measurement),
+                        None => measurement,
+                    },
+                };
+                match (&dashboard, &humidity) {
+                    (Some(_), _) => {}
+                    (None, Some((input, correction))) => println!(
+                        "{}",
+                        correction
+                            .apply(&measurement, input.latest(MAX_RH_AGE))
+                            .csv_row()
+                    ),
+                    (None, None) => println!("{}", measurement.csv_row()),
+                }
+                Some(measurement)
+            }
+            Ok(None) => {
+                empty_responses += 1;
+                if empty_responses >= MAX_EMPTY_RESPONSES {
+                    eprintln!(
+                        "No data for {} consecutive reads, resetting sensor",
+                        empty_responses
+                    );
+                    if let Err(e) = sps30.recover() {
+                        eprintln!("failed to recover sensor: {}", e);
                     }
-                } else {
-                    eprintln!("Received unexpected frame {}", frame.cmd);
+                    empty_responses = 0;
                 }
+                None
+            }
+            Err(e) => {
+                eprintln!("failed to read measurement: {}", e);
+                last_error = Some(format!("failed to read measurement: {}", e));
+                None
+            }
+        };
+        if let Some((merged, source)) = &merged {
+            let mut merged = merged.lock().unwrap();
+            if let Some(measurement) = &measurement {
+                let values: Vec<f32> = Channel::ALL
+                    .iter()
+                    .map(|c| measurement.measurement.get(*c))
+                    .collect();
+                merged.push(*source, measurement.time, &values);
+            }
+            merged.write_ready();
+        }
+        if let Some(exporter) = &exporter {
+            exporter.update(measurement.as_ref(), sps30.stats());
+        }
+        if let Some(web) = &web {
+            web.update(measurement.as_ref(), sps30.stats());
+        }
+        if let (Some(purpleair), Some(measurement)) = (&purpleair, &measurement) {
+            purpleair.update(measurement);
+        }
+        if let (Some(pms5003), Some(measurement)) = (&pms5003, &measurement) {
+            *pms5003.lock().unwrap() = Some((*measurement, interval));
+        }
+        #[cfg(feature = "sqlite")]
+        if let (Some(sqlite), Some(measurement)) = (&mut sqlite, &measurement) {
+            if let Err(e) = sqlite.push(measurement) {
+                eprintln!("failed to log to SQLite database: {}", e);
             }
         }
-        std::thread::sleep(std::time::Duration::new(5, 0));
-        buf.clear();
+        if let (Some(influxdb), Some(measurement)) = (&influxdb, &measurement) {
+            influxdb.send(*measurement);
+        }
+        if let (Some(sensor_community), Some(measurement)) = (&sensor_community, &measurement) {
+            sensor_community.send(*measurement);
+        }
+        if let Some(mqtt) = &mut mqtt {
+            let result = match &measurement {
+                Some(measurement) => mqtt.publish_measurement(measurement),
+                None => mqtt.keep_alive(),
+            };
+            if let Err(e) = result {
+                eprintln!("failed to publish to MQTT broker: {}", e);
+            }
+        }
+        // Older firmware doesn't have a status register.
+        let status = if (dashboard.is_some() || modbus.is_some() || bacnet.is_some())
+            && version.supports_status_register()
+        {
+            sps30.read_device_status().map_or_else(
+                |e| {
+                    eprintln!("failed to read device status: {}", e);
+                    last_error = Some(format!("failed to read device status: {}", e));
+                    None
+                },
+                Some,
+            )
+        } else {
+            None
+        };
+        if let Some(modbus) = &modbus {
+            modbus.update(measurement.as_ref(), sps30.stats(), status);
+        }
+        if let Some(bacnet) = &bacnet {
+            bacnet.update(measurement.as_ref(), status);
+        }
+        if let Some(dashboard) = &mut dashboard {
+            if let Some(measurement) = &measurement {
+                dashboard.push(measurement);
+            }
+            let frame = dashboard.render(
+                time::OffsetDateTime::now_utc(),
+                sps30.stats(),
+                status,
+                last_error.as_deref(),
+            );
+            if let Err(e) = sps30rs::tui::draw(&mut std::io::stdout(), &frame) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+    for uploader in [influxdb, sensor_community].into_iter().flatten() {
+        uploader.finish();
     }
 }
@@ -0,0 +1,342 @@
+use super::measurement::*;
+use std::fmt;
+use time::OffsetDateTime;
+
+const CFM_PER_M3_PER_HOUR: f64 = 0.588_578;
+
+/// DecayFit is an exponential decay, C(t) = C0 * exp(-k * t), fitted to the
+/// decay segment of one channel using a log-linear regression.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecayFit {
+    pub channel: Channel,
+    pub start: OffsetDateTime,
+    pub end: OffsetDateTime,
+    pub points: usize,
+    /// The fitted concentration at start.
+    pub initial: f64,
+    /// k, in 1/h.
+    pub decay_rate: f64,
+    /// Standard error of decay_rate, in 1/h.
+    pub standard_error: f64,
+    /// Coefficient of determination of the log-linear regression.
+    pub r_squared: f64,
+}
+
+/// DecayFitter detects the decay segment of a logged series, and fits an
+/// exponential decay to it.
+///
+/// The segment starts at the first sample after the peak that has dropped to
+/// start_fraction of the peak (to skip the period where the room is still
+/// mixing), and ends before the first sample below end_fraction of the peak.
+/// The background concentration is not subtracted, so end_fraction should be
+/// well above the background: any remaining loss mechanisms are accounted for
+/// by the natural decay run.
+#[derive(Clone, Copy, Debug)]
+pub struct DecayFitter {
+    start_fraction: f64,
+    end_fraction: f64,
+    min_points: usize,
+}
+
+impl Default for DecayFitter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecayFitter {
+    pub fn new() -> Self {
+        DecayFitter {
+            start_fraction: 0.9,
+            end_fraction: 0.1,
+            min_points: 10,
+        }
+    }
+
+    pub fn start_fraction(mut self, start_fraction: f64) -> Self {
+        self.start_fraction = start_fraction;
+        self
+    }
+
+    pub fn end_fraction(mut self, end_fraction: f64) -> Self {
+        self.end_fraction = end_fraction;
+        self
+    }
+
+    pub fn min_points(mut self, min_points: usize) -> Self {
+        self.min_points = min_points.max(3);
+        self
+    }
+
+    pub fn fit(
+        &self,
+        series: &[TimestampedMeasurement],
+        channel: Channel,
+    ) -> Result<DecayFit, String> {
+        let samples: Vec<(OffsetDateTime, f64)> = series
+            .iter()
+            .map(|m| (m.time, m.measurement.get(channel) as f64))
+            .filter(|(_, value)| value.is_finite())
+            .collect();
+        let (peak_index, peak) = samples
+            .iter()
+            .enumerate()
+            .map(|(i, (_, value))| (i, *value))
+            .fold((0, f64::MIN), |max, s| if s.1 > max.1 { s } else { max });
+        if peak <= 0.0 {
+            return Err(format!("no {} particles", channel.key()));
+        }
+        let start = samples[peak_index..]
+            .iter()
+            .position(|(_, value)| *value <= peak * self.start_fraction)
+            .map(|i| peak_index + i)
+            .ok_or_else(|| String::from("no decay after the peak"))?;
+        let end = samples[start..]
+            .iter()
+            .position(|(_, value)| *value < peak * self.end_fraction)
+            .map_or(samples.len(), |i| start + i);
+        let segment = &samples[start..end];
+        if segment.len() < self.min_points {
+            return Err(format!(
+                "decay segment has {} points, at least {} are required",
+                segment.len(),
+                self.min_points
+            ));
+        }
+
+        let start_time = segment[0].0;
+        let points: Vec<(f64, f64)> = segment
+            .iter()
+            .map(|(time, value)| ((*time - start_time).as_seconds_f64() / 3600.0, value.ln()))
+            .collect();
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let sxx: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        let sxy: f64 = points
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum();
+        let syy: f64 = points.iter().map(|(_, y)| (y - mean_y).powi(2)).sum();
+        if sxx <= 0.0 {
+            return Err(String::from("decay segment has no duration"));
+        }
+        let slope = sxy / sxx;
+        let intercept = mean_y - slope * mean_x;
+        let sse: f64 = points
+            .iter()
+            .map(|(x, y)| (y - intercept - slope * x).powi(2))
+            .sum();
+        Ok(DecayFit {
+            channel,
+            start: start_time,
+            end: segment[segment.len() - 1].0,
+            points: points.len(),
+            initial: intercept.exp(),
+            decay_rate: -slope,
+            standard_error: (sse / (n - 2.0) / sxx).sqrt(),
+            r_squared: if syy > 0.0 { 1.0 - sse / syy } else { 1.0 },
+        })
+    }
+}
+
+/// Cadr is the clean air delivery rate of an air cleaner, i.e. the room
+/// volume times the decay rate with the air cleaner running, minus the
+/// natural decay rate (from a run without the air cleaner) if available.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cadr {
+    pub decay: DecayFit,
+    pub natural_decay: Option<DecayFit>,
+    pub room_volume_m3: f64,
+    pub cadr_m3_per_hour: f64,
+    pub standard_error_m3_per_hour: f64,
+}
+
+impl Cadr {
+    pub fn new(decay: DecayFit, natural_decay: Option<DecayFit>, room_volume_m3: f64) -> Self {
+        let (natural_rate, natural_error) = natural_decay.map_or((0.0, 0.0), |natural| {
+            (natural.decay_rate, natural.standard_error)
+        });
+        Cadr {
+            decay,
+            natural_decay,
+            room_volume_m3,
+            cadr_m3_per_hour: room_volume_m3 * (decay.decay_rate - natural_rate),
+            standard_error_m3_per_hour: room_volume_m3
+                * (decay.standard_error.powi(2) + natural_error.powi(2)).sqrt(),
+        }
+    }
+
+    pub fn cadr_cfm(&self) -> f64 {
+        self.cadr_m3_per_hour * CFM_PER_M3_PER_HOUR
+    }
+}
+
+/// Report is the result of a CADR analysis for several channels.
+pub struct Report {
+    pub results: Vec<(Channel, Result<Cadr, String>)>,
+}
+
+impl Report {
+    /// analyse fits every channel in decay (and natural_decay, if present).
+    pub fn analyse(
+        fitter: &DecayFitter,
+        decay: &[TimestampedMeasurement],
+        natural_decay: Option<&[TimestampedMeasurement]>,
+        room_volume_m3: f64,
+        channels: &[Channel],
+    ) -> Self {
+        let results = channels
+            .iter()
+            .map(|channel| {
+                let result = fitter.fit(decay, *channel).and_then(|fit| {
+                    let natural = natural_decay
+                        .map(|series| fitter.fit(series, *channel))
+                        .transpose()
+                        .map_err(|e| format!("natural decay: {}", e))?;
+                    Ok(Cadr::new(fit, natural, room_volume_m3))
+                });
+                (*channel, result)
+            })
+            .collect();
+        Report { results }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<28} {:>6} {:>9} {:>7} {:>9} {:>7} {:>10} {:>8} {:>8}",
+            "Channel", "Points", "k (1/h)", "R2", "Natural", "R2", "CADR m3/h", "+/-", "CFM"
+        )?;
+        for (channel, result) in &self.results {
+            match result {
+                Ok(cadr) => {
+                    let (natural, natural_r_squared) = match &cadr.natural_decay {
+                        Some(natural) => (
+                            format!("{:.3}", natural.decay_rate),
+                            format!("{:.4}", natural.r_squared),
+                        ),
+                        None => (String::from("-"), String::from("-")),
+                    };
+                    writeln!(
+                        f,
+                        "{:<28} {:>6} {:>9.3} {:>7.4} {:>9} {:>7} {:>10.1} {:>8.1} {:>8.1}",
+                        channel.label(),
+                        cadr.decay.points,
+                        cadr.decay.decay_rate,
+                        cadr.decay.r_squared,
+                        natural,
+                        natural_r_squared,
+                        cadr.cadr_m3_per_hour,
+                        cadr.standard_error_m3_per_hour,
+                        cadr.cadr_cfm()
+                    )?;
+                }
+                Err(e) => writeln!(f, "{:<28} {}", channel.label(), e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+    use time::Duration;
+
+    const START: OffsetDateTime = datetime!(2024-06-01 12:00 UTC);
+
+    // A series that rises to peak over 5 minutes, and then decays at rate
+    // (1/h) for an hour, sampled every minute.
+    fn series(peak: f32, rate: f64) -> Vec<TimestampedMeasurement> {
+        (0..65)
+            .map(|minute| {
+                let value = if minute < 5 {
+                    peak * minute as f32 / 5.0
+                } else {
+                    peak * (-rate * (minute - 5) as f64 / 60.0).exp() as f32
+                };
+                let mut measurement = Measurement::default();
+                for channel in Channel::ALL {
+                    measurement.set(channel, value);
+                }
+                TimestampedMeasurement {
+                    time: START + Duration::minutes(minute),
+                    measurement,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fit() {
+        struct TestCase {
+            rate: f64,
+            start_minute: i64,
+            end_minute: i64,
+        }
+        let tests = [
+            // Drops below 90% after 2 minutes, and below 10% after 35.
+            TestCase {
+                rate: 4.0,
+                start_minute: 7,
+                end_minute: 39,
+            },
+            // Never drops below 10%.
+            TestCase {
+                rate: 1.5,
+                start_minute: 10,
+                end_minute: 64,
+            },
+        ];
+        for case in tests {
+            let fit = DecayFitter::new()
+                .fit(&series(1000.0, case.rate), Channel::NumberPm1_0)
+                .unwrap();
+            assert_eq!(fit.start, START + Duration::minutes(case.start_minute));
+            assert_eq!(fit.end, START + Duration::minutes(case.end_minute));
+            assert_eq!(fit.points as i64, case.end_minute - case.start_minute + 1);
+            assert!((fit.decay_rate - case.rate).abs() < 1e-4, "{:?}", fit);
+            assert!(fit.r_squared > 0.9999, "{:?}", fit);
+        }
+    }
+
+    #[test]
+    fn test_fit_errors() {
+        let fitter = DecayFitter::new();
+        assert!(fitter.fit(&[], Channel::MassPm2_5).is_err());
+        assert!(fitter.fit(&series(0.0, 4.0), Channel::MassPm2_5).is_err());
+        assert!(fitter
+            .min_points(100)
+            .fit(&series(1000.0, 4.0), Channel::MassPm2_5)
+            .is_err());
+    }
+
+    #[test]
+    fn test_cadr() {
+        let report = Report::analyse(
+            &DecayFitter::new(),
+            &series(500.0, 4.5),
+            Some(&series(500.0, 0.5)),
+            30.0,
+            &[Channel::MassPm2_5],
+        );
+        let cadr = report.results[0].1.as_ref().unwrap();
+        assert!((cadr.cadr_m3_per_hour - 120.0).abs() < 1e-2, "{:?}", cadr);
+        assert!((cadr.cadr_cfm() - 70.63).abs() < 1e-2, "{:?}", cadr);
+        assert!(cadr.standard_error_m3_per_hour < 1e-2, "{:?}", cadr);
+
+        // The natural decay run never decays.
+        let report = Report::analyse(
+            &DecayFitter::new(),
+            &series(500.0, 4.5),
+            Some(&series(500.0, 0.0)),
+            30.0,
+            &[Channel::MassPm2_5],
+        );
+        assert!(report.results[0].1.is_err());
+    }
+}
@@ -0,0 +1,541 @@
+use super::csv::nearest;
+use super::measurement::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use time::Duration;
+
+/// ModelKind selects the model that fit will use.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModelKind {
+    Linear,
+    /// A polynomial of the given degree.
+    Polynomial(usize),
+    /// A continuous piecewise linear function with the given number of
+    /// segments, with breakpoints at quantiles of the sensor values.
+    Piecewise(usize),
+}
+
+/// Model maps a raw sensor value to a calibrated value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Model {
+    Linear {
+        slope: f64,
+        intercept: f64,
+    },
+    /// coefficients[i] is the coefficient of x^i.
+    Polynomial {
+        coefficients: Vec<f64>,
+    },
+    /// intercept + slope * x, plus change * max(0, x - knot) for every
+    /// (knot, change) pair.
+    Piecewise {
+        intercept: f64,
+        slope: f64,
+        knots: Vec<(f64, f64)>,
+    },
+}
+
+impl Model {
+    pub fn evaluate(&self, x: f64) -> f64 {
+        match self {
+            Model::Linear { slope, intercept } => slope * x + intercept,
+            Model::Polynomial { coefficients } => {
+                coefficients.iter().rev().fold(0.0, |sum, c| sum * x + c)
+            }
+            Model::Piecewise {
+                intercept,
+                slope,
+                knots,
+            } => {
+                intercept
+                    + slope * x
+                    + knots
+                        .iter()
+                        .map(|(knot, change)| change * (x - knot).max(0.0))
+                        .sum::<f64>()
+            }
+        }
+    }
+
+    // The parameters, in the order used by the calibration file.
+    fn parameters(&self) -> Vec<f64> {
+        match self {
+            Model::Linear { slope, intercept } => vec![*slope, *intercept],
+            Model::Polynomial { coefficients } => coefficients.clone(),
+            Model::Piecewise {
+                intercept,
+                slope,
+                knots,
+            } => [*intercept, *slope]
+                .into_iter()
+                .chain(knots.iter().flat_map(|(knot, change)| [*knot, *change]))
+                .collect(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Model::Linear { .. } => "linear",
+            Model::Polynomial { .. } => "polynomial",
+            Model::Piecewise { .. } => "piecewise",
+        }
+    }
+
+    fn from_parameters(name: &str, parameters: &[f64]) -> Result<Self, String> {
+        match (name, parameters) {
+            ("linear", [slope, intercept]) => Ok(Model::Linear {
+                slope: *slope,
+                intercept: *intercept,
+            }),
+            ("polynomial", coefficients) if !coefficients.is_empty() => Ok(Model::Polynomial {
+                coefficients: coefficients.to_vec(),
+            }),
+            ("piecewise", [intercept, slope, knots @ ..]) if knots.len() % 2 == 0 => {
+                Ok(Model::Piecewise {
+                    intercept: *intercept,
+                    slope: *slope,
+                    knots: knots.chunks(2).map(|k| (k[0], k[1])).collect(),
+                })
+            }
+            _ => Err(format!(
+                "invalid {} model with {} parameters",
+                name,
+                parameters.len()
+            )),
+        }
+    }
+}
+
+// Solves a (small, dense) linear system using Gaussian elimination with
+// partial pivoting.
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Result<Vec<f64>, String> {
+    let n = b.len();
+    for column in 0..n {
+        let pivot = (column..n)
+            .max_by(|i, j| a[*i][column].abs().total_cmp(&a[*j][column].abs()))
+            .unwrap();
+        if a[pivot][column].abs() < 1e-12 {
+            return Err(String::from("not enough distinct values to fit the model"));
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+        let pivot_row = a[column].clone();
+        for row in column + 1..n {
+            let factor = a[row][column] / pivot_row[column];
+            for (value, pivot) in a[row].iter_mut().zip(&pivot_row).skip(column) {
+                *value -= factor * pivot;
+            }
+            b[row] -= factor * b[column];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Ok(x)
+}
+
+// Least squares fit of y against the basis functions, via the normal
+// equations.
+fn least_squares(
+    points: &[(f64, f64)],
+    basis: &dyn Fn(f64) -> Vec<f64>,
+) -> Result<Vec<f64>, String> {
+    let rows: Vec<Vec<f64>> = points.iter().map(|(x, _)| basis(*x)).collect();
+    let n = rows[0].len();
+    let mut ata = vec![vec![0.0; n]; n];
+    let mut aty = vec![0.0; n];
+    for (row, (_, y)) in rows.iter().zip(points) {
+        for i in 0..n {
+            aty[i] += row[i] * y;
+            for j in 0..n {
+                ata[i][j] += row[i] * row[j];
+            }
+        }
+    }
+    solve(ata, aty)
+}
+
+fn binomial(n: usize, k: usize) -> f64 {
+    (0..k).fold(1.0, |c, i| c * (n - i) as f64 / (i + 1) as f64)
+}
+
+/// Fit is a fitted model, along with its diagnostics.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fit {
+    pub model: Model,
+    pub points: usize,
+    pub r_squared: f64,
+    /// Root mean squared error, in the reference instrument's units.
+    pub rmse: f64,
+}
+
+/// fit fits a model to (sensor, reference) pairs, e.g. from align.
+pub fn fit(kind: ModelKind, points: &[(f64, f64)]) -> Result<Fit, String> {
+    let parameters = match kind {
+        ModelKind::Linear => 2,
+        ModelKind::Polynomial(degree) => degree + 1,
+        ModelKind::Piecewise(segments) => segments + 1,
+    };
+    if parameters < 2 {
+        return Err(String::from("models need at least 2 parameters"));
+    }
+    if points.len() <= parameters {
+        return Err(format!(
+            "{} points are not enough to fit {} parameters",
+            points.len(),
+            parameters
+        ));
+    }
+
+    let model = match kind {
+        ModelKind::Linear => {
+            let c = least_squares(points, &|x| vec![1.0, x])?;
+            Model::Linear {
+                slope: c[1],
+                intercept: c[0],
+            }
+        }
+        ModelKind::Polynomial(degree) => {
+            // Fit against (x - mean) / scale for better conditioning, and
+            // expand the result back into coefficients of x.
+            let n = points.len() as f64;
+            let mean = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+            let scale = points
+                .iter()
+                .map(|(x, _)| (x - mean).abs())
+                .fold(0.0, f64::max)
+                .max(1e-12);
+            let scaled = least_squares(points, &|x| {
+                (0..=degree)
+                    .map(|k| ((x - mean) / scale).powi(k as i32))
+                    .collect()
+            })?;
+            let coefficients = (0..=degree)
+                .map(|j| {
+                    (j..=degree)
+                        .map(|k| {
+                            scaled[k] * binomial(k, j) * (-mean).powi((k - j) as i32)
+                                / scale.powi(k as i32)
+                        })
+                        .sum()
+                })
+                .collect();
+            Model::Polynomial { coefficients }
+        }
+        ModelKind::Piecewise(segments) => {
+            let mut xs: Vec<f64> = points.iter().map(|(x, _)| *x).collect();
+            xs.sort_by(f64::total_cmp);
+            let knots: Vec<f64> = (1..segments)
+                .map(|i| xs[i * (xs.len() - 1) / segments])
+                .collect();
+            let c = least_squares(points, &|x| {
+                [1.0, x]
+                    .into_iter()
+                    .chain(knots.iter().map(|knot| (x - knot).max(0.0)))
+                    .collect()
+            })?;
+            Model::Piecewise {
+                intercept: c[0],
+                slope: c[1],
+                knots: knots.into_iter().zip(c[2..].iter().copied()).collect(),
+            }
+        }
+    };
+
+    let n = points.len() as f64;
+    let mean = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let total: f64 = points.iter().map(|(_, y)| (y - mean).powi(2)).sum();
+    let residual: f64 = points
+        .iter()
+        .map(|(x, y)| (y - model.evaluate(*x)).powi(2))
+        .sum();
+    Ok(Fit {
+        model,
+        points: points.len(),
+        r_squared: if total > 0.0 {
+            1.0 - residual / total
+        } else {
+            1.0
+        },
+        rmse: (residual / n).sqrt(),
+    })
+}
+
+/// align pairs every sensor measurement of channel with the nearest
+/// reference sample within tolerance, returning (sensor, reference) pairs.
+/// reference must be sorted by time.
+pub fn align(
+    sensor: &[TimestampedMeasurement],
+    channel: Channel,
+    reference: &[(time::OffsetDateTime, f32)],
+    tolerance: Duration,
+) -> Vec<(f64, f64)> {
+    sensor
+        .iter()
+        .filter_map(|m| {
+            let value = m.measurement.get(channel);
+            let reference = nearest(reference, m.time, tolerance)?;
+            (value.is_finite() && reference.is_finite()).then_some((value as f64, reference as f64))
+        })
+        .collect()
+}
+
+/// Calibration is the set of models for one sensor.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Calibration {
+    pub models: BTreeMap<&'static str, (Channel, Model)>,
+}
+
+impl Calibration {
+    pub fn set(&mut self, channel: Channel, model: Model) {
+        self.models.insert(channel.key(), (channel, model));
+    }
+
+    /// apply applies the model for every calibrated channel, other channels
+    /// are left as is. Calibrated values are clamped to be non-negative.
+    pub fn apply(&self, measurement: &Measurement) -> Measurement {
+        let mut calibrated = *measurement;
+        for (channel, model) in self.models.values() {
+            let value = model.evaluate(measurement.get(*channel) as f64);
+            calibrated.set(*channel, value.max(0.0) as f32);
+        }
+        calibrated
+    }
+}
+
+/// CalibrationStore holds calibrations keyed by SPS30 serial number. They are
+/// stored as text, one model per line:
+///
+/// ```text
+/// <serial> <channel key> linear <slope> <intercept>
+/// <serial> <channel key> polynomial <c0> <c1> <c2> ...
+/// <serial> <channel key> piecewise <intercept> <slope> [<knot> <change>]...
+/// ```
+///
+/// Empty lines and lines starting with # are ignored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CalibrationStore {
+    calibrations: BTreeMap<String, Calibration>,
+}
+
+impl CalibrationStore {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut store = CalibrationStore::default();
+        for (i, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", i + 1, message);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [serial, key, name, parameters @ ..] = fields.as_slice() else {
+                return Err(error(String::from("expected serial, channel and model")));
+            };
+            let channel =
+                Channel::from_key(key).ok_or_else(|| error(format!("unknown channel {}", key)))?;
+            let parameters = parameters
+                .iter()
+                .map(|p| p.parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|e| error(e.to_string()))?;
+            let model = Model::from_parameters(name, &parameters).map_err(error)?;
+            store.set(serial, channel, model);
+        }
+        Ok(store)
+    }
+
+    /// load reads a store from path, a missing file is an empty store.
+    pub fn load(path: &str) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(input) => Self::parse(&input),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("failed to read {}: {}", path, e)),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        std::fs::write(path, self.to_string())
+            .map_err(|e| format!("failed to write {}: {}", path, e))
+    }
+
+    pub fn set(&mut self, serial: &str, channel: Channel, model: Model) {
+        self.calibrations
+            .entry(String::from(serial))
+            .or_default()
+            .set(channel, model);
+    }
+
+    pub fn get(&self, serial: &str) -> Option<&Calibration> {
+        self.calibrations.get(serial)
+    }
+}
+
+impl fmt::Display for CalibrationStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# serial channel model parameters...")?;
+        for (serial, calibration) in &self.calibrations {
+            for (key, (_, model)) in &calibration.models {
+                write!(f, "{} {} {}", serial, key, model.name())?;
+                for parameter in model.parameters() {
+                    write!(f, " {}", parameter)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn points(f: impl Fn(f64) -> f64) -> Vec<(f64, f64)> {
+        (0..50).map(|i| i as f64 * 2.0).map(|x| (x, f(x))).collect()
+    }
+
+    #[test]
+    fn test_fit() {
+        struct TestCase {
+            kind: ModelKind,
+            f: fn(f64) -> f64,
+        }
+        let tests = [
+            TestCase {
+                kind: ModelKind::Linear,
+                f: |x| 0.8 * x + 2.0,
+            },
+            TestCase {
+                kind: ModelKind::Polynomial(2),
+                f: |x| 0.01 * x * x + 0.5 * x - 1.0,
+            },
+            TestCase {
+                kind: ModelKind::Polynomial(3),
+                f: |x| 0.0001 * x * x * x - 0.02 * x * x + x + 3.0,
+            },
+            // Knots are at the 1/2 quantile, i.e. x = 48.
+            TestCase {
+                kind: ModelKind::Piecewise(2),
+                f: |x| if x < 48.0 { x } else { 48.0 + 0.5 * (x - 48.0) },
+            },
+        ];
+        for case in tests {
+            let points = points(case.f);
+            let fit = fit(case.kind, &points).unwrap();
+            assert_eq!(fit.points, 50);
+            assert!(fit.r_squared > 0.999_999, "{:?}", fit);
+            for x in [0.0, 33.0, 97.0] {
+                let expected = (case.f)(x);
+                assert!(
+                    (fit.model.evaluate(x) - expected).abs() < 1e-6 * expected.abs().max(1.0),
+                    "{:?} at {}: expected {}, got {}",
+                    case.kind,
+                    x,
+                    expected,
+                    fit.model.evaluate(x)
+                );
+            }
+        }
+        assert!(fit(ModelKind::Linear, &[(1.0, 1.0), (2.0, 2.0)]).is_err());
+        assert!(fit(ModelKind::Linear, &[(1.0, 1.0); 10]).is_err());
+        assert!(fit(ModelKind::Polynomial(0), &points(|x| x)).is_err());
+    }
+
+    #[test]
+    fn test_align() {
+        let start = datetime!(2024-06-01 12:00 UTC);
+        let sensor: Vec<TimestampedMeasurement> = (0..4)
+            .map(|i| TimestampedMeasurement {
+                time: start + Duration::seconds(i * 5),
+                measurement: Measurement {
+                    mass_concentration_pm_2_5: i as f32,
+                    ..Default::default()
+                },
+            })
+            .collect();
+        let reference = vec![
+            (start, 10.0),
+            (start + Duration::seconds(6), 11.0),
+            (start + Duration::seconds(60), 20.0),
+        ];
+        assert_eq!(
+            align(
+                &sensor,
+                Channel::MassPm2_5,
+                &reference,
+                Duration::seconds(2)
+            ),
+            vec![(0.0, 10.0), (1.0, 11.0)]
+        );
+    }
+
+    #[test]
+    fn test_store() {
+        let mut store = CalibrationStore::default();
+        store.set(
+            "ABC123",
+            Channel::MassPm2_5,
+            Model::Linear {
+                slope: 0.5,
+                intercept: 1.0,
+            },
+        );
+        store.set(
+            "ABC123",
+            Channel::NumberPm1_0,
+            Model::Piecewise {
+                intercept: 0.0,
+                slope: 1.0,
+                knots: vec![(10.0, -2.0)],
+            },
+        );
+        store.set(
+            "DEF456",
+            Channel::MassPm10_0,
+            Model::Polynomial {
+                coefficients: vec![1.0, 0.0, 0.25],
+            },
+        );
+        let parsed = CalibrationStore::parse(&store.to_string()).unwrap();
+        assert_eq!(parsed, store);
+
+        let measurement = Measurement {
+            mass_concentration_pm_2_5: 10.0,
+            number_concentration_pm_1_0: 20.0,
+            mass_concentration_pm_10_0: 4.0,
+            ..Default::default()
+        };
+        let calibrated = parsed.get("ABC123").unwrap().apply(&measurement);
+        assert_eq!(calibrated.mass_concentration_pm_2_5, 6.0);
+        // 20 - 2 * 10, clamped.
+        assert_eq!(calibrated.number_concentration_pm_1_0, 0.0);
+        assert_eq!(calibrated.mass_concentration_pm_10_0, 4.0);
+        assert_eq!(
+            parsed
+                .get("DEF456")
+                .unwrap()
+                .apply(&measurement)
+                .mass_concentration_pm_10_0,
+            5.0
+        );
+        assert!(parsed.get("XYZ").is_none());
+
+        for input in [
+            "ABC123 mass_pm2_5",
+            "ABC123 mass_pm2_6 linear 1 0",
+            "ABC123 mass_pm2_5 linear 1",
+            "ABC123 mass_pm2_5 piecewise 1 0 2",
+            "ABC123 mass_pm2_5 cubic 1 2",
+            "ABC123 mass_pm2_5 linear 1 x",
+        ] {
+            assert!(CalibrationStore::parse(input).is_err(), "{}", input);
+        }
+        assert_eq!(
+            CalibrationStore::parse("# comment\n\n").unwrap(),
+            CalibrationStore::default()
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use super::measurement::*;
+use std::fmt;
+
+/// The particle size (in um) that classification is based on: the SPS30's
+/// smallest cumulative threshold that matches one of the ISO 14644-1 sizes.
+pub const CONSIDERED_SIZE_UM: f64 = 0.5;
+
+/// The caveat that applies to every classification made with an SPS30.
+pub const NO_5UM_CHANNEL: &str = "The SPS30 has no 5um channel, so the >=5.0um limits (which apply to ISO classes 7-9) are not checked";
+
+const NOT_CERTIFIED: &str = "The SPS30 is not a particle counter calibrated according to ISO 21501-4, so this is an indicative pre-check only";
+
+// ISO 14644-1:2015 Table A.1: the minimum number of sample locations for
+// cleanrooms up to the given area (in m2).
+const LOCATIONS: [(f64, usize); 27] = [
+    (2.0, 1),
+    (4.0, 2),
+    (6.0, 3),
+    (8.0, 4),
+    (10.0, 5),
+    (24.0, 6),
+    (28.0, 7),
+    (32.0, 8),
+    (36.0, 9),
+    (52.0, 10),
+    (56.0, 11),
+    (64.0, 12),
+    (68.0, 13),
+    (72.0, 14),
+    (76.0, 15),
+    (104.0, 16),
+    (108.0, 17),
+    (116.0, 18),
+    (148.0, 19),
+    (156.0, 20),
+    (192.0, 21),
+    (232.0, 22),
+    (276.0, 23),
+    (352.0, 24),
+    (436.0, 25),
+    (636.0, 26),
+    (1000.0, 27),
+];
+
+/// particles_per_m3 returns the concentration of particles >=0.5um, in
+/// particles per m3. The SPS30 only counts particles up to 10um, which is
+/// negligible for cleanroom purposes.
+pub fn particles_per_m3(measurement: &Measurement) -> f64 {
+    let count = measurement.number_concentration_pm_10_0 as f64
+        - measurement.number_concentration_pm_0_5 as f64;
+    count.max(0.0) * 1e6
+}
+
+/// concentration_limit returns the maximum concentration (in particles per
+/// m3) of particles >= size_um for an ISO class, as per the ISO 14644-1
+/// formula: 10^N * (0.1 / D)^2.08.
+pub fn concentration_limit(class: f64, size_um: f64) -> f64 {
+    10f64.powf(class) * (0.1 / size_um).powf(2.08)
+}
+
+/// class returns the (unrounded) ISO class corresponding to a concentration
+/// (in particles per m3) of particles >= size_um, i.e. the inverse of
+/// concentration_limit. Classes below 1 aren't defined, so cleaner air is
+/// reported as class 1.
+pub fn class(concentration_per_m3: f64, size_um: f64) -> f64 {
+    (concentration_per_m3.log10() + 2.08 * (size_um / 0.1).log10()).max(1.0)
+}
+
+/// required_locations returns the minimum number of sample locations for a
+/// cleanroom of area_m2, as per ISO 14644-1:2015 Table A.1.
+pub fn required_locations(area_m2: f64) -> usize {
+    match LOCATIONS.iter().find(|(area, _)| area_m2 <= *area) {
+        Some((_, locations)) => *locations,
+        None => (27.0 * area_m2 / 1000.0).ceil() as usize,
+    }
+}
+
+/// minimum_sample_volume_litres returns the minimum volume that must be
+/// sampled at each location for a class, such that at least 20 particles
+/// would be counted at the limit (but at least 2 litres).
+pub fn minimum_sample_volume_litres(class: f64) -> f64 {
+    (20.0 / concentration_limit(class, CONSIDERED_SIZE_UM) * 1000.0).max(2.0)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocationResult {
+    pub name: String,
+    pub samples: usize,
+    /// The volume sampled at this location (between the first and last
+    /// measurement), if the sample flow is known.
+    pub sampled_volume_litres: Option<f64>,
+    /// Mean concentration of particles >=0.5um, in particles per m3.
+    pub concentration_per_m3: f64,
+    pub class: f64,
+    pub meets_target: bool,
+}
+
+/// Survey collects measurements from the sample locations in a cleanroom, to
+/// check whether it meets a target ISO class.
+pub struct Survey {
+    target_class: f64,
+    area_m2: Option<f64>,
+    sample_flow_lpm: Option<f64>,
+    locations: Vec<(String, Vec<TimestampedMeasurement>)>,
+}
+
+impl Survey {
+    pub fn new(target_class: f64) -> Result<Self, String> {
+        if !(2.0..=9.0).contains(&target_class) {
+            return Err(format!(
+                "ISO class must be between 2 and 9, got {} (class 1 has no >=0.5um limit, and the SPS30 has no 0.1, 0.2 or 0.3um channels)",
+                target_class
+            ));
+        }
+        Ok(Survey {
+            target_class,
+            area_m2: None,
+            sample_flow_lpm: None,
+            locations: Vec::new(),
+        })
+    }
+
+    /// area sets the area of the cleanroom, which determines how many sample
+    /// locations are required.
+    pub fn area(mut self, area_m2: f64) -> Self {
+        self.area_m2 = Some(area_m2);
+        self
+    }
+
+    /// sample_flow sets the rate (in litres per minute) at which the sensor
+    /// samples air, which determines the volume sampled at each location.
+    pub fn sample_flow(mut self, litres_per_minute: f64) -> Self {
+        self.sample_flow_lpm = Some(litres_per_minute);
+        self
+    }
+
+    pub fn add_location(&mut self, name: &str, measurements: &[TimestampedMeasurement]) {
+        self.locations
+            .push((String::from(name), measurements.to_vec()));
+    }
+
+    pub fn classify(&self) -> Result<Classification, String> {
+        if self.locations.is_empty() {
+            return Err(String::from("no sample locations"));
+        }
+        let limit = concentration_limit(self.target_class, CONSIDERED_SIZE_UM);
+        let mut locations = Vec::new();
+        for (name, measurements) in &self.locations {
+            let (Some(first), Some(last)) = (measurements.first(), measurements.last()) else {
+                return Err(format!("no measurements for location {}", name));
+            };
+            let concentration = measurements
+                .iter()
+                .map(|m| particles_per_m3(&m.measurement))
+                .sum::<f64>()
+                / measurements.len() as f64;
+            let minutes = (last.time - first.time).as_seconds_f64() / 60.0;
+            locations.push(LocationResult {
+                name: name.clone(),
+                samples: measurements.len(),
+                sampled_volume_litres: self.sample_flow_lpm.map(|flow| flow * minutes),
+                concentration_per_m3: concentration,
+                class: class(concentration, CONSIDERED_SIZE_UM),
+                meets_target: concentration <= limit,
+            });
+        }
+
+        let required_locations = self.area_m2.map(required_locations);
+        let mut notes = vec![String::from(NO_5UM_CHANNEL), String::from(NOT_CERTIFIED)];
+        let enough_locations = match required_locations {
+            Some(required) if locations.len() < required => {
+                notes.push(format!(
+                    "{} sample locations are required for {} m2, got {}",
+                    required,
+                    self.area_m2.unwrap(),
+                    locations.len()
+                ));
+                false
+            }
+            Some(_) => true,
+            None => {
+                notes.push(String::from(
+                    "No area given, so the number of sample locations wasn't checked",
+                ));
+                true
+            }
+        };
+        let minimum_volume = minimum_sample_volume_litres(self.target_class);
+        let short_locations: Vec<&LocationResult> = locations
+            .iter()
+            .filter(|l| l.sampled_volume_litres.is_some_and(|v| v < minimum_volume))
+            .collect();
+        if self.sample_flow_lpm.is_none() {
+            notes.push(format!(
+                "No sample flow given, so the volume sampled at each location (at least {:.1} litres) wasn't checked",
+                minimum_volume
+            ));
+        }
+        for location in &short_locations {
+            notes.push(format!(
+                "{:.1} litres must be sampled at each location, got {:.1} at {}",
+                minimum_volume,
+                location.sampled_volume_litres.unwrap(),
+                location.name
+            ));
+        }
+        let enough_volume = short_locations.is_empty();
+        Ok(Classification {
+            target_class: self.target_class,
+            limit_per_m3: limit,
+            required_locations,
+            minimum_volume_litres: minimum_volume,
+            class: locations.iter().map(|l| l.class).fold(1.0, f64::max),
+            passed: enough_locations && enough_volume && locations.iter().all(|l| l.meets_target),
+            locations,
+            notes,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Classification {
+    pub target_class: f64,
+    /// Maximum concentration of particles >=0.5um for target_class, in
+    /// particles per m3.
+    pub limit_per_m3: f64,
+    pub required_locations: Option<usize>,
+    /// The minimum volume that must be sampled at each location for
+    /// target_class.
+    pub minimum_volume_litres: f64,
+    pub locations: Vec<LocationResult>,
+    /// The ISO class of the worst location.
+    pub class: f64,
+    /// Whether every location meets the target class, and there are enough
+    /// locations and sampled volume (if known).
+    pub passed: bool,
+    /// Caveats about the classification, which always include
+    /// NO_5UM_CHANNEL.
+    pub notes: Vec<String>,
+}
+
+impl fmt::Display for Classification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Target: ISO class {} (<= {:.0} particles/m3 >= {}um)",
+            self.target_class, self.limit_per_m3, CONSIDERED_SIZE_UM
+        )?;
+        writeln!(
+            f,
+            "{:<20} {:>8} {:>8} {:>16} {:>6}",
+            "Location", "Samples", "Litres", "Particles/m3", "Class"
+        )?;
+        for location in &self.locations {
+            let volume = match location.sampled_volume_litres {
+                Some(volume) => format!("{:.1}", volume),
+                None => String::from("-"),
+            };
+            writeln!(
+                f,
+                "{:<20} {:>8} {:>8} {:>16.0} {:>6.1}{}",
+                location.name,
+                location.samples,
+                volume,
+                location.concentration_per_m3,
+                location.class,
+                if location.meets_target { "" } else { " FAIL" }
+            )?;
+        }
+        writeln!(
+            f,
+            "Result: ISO class {:.1}, {}",
+            self.class,
+            if self.passed { "PASS" } else { "FAIL" }
+        )?;
+        for note in &self.notes {
+            writeln!(f, "Note: {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(per_cm3: f32) -> Measurement {
+        Measurement {
+            number_concentration_pm_0_5: 100.0,
+            number_concentration_pm_10_0: 100.0 + per_cm3,
+            ..Default::default()
+        }
+    }
+
+    // Returns one measurement per minute with the given concentrations.
+    fn samples(per_cm3: &[f32]) -> Vec<TimestampedMeasurement> {
+        per_cm3
+            .iter()
+            .enumerate()
+            .map(|(i, value)| TimestampedMeasurement {
+                time: time::macros::datetime!(2024-06-01 12:00 UTC)
+                    + time::Duration::minutes(i as i64),
+                measurement: measurement(*value),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_formula() {
+        struct TestCase {
+            class: f64,
+            // From ISO 14644-1:2015 Table 1, for >= 0.5um.
+            limit: f64,
+        }
+        let tests = [
+            TestCase {
+                class: 5.0,
+                limit: 3520.0,
+            },
+            TestCase {
+                class: 7.0,
+                limit: 352000.0,
+            },
+            TestCase {
+                class: 8.0,
+                limit: 3520000.0,
+            },
+        ];
+        for case in tests {
+            let limit = concentration_limit(case.class, CONSIDERED_SIZE_UM);
+            // The table is rounded to 3 significant figures.
+            assert!((limit - case.limit).abs() < 0.005 * case.limit, "{}", limit);
+            assert!((class(limit, CONSIDERED_SIZE_UM) - case.class).abs() < 1e-9);
+        }
+        assert_eq!(class(0.0, CONSIDERED_SIZE_UM), 1.0);
+        assert_eq!(particles_per_m3(&measurement(0.5)), 0.5e6);
+    }
+
+    #[test]
+    fn test_required_locations() {
+        struct TestCase {
+            area: f64,
+            expected_output: usize,
+        }
+        let tests = [
+            TestCase {
+                area: 1.5,
+                expected_output: 1,
+            },
+            TestCase {
+                area: 10.0,
+                expected_output: 5,
+            },
+            TestCase {
+                area: 10.5,
+                expected_output: 6,
+            },
+            TestCase {
+                area: 1000.0,
+                expected_output: 27,
+            },
+            TestCase {
+                area: 2000.0,
+                expected_output: 54,
+            },
+        ];
+        for case in tests {
+            assert_eq!(
+                required_locations(case.area),
+                case.expected_output,
+                "{}",
+                case.area
+            );
+        }
+        assert_eq!(minimum_sample_volume_litres(8.0), 2.0);
+        assert!((minimum_sample_volume_litres(5.0) - 5.68).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_survey() {
+        assert!(Survey::new(0.5).is_err());
+        assert!(Survey::new(1.0).is_err());
+        assert!(Survey::new(2.0).is_ok());
+        assert!(Survey::new(7.0).unwrap().classify().is_err());
+
+        // Limit for class 7 is 352000/m3, i.e. 0.352/cm3.
+        let mut survey = Survey::new(7.0).unwrap().area(6.0);
+        survey.add_location("A", &samples(&[0.1, 0.3]));
+        survey.add_location("B", &samples(&[0.3]));
+        let classification = survey.classify().unwrap();
+        assert_eq!(classification.required_locations, Some(3));
+        assert!(classification.locations.iter().all(|l| l.meets_target));
+        assert!(!classification.passed);
+        assert!(classification.notes.iter().any(|n| n == NO_5UM_CHANNEL));
+
+        survey.add_location("C", &samples(&[0.4]));
+        let classification = survey.classify().unwrap();
+        assert!(!classification.passed);
+        assert!(!classification.locations[2].meets_target);
+        assert!((classification.class - 7.056).abs() < 1e-3);
+
+        let mut survey = Survey::new(7.0).unwrap().area(4.0);
+        survey.add_location("A", &samples(&[0.1]));
+        survey.add_location("B", &samples(&[0.2]));
+        let classification = survey.classify().unwrap();
+        assert!(classification.passed);
+        assert!((classification.class - 6.755).abs() < 1e-3);
+        assert_eq!(classification.locations[0].sampled_volume_litres, None);
+    }
+
+    #[test]
+    fn test_sampled_volume() {
+        // Class 5 requires 5.68 litres per location: at 1 litre per minute,
+        // A (7 minutes) has sampled enough but B (3 minutes) hasn't.
+        let mut survey = Survey::new(5.0).unwrap().sample_flow(1.0);
+        survey.add_location("A", &samples(&[0.001; 8]));
+        survey.add_location("B", &samples(&[0.001; 4]));
+        let classification = survey.classify().unwrap();
+        assert!((classification.minimum_volume_litres - 5.68).abs() < 0.01);
+        assert_eq!(
+            classification
+                .locations
+                .iter()
+                .map(|l| l.sampled_volume_litres)
+                .collect::<Vec<_>>(),
+            vec![Some(7.0), Some(3.0)]
+        );
+        assert!(classification.locations.iter().all(|l| l.meets_target));
+        assert!(!classification.passed);
+        assert!(classification
+            .notes
+            .iter()
+            .any(|n| n == "5.7 litres must be sampled at each location, got 3.0 at B"));
+
+        let mut survey = Survey::new(5.0).unwrap().sample_flow(2.0);
+        survey.add_location("A", &samples(&[0.001; 8]));
+        survey.add_location("B", &samples(&[0.001; 4]));
+        assert!(survey.classify().unwrap().passed);
+    }
+}
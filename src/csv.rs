@@ -0,0 +1,600 @@
+use super::measurement::*;
+use std::fmt;
+use std::io::BufRead;
+
+/// TimeFormat specifies how the time column is parsed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeFormat {
+    // Any of the formats below.
+    Auto,
+    // The zone-less format written by csv_row, which is always UTC.
+    NaiveUtc,
+    Rfc3339,
+    // Seconds since the Unix epoch, may contain a fractional part.
+    UnixSeconds,
+}
+
+/// parse_time parses a time as found in a log, e.g. for selecting a time range.
+pub fn parse_time(value: &str, format: TimeFormat) -> Result<time::OffsetDateTime, String> {
+    let naive_utc = || {
+        time::PrimitiveDateTime::parse(value, &CSV_TIME_FORMAT)
+            .map(|t| t.assume_utc())
+            .ok()
+    };
+    let rfc3339 =
+        || time::OffsetDateTime::parse(value, &time::format_description::well_known::Rfc3339).ok();
+    let unix_seconds = || {
+        value
+            .parse::<f64>()
+            .ok()
+            .filter(|s| s.is_finite())
+            .and_then(|s| {
+                time::OffsetDateTime::from_unix_timestamp_nanos((s * 1e9).round() as i128).ok()
+            })
+    };
+    let parsed = match format {
+        TimeFormat::Auto => naive_utc().or_else(rfc3339).or_else(unix_seconds),
+        TimeFormat::NaiveUtc => naive_utc(),
+        TimeFormat::Rfc3339 => rfc3339(),
+        TimeFormat::UnixSeconds => unix_seconds(),
+    };
+    parsed.ok_or_else(|| format!("invalid time {:?}", value))
+}
+
+/// CsvLayout describes which column contains what. The layout written by
+/// Measurement::csv_header/csv_row is CsvLayout::default(), other layouts can
+/// be built by hand or derived from a header (see CsvLayout::from_header).
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsvLayout {
+    delimiter: char,
+    time_column: usize,
+    time_format: TimeFormat,
+    channels: Vec<(usize, Channel)>,
+    has_header: bool,
+}
+
+impl Default for CsvLayout {
+    fn default() -> Self {
+        CsvLayout {
+            delimiter: ',',
+            time_column: 0,
+            time_format: TimeFormat::NaiveUtc,
+            channels: Channel::ALL
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (i + 1, *c))
+                .collect(),
+            has_header: true,
+        }
+    }
+}
+
+impl CsvLayout {
+    /// new creates a layout with only a time column, use column to add
+    /// channels.
+    pub fn new(time_column: usize) -> Self {
+        CsvLayout {
+            delimiter: ',',
+            time_column,
+            time_format: TimeFormat::Auto,
+            channels: vec![],
+            has_header: false,
+        }
+    }
+
+    pub fn delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn time_format(mut self, time_format: TimeFormat) -> Self {
+        self.time_format = time_format;
+        self
+    }
+
+    pub fn column(mut self, index: usize, channel: Channel) -> Self {
+        self.channels.retain(|(_, c)| *c != channel);
+        self.channels.push((index, channel));
+        self
+    }
+
+    /// has_header specifies whether the first line should be skipped.
+    pub fn has_header(mut self, has_header: bool) -> Self {
+        self.has_header = has_header;
+        self
+    }
+
+    /// from_header derives a layout from a header line. Columns are matched
+    /// by channel label (as written by Measurement::csv_header, with or
+    /// without the unit) or key (see Channel::key), unknown columns are
+    /// ignored. The time column must be called time or timestamp.
+    pub fn from_header(header: &str, delimiter: char) -> Result<Self, String> {
+        let mut time_column = None;
+        let mut channels = vec![];
+        for (i, name) in split(header, delimiter).enumerate() {
+            let name = strip_unit(name);
+            if is_time_column(name) {
+                time_column = Some(i);
+            } else if let Some(channel) = Channel::ALL
+                .iter()
+                .find(|c| name.eq_ignore_ascii_case(c.label()) || name == c.key())
+            {
+                channels.push((i, *channel));
+            }
+        }
+        let time_column = time_column.ok_or_else(|| String::from("header has no time column"))?;
+        if channels.is_empty() {
+            return Result::Err(String::from("header has no known measurement columns"));
+        }
+        Result::Ok(CsvLayout {
+            delimiter,
+            time_column,
+            time_format: TimeFormat::Auto,
+            channels,
+            has_header: true,
+        })
+    }
+
+    /// parse_row parses one line. Channels that aren't part of the layout are
+    /// set to NaN.
+    pub fn parse_row(&self, line: &str) -> Result<TimestampedMeasurement, String> {
+        let fields: Vec<&str> = split(line, self.delimiter).collect();
+        let field = |index: usize| {
+            fields.get(index).copied().ok_or_else(|| {
+                format!(
+                    "expected at least {} columns, got {}",
+                    index + 1,
+                    fields.len()
+                )
+            })
+        };
+        let time = parse_time(field(self.time_column)?, self.time_format)?;
+        let mut measurement = Measurement::default();
+        for channel in Channel::ALL {
+            measurement.set(channel, f32::NAN);
+        }
+        for (index, channel) in &self.channels {
+            let value = field(*index)?;
+            let value = value
+                .parse::<f32>()
+                .map_err(|_| format!("invalid value {:?} for {}", value, channel.key()))?;
+            measurement.set(*channel, value);
+        }
+        Result::Ok(TimestampedMeasurement { time, measurement })
+    }
+}
+
+// Strips the unit from a column name, e.g. "Typical Particle Size (um)".
+fn strip_unit(name: &str) -> &str {
+    match name.rfind(" (") {
+        Some(index) if name.ends_with(')') => &name[..index],
+        _ => name,
+    }
+}
+
+fn is_time_column(name: &str) -> bool {
+    name.eq_ignore_ascii_case("time") || name.eq_ignore_ascii_case("timestamp")
+}
+
+/// TimeSeries is a single column of timestamped values, e.g. humidity or the
+/// output of another instrument.
+pub type TimeSeries = Vec<(time::OffsetDateTime, f32)>;
+
+/// nearest returns the value of the sample nearest to time, if it's within
+/// tolerance. series must be sorted by time.
+pub fn nearest(
+    series: &[(time::OffsetDateTime, f32)],
+    time: time::OffsetDateTime,
+    tolerance: time::Duration,
+) -> Option<f32> {
+    let index = series.partition_point(|(t, _)| *t < time);
+    [index.checked_sub(1), Some(index)]
+        .into_iter()
+        .flatten()
+        .filter_map(|i| series.get(i))
+        .map(|(t, value)| ((*t - time).abs(), *value))
+        .filter(|(distance, _)| *distance <= tolerance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, value)| value)
+}
+
+/// read_column reads the time column (see CsvLayout::from_header), and the
+/// first column matching one of names (ignoring case and units), from a CSV
+/// file with a header. As with CsvReader, bad lines are returned as errors
+/// without stopping.
+pub fn read_column<R: BufRead>(
+    reader: R,
+    delimiter: char,
+    names: &[&str],
+) -> Result<(TimeSeries, Vec<LineError>), String> {
+    let mut lines = reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |l| !l.trim().is_empty()));
+    let header = match lines.next() {
+        Some((_, line)) => line.map_err(|e| format!("failed to read header: {}", e))?,
+        None => return Result::Err(String::from("missing header")),
+    };
+    let columns: Vec<&str> = split(&header, delimiter).map(strip_unit).collect();
+    let time_column = columns
+        .iter()
+        .position(|c| is_time_column(c))
+        .ok_or_else(|| String::from("header has no time column"))?;
+    let value_column = names
+        .iter()
+        .find_map(|name| columns.iter().position(|c| c.eq_ignore_ascii_case(name)))
+        .ok_or_else(|| format!("header has no {} column", names.join("/")))?;
+
+    let mut series = vec![];
+    let mut errors = vec![];
+    for (index, line) in lines {
+        let error = |message: String| LineError {
+            line: index + 1,
+            message,
+        };
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                errors.push(error(format!("failed to read line: {}", e)));
+                break;
+            }
+        };
+        let fields: Vec<&str> = split(&line, delimiter).collect();
+        let (Some(time), Some(value)) = (fields.get(time_column), fields.get(value_column)) else {
+            errors.push(error(format!(
+                "expected at least {} columns",
+                time_column.max(value_column) + 1
+            )));
+            continue;
+        };
+        let parsed = parse_time(time, TimeFormat::Auto).and_then(|time| {
+            value
+                .parse::<f32>()
+                .map(|value| (time, value))
+                .map_err(|_| format!("invalid value {:?}", value))
+        });
+        match parsed {
+            Ok(sample) => series.push(sample),
+            Err(e) => errors.push(error(e)),
+        }
+    }
+    Result::Ok((series, errors))
+}
+
+/// Table is every column of a CSV file, keyed by the time column.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Table {
+    /// Column names without units, excluding the time column.
+    pub columns: Vec<String>,
+    pub rows: Vec<(time::OffsetDateTime, Vec<f32>)>,
+}
+
+/// read_table reads every column of a CSV file with a header and a time
+/// column (see CsvLayout::from_header), e.g. a log from another instrument.
+/// Empty or non-numeric values are read as NaN, bad lines are returned as
+/// errors without stopping.
+pub fn read_table<R: BufRead>(
+    reader: R,
+    delimiter: char,
+) -> Result<(Table, Vec<LineError>), String> {
+    let mut lines = reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |l| !l.trim().is_empty()));
+    let header = match lines.next() {
+        Some((_, line)) => line.map_err(|e| format!("failed to read header: {}", e))?,
+        None => return Result::Err(String::from("missing header")),
+    };
+    let names: Vec<&str> = split(&header, delimiter).map(strip_unit).collect();
+    let time_column = names
+        .iter()
+        .position(|c| is_time_column(c))
+        .ok_or_else(|| String::from("header has no time column"))?;
+
+    let mut table = Table {
+        columns: names
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != time_column)
+            .map(|(_, name)| String::from(*name))
+            .collect(),
+        rows: vec![],
+    };
+    let mut errors = vec![];
+    for (index, line) in lines {
+        let error = |message: String| LineError {
+            line: index + 1,
+            message,
+        };
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                errors.push(error(format!("failed to read line: {}", e)));
+                break;
+            }
+        };
+        let fields: Vec<&str> = split(&line, delimiter).collect();
+        if fields.len() != names.len() {
+            errors.push(error(format!(
+                "expected {} columns, got {}",
+                names.len(),
+                fields.len()
+            )));
+            continue;
+        }
+        match parse_time(fields[time_column], TimeFormat::Auto) {
+            Ok(time) => table.rows.push((
+                time,
+                fields
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| *i != time_column)
+                    .map(|(_, value)| value.parse().unwrap_or(f32::NAN))
+                    .collect(),
+            )),
+            Err(e) => errors.push(error(e)),
+        }
+    }
+    Result::Ok((table, errors))
+}
+
+fn split(line: &str, delimiter: char) -> impl Iterator<Item = &str> {
+    line.split(delimiter)
+        .map(|field| field.trim().trim_matches('"'))
+}
+
+/// LineError is a problem with a single line, which doesn't prevent reading
+/// the following lines.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineError {
+    // 1-based, like every text editor.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// CsvReader reads timestamped measurements from CSV files. If no layout is
+/// specified, it is derived from the header (see CsvLayout::from_header).
+///
+/// Empty lines are skipped, and bad lines are returned as errors without
+/// stopping iteration. The only exception is a missing or invalid header,
+/// which ends iteration after returning the error.
+pub struct CsvReader<R: BufRead> {
+    lines: std::io::Lines<R>,
+    layout: Option<CsvLayout>,
+    skip_header: bool,
+    line_number: usize,
+    done: bool,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    pub fn new(reader: R, layout: Option<CsvLayout>) -> Self {
+        CsvReader {
+            lines: reader.lines(),
+            skip_header: layout.as_ref().is_some_and(|l| l.has_header),
+            layout,
+            line_number: 0,
+            done: false,
+        }
+    }
+
+    /// read_all reads all measurements, and all errors.
+    pub fn read_all(self) -> (Vec<TimestampedMeasurement>, Vec<LineError>) {
+        let mut measurements = vec![];
+        let mut errors = vec![];
+        for result in self {
+            match result {
+                Ok(measurement) => measurements.push(measurement),
+                Err(e) => errors.push(e),
+            }
+        }
+        (measurements, errors)
+    }
+
+    fn error(&self, message: String) -> LineError {
+        LineError {
+            line: self.line_number,
+            message,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for CsvReader<R> {
+    type Item = Result<TimestampedMeasurement, LineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => {
+                    self.done = true;
+                    self.line_number += 1;
+                    return Some(Err(self.error(format!("failed to read line: {}", e))));
+                }
+            };
+            self.line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let layout = match &self.layout {
+                Some(layout) => layout,
+                None => match CsvLayout::from_header(&line, ',') {
+                    Ok(layout) => {
+                        self.layout = Some(layout);
+                        continue;
+                    }
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(self.error(e)));
+                    }
+                },
+            };
+            if self.skip_header {
+                self.skip_header = false;
+                continue;
+            }
+            return Some(layout.parse_row(&line).map_err(|e| self.error(e)));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_round_trip() {
+        let measurement = TimestampedMeasurement {
+            time: datetime!(2024-06-01 12:34:56 UTC),
+            measurement: Measurement {
+                mass_concentration_pm_1_0: 1.5,
+                mass_concentration_pm_2_5: 2.5,
+                number_concentration_pm_0_5: 10.25,
+                typical_particle_size: 0.6,
+                ..Default::default()
+            },
+        };
+        let data = format!("{}\n{}\n", Measurement::csv_header(), measurement.csv_row());
+
+        for layout in [None, Some(CsvLayout::default())] {
+            let (measurements, errors) = CsvReader::new(data.as_bytes(), layout).read_all();
+            assert_eq!(measurements, vec![measurement]);
+            assert!(errors.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_bad_lines() {
+        let data = format!(
+            "{}\n\
+            2024-06-01T12:00:00,1,2,3,4,5,6,7,8,9,0.5\n\
+            2024-06-01T12:00:05,1,2,3\n\
+            \n\
+            yesterday,1,2,3,4,5,6,7,8,9,0.5\n\
+            2024-06-01T12:00:15,1,2,3,4,5,6,7,8,nine,0.5\n\
+            2024-06-01T12:00:20,1,2,3,4,5,6,7,8,9,0.5\n",
+            Measurement::csv_header()
+        );
+        let (measurements, errors) = CsvReader::new(data.as_bytes(), None).read_all();
+        assert_eq!(measurements.len(), 2);
+        assert_eq!(measurements[1].time, datetime!(2024-06-01 12:00:20 UTC));
+        assert_eq!(
+            errors,
+            vec![
+                LineError {
+                    line: 3,
+                    message: String::from("expected at least 5 columns, got 4"),
+                },
+                LineError {
+                    line: 5,
+                    message: String::from("invalid time \"yesterday\""),
+                },
+                LineError {
+                    line: 6,
+                    message: String::from("invalid value \"nine\" for number_pm10_0"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_header_layouts() {
+        let data = "pm2_5,ignored,time,mass_pm10_0\n\
+            1.5,x,2024-06-01T12:00:00Z,3\n\
+            2.5,x,1717243205.5,4\n";
+        let (measurements, errors) = CsvReader::new(data.as_bytes(), None).read_all();
+        assert!(errors.is_empty(), "{:?}", errors);
+        // "pm2_5" isn't a known column name, so only PM10 gets parsed.
+        assert!(measurements[0]
+            .measurement
+            .mass_concentration_pm_2_5
+            .is_nan());
+        assert_eq!(measurements[0].measurement.mass_concentration_pm_10_0, 3.0);
+        assert_eq!(measurements[1].time, datetime!(2024-06-01 12:00:05.5 UTC));
+
+        let (measurements, errors) = CsvReader::new("a,b\n1,2\n".as_bytes(), None).read_all();
+        assert!(measurements.is_empty());
+        assert_eq!(
+            errors,
+            vec![LineError {
+                line: 1,
+                message: String::from("header has no time column"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_custom_layout() {
+        let layout = CsvLayout::new(2)
+            .delimiter(';')
+            .time_format(TimeFormat::UnixSeconds)
+            .column(0, Channel::MassPm2_5)
+            .column(1, Channel::NumberPm0_5);
+        let data = "1.5;20;1717243200\n2.5;30;2024-06-01T12:00:00\n";
+        let (measurements, errors) = CsvReader::new(data.as_bytes(), Some(layout)).read_all();
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(measurements[0].time, datetime!(2024-06-01 12:00:00 UTC));
+        assert_eq!(measurements[0].measurement.mass_concentration_pm_2_5, 1.5);
+        assert_eq!(
+            measurements[0].measurement.number_concentration_pm_0_5,
+            20.0
+        );
+        assert_eq!(
+            errors,
+            vec![LineError {
+                line: 2,
+                message: String::from("invalid time \"2024-06-01T12:00:00\""),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_read_column() {
+        let input = "timestamp,PM2.5,Relative Humidity (%)\n\
+                     2024-06-01T12:00:00,1,40.5\n\
+                     \n\
+                     2024-06-01T12:00:05,2,oops\n\
+                     1717243210,3,41\n";
+        let (series, errors) =
+            read_column(input.as_bytes(), ',', &["rh", "relative humidity"]).unwrap();
+        assert_eq!(
+            series,
+            vec![
+                (datetime!(2024-06-01 12:00:00 UTC), 40.5),
+                (datetime!(2024-06-01 12:00:10 UTC), 41.0),
+            ]
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 4);
+        assert!(read_column(input.as_bytes(), ',', &["temperature"]).is_err());
+        assert!(read_column("".as_bytes(), ',', &["rh"]).is_err());
+    }
+
+    #[test]
+    fn test_read_table() {
+        let input = "Concentration (#/cc),Time,Flags\n\
+                     1523,2024-06-01T12:00:00,\n\
+                     1498.5,2024-06-01T12:00:01,3\n\
+                     1502,2024-06-01T12:00:02\n\
+                     1502,later,1\n";
+        let (table, errors) = read_table(input.as_bytes(), ',').unwrap();
+        assert_eq!(table.columns, vec!["Concentration", "Flags"]);
+        assert_eq!(table.rows.len(), 2);
+        assert_eq!(table.rows[0].0, datetime!(2024-06-01 12:00:00 UTC));
+        assert_eq!(table.rows[0].1[0], 1523.0);
+        assert!(table.rows[0].1[1].is_nan());
+        assert_eq!(table.rows[1].1, vec![1498.5, 3.0]);
+        assert_eq!(
+            errors.iter().map(|e| e.line).collect::<Vec<_>>(),
+            vec![4, 5]
+        );
+        assert!(read_table("PM2.5\n1\n".as_bytes(), ',').is_err());
+    }
+}
@@ -0,0 +1,436 @@
+use super::measurement::*;
+use super::shdlc;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::time::Duration;
+
+// Command IDs, see page 15 onwards of the datasheet.
+const CMD_START_MEASUREMENT: u8 = 0x00;
+const CMD_STOP_MEASUREMENT: u8 = 0x01;
+const CMD_READ_MEASURED_VALUES: u8 = 0x03;
+const CMD_START_FAN_CLEANING: u8 = 0x56;
+const CMD_DEVICE_INFORMATION: u8 = 0xD0;
+const CMD_READ_VERSION: u8 = 0xD1;
+const CMD_READ_DEVICE_STATUS_REGISTER: u8 = 0xD2;
+const CMD_DEVICE_RESET: u8 = 0xD3;
+
+// Subcommands for CMD_DEVICE_INFORMATION.
+const DEVICE_INFORMATION_PRODUCT_TYPE: u8 = 0x00;
+const DEVICE_INFORMATION_SERIAL_NUMBER: u8 = 0x03;
+
+// Bits of the device status register, see page 21 of the datasheet.
+const STATUS_FAN_SPEED_WARNING: u32 = 1 << 21;
+const STATUS_LASER_ERROR: u32 = 1 << 5;
+const STATUS_FAN_ERROR: u32 = 1 << 4;
+
+/// DeviceStats counts problems seen on the link to the sensor, which is
+/// useful to diagnose cabling or power issues (see also the Known issues in
+/// README.md).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeviceStats {
+    // Frames that could not be decoded for reasons other than the checksum.
+    pub frame_errors: u64,
+    pub checksum_failures: u64,
+    // ReadMeasuredValues responses without any data.
+    pub empty_responses: u64,
+    pub recovery_attempts: u64,
+}
+
+/// VersionInfo is the response to the Read Version command.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VersionInfo {
+    pub firmware_major: u8,
+    pub firmware_minor: u8,
+    pub hardware_revision: u8,
+    pub shdlc_major: u8,
+    pub shdlc_minor: u8,
+}
+
+impl VersionInfo {
+    pub fn firmware_version(&self) -> String {
+        format!("{}.{}", self.firmware_major, self.firmware_minor)
+    }
+
+    /// supports_status_register returns true if the firmware implements the
+    /// Read Device Status Register command (added in firmware 2.2).
+    pub fn supports_status_register(&self) -> bool {
+        (self.firmware_major, self.firmware_minor) >= (2, 2)
+    }
+}
+
+/// DeviceStatus is the decoded device status register, which flags problems
+/// with the sensor itself (as opposed to the link, see DeviceStats).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeviceStatus {
+    /// The fan speed is more than 3% off its target, e.g. because it is
+    /// blocked or the supply voltage is too low. Measurements may be
+    /// inaccurate.
+    pub fan_speed_warning: bool,
+    /// The laser current is out of range, measurements are unreliable.
+    pub laser_error: bool,
+    /// The fan is switched on but not turning.
+    pub fan_error: bool,
+}
+
+impl DeviceStatus {
+    pub fn from_register(register: u32) -> Self {
+        DeviceStatus {
+            fan_speed_warning: register & STATUS_FAN_SPEED_WARNING != 0,
+            laser_error: register & STATUS_LASER_ERROR != 0,
+            fan_error: register & STATUS_FAN_ERROR != 0,
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        *self == DeviceStatus::default()
+    }
+}
+
+/// Sps30 talks to an SPS30 over UART using SHDLC.
+///
+/// Reading and writing happen via separate handles as that's what serialport
+/// provides (see Sps30::open), this also makes it easy to test against canned
+/// responses.
+pub struct Sps30<R: Read, W: Write> {
+    reader: BufReader<R>,
+    writer: W,
+    stats: DeviceStats,
+}
+
+pub type SerialSps30 = Sps30<Box<dyn serialport::SerialPort>, Box<dyn serialport::SerialPort>>;
+
+impl SerialSps30 {
+    pub fn open(path: &str) -> Result<SerialSps30, String> {
+        let port = serialport::new(path, /* baud_rate */ 115_200)
+            .data_bits(serialport::DataBits::Eight)
+            .parity(serialport::Parity::None)
+            .stop_bits(serialport::StopBits::One)
+            .timeout(core::time::Duration::new(5, 0))
+            .open()
+            .map_err(|e| format!("unable to open serial port {}: {}", path, e))?;
+        let reader = port
+            .try_clone()
+            .map_err(|e| format!("failed to clone serial port: {}", e))?;
+        Result::Ok(Sps30::new(reader, port))
+    }
+}
+
+impl<R: Read, W: Write> Sps30<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Sps30 {
+            reader: BufReader::new(reader),
+            writer,
+            stats: DeviceStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> DeviceStats {
+        self.stats
+    }
+
+    /// read_frame reads one entire (still stuffed) frame, including start and
+    /// stop bytes.
+    fn read_frame(&mut self) -> Result<Vec<u8>, String> {
+        // Skip everything up to, and including, the start byte. We expect
+        // nothing prior to the start byte, but it's easy enough to resync.
+        loop {
+            let mut skipped = vec![];
+            let n = self
+                .reader
+                .read_until(0x7E, &mut skipped)
+                .map_err(|e| format!("failure reading data: {}", e))?;
+            if n == 0 {
+                return Result::Err(String::from("unexpected end of data"));
+            }
+            if skipped.len() > 1 {
+                self.stats.frame_errors += 1;
+            }
+            if skipped.last() == Some(&0x7E) {
+                break;
+            }
+        }
+
+        let mut frame = vec![0x7E];
+        loop {
+            let n = self
+                .reader
+                .read_until(0x7E, &mut frame)
+                .map_err(|e| format!("failure reading data: {}", e))?;
+            if n == 0 || frame.last() != Some(&0x7E) {
+                return Result::Err(String::from("unexpected end of data"));
+            }
+            // Two consecutive 0x7E mean we were out of sync, and the second
+            // one is the real start byte.
+            if frame.len() == 2 {
+                frame.truncate(1);
+                continue;
+            }
+            return Result::Ok(frame);
+        }
+    }
+
+    /// execute sends a command, and returns the sensor's response.
+    pub fn execute(&mut self, cmd: u8, data: &[u8]) -> Result<shdlc::MisoFrame, String> {
+        let request = shdlc::mosi_frame(0, cmd, data)?;
+        self.writer
+            .write_all(&request)
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| format!("failed to write frame: {}", e))?;
+
+        let raw = self.read_frame()?;
+        let frame = match shdlc::decode_miso_frame(&raw) {
+            Ok(frame) => frame,
+            Err(e) => {
+                if shdlc::has_valid_checksum(&raw) {
+                    self.stats.frame_errors += 1;
+                } else {
+                    self.stats.checksum_failures += 1;
+                }
+                return Result::Err(e);
+            }
+        };
+        if frame.cmd != cmd {
+            self.stats.frame_errors += 1;
+            return Result::Err(format!(
+                "expected response to cmd {:#04X}, got {}",
+                cmd, frame
+            ));
+        }
+        if frame.state != 0 {
+            return Result::Err(format!(
+                "cmd {:#04X} failed with state {:#04X}",
+                cmd, frame.state
+            ));
+        }
+        Result::Ok(frame)
+    }
+
+    fn device_information(&mut self, subcommand: u8) -> Result<String, String> {
+        let frame = self.execute(CMD_DEVICE_INFORMATION, &[subcommand])?;
+        // Strings are null-terminated.
+        let data: Vec<u8> = frame.data.into_iter().take_while(|b| *b != 0).collect();
+        String::from_utf8(data).map_err(|_| String::from("device information is not valid utf-8"))
+    }
+
+    pub fn product_type(&mut self) -> Result<String, String> {
+        self.device_information(DEVICE_INFORMATION_PRODUCT_TYPE)
+    }
+
+    pub fn serial_number(&mut self) -> Result<String, String> {
+        self.device_information(DEVICE_INFORMATION_SERIAL_NUMBER)
+    }
+
+    pub fn read_version(&mut self) -> Result<VersionInfo, String> {
+        let frame = self.execute(CMD_READ_VERSION, &[])?;
+        if frame.data.len() != 7 {
+            return Result::Err(format!(
+                "Read Version MISO frame has unexpected length, actual={}, frame={}",
+                frame.data.len(),
+                frame
+            ));
+        }
+        // Bytes 2 and 4 are reserved.
+        Result::Ok(VersionInfo {
+            firmware_major: frame.data[0],
+            firmware_minor: frame.data[1],
+            hardware_revision: frame.data[3],
+            shdlc_major: frame.data[5],
+            shdlc_minor: frame.data[6],
+        })
+    }
+
+    /// read_device_status reads the device status register, without clearing
+    /// it. Flags stay set until cleared, or until the sensor is reset, so a
+    /// flag may describe a problem that has since gone away.
+    pub fn read_device_status(&mut self) -> Result<DeviceStatus, String> {
+        let frame = self.execute(CMD_READ_DEVICE_STATUS_REGISTER, &[/* don't clear */ 0x00])?;
+        if frame.data.len() != 5 {
+            return Result::Err(format!(
+                "Read Device Status Register MISO frame has unexpected length, actual={}, frame={}",
+                frame.data.len(),
+                frame
+            ));
+        }
+        // Byte 4 is reserved.
+        let register =
+            u32::from_be_bytes([frame.data[0], frame.data[1], frame.data[2], frame.data[3]]);
+        Result::Ok(DeviceStatus::from_register(register))
+    }
+
+    pub fn start_measurement(&mut self) -> Result<(), String> {
+        self.execute(
+            CMD_START_MEASUREMENT,
+            &[
+                /* subcommand, must be 0x01 */ 0x01,
+                /* output as big-endian IEEE754 float values */ 0x03,
+            ],
+        )?;
+        Result::Ok(())
+    }
+
+    pub fn stop_measurement(&mut self) -> Result<(), String> {
+        self.execute(CMD_STOP_MEASUREMENT, &[])?;
+        Result::Ok(())
+    }
+
+    /// read_measurement returns None if no new measurement is available, which
+    /// is expected for the first second after starting measurement (and
+    /// unfortunately sometimes for much longer, see README.md).
+    pub fn read_measurement(&mut self) -> Result<Option<Measurement>, String> {
+        let frame = self.execute(CMD_READ_MEASURED_VALUES, &[])?;
+        if frame.data.is_empty() {
+            self.stats.empty_responses += 1;
+            return Result::Ok(None);
+        }
+        decode_measurement_frame(&frame).map(Some)
+    }
+
+    /// start_fan_cleaning runs the fan at maximum speed for 10 seconds to blow
+    /// out dust, which is only possible while measuring. The sensor also does
+    /// this by itself, once a week by default.
+    pub fn start_fan_cleaning(&mut self) -> Result<(), String> {
+        self.execute(CMD_START_FAN_CLEANING, &[])?;
+        Result::Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<(), String> {
+        self.execute(CMD_DEVICE_RESET, &[])?;
+        Result::Ok(())
+    }
+
+    /// recover resets the sensor and restarts measurement, which may (or may
+    /// not) fix a sensor that is stuck returning empty responses.
+    pub fn recover(&mut self) -> Result<(), String> {
+        self.stats.recovery_attempts += 1;
+        self.reset()?;
+        // The datasheet doesn't specify how long a reset takes, this is
+        // (hopefully) plenty.
+        std::thread::sleep(Duration::from_millis(500));
+        self.start_measurement()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Build a miso frame with a correct checksum (unlike shdlc::mosi_frame, the
+    // state byte is included).
+    fn miso_frame(cmd: u8, state: u8, data: &[u8]) -> Vec<u8> {
+        let mut unstuffed = vec![0, cmd, state, data.len() as u8];
+        unstuffed.extend_from_slice(data);
+        let chk = !unstuffed.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        let mut out = vec![0x7E];
+        for byte in unstuffed.iter().chain(std::iter::once(&chk)) {
+            match byte {
+                0x7E | 0x7D | 0x11 | 0x13 => out.extend_from_slice(&[0x7D, byte ^ 0x20]),
+                _ => out.push(*byte),
+            }
+        }
+        out.push(0x7E);
+        out
+    }
+
+    fn device(responses: &[Vec<u8>]) -> Sps30<Cursor<Vec<u8>>, Vec<u8>> {
+        Sps30::new(Cursor::new(responses.concat()), Vec::new())
+    }
+
+    #[test]
+    fn test_serial_number() {
+        let mut sps30 = device(&[miso_frame(0xD0, 0, b"ABCDEF0123456789\0\0\0")]);
+        assert_eq!(sps30.serial_number().unwrap(), "ABCDEF0123456789");
+        assert_eq!(sps30.writer, vec![0x7E, 0, 0xD0, 1, 3, 0x2B, 0x7E]);
+    }
+
+    #[test]
+    fn test_read_version() {
+        let mut sps30 = device(&[miso_frame(0xD1, 0, &[2, 2, 0, 7, 0, 2, 0])]);
+        let version = sps30.read_version().unwrap();
+        assert_eq!(version.firmware_version(), "2.2");
+        assert_eq!(version.hardware_revision, 7);
+        assert_eq!((version.shdlc_major, version.shdlc_minor), (2, 0));
+    }
+
+    #[test]
+    fn test_read_device_status() {
+        let mut sps30 = device(&[
+            miso_frame(0xD2, 0, &[0, 0, 0, 0, 0]),
+            miso_frame(0xD2, 0, &[0x00, 0x20, 0x00, 0x10, 0]),
+        ]);
+        assert!(sps30.read_device_status().unwrap().is_healthy());
+        assert_eq!(
+            sps30.read_device_status().unwrap(),
+            DeviceStatus {
+                fan_speed_warning: true,
+                laser_error: false,
+                fan_error: true,
+            }
+        );
+        assert_eq!(&sps30.writer[..7], &[0x7E, 0, 0xD2, 1, 0, 0x2C, 0x7E]);
+
+        let version = VersionInfo {
+            firmware_major: 2,
+            firmware_minor: 1,
+            ..Default::default()
+        };
+        assert!(!version.supports_status_register());
+        assert!(VersionInfo {
+            firmware_minor: 2,
+            ..version
+        }
+        .supports_status_register());
+    }
+
+    #[test]
+    fn test_read_measurement() {
+        let mut data = vec![];
+        for value in 1..=10 {
+            data.extend_from_slice(&(value as f32).to_be_bytes());
+        }
+        let mut sps30 = device(&[miso_frame(0x03, 0, &[]), miso_frame(0x03, 0, &data)]);
+
+        assert_eq!(sps30.read_measurement().unwrap(), None);
+        let measurement = sps30.read_measurement().unwrap().unwrap();
+        assert_eq!(measurement.mass_concentration_pm_1_0, 1.0);
+        assert_eq!(measurement.typical_particle_size, 10.0);
+        assert_eq!(
+            sps30.stats(),
+            DeviceStats {
+                empty_responses: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut corrupted = miso_frame(0x03, 0, &[]);
+        corrupted[5] ^= 0xFF;
+        let mut sps30 = device(&[
+            // Garbage prior to the start byte.
+            vec![0x01, 0x02],
+            miso_frame(0x00, 0, &[]),
+            corrupted,
+            // Response to the wrong command.
+            miso_frame(0x01, 0, &[]),
+            // Error state.
+            miso_frame(0x03, 0x43, &[]),
+        ]);
+
+        assert!(sps30.start_measurement().is_ok());
+        assert!(sps30.read_measurement().is_err());
+        assert!(sps30.read_measurement().is_err());
+        assert!(sps30.read_measurement().is_err());
+        assert_eq!(
+            sps30.stats(),
+            DeviceStats {
+                frame_errors: 2,
+                checksum_failures: 1,
+                empty_responses: 0,
+                recovery_attempts: 0,
+            }
+        );
+        // Nothing left to read.
+        assert!(sps30.read_measurement().is_err());
+    }
+}
@@ -0,0 +1,524 @@
+use super::measurement::*;
+
+/// Bin edges (in um) of the SPS30's number concentrations: the sensor counts
+/// particles from 0.3um, and each cumulative channel counts everything from
+/// 0.3um up to the given size.
+pub const BIN_EDGES_UM: [f32; 6] = [0.3, 0.5, 1.0, 2.5, 4.0, 10.0];
+
+const NUMBER_CHANNELS: [Channel; 5] = [
+    Channel::NumberPm0_5,
+    Channel::NumberPm1_0,
+    Channel::NumberPm2_5,
+    Channel::NumberPm4_0,
+    Channel::NumberPm10_0,
+];
+
+const MASS_CHANNELS: [Channel; 4] = [
+    Channel::MassPm1_0,
+    Channel::MassPm2_5,
+    Channel::MassPm4_0,
+    Channel::MassPm10_0,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeBin {
+    pub lower_um: f32,
+    pub upper_um: f32,
+    // #/cm3
+    pub count: f32,
+    // #/cm3, i.e. count normalised by the (log) bin width.
+    pub dn_dlogdp: f32,
+    // ug/m3
+    pub mass: f32,
+}
+
+impl SizeBin {
+    /// geometric_mean_um is the geometric mean of the bin's edges, which is
+    /// the usual "typical" diameter for a bin on a log scale.
+    pub fn geometric_mean_um(&self) -> f32 {
+        (self.lower_um * self.upper_um).sqrt()
+    }
+}
+
+/// Inconsistency records a pair of cumulative channels where the larger size
+/// has a lower value than the smaller size, which is physically impossible.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Inconsistency {
+    pub smaller: Channel,
+    pub larger: Channel,
+    pub smaller_value: f32,
+    pub larger_value: f32,
+}
+
+/// SizeDistribution is the differential (per-bin) view of the cumulative
+/// concentrations reported by the SPS30.
+///
+/// The SPS30 doesn't report the mass below 0.5um, so the PM1.0 mass is split
+/// between the 0.3-0.5um and 0.5-1.0um bins in proportion to their volume,
+/// estimated from their counts assuming spherical particles at each bin's
+/// geometric mean diameter. All other bins use the reported mass.
+///
+/// Bins are computed from the raw values even if they are inconsistent, in
+/// which case some bins will be negative.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SizeDistribution {
+    pub bins: [SizeBin; 5],
+    pub inconsistencies: Vec<Inconsistency>,
+}
+
+fn inconsistencies(measurement: &Measurement, channels: &[Channel]) -> Vec<Inconsistency> {
+    channels
+        .windows(2)
+        .filter(|pair| measurement.get(pair[1]) < measurement.get(pair[0]))
+        .map(|pair| Inconsistency {
+            smaller: pair[0],
+            larger: pair[1],
+            smaller_value: measurement.get(pair[0]),
+            larger_value: measurement.get(pair[1]),
+        })
+        .collect()
+}
+
+impl SizeDistribution {
+    pub fn from_measurement(measurement: &Measurement) -> Self {
+        let cumulative_count = |i: usize| match i {
+            0 => 0.0,
+            i => measurement.get(NUMBER_CHANNELS[i - 1]),
+        };
+        let bins: [SizeBin; 5] = std::array::from_fn(|i| {
+            let (lower_um, upper_um) = (BIN_EDGES_UM[i], BIN_EDGES_UM[i + 1]);
+            let count = cumulative_count(i + 1) - cumulative_count(i);
+            SizeBin {
+                lower_um,
+                upper_um,
+                count,
+                dn_dlogdp: count / (upper_um.log10() - lower_um.log10()),
+                mass: 0.0,
+            }
+        });
+
+        let mut distribution = SizeDistribution {
+            bins,
+            inconsistencies: [
+                inconsistencies(measurement, &NUMBER_CHANNELS),
+                inconsistencies(measurement, &MASS_CHANNELS),
+            ]
+            .concat(),
+        };
+
+        // Split PM1.0 by volume, see above.
+        let volume = |bin: &SizeBin| bin.count.max(0.0) * bin.geometric_mean_um().powi(3);
+        let (small, medium) = (volume(&distribution.bins[0]), volume(&distribution.bins[1]));
+        let pm1_0 = measurement.mass_concentration_pm_1_0;
+        let small_fraction = if small + medium > 0.0 {
+            small / (small + medium)
+        } else {
+            0.0
+        };
+        distribution.bins[0].mass = pm1_0 * small_fraction;
+        distribution.bins[1].mass = pm1_0 * (1.0 - small_fraction);
+        for (bin, pair) in distribution.bins[2..]
+            .iter_mut()
+            .zip(MASS_CHANNELS.windows(2))
+        {
+            bin.mass = measurement.get(pair[1]) - measurement.get(pair[0]);
+        }
+        distribution
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+impl From<&Measurement> for SizeDistribution {
+    fn from(measurement: &Measurement) -> Self {
+        SizeDistribution::from_measurement(measurement)
+    }
+}
+
+// Standard normal CDF, using the Abramowitz and Stegun 7.1.26 approximation of
+// erf, which has a maximum error of 1.5e-7.
+fn normal_cdf(z: f64) -> f64 {
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    let poly = t
+        * (0.254_829_592
+            + t * (-0.284_496_736
+                + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+    let erf = 1.0 - poly * (-x * x).exp();
+    if z >= 0.0 {
+        0.5 * (1.0 + erf)
+    } else {
+        0.5 * (1.0 - erf)
+    }
+}
+
+/// LognormalFit is a lognormal number distribution fitted to the cumulative
+/// number concentrations and the typical particle size of a measurement.
+///
+/// The SPS30 only counts particles between 0.3um and 10um, so the fit is made
+/// against the fraction of that count below each bin edge, and the typical
+/// particle size is treated as the median of that range. total is the count of
+/// the whole fitted distribution, which includes an extrapolation below 0.3um:
+/// it's only meaningful if the count median diameter is well within the
+/// measured range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LognormalFit {
+    // #/cm3
+    pub total: f32,
+    pub count_median_diameter_um: f32,
+    pub geometric_std_dev: f32,
+    /// r_squared is the coefficient of determination of the fitted cumulative
+    /// fractions.
+    pub r_squared: f32,
+    /// rmse is the root mean squared error of the fitted cumulative fractions.
+    pub rmse: f32,
+}
+
+impl LognormalFit {
+    pub fn fit(measurement: &Measurement) -> Result<Self, String> {
+        // Only the number concentrations are used, so inconsistent mass
+        // concentrations don't matter.
+        if !inconsistencies(measurement, &NUMBER_CHANNELS).is_empty() {
+            return Err(String::from(
+                "cumulative number concentrations are not monotonic",
+            ));
+        }
+        let count = measurement.number_concentration_pm_10_0 as f64;
+        if count.is_nan() || count <= 0.0 {
+            return Err(String::from("no particles to fit"));
+        }
+
+        // (ln(diameter), fraction of the measured count below diameter)
+        let mut points: Vec<(f64, f64)> = NUMBER_CHANNELS[..4]
+            .iter()
+            .zip(&BIN_EDGES_UM[1..5])
+            .map(|(channel, edge)| {
+                let fraction = measurement.get(*channel) as f64 / count;
+                ((*edge as f64).ln(), fraction)
+            })
+            .collect();
+        let typical_size = measurement.typical_particle_size;
+        if typical_size > BIN_EDGES_UM[0] && typical_size < BIN_EDGES_UM[5] {
+            points.push(((typical_size as f64).ln(), 0.5));
+        }
+
+        let lower = (BIN_EDGES_UM[0] as f64).ln();
+        let upper = (BIN_EDGES_UM[5] as f64).ln();
+        let measured_fraction = |mu: f64, sigma: f64| {
+            normal_cdf((upper - mu) / sigma) - normal_cdf((lower - mu) / sigma)
+        };
+        let sse = |mu: f64, sigma: f64| {
+            let measured = measured_fraction(mu, sigma);
+            if measured < 1e-9 {
+                return f64::INFINITY;
+            }
+            let below_lower = normal_cdf((lower - mu) / sigma);
+            points
+                .iter()
+                .map(|(x, y)| ((normal_cdf((x - mu) / sigma) - below_lower) / measured - y).powi(2))
+                .sum::<f64>()
+        };
+
+        // A coarse grid search over CMDs of 0.01-10um and GSDs of 1.05-4,
+        // followed by a pattern search from the best grid point.
+        let (mu_min, mu_max) = (0.01f64.ln(), 10f64.ln());
+        let (sigma_min, sigma_max) = (1.05f64.ln(), 4f64.ln());
+        let (mut mu_step, mut sigma_step) =
+            ((mu_max - mu_min) / 60.0, (sigma_max - sigma_min) / 40.0);
+        let mut best = (f64::INFINITY, 0.0, 0.0);
+        for i in 0..=60 {
+            for j in 0..=40 {
+                let (mu, sigma) = (
+                    mu_min + i as f64 * mu_step,
+                    sigma_min + j as f64 * sigma_step,
+                );
+                let error = sse(mu, sigma);
+                if error < best.0 {
+                    best = (error, mu, sigma);
+                }
+            }
+        }
+        if !best.0.is_finite() {
+            return Err(String::from("unable to fit distribution"));
+        }
+        while mu_step > 1e-7 || sigma_step > 1e-7 {
+            let (_, mu, sigma) = best;
+            let candidates = [
+                (mu + mu_step, sigma),
+                (mu - mu_step, sigma),
+                (mu, sigma + sigma_step),
+                (mu, (sigma - sigma_step).max(1e-3)),
+            ];
+            let mut improved = false;
+            for (mu, sigma) in candidates {
+                let error = sse(mu, sigma);
+                if error < best.0 {
+                    best = (error, mu, sigma);
+                    improved = true;
+                }
+            }
+            if !improved {
+                mu_step /= 2.0;
+                sigma_step /= 2.0;
+            }
+        }
+
+        let (error, mu, sigma) = best;
+        let mean = points.iter().map(|(_, y)| y).sum::<f64>() / points.len() as f64;
+        let total_variance: f64 = points.iter().map(|(_, y)| (y - mean).powi(2)).sum();
+        let r_squared = if total_variance > 0.0 {
+            1.0 - error / total_variance
+        } else {
+            0.0
+        };
+        Ok(LognormalFit {
+            total: (count / measured_fraction(mu, sigma)) as f32,
+            count_median_diameter_um: mu.exp() as f32,
+            geometric_std_dev: sigma.exp() as f32,
+            r_squared: r_squared as f32,
+            rmse: (error / points.len() as f64).sqrt() as f32,
+        })
+    }
+
+    /// cumulative_fraction returns the fraction (0-1) of the whole fitted
+    /// distribution that is smaller than diameter_um.
+    pub fn cumulative_fraction(&self, diameter_um: f32) -> f32 {
+        let (mu, sigma) = self.log_parameters();
+        normal_cdf(((diameter_um as f64).ln() - mu) / sigma) as f32
+    }
+
+    fn log_parameters(&self) -> (f64, f64) {
+        (
+            (self.count_median_diameter_um as f64).ln(),
+            (self.geometric_std_dev as f64).ln(),
+        )
+    }
+
+    // Returns the k-th moment of the fitted distribution between lower_um and
+    // upper_um, i.e. the integral of d^k dN.
+    fn moment(&self, k: i32, lower_um: f32, upper_um: f32) -> f64 {
+        let (mu, sigma) = self.log_parameters();
+        let shifted = mu + k as f64 * sigma * sigma;
+        let fraction = normal_cdf(((upper_um as f64).ln() - shifted) / sigma)
+            - normal_cdf(((lower_um as f64).ln() - shifted) / sigma);
+        self.total as f64 * (k as f64 * mu + (k as f64 * sigma).powi(2) / 2.0).exp() * fraction
+    }
+
+    /// surface_area_concentration returns the surface area (in um2/cm3) of
+    /// the fitted distribution between 0.3um and 10um, assuming spherical
+    /// particles.
+    pub fn surface_area_concentration(&self) -> f32 {
+        (std::f64::consts::PI * self.moment(2, BIN_EDGES_UM[0], BIN_EDGES_UM[5])) as f32
+    }
+
+    /// volume_concentration returns the volume (in um3/cm3) of the fitted
+    /// distribution between 0.3um and 10um, assuming spherical particles.
+    pub fn volume_concentration(&self) -> f32 {
+        (std::f64::consts::PI / 6.0 * self.moment(3, BIN_EDGES_UM[0], BIN_EDGES_UM[5])) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4 * expected.abs().max(1.0),
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_from_measurement() {
+        let measurement = Measurement {
+            mass_concentration_pm_1_0: 10.0,
+            mass_concentration_pm_2_5: 12.0,
+            mass_concentration_pm_4_0: 12.5,
+            mass_concentration_pm_10_0: 12.75,
+            number_concentration_pm_0_5: 60.0,
+            number_concentration_pm_1_0: 75.0,
+            number_concentration_pm_2_5: 78.0,
+            number_concentration_pm_4_0: 78.5,
+            number_concentration_pm_10_0: 78.6,
+            typical_particle_size: 0.5,
+        };
+        let distribution = SizeDistribution::from(&measurement);
+        assert!(distribution.is_consistent());
+
+        let counts: Vec<f32> = distribution.bins.iter().map(|b| b.count).collect();
+        for (actual, expected) in counts.iter().zip([60.0, 15.0, 3.0, 0.5, 0.1]) {
+            assert_close(*actual, expected);
+        }
+        // log10(0.5) - log10(0.3) = 0.2218
+        assert_close(distribution.bins[0].dn_dlogdp, 60.0 / 0.221_849);
+        assert_close(distribution.bins[4].dn_dlogdp, 0.1 / 0.397_94);
+
+        // The geometric mean diameters are sqrt(0.15)um and sqrt(0.5)um.
+        let small_fraction =
+            60.0 * 0.15f32.powf(1.5) / (60.0 * 0.15f32.powf(1.5) + 15.0 * 0.5f32.powf(1.5));
+        assert_close(distribution.bins[0].mass, 10.0 * small_fraction);
+        assert_close(distribution.bins[1].mass, 10.0 * (1.0 - small_fraction));
+        assert_close(distribution.bins[2].mass, 2.0);
+        assert_close(distribution.bins[3].mass, 0.5);
+        assert_close(distribution.bins[4].mass, 0.25);
+        let total: f32 = distribution.bins.iter().map(|b| b.mass).sum();
+        assert_close(total, 12.75);
+    }
+
+    #[test]
+    fn test_inconsistent() {
+        let measurement = Measurement {
+            mass_concentration_pm_1_0: 10.0,
+            mass_concentration_pm_2_5: 9.0,
+            mass_concentration_pm_4_0: 9.0,
+            mass_concentration_pm_10_0: 9.0,
+            number_concentration_pm_0_5: 60.0,
+            number_concentration_pm_1_0: 75.0,
+            number_concentration_pm_2_5: 74.0,
+            number_concentration_pm_4_0: 74.0,
+            number_concentration_pm_10_0: 74.0,
+            typical_particle_size: 0.5,
+        };
+        let distribution = SizeDistribution::from_measurement(&measurement);
+        assert!(!distribution.is_consistent());
+        assert_eq!(
+            distribution.inconsistencies,
+            vec![
+                Inconsistency {
+                    smaller: Channel::NumberPm1_0,
+                    larger: Channel::NumberPm2_5,
+                    smaller_value: 75.0,
+                    larger_value: 74.0,
+                },
+                Inconsistency {
+                    smaller: Channel::MassPm1_0,
+                    larger: Channel::MassPm2_5,
+                    smaller_value: 10.0,
+                    larger_value: 9.0,
+                },
+            ]
+        );
+        assert_eq!(distribution.bins[2].count, -1.0);
+        assert_eq!(distribution.bins[2].mass, -1.0);
+    }
+
+    // Returns the measurement that the SPS30 would report for fit.
+    fn lognormal_measurement(fit: &LognormalFit) -> Measurement {
+        let below_lower = fit.cumulative_fraction(BIN_EDGES_UM[0]);
+        let count = |d: f32| fit.total * (fit.cumulative_fraction(d) - below_lower);
+        // Bisect for the median of the measured range.
+        let median = count(BIN_EDGES_UM[5]) / 2.0;
+        let (mut low, mut high) = (BIN_EDGES_UM[0], BIN_EDGES_UM[5]);
+        for _ in 0..50 {
+            let mid = (low * high).sqrt();
+            if count(mid) < median {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        Measurement {
+            number_concentration_pm_0_5: count(0.5),
+            number_concentration_pm_1_0: count(1.0),
+            number_concentration_pm_2_5: count(2.5),
+            number_concentration_pm_4_0: count(4.0),
+            number_concentration_pm_10_0: count(10.0),
+            typical_particle_size: low,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_lognormal_fit() {
+        struct TestCase {
+            count_median_diameter_um: f32,
+            geometric_std_dev: f32,
+        }
+        let tests = [
+            TestCase {
+                count_median_diameter_um: 0.4,
+                geometric_std_dev: 1.6,
+            },
+            TestCase {
+                count_median_diameter_um: 0.8,
+                geometric_std_dev: 2.0,
+            },
+            TestCase {
+                count_median_diameter_um: 1.5,
+                geometric_std_dev: 1.3,
+            },
+        ];
+        for case in tests {
+            let expected = LognormalFit {
+                total: 100.0,
+                count_median_diameter_um: case.count_median_diameter_um,
+                geometric_std_dev: case.geometric_std_dev,
+                r_squared: 1.0,
+                rmse: 0.0,
+            };
+            let mut measurement = lognormal_measurement(&expected);
+            // Inconsistent mass concentrations are irrelevant to the fit.
+            measurement.mass_concentration_pm_1_0 = 10.0;
+            let fit = LognormalFit::fit(&measurement).unwrap();
+            let close = |a: f32, b: f32| (a - b).abs() < 0.01 * b;
+            assert!(
+                close(
+                    fit.count_median_diameter_um,
+                    expected.count_median_diameter_um
+                ) && close(fit.geometric_std_dev, expected.geometric_std_dev)
+                    && close(fit.total, expected.total),
+                "expected {:?}, got {:?}",
+                expected,
+                fit
+            );
+            assert!(fit.r_squared > 0.9999, "{:?}", fit);
+            assert!(fit.rmse < 1e-3, "{:?}", fit);
+        }
+    }
+
+    #[test]
+    fn test_lognormal_moments() {
+        let fit = LognormalFit {
+            total: 100.0,
+            count_median_diameter_um: 0.5,
+            geometric_std_dev: 1.8,
+            r_squared: 1.0,
+            rmse: 0.0,
+        };
+        // Integrate numerically over ln(d).
+        let (lower, upper) = (BIN_EDGES_UM[0].ln() as f64, BIN_EDGES_UM[5].ln() as f64);
+        let (mu, sigma) = (0.5f64.ln(), 1.8f64.ln());
+        let steps = 10000;
+        let (mut surface, mut volume) = (0.0, 0.0);
+        for i in 0..steps {
+            let x = lower + (i as f64 + 0.5) * (upper - lower) / steps as f64;
+            let dn = 100.0 * (-((x - mu) / sigma).powi(2) / 2.0).exp()
+                / (sigma * (2.0 * std::f64::consts::PI).sqrt())
+                * (upper - lower)
+                / steps as f64;
+            surface += std::f64::consts::PI * (2.0 * x).exp() * dn;
+            volume += std::f64::consts::PI / 6.0 * (3.0 * x).exp() * dn;
+        }
+        assert_close(fit.surface_area_concentration(), surface as f32);
+        assert_close(fit.volume_concentration(), volume as f32);
+    }
+
+    #[test]
+    fn test_lognormal_errors() {
+        assert!(LognormalFit::fit(&Measurement::default()).is_err());
+        let measurement = Measurement {
+            number_concentration_pm_0_5: 10.0,
+            number_concentration_pm_1_0: 5.0,
+            number_concentration_pm_2_5: 5.0,
+            number_concentration_pm_4_0: 5.0,
+            number_concentration_pm_10_0: 5.0,
+            ..Default::default()
+        };
+        assert!(LognormalFit::fit(&measurement).is_err());
+    }
+}
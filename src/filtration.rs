@@ -0,0 +1,376 @@
+use super::distribution::{SizeDistribution, BIN_EDGES_UM};
+use super::measurement::*;
+use std::fmt;
+
+const CUMULATIVE_CHANNELS: [Channel; 5] = [
+    Channel::NumberPm0_5,
+    Channel::NumberPm1_0,
+    Channel::NumberPm2_5,
+    Channel::NumberPm4_0,
+    Channel::NumberPm10_0,
+];
+
+const RANGES: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SizeRange {
+    /// One of the SPS30's cumulative number concentrations.
+    Cumulative(Channel),
+    /// A differential bin, as per SizeDistribution.
+    Differential { lower_um: f32, upper_um: f32 },
+}
+
+impl SizeRange {
+    /// all lists the cumulative ranges, followed by the differential bins.
+    pub fn all() -> [SizeRange; RANGES] {
+        std::array::from_fn(|i| match i {
+            0..=4 => SizeRange::Cumulative(CUMULATIVE_CHANNELS[i]),
+            _ => SizeRange::Differential {
+                lower_um: BIN_EDGES_UM[i - 5],
+                upper_um: BIN_EDGES_UM[i - 4],
+            },
+        })
+    }
+}
+
+impl fmt::Display for SizeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SizeRange::Cumulative(channel) => write!(f, "{}", channel.label()),
+            SizeRange::Differential { lower_um, upper_um } => {
+                write!(f, "{}-{}um", lower_um, upper_um)
+            }
+        }
+    }
+}
+
+// Returns the concentration of every SizeRange, in the same order.
+fn values(measurement: &Measurement) -> [f64; RANGES] {
+    let distribution = SizeDistribution::from_measurement(measurement);
+    std::array::from_fn(|i| match i {
+        0..=4 => measurement.get(CUMULATIVE_CHANNELS[i]) as f64,
+        _ => distribution.bins[i - 5].count as f64,
+    })
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Sums {
+    upstream: [f64; RANGES],
+    downstream: [f64; RANGES],
+    count: usize,
+}
+
+impl Sums {
+    fn record(&mut self, upstream: &Measurement, downstream: &Measurement) {
+        for (sum, value) in self.upstream.iter_mut().zip(values(upstream)) {
+            *sum += value;
+        }
+        for (sum, value) in self.downstream.iter_mut().zip(values(downstream)) {
+            *sum += value;
+        }
+        self.count += 1;
+    }
+
+    fn upstream_mean(&self, i: usize) -> f64 {
+        self.upstream[i] / self.count as f64
+    }
+
+    fn downstream_mean(&self, i: usize) -> f64 {
+        self.downstream[i] / self.count as f64
+    }
+}
+
+// Two-sided 95% critical values of Student's t distribution, for 1-30
+// degrees of freedom.
+const T_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+fn t_95(degrees_of_freedom: usize) -> f64 {
+    T_95.get(degrees_of_freedom - 1).copied().unwrap_or(1.96)
+}
+
+/// FiltrationTest computes the filtration efficiency of a filter from one
+/// sensor upstream and one sensor downstream of it.
+///
+/// Measurements are averaged over sampling windows, which are delimited by
+/// the caller using finish_window. The efficiency is computed for every
+/// window, and the report contains their mean along with a 95% confidence
+/// interval from their variance, so at least 2 windows are required.
+///
+/// If both sensors have first sampled the same air (i.e. without the filter)
+/// via record_baseline, the downstream concentrations are corrected for the
+/// measured sensor-to-sensor bias.
+#[derive(Default)]
+pub struct FiltrationTest {
+    baseline: Sums,
+    current: Sums,
+    windows: Vec<Sums>,
+}
+
+impl FiltrationTest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// record_baseline adds a pair of measurements taken while both sensors
+    /// sample the same, unfiltered, air.
+    pub fn record_baseline(&mut self, upstream: &Measurement, downstream: &Measurement) {
+        self.baseline.record(upstream, downstream);
+    }
+
+    /// record adds a pair of measurements to the current window.
+    pub fn record(&mut self, upstream: &Measurement, downstream: &Measurement) {
+        self.current.record(upstream, downstream);
+    }
+
+    pub fn finish_window(&mut self) -> Result<(), String> {
+        if self.current.count == 0 {
+            return Err(String::from("no measurements in window"));
+        }
+        self.windows.push(std::mem::take(&mut self.current));
+        Ok(())
+    }
+
+    pub fn window_count(&self) -> usize {
+        self.windows.len()
+    }
+
+    // Returns the ratio of the downstream and upstream sensor for each range,
+    // or 1 without a (usable) baseline.
+    fn bias(&self, i: usize) -> f64 {
+        if self.baseline.count == 0 {
+            return 1.0;
+        }
+        let bias = self.baseline.downstream_mean(i) / self.baseline.upstream_mean(i);
+        if bias.is_finite() && bias > 0.0 {
+            bias
+        } else {
+            1.0
+        }
+    }
+
+    pub fn report(&self) -> Result<Report, String> {
+        if self.windows.len() < 2 {
+            return Err(format!(
+                "at least 2 windows are required, got {}",
+                self.windows.len()
+            ));
+        }
+        let ranges = SizeRange::all()
+            .into_iter()
+            .enumerate()
+            .map(|(i, range)| {
+                let bias = self.bias(i);
+                // Windows without upstream particles in this range don't
+                // tell us anything.
+                let efficiencies: Vec<f64> = self
+                    .windows
+                    .iter()
+                    .filter(|w| w.upstream_mean(i) > 0.0)
+                    .map(|w| 1.0 - w.downstream_mean(i) / bias / w.upstream_mean(i))
+                    .collect();
+                let n = efficiencies.len() as f64;
+                let mean = efficiencies.iter().sum::<f64>() / n;
+                let variance =
+                    efficiencies.iter().map(|e| (e - mean).powi(2)).sum::<f64>() / (n - 1.0);
+                let half_width = if efficiencies.len() >= 2 {
+                    t_95(efficiencies.len() - 1) * (variance / n).sqrt()
+                } else {
+                    f64::NAN
+                };
+                let total = |f: fn(&Sums, usize) -> f64| {
+                    self.windows.iter().map(|w| f(w, i)).sum::<f64>() / self.windows.len() as f64
+                };
+                RangeEfficiency {
+                    range,
+                    upstream: total(Sums::upstream_mean) as f32,
+                    downstream: total(Sums::downstream_mean) as f32,
+                    bias: bias as f32,
+                    efficiency: mean as f32,
+                    confidence_interval: ((mean - half_width) as f32, (mean + half_width) as f32),
+                }
+            })
+            .collect();
+        Ok(Report {
+            windows: self.windows.len(),
+            baseline_samples: self.baseline.count,
+            ranges,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RangeEfficiency {
+    pub range: SizeRange,
+    /// Mean upstream concentration, in #/cm3.
+    pub upstream: f32,
+    /// Mean downstream concentration (before bias correction), in #/cm3.
+    pub downstream: f32,
+    /// The ratio of the downstream and upstream sensor during the baseline,
+    /// or 1 without a baseline.
+    pub bias: f32,
+    /// Filtration efficiency (0-1), or NaN if there were no upstream
+    /// particles in this range.
+    pub efficiency: f32,
+    /// 95% confidence interval of the efficiency, or NaN if fewer than 2
+    /// windows had upstream particles in this range.
+    pub confidence_interval: (f32, f32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub windows: usize,
+    pub baseline_samples: usize,
+    pub ranges: Vec<RangeEfficiency>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Filtration efficiency over {} windows ({})",
+            self.windows,
+            if self.baseline_samples > 0 {
+                format!("bias corrected from {} samples", self.baseline_samples)
+            } else {
+                String::from("no bias correction")
+            }
+        )?;
+        writeln!(
+            f,
+            "{:<28} {:>10} {:>10} {:>6} {:>11} {:>18}",
+            "Size range", "Upstream", "Downstream", "Bias", "Efficiency", "95% CI"
+        )?;
+        for (i, range) in self.ranges.iter().enumerate() {
+            let (low, high) = range.confidence_interval;
+            writeln!(
+                f,
+                "{:<28} {:>10.2} {:>10.2} {:>6.2} {:>10.1}% {:>17}",
+                range.range.to_string(),
+                range.upstream,
+                range.downstream,
+                range.bias,
+                range.efficiency * 100.0,
+                format!("{:.1}-{:.1}%", low * 100.0, high * 100.0),
+            )?;
+            if i == 4 {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A measurement with the given differential counts.
+    fn measurement(bins: [f32; 5]) -> Measurement {
+        let mut measurement = Measurement::default();
+        let mut cumulative = 0.0;
+        for (channel, count) in CUMULATIVE_CHANNELS.iter().zip(bins) {
+            cumulative += count;
+            measurement.set(*channel, cumulative);
+        }
+        measurement
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_efficiency() {
+        let mut test = FiltrationTest::new();
+        let upstream = measurement([100.0, 40.0, 10.0, 2.0, 0.0]);
+        assert!(test.finish_window().is_err());
+        // Efficiencies of 90%/95%/99%/100% in the first window, and
+        // 80%/85%/89%/90% in the second.
+        test.record(&upstream, &measurement([10.0, 2.0, 0.1, 0.0, 0.0]));
+        test.finish_window().unwrap();
+        assert!(test.report().is_err());
+        test.record(&upstream, &measurement([20.0, 6.0, 1.1, 0.2, 0.0]));
+        test.record(&upstream, &measurement([20.0, 6.0, 1.1, 0.2, 0.0]));
+        test.finish_window().unwrap();
+
+        let report = test.report().unwrap();
+        assert_eq!(report.windows, 2);
+        assert_eq!(report.ranges.len(), 10);
+        struct TestCase {
+            range: usize,
+            efficiency: f32,
+            half_width: f32,
+        }
+        let tests = [
+            TestCase {
+                range: 5,
+                efficiency: 0.85,
+                half_width: 12.706 * 0.05,
+            },
+            TestCase {
+                range: 7,
+                efficiency: 0.94,
+                half_width: 12.706 * 0.05,
+            },
+            // 1 - 12.1 / 150 and 1 - 27.1 / 150
+            TestCase {
+                range: 2,
+                efficiency: 1.0 - (12.1 + 27.1) / 300.0,
+                half_width: 12.706 * (27.1 - 12.1) / 300.0,
+            },
+        ];
+        for case in tests {
+            let range = &report.ranges[case.range];
+            assert_close(range.bias, 1.0);
+            assert_close(range.efficiency, case.efficiency);
+            assert_close(
+                range.confidence_interval.1 - range.efficiency,
+                case.half_width,
+            );
+        }
+        // No upstream particles.
+        assert!(report.ranges[9].efficiency.is_nan());
+        assert_eq!(
+            report.ranges[5].range,
+            SizeRange::Differential {
+                lower_um: 0.3,
+                upper_um: 0.5
+            }
+        );
+        assert_eq!(report.ranges[5].range.to_string(), "0.3-0.5um");
+    }
+
+    #[test]
+    fn test_bias_correction() {
+        let mut test = FiltrationTest::new();
+        // The downstream sensor reads 20% higher.
+        test.record_baseline(
+            &measurement([100.0, 10.0, 1.0, 1.0, 1.0]),
+            &measurement([120.0, 12.0, 1.2, 1.2, 1.2]),
+        );
+        for _ in 0..3 {
+            test.record(
+                &measurement([100.0, 10.0, 1.0, 1.0, 1.0]),
+                &measurement([12.0, 1.2, 0.12, 0.12, 0.12]),
+            );
+            test.finish_window().unwrap();
+        }
+        let report = test.report().unwrap();
+        assert_eq!(report.baseline_samples, 1);
+        for range in &report.ranges {
+            assert_close(range.bias, 1.2);
+            assert_close(range.efficiency, 0.9);
+            assert_close(range.confidence_interval.0, 0.9);
+            assert_close(range.confidence_interval.1, 0.9);
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use super::measurement::*;
+use std::fmt;
+use std::time::Duration;
+
+/// The lowest in-mask concentration (in #/cm3) used when computing fit
+/// factors, so that an empty in-mask reading results in a large, but finite,
+/// fit factor.
+pub const MIN_MASK_CONCENTRATION: f32 = 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exercise {
+    pub name: &'static str,
+    pub instructions: &'static str,
+    pub duration: Duration,
+    /// Whether the exercise's fit factor is included in the overall fit
+    /// factor.
+    pub counted: bool,
+}
+
+const NORMAL_BREATHING: Exercise = Exercise {
+    name: "Normal breathing",
+    instructions: "In a normal standing position, without talking, breathe normally.",
+    duration: Duration::from_secs(60),
+    counted: true,
+};
+
+/// The fit test exercises from OSHA 29 CFR 1910.134 Appendix A, Part I.A.14.
+/// Each exercise takes one minute, except for the grimace which takes 15
+/// seconds. The grimace is intended to break the seal, so (as with the TSI
+/// PortaCount) it isn't included in the overall fit factor.
+pub const OSHA_EXERCISES: [Exercise; 8] = [
+    NORMAL_BREATHING,
+    Exercise {
+        name: "Deep breathing",
+        instructions: "In a normal standing position, breathe slowly and deeply, taking caution so as not to hyperventilate.",
+        duration: Duration::from_secs(60),
+        counted: true,
+    },
+    Exercise {
+        name: "Turning head side to side",
+        instructions: "Standing in place, slowly turn your head from side to side between the extreme positions on each side. Hold your head at each extreme momentarily and inhale.",
+        duration: Duration::from_secs(60),
+        counted: true,
+    },
+    Exercise {
+        name: "Moving head up and down",
+        instructions: "Standing in place, slowly move your head up and down. Inhale in the up position (when looking toward the ceiling).",
+        duration: Duration::from_secs(60),
+        counted: true,
+    },
+    Exercise {
+        name: "Talking",
+        instructions: "Talk out loud slowly and loud enough to be heard clearly, e.g. read the Rainbow Passage or count backward from 100.",
+        duration: Duration::from_secs(60),
+        counted: true,
+    },
+    Exercise {
+        name: "Grimace",
+        instructions: "Smile or frown.",
+        duration: Duration::from_secs(15),
+        counted: false,
+    },
+    Exercise {
+        name: "Bending over",
+        instructions: "Bend at the waist as if you were going to touch your toes.",
+        duration: Duration::from_secs(60),
+        counted: true,
+    },
+    NORMAL_BREATHING,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExerciseResult {
+    pub exercise: Exercise,
+    /// Mean ambient concentration, in #/cm3.
+    pub ambient: f32,
+    /// Mean in-mask concentration, in #/cm3.
+    pub mask: f32,
+    pub fit_factor: f32,
+}
+
+/// FitTest computes respirator fit factors from an ambient sensor and a
+/// sensor sampling from inside the mask, over a sequence of exercises.
+///
+/// Measurements are recorded for the current exercise until finish_exercise
+/// is called, the caller is responsible for timing (and prompting) each
+/// exercise. The fit factor for each exercise is the ratio of the mean ambient
+/// and in-mask concentrations.
+pub struct FitTest {
+    exercises: Vec<Exercise>,
+    channel: Channel,
+    pass_level: f32,
+    ambient: Vec<f32>,
+    mask: Vec<f32>,
+    results: Vec<ExerciseResult>,
+}
+
+impl Default for FitTest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FitTest {
+    /// new creates a fit test using the OSHA exercises, the 0.3-1.0um number
+    /// concentration, and a pass level of 100 (the OSHA minimum for half
+    /// masks, full facepieces require 500).
+    pub fn new() -> Self {
+        FitTest {
+            exercises: OSHA_EXERCISES.to_vec(),
+            channel: Channel::NumberPm1_0,
+            pass_level: 100.0,
+            ambient: Vec::new(),
+            mask: Vec::new(),
+            results: Vec::new(),
+        }
+    }
+
+    pub fn exercises(mut self, exercises: &[Exercise]) -> Self {
+        self.exercises = exercises.to_vec();
+        self
+    }
+
+    /// channel sets the number concentration used for the fit factors, only
+    /// the sub-micron channels (NumberPm0_5 and NumberPm1_0) are supported.
+    pub fn channel(mut self, channel: Channel) -> Result<Self, String> {
+        match channel {
+            Channel::NumberPm0_5 | Channel::NumberPm1_0 => {
+                self.channel = channel;
+                Ok(self)
+            }
+            _ => Err(format!("unsupported fit test channel {}", channel.key())),
+        }
+    }
+
+    pub fn pass_level(mut self, pass_level: f32) -> Self {
+        self.pass_level = pass_level;
+        self
+    }
+
+    /// current returns the exercise being recorded, or None once all
+    /// exercises have been finished.
+    pub fn current(&self) -> Option<&Exercise> {
+        self.exercises.get(self.results.len())
+    }
+
+    /// position returns the (1-based) index of the current exercise, and the
+    /// number of exercises.
+    pub fn position(&self) -> (usize, usize) {
+        (self.results.len() + 1, self.exercises.len())
+    }
+
+    /// record adds a pair of simultaneous measurements to the current
+    /// exercise.
+    pub fn record(&mut self, ambient: &Measurement, mask: &Measurement) {
+        self.ambient.push(ambient.get(self.channel));
+        self.mask.push(mask.get(self.channel));
+    }
+
+    /// finish_exercise computes the fit factor for the current exercise, and
+    /// moves on to the next exercise.
+    pub fn finish_exercise(&mut self) -> Result<ExerciseResult, String> {
+        let exercise = *self
+            .current()
+            .ok_or_else(|| String::from("all exercises have been finished"))?;
+        if self.ambient.is_empty() {
+            return Err(format!("no measurements for {}", exercise.name));
+        }
+        let mean = |values: &[f32]| values.iter().sum::<f32>() / values.len() as f32;
+        let ambient = mean(&self.ambient);
+        let mask = mean(&self.mask);
+        self.ambient.clear();
+        self.mask.clear();
+        if ambient.is_nan() || ambient <= 0.0 {
+            return Err(format!("no ambient particles during {}", exercise.name));
+        }
+        let result = ExerciseResult {
+            exercise,
+            ambient,
+            mask,
+            fit_factor: ambient / mask.max(MIN_MASK_CONCENTRATION),
+        };
+        self.results.push(result);
+        Ok(result)
+    }
+
+    /// report returns the results once all exercises have been finished.
+    pub fn report(&self) -> Result<Report, String> {
+        if let Some(exercise) = self.current() {
+            return Err(format!("{} has not been finished", exercise.name));
+        }
+        Ok(Report {
+            results: self.results.clone(),
+            overall_fit_factor: overall_fit_factor(&self.results),
+            pass_level: self.pass_level,
+        })
+    }
+}
+
+/// overall_fit_factor returns the harmonic mean of the fit factors of the
+/// counted exercises.
+pub fn overall_fit_factor(results: &[ExerciseResult]) -> f32 {
+    let counted: Vec<f32> = results
+        .iter()
+        .filter(|r| r.exercise.counted)
+        .map(|r| r.fit_factor)
+        .collect();
+    counted.len() as f32 / counted.iter().map(|f| 1.0 / f).sum::<f32>()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub results: Vec<ExerciseResult>,
+    pub overall_fit_factor: f32,
+    pub pass_level: f32,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.overall_fit_factor >= self.pass_level
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<28} {:>10} {:>10} {:>10}",
+            "Exercise", "Ambient", "Mask", "Fit factor"
+        )?;
+        for result in &self.results {
+            writeln!(
+                f,
+                "{:<28} {:>10.2} {:>10.2} {:>10.0}{}",
+                result.exercise.name,
+                result.ambient,
+                result.mask,
+                result.fit_factor,
+                if result.exercise.counted {
+                    ""
+                } else {
+                    " (not counted)"
+                }
+            )?;
+        }
+        write!(
+            f,
+            "Overall fit factor: {:.0} ({}, pass level {:.0})",
+            self.overall_fit_factor,
+            if self.passed() { "PASS" } else { "FAIL" },
+            self.pass_level
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(number_pm1_0: f32) -> Measurement {
+        Measurement {
+            number_concentration_pm_0_5: number_pm1_0 * 0.8,
+            number_concentration_pm_1_0: number_pm1_0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fit_test() {
+        struct TestCase {
+            // In-mask concentration for each exercise, ambient is always 1000.
+            mask: [f32; 8],
+            expected_fit_factor: f32,
+            expected_pass: bool,
+        }
+        let tests = [
+            TestCase {
+                mask: [5.0; 8],
+                expected_fit_factor: 200.0,
+                expected_pass: true,
+            },
+            TestCase {
+                // The grimace isn't counted, the rest is 7 / (6 / 200 + 1 / 20).
+                mask: [5.0, 5.0, 5.0, 5.0, 50.0, 500.0, 5.0, 5.0],
+                expected_fit_factor: 87.5,
+                expected_pass: false,
+            },
+            TestCase {
+                // An empty mask reading is clamped to MIN_MASK_CONCENTRATION.
+                mask: [0.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0],
+                expected_fit_factor: 7.0 / (6.0 / 1000.0 + 1.0 / 100000.0),
+                expected_pass: true,
+            },
+        ];
+        for case in tests {
+            let mut fit_test = FitTest::new();
+            for mask in case.mask {
+                assert!(fit_test.report().is_err());
+                fit_test.record(&measurement(1000.0), &measurement(mask));
+                fit_test.record(&measurement(1000.0), &measurement(mask));
+                fit_test.finish_exercise().unwrap();
+            }
+            assert_eq!(fit_test.current(), None);
+            let report = fit_test.report().unwrap();
+            assert!(
+                (report.overall_fit_factor - case.expected_fit_factor).abs()
+                    < 1e-3 * case.expected_fit_factor,
+                "expected {}, got {}",
+                case.expected_fit_factor,
+                report.overall_fit_factor
+            );
+            assert_eq!(report.passed(), case.expected_pass);
+        }
+    }
+
+    #[test]
+    fn test_fit_test_errors() {
+        assert!(FitTest::new().channel(Channel::MassPm1_0).is_err());
+
+        let mut fit_test = FitTest::new()
+            .channel(Channel::NumberPm0_5)
+            .unwrap()
+            .exercises(&OSHA_EXERCISES[..1]);
+        assert!(fit_test.finish_exercise().is_err());
+        fit_test.record(&measurement(0.0), &measurement(0.0));
+        assert!(fit_test.finish_exercise().is_err());
+        // The failed exercise can be retried.
+        fit_test.record(&measurement(100.0), &measurement(1.0));
+        let result = fit_test.finish_exercise().unwrap();
+        assert_eq!(result.ambient, 80.0);
+        assert_eq!(result.fit_factor, 100.0);
+        assert!(fit_test.finish_exercise().is_err());
+    }
+}
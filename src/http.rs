@@ -0,0 +1,404 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+// A deliberately minimal HTTP/1.1 client and server, covering just enough for
+// the upload sinks and exporters: plain http:// only (no TLS), one request per
+// connection, and the response is read until the server closes the connection.
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    // Includes the query string, if any. Always starts with '/'.
+    pub path: String,
+}
+
+pub fn parse_url(url: &str) -> Result<Url, String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("unsupported url (only http:// is supported): {}", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse::<u16>()
+                .map_err(|e| format!("invalid port in url {}: {}", url, e))?,
+        ),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Result::Err(format!("missing host in url: {}", url));
+    }
+    Result::Ok(Url {
+        host: String::from(host),
+        port,
+        path: String::from(path),
+    })
+}
+
+/// percent_encode encodes everything except RFC 3986 unreserved characters,
+/// for use in query strings.
+pub fn percent_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            content_type: String::from(content_type),
+            body,
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Response::new(404, "text/plain; charset=utf-8", b"not found\n".to_vec())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    // Excludes the query string.
+    pub path: String,
+    pub query: String,
+}
+
+pub fn post(url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Response, String> {
+    let url = parse_url(url)?;
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port))
+        .map_err(|e| format!("failed to connect to {}:{}: {}", url.host, url.port, e))?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+        .map_err(|e| format!("failed to set socket timeout: {}", e))?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.host,
+        url.port,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|_| stream.write_all(body))
+        .map_err(|e| format!("failed to send request: {}", e))?;
+
+    let mut raw = Vec::new();
+    stream
+        .read_to_end(&mut raw)
+        .map_err(|e| format!("failed to read response: {}", e))?;
+    parse_response(&raw)
+}
+
+fn parse_response(raw: &[u8]) -> Result<Response, String> {
+    let header_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| String::from("incomplete http response"))?;
+    let head = std::str::from_utf8(&raw[..header_end])
+        .map_err(|_| String::from("http response headers are not valid utf-8"))?;
+    let mut lines = head.lines();
+    let status_line = lines.next().unwrap_or_default();
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| format!("invalid http status line: {}", status_line))?;
+    let content_type = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.trim())
+        .unwrap_or_default();
+    Result::Ok(Response::new(
+        status,
+        content_type,
+        raw[header_end + 4..].to_vec(),
+    ))
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request, String> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader
+        .read_line(&mut request_line)
+        .map_err(|e| format!("failed to read request: {}", e))?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Result::Err(format!("invalid request line: {}", request_line)),
+    };
+    // We only care about the request line, but still need to consume the
+    // headers (we don't support request bodies).
+    loop {
+        let mut line = String::new();
+        let n = reader
+            .read_line(&mut line)
+            .map_err(|e| format!("failed to read request: {}", e))?;
+        if n == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Result::Ok(Request {
+        method: String::from(method),
+        path: String::from(path),
+        query: String::from(query),
+    })
+}
+
+/// write_response writes the response to request, which has no body if the
+/// request was HEAD (but still has the Content-Length of a GET response).
+pub fn write_response(
+    stream: &mut TcpStream,
+    request: &Request,
+    response: &Response,
+) -> std::io::Result<()> {
+    write_head(stream, response)?;
+    if request.method != "HEAD" {
+        stream.write_all(&response.body)?;
+    }
+    Result::Ok(())
+}
+
+fn write_head(stream: &mut TcpStream, response: &Response) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        status_text(response.status),
+        response.content_type,
+        response.body.len()
+    )
+}
+
+/// write_stream_head starts a response whose body is streamed until the
+/// connection is closed, e.g. Server-Sent Events.
+pub fn write_stream_head(stream: &mut TcpStream, content_type: &str) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        content_type
+    )?;
+    stream.flush()
+}
+
+/// serve handles requests on a background thread (and one thread per
+/// connection), until the process exits.
+pub fn serve<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    serve_connections(listener, move |request, mut stream| {
+        if let Err(e) = write_response(&mut stream, request, &handler(request)) {
+            eprintln!("failed to write http response: {}", e);
+        }
+    });
+}
+
+/// serve_connections is like serve, but hands the connection to handler
+/// along with the request, so that it can keep the connection open (see
+/// write_stream_head). Requests other than GET and HEAD are still rejected.
+pub fn serve_connections<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(&Request, TcpStream) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("failed to accept http connection: {}", e);
+                    continue;
+                }
+            };
+            let handler = handler.clone();
+            std::thread::spawn(move || {
+                let _ = stream.set_read_timeout(Some(TIMEOUT));
+                let _ = stream.set_write_timeout(Some(TIMEOUT));
+                let response = match read_request(&stream) {
+                    Ok(request) if request.method == "GET" || request.method == "HEAD" => {
+                        return handler(&request, stream);
+                    }
+                    Ok(_) => Response::new(405, "text/plain; charset=utf-8", vec![]),
+                    Err(_) => Response::new(400, "text/plain; charset=utf-8", vec![]),
+                };
+                // Error responses have no body anyway.
+                if let Err(e) = write_head(&mut stream, &response) {
+                    eprintln!("failed to write http response: {}", e);
+                }
+            });
+        }
+    });
+}
+
+/// get performs a GET request, this is mostly useful for testing the
+/// exporters.
+pub fn get(url: &str) -> Result<Response, String> {
+    let url = parse_url(url)?;
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port))
+        .map_err(|e| format!("failed to connect to {}:{}: {}", url.host, url.port, e))?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .map_err(|e| format!("failed to set socket timeout: {}", e))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\n\r\n",
+        url.path, url.host, url.port
+    )
+    .map_err(|e| format!("failed to send request: {}", e))?;
+    let mut raw = Vec::new();
+    stream
+        .read_to_end(&mut raw)
+        .map_err(|e| format!("failed to read response: {}", e))?;
+    parse_response(&raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        struct TestCase<'a> {
+            input: &'a str,
+            expected_result: Result<Url, String>,
+        }
+        let tests = [
+            TestCase {
+                input: "http://localhost:8086/api/v2/write?org=a",
+                expected_result: Result::Ok(Url {
+                    host: String::from("localhost"),
+                    port: 8086,
+                    path: String::from("/api/v2/write?org=a"),
+                }),
+            },
+            TestCase {
+                input: "http://example.com",
+                expected_result: Result::Ok(Url {
+                    host: String::from("example.com"),
+                    port: 80,
+                    path: String::from("/"),
+                }),
+            },
+            TestCase {
+                input: "https://example.com",
+                expected_result: Result::Err(String::from(
+                    "unsupported url (only http:// is supported): https://example.com",
+                )),
+            },
+            TestCase {
+                input: "http://:80/",
+                expected_result: Result::Err(String::from("missing host in url: http://:80/")),
+            },
+        ];
+        for case in tests {
+            assert_eq!(case.expected_result, parse_url(case.input));
+        }
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("my-bucket_1.0~"), "my-bucket_1.0~");
+        assert_eq!(percent_encode("a b&c=d"), "a%20b%26c%3Dd");
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response = parse_response(
+            b"HTTP/1.1 204 No Content\r\nX-Foo: bar\r\ncontent-type: text/plain\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.status, 204);
+        assert_eq!(response.content_type, "text/plain");
+        assert!(response.is_success());
+        assert!(response.body.is_empty());
+
+        let response = parse_response(b"HTTP/1.1 400 Bad Request\r\n\r\noops").unwrap();
+        assert_eq!(response.status, 400);
+        assert!(!response.is_success());
+        assert_eq!(response.body, b"oops");
+
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        serve(listener, |request| {
+            if request.path == "/hello" {
+                Response::new(200, "text/plain", request.query.clone().into_bytes())
+            } else {
+                Response::not_found()
+            }
+        });
+
+        let response = get(&format!("{}/hello?name=world", url)).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, "text/plain");
+        assert_eq!(response.body, b"name=world");
+
+        assert_eq!(get(&format!("{}/nope", url)).unwrap().status, 404);
+        assert_eq!(post(&url, &[], b"").unwrap().status, 405);
+
+        // HEAD gets the headers of the GET response, without a body.
+        let mut stream = TcpStream::connect(url.strip_prefix("http://").unwrap()).unwrap();
+        write!(
+            stream,
+            "HEAD /hello?name=world HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        )
+        .unwrap();
+        let mut raw = String::new();
+        stream.read_to_string(&mut raw).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 200 OK\r\n"), "{}", raw);
+        assert!(raw.contains("Content-Length: 10\r\n"), "{}", raw);
+        assert!(raw.ends_with("\r\n\r\n"), "{}", raw);
+    }
+}
@@ -0,0 +1,466 @@
+use super::csv::{nearest, TimeSeries};
+use super::distribution::{SizeDistribution, BIN_EDGES_UM};
+use super::measurement::*;
+use std::io::BufRead;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use time::{Duration, OffsetDateTime};
+
+/// KappaKohler corrects for hygroscopic growth using kappa-Kohler theory
+/// (Petters and Kreidenweis, 2007), i.e. it estimates what the SPS30 would
+/// report for the same particles without any absorbed water.
+///
+/// Each bin has its own kappa (the hygroscopicity parameter). Particles in a
+/// bin have grown by a diameter growth factor of (1 + kappa * aw / (1 -
+/// aw))^(1/3), with aw = RH / 100, so each bin's number and mass are moved to
+/// the bins matching their dry diameters (assuming particles are spread
+/// evenly over each bin on a log scale). Bin mass is also divided by the mass
+/// growth factor 1 + (kappa / dry_density) * aw / (1 - aw), as per Crilley et
+/// al. (2018). Particles that shrink below 0.3um are dropped, as the sensor
+/// wouldn't count them.
+///
+/// The correction diverges as RH approaches 100%, so RH is clamped to
+/// max_relative_humidity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KappaKohler {
+    kappas: [f32; 5],
+    dry_density: f32,
+    max_relative_humidity: f32,
+}
+
+impl KappaKohler {
+    /// new uses the same kappa for every bin, a dry density of 1.65 g/cm3,
+    /// and a maximum RH of 95%. Crilley et al. found kappas of 0.38-0.41 for
+    /// urban background aerosol.
+    pub fn new(kappa: f32) -> Self {
+        KappaKohler {
+            kappas: [kappa; 5],
+            dry_density: 1.65,
+            max_relative_humidity: 95.0,
+        }
+    }
+
+    /// bin_kappas sets the kappa for each bin, in the same order as
+    /// SizeDistribution.
+    pub fn bin_kappas(mut self, kappas: [f32; 5]) -> Self {
+        self.kappas = kappas;
+        self
+    }
+
+    /// dry_density sets the density (in g/cm3) of the dry particles.
+    pub fn dry_density(mut self, dry_density: f32) -> Self {
+        self.dry_density = dry_density;
+        self
+    }
+
+    pub fn max_relative_humidity(mut self, max_relative_humidity: f32) -> Self {
+        self.max_relative_humidity = max_relative_humidity;
+        self
+    }
+
+    // Returns aw / (1 - aw).
+    fn water_ratio(&self, relative_humidity: f32) -> f32 {
+        let activity = relative_humidity.clamp(0.0, self.max_relative_humidity) / 100.0;
+        activity / (1.0 - activity)
+    }
+
+    /// growth_factor returns the ratio of wet and dry diameters for a bin.
+    pub fn growth_factor(&self, bin: usize, relative_humidity: f32) -> f32 {
+        (1.0 + self.kappas[bin] * self.water_ratio(relative_humidity)).cbrt()
+    }
+
+    /// mass_growth_factor returns the ratio of wet and dry mass for a bin.
+    pub fn mass_growth_factor(&self, bin: usize, relative_humidity: f32) -> f32 {
+        1.0 + self.kappas[bin] / self.dry_density * self.water_ratio(relative_humidity)
+    }
+
+    pub fn correct(&self, measurement: &Measurement, relative_humidity: f32) -> Measurement {
+        let distribution = SizeDistribution::from_measurement(measurement);
+        let mut counts = [0.0f32; 5];
+        let mut masses = [0.0f32; 5];
+        for (i, bin) in distribution.bins.iter().enumerate() {
+            let growth = self.growth_factor(i, relative_humidity);
+            let mass = bin.mass.max(0.0) / self.mass_growth_factor(i, relative_humidity);
+            let (dry_lower, dry_upper) =
+                ((bin.lower_um / growth).ln(), (bin.upper_um / growth).ln());
+            for j in 0..5 {
+                let overlap =
+                    dry_upper.min(BIN_EDGES_UM[j + 1].ln()) - dry_lower.max(BIN_EDGES_UM[j].ln());
+                if overlap > 0.0 {
+                    let fraction = overlap / (dry_upper - dry_lower);
+                    counts[j] += bin.count.max(0.0) * fraction;
+                    masses[j] += mass * fraction;
+                }
+            }
+        }
+
+        let mut corrected = *measurement;
+        let number_channels = [
+            Channel::NumberPm0_5,
+            Channel::NumberPm1_0,
+            Channel::NumberPm2_5,
+            Channel::NumberPm4_0,
+            Channel::NumberPm10_0,
+        ];
+        let mut cumulative = 0.0;
+        for (channel, count) in number_channels.iter().zip(counts) {
+            cumulative += count;
+            corrected.set(*channel, cumulative);
+        }
+        // The first mass channel is PM1.0, i.e. the first two bins.
+        let mass_channels = [
+            Channel::MassPm1_0,
+            Channel::MassPm2_5,
+            Channel::MassPm4_0,
+            Channel::MassPm10_0,
+        ];
+        let mut cumulative = masses[0];
+        for (channel, mass) in mass_channels.iter().zip(&masses[1..]) {
+            cumulative += mass;
+            corrected.set(*channel, cumulative);
+        }
+        let typical_bin = distribution
+            .bins
+            .iter()
+            .position(|b| measurement.typical_particle_size < b.upper_um)
+            .unwrap_or(4);
+        corrected.typical_particle_size =
+            measurement.typical_particle_size / self.growth_factor(typical_bin, relative_humidity);
+        corrected
+    }
+}
+
+/// LinearRhCorrection is a linear correction of the form slope * value +
+/// rh_coefficient * RH + intercept, applied to a set of channels (PM2.5 by
+/// default). Results are clamped to be non-negative.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinearRhCorrection {
+    slope: f32,
+    rh_coefficient: f32,
+    intercept: f32,
+    channels: Vec<Channel>,
+}
+
+impl LinearRhCorrection {
+    pub fn new(slope: f32, rh_coefficient: f32, intercept: f32) -> Self {
+        LinearRhCorrection {
+            slope,
+            rh_coefficient,
+            intercept,
+            channels: vec![Channel::MassPm2_5],
+        }
+    }
+
+    /// epa_purpleair is the US-wide PurpleAir PM2.5 correction from Barkjohn
+    /// et al. (2021): 0.524 * PM2.5 - 0.0862 * RH + 5.75. It was derived for
+    /// the PurpleAir's Plantower sensors, so it's only a starting point for
+    /// the SPS30.
+    pub fn epa_purpleair() -> Self {
+        Self::new(0.524, -0.0862, 5.75)
+    }
+
+    pub fn channels(mut self, channels: &[Channel]) -> Self {
+        self.channels = channels.to_vec();
+        self
+    }
+
+    pub fn correct(&self, measurement: &Measurement, relative_humidity: f32) -> Measurement {
+        let mut corrected = *measurement;
+        for channel in &self.channels {
+            let value = self.slope * measurement.get(*channel)
+                + self.rh_coefficient * relative_humidity
+                + self.intercept;
+            corrected.set(*channel, value.max(0.0));
+        }
+        corrected
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Correction {
+    KappaKohler(KappaKohler),
+    Linear(LinearRhCorrection),
+}
+
+impl Correction {
+    /// apply corrects a measurement, if relative_humidity is NaN (i.e.
+    /// unknown) all corrected values are NaN.
+    pub fn apply(
+        &self,
+        measurement: &TimestampedMeasurement,
+        relative_humidity: f32,
+    ) -> CorrectedMeasurement {
+        let corrected = if relative_humidity.is_nan() {
+            let mut corrected = Measurement::default();
+            for channel in Channel::ALL {
+                corrected.set(channel, f32::NAN);
+            }
+            corrected
+        } else {
+            match self {
+                Correction::KappaKohler(k) => {
+                    k.correct(&measurement.measurement, relative_humidity)
+                }
+                Correction::Linear(l) => l.correct(&measurement.measurement, relative_humidity),
+            }
+        };
+        CorrectedMeasurement {
+            raw: *measurement,
+            relative_humidity,
+            corrected,
+        }
+    }
+}
+
+/// CorrectedMeasurement keeps the raw measurement alongside the corrected
+/// values, and the RH that was used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CorrectedMeasurement {
+    pub raw: TimestampedMeasurement,
+    pub relative_humidity: f32,
+    pub corrected: Measurement,
+}
+
+impl CorrectedMeasurement {
+    /// csv_header extends Measurement::csv_header with the RH and corrected
+    /// columns, so the output can still be read by CsvReader (which will
+    /// read the raw values).
+    pub fn csv_header() -> String {
+        let mut header = Measurement::csv_header();
+        header.push_str(",Relative Humidity (%)");
+        for channel in Channel::ALL {
+            header.push_str(&format!(
+                ",Corrected {} ({})",
+                channel.label(),
+                channel.unit()
+            ));
+        }
+        header
+    }
+
+    pub fn csv_row(&self) -> String {
+        let mut row = self.raw.csv_row();
+        row.push_str(&format!(",{}", self.relative_humidity));
+        for channel in Channel::ALL {
+            row.push_str(&format!(",{}", self.corrected.get(channel)));
+        }
+        row
+    }
+}
+
+/// HumiditySeries looks up the RH for a measurement from a logged series,
+/// e.g. from read_column.
+pub struct HumiditySeries {
+    samples: TimeSeries,
+    tolerance: Duration,
+}
+
+impl HumiditySeries {
+    /// new creates a series where RH samples are used for measurements up to
+    /// tolerance away.
+    pub fn new(mut samples: TimeSeries, tolerance: Duration) -> Self {
+        samples.sort_by_key(|(time, _)| *time);
+        HumiditySeries { samples, tolerance }
+    }
+
+    /// at returns the nearest RH sample, or NaN if there is none within the
+    /// tolerance.
+    pub fn at(&self, time: OffsetDateTime) -> f32 {
+        nearest(&self.samples, time, self.tolerance).unwrap_or(f32::NAN)
+    }
+}
+
+/// HumidityInput reads RH from a live source in the background, e.g. a FIFO
+/// or serial port that a second sensor writes to. Each line contains the RH
+/// as its last comma-separated field, so both "55.2" and "<time>,55.2" work.
+#[derive(Clone)]
+pub struct HumidityInput {
+    latest: Arc<Mutex<Option<(Instant, f32)>>>,
+}
+
+impl HumidityInput {
+    pub fn spawn<R: BufRead + Send + 'static>(reader: R) -> Self {
+        let latest = Arc::new(Mutex::new(None));
+        let input = HumidityInput {
+            latest: latest.clone(),
+        };
+        std::thread::spawn(move || {
+            for line in reader.lines() {
+                let Ok(line) = line else { break };
+                let value = line.rsplit(',').next().unwrap_or("").trim();
+                match value.parse::<f32>() {
+                    Ok(rh) => *latest.lock().unwrap() = Some((Instant::now(), rh)),
+                    Err(_) => eprintln!("ignoring invalid RH input {:?}", line),
+                }
+            }
+        });
+        input
+    }
+
+    /// latest returns the most recent RH, or NaN if there is none from within
+    /// max_age.
+    pub fn latest(&self, max_age: std::time::Duration) -> f32 {
+        match *self.latest.lock().unwrap() {
+            Some((received, rh)) if received.elapsed() <= max_age => rh,
+            _ => f32::NAN,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-3 * expected.abs().max(1.0),
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    const MEASUREMENT: Measurement = Measurement {
+        mass_concentration_pm_1_0: 10.0,
+        mass_concentration_pm_2_5: 12.0,
+        mass_concentration_pm_4_0: 12.5,
+        mass_concentration_pm_10_0: 12.75,
+        number_concentration_pm_0_5: 60.0,
+        number_concentration_pm_1_0: 75.0,
+        number_concentration_pm_2_5: 78.0,
+        number_concentration_pm_4_0: 78.5,
+        number_concentration_pm_10_0: 78.6,
+        typical_particle_size: 0.6,
+    };
+
+    #[test]
+    fn test_kappa_kohler() {
+        // No water, no change.
+        let corrected = KappaKohler::new(0.4).correct(&MEASUREMENT, 0.0);
+        for channel in Channel::ALL {
+            assert_close(corrected.get(channel), MEASUREMENT.get(channel));
+        }
+
+        // At 75% RH, aw / (1 - aw) = 3, so with kappa = 7/3 the growth factor
+        // is exactly 2: everything moves down by a factor of 2 in diameter,
+        // e.g. the 1.0-2.5um bin becomes 0.5-1.25um.
+        let kappa_kohler = KappaKohler::new(7.0 / 3.0).dry_density(2.0);
+        assert_close(kappa_kohler.growth_factor(0, 75.0), 2.0);
+        assert_close(kappa_kohler.mass_growth_factor(0, 75.0), 4.5);
+        let corrected = kappa_kohler.correct(&MEASUREMENT, 75.0);
+        // 0.3-0.5um is now 0.15-0.25um, and 0.5-1.0um is 0.25-0.5um, so only
+        // log(0.5 / 0.3) / log(2) of the latter is still counted.
+        let counted = (0.5f32 / 0.3).ln() / 2f32.ln();
+        assert_close(corrected.number_concentration_pm_0_5, 15.0 * counted);
+        // 1.0-2.5um is now 0.5-1.25um.
+        let below_1 = 2f32.ln() / 2.5f32.ln();
+        assert_close(
+            corrected.number_concentration_pm_1_0,
+            15.0 * counted + 3.0 * below_1,
+        );
+        assert_close(corrected.number_concentration_pm_10_0, 15.0 * counted + 3.6);
+        assert_close(corrected.mass_concentration_pm_10_0, {
+            let distribution = SizeDistribution::from_measurement(&MEASUREMENT);
+            (distribution.bins[1].mass * counted + 2.75) / 4.5
+        });
+        assert_close(corrected.typical_particle_size, 0.3);
+
+        // RH is clamped.
+        assert_eq!(
+            kappa_kohler.correct(&MEASUREMENT, 99.0),
+            kappa_kohler.correct(&MEASUREMENT, 95.0)
+        );
+    }
+
+    #[test]
+    fn test_linear() {
+        let corrected = LinearRhCorrection::epa_purpleair().correct(&MEASUREMENT, 50.0);
+        assert_close(
+            corrected.mass_concentration_pm_2_5,
+            0.524 * 12.0 - 4.31 + 5.75,
+        );
+        assert_eq!(corrected.mass_concentration_pm_1_0, 10.0);
+
+        let corrected = LinearRhCorrection::new(1.0, -1.0, 0.0)
+            .channels(&[Channel::MassPm1_0, Channel::MassPm10_0])
+            .correct(&MEASUREMENT, 11.0);
+        assert_eq!(corrected.mass_concentration_pm_1_0, 0.0);
+        assert_close(corrected.mass_concentration_pm_10_0, 1.75);
+        assert_eq!(corrected.mass_concentration_pm_2_5, 12.0);
+    }
+
+    #[test]
+    fn test_corrected_csv() {
+        let time = datetime!(2024-06-01 12:00 UTC);
+        let measurement = TimestampedMeasurement {
+            time,
+            measurement: MEASUREMENT,
+        };
+        let correction = Correction::Linear(LinearRhCorrection::new(2.0, 0.0, 0.0));
+        let corrected = correction.apply(&measurement, 50.0);
+        assert_eq!(corrected.raw, measurement);
+        assert_eq!(corrected.corrected.mass_concentration_pm_2_5, 24.0);
+        let header = CorrectedMeasurement::csv_header();
+        let row = corrected.csv_row();
+        assert_eq!(header.split(',').count(), 22);
+        assert_eq!(row.split(',').count(), 22);
+        assert!(row.starts_with(&measurement.csv_row()));
+        assert!(row.contains(",50,10,24,12.5,"));
+
+        let unknown = correction.apply(&measurement, f32::NAN);
+        assert!(unknown.corrected.mass_concentration_pm_2_5.is_nan());
+    }
+
+    #[test]
+    fn test_humidity_series() {
+        let start = datetime!(2024-06-01 12:00 UTC);
+        let series = HumiditySeries::new(
+            vec![(start + Duration::minutes(1), 60.0), (start, 50.0)],
+            Duration::seconds(20),
+        );
+        struct TestCase {
+            offset: Duration,
+            expected_output: f32,
+        }
+        let tests = [
+            TestCase {
+                offset: Duration::seconds(-20),
+                expected_output: 50.0,
+            },
+            TestCase {
+                offset: Duration::seconds(25),
+                expected_output: f32::NAN,
+            },
+            TestCase {
+                offset: Duration::seconds(45),
+                expected_output: 60.0,
+            },
+            TestCase {
+                offset: Duration::minutes(2),
+                expected_output: f32::NAN,
+            },
+        ];
+        for case in tests {
+            let rh = series.at(start + case.offset);
+            assert!(
+                rh == case.expected_output || (rh.is_nan() && case.expected_output.is_nan()),
+                "{}: expected {}, got {}",
+                case.offset,
+                case.expected_output,
+                rh
+            );
+        }
+    }
+
+    #[test]
+    fn test_humidity_input() {
+        let input = HumidityInput::spawn("40\nbad\n2024-06-01T12:00:00,55.5\n".as_bytes());
+        let deadline = Instant::now() + std::time::Duration::from_secs(5);
+        while input.latest(std::time::Duration::from_secs(60)) != 55.5 {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(input.latest(std::time::Duration::ZERO).is_nan());
+    }
+}
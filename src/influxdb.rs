@@ -0,0 +1,392 @@
+use super::http;
+use super::measurement::*;
+use std::time::Duration;
+
+/// LineProtocolFormatter formats measurements using the
+/// [InfluxDB line protocol][line_protocol], with one field per channel (see
+/// Channel::key for the field names) and a nanosecond timestamp.
+///
+/// [line_protocol]: <https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/>
+pub struct LineProtocolFormatter {
+    measurement_name: String,
+    serial: Option<String>,
+    location: Option<String>,
+}
+
+impl LineProtocolFormatter {
+    pub fn new(measurement_name: &str) -> Self {
+        LineProtocolFormatter {
+            measurement_name: String::from(measurement_name),
+            serial: None,
+            location: None,
+        }
+    }
+
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.serial = Some(String::from(serial));
+        self
+    }
+
+    pub fn location(mut self, location: &str) -> Self {
+        self.location = Some(String::from(location));
+        self
+    }
+
+    /// format returns the line for a measurement, or None if none of its values
+    /// can be written: a line without fields is invalid, and InfluxDB would
+    /// reject the whole batch containing it.
+    pub fn format(&self, measurement: &TimestampedMeasurement) -> Option<String> {
+        // NaN and infinity aren't representable in line protocol, the SPS30
+        // shouldn't produce them but we skip them just in case.
+        let fields: Vec<String> = Channel::ALL
+            .iter()
+            .map(|c| (c.key(), measurement.measurement.get(*c)))
+            .filter(|(_, value)| value.is_finite())
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        if fields.is_empty() {
+            return None;
+        }
+
+        let mut line = escape(&self.measurement_name, &[',', ' ']);
+        // InfluxDB rejects empty tag values, so we simply omit those tags.
+        for (key, value) in [("serial", &self.serial), ("location", &self.location)] {
+            if let Some(value) = value.as_deref().filter(|v| !v.is_empty()) {
+                line.push_str(&format!(",{}={}", key, escape(value, &[',', '=', ' '])));
+            }
+        }
+
+        line.push(' ');
+        line.push_str(&fields.join(","));
+
+        line.push_str(&format!(" {}", measurement.time.unix_timestamp_nanos()));
+        Some(line)
+    }
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// InfluxDbSink batches measurements and writes them to an InfluxDB v2
+/// `/api/v2/write` endpoint, retrying failed writes.
+///
+/// Batches that could not be written (after exhausting all attempts) are kept
+/// and retried on the next flush, up to max_pending lines: beyond that the
+/// oldest lines are dropped. Batches that are rejected by the server as
+/// invalid (4xx) are dropped immediately as retrying would be pointless.
+pub struct InfluxDbSink {
+    write_url: String,
+    token: Option<String>,
+    formatter: LineProtocolFormatter,
+    batch_size: usize,
+    max_pending: usize,
+    max_attempts: u32,
+    retry_delay: Duration,
+    pending: Vec<String>,
+}
+
+impl InfluxDbSink {
+    /// url is the base url of the InfluxDB instance, e.g. http://localhost:8086
+    pub fn new(url: &str, org: &str, bucket: &str, formatter: LineProtocolFormatter) -> Self {
+        InfluxDbSink {
+            write_url: format!(
+                "{}/api/v2/write?org={}&bucket={}&precision=ns",
+                url.trim_end_matches('/'),
+                http::percent_encode(org),
+                http::percent_encode(bucket)
+            ),
+            token: None,
+            formatter,
+            batch_size: 12,
+            max_pending: 10_000,
+            max_attempts: 3,
+            retry_delay: Duration::from_secs(1),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = Some(String::from(token));
+        self
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    pub fn retries(mut self, max_attempts: u32, retry_delay: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// push queues a measurement, and writes the current batch once it is full.
+    /// Measurements without any finite values are skipped.
+    pub fn push(&mut self, measurement: &TimestampedMeasurement) -> Result<(), String> {
+        let Some(line) = self.formatter.format(measurement) else {
+            return Result::Ok(());
+        };
+        self.pending.push(line);
+        if self.pending.len() > self.max_pending {
+            let excess = self.pending.len() - self.max_pending;
+            self.pending.drain(..excess);
+        }
+        if self.pending.len() >= self.batch_size {
+            return self.flush();
+        }
+        Result::Ok(())
+    }
+
+    /// flush writes all pending measurements.
+    pub fn flush(&mut self) -> Result<(), String> {
+        if self.pending.is_empty() {
+            return Result::Ok(());
+        }
+        let body = self.pending.join("\n");
+        let authorization = self.token.as_ref().map(|t| format!("Token {}", t));
+        let mut headers = vec![("Content-Type", "text/plain; charset=utf-8")];
+        if let Some(authorization) = &authorization {
+            headers.push(("Authorization", authorization));
+        }
+
+        let mut last_error = String::new();
+        for attempt in 0..self.max_attempts {
+            if attempt > 0 {
+                std::thread::sleep(self.retry_delay);
+            }
+            match http::post(&self.write_url, &headers, body.as_bytes()) {
+                Ok(response) if response.is_success() => {
+                    self.pending.clear();
+                    return Result::Ok(());
+                }
+                Ok(response) if response.status == 429 || response.status >= 500 => {
+                    last_error = format!("InfluxDB write failed with status {}", response.status);
+                }
+                Ok(response) => {
+                    self.pending.clear();
+                    return Result::Err(format!(
+                        "InfluxDB rejected write with status {} (dropping batch): {}",
+                        response.status,
+                        String::from_utf8_lossy(&response.body)
+                    ));
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Result::Err(format!(
+            "InfluxDB write failed after {} attempts, last error: {}",
+            self.max_attempts, last_error
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    fn measurement() -> TimestampedMeasurement {
+        TimestampedMeasurement {
+            time: time::macros::datetime!(2024-06-01 12:00:00.5 UTC),
+            measurement: Measurement {
+                mass_concentration_pm_1_0: 1.5,
+                mass_concentration_pm_2_5: 2.0,
+                mass_concentration_pm_4_0: 2.25,
+                mass_concentration_pm_10_0: 2.5,
+                number_concentration_pm_0_5: 10.0,
+                number_concentration_pm_1_0: 11.0,
+                number_concentration_pm_2_5: 12.0,
+                number_concentration_pm_4_0: 12.5,
+                number_concentration_pm_10_0: 13.0,
+                typical_particle_size: 0.5,
+            },
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let fields = "mass_pm1_0=1.5,mass_pm2_5=2,mass_pm4_0=2.25,mass_pm10_0=2.5,\
+            number_pm0_5=10,number_pm1_0=11,number_pm2_5=12,number_pm4_0=12.5,\
+            number_pm10_0=13,typical_particle_size=0.5";
+        let mut partial = measurement();
+        partial.measurement.mass_concentration_pm_1_0 = f32::NAN;
+        partial.measurement.typical_particle_size = f32::INFINITY;
+        let mut empty = measurement();
+        for channel in Channel::ALL {
+            empty.measurement.set(channel, f32::NAN);
+        }
+        struct TestCase {
+            formatter: LineProtocolFormatter,
+            measurement: TimestampedMeasurement,
+            expected_output: Option<String>,
+        }
+        let tests = [
+            TestCase {
+                formatter: LineProtocolFormatter::new("sps30"),
+                measurement: measurement(),
+                expected_output: Some(format!("sps30 {} 1717243200500000000", fields)),
+            },
+            TestCase {
+                formatter: LineProtocolFormatter::new("sps30")
+                    .serial("ABCD1234")
+                    .location("lab"),
+                measurement: measurement(),
+                expected_output: Some(format!(
+                    "sps30,serial=ABCD1234,location=lab {} 1717243200500000000",
+                    fields
+                )),
+            },
+            TestCase {
+                formatter: LineProtocolFormatter::new("air quality,x")
+                    .serial("")
+                    .location("room 1,a=b"),
+                measurement: measurement(),
+                expected_output: Some(format!(
+                    "air\\ quality\\,x,location=room\\ 1\\,a\\=b {} 1717243200500000000",
+                    fields
+                )),
+            },
+            TestCase {
+                formatter: LineProtocolFormatter::new("sps30"),
+                measurement: partial,
+                expected_output: Some(String::from(
+                    "sps30 mass_pm2_5=2,mass_pm4_0=2.25,mass_pm10_0=2.5,number_pm0_5=10,\
+                    number_pm1_0=11,number_pm2_5=12,number_pm4_0=12.5,number_pm10_0=13 \
+                    1717243200500000000",
+                )),
+            },
+            TestCase {
+                formatter: LineProtocolFormatter::new("sps30"),
+                measurement: empty,
+                expected_output: None,
+            },
+        ];
+        for case in tests {
+            assert_eq!(
+                case.expected_output,
+                case.formatter.format(&case.measurement)
+            );
+        }
+    }
+
+    // Accepts one connection per entry in statuses, responding with that status,
+    // and sends the body of each request back to the test.
+    fn mock_server(statuses: Vec<u16>) -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                tx.send((request_line, String::from_utf8(body).unwrap()))
+                    .unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_sink_batches_and_retries() {
+        let (url, requests) = mock_server(vec![503, 204]);
+        let mut sink = InfluxDbSink::new(&url, "my org", "bucket", LineProtocolFormatter::new("m"))
+            .batch_size(2)
+            .retries(2, Duration::from_millis(1));
+
+        sink.push(&measurement()).unwrap();
+        assert!(requests.try_recv().is_err());
+        sink.push(&measurement()).unwrap();
+
+        let line = LineProtocolFormatter::new("m")
+            .format(&measurement())
+            .unwrap();
+        for _ in 0..2 {
+            let (request_line, body) = requests.recv().unwrap();
+            assert_eq!(
+                request_line,
+                "POST /api/v2/write?org=my%20org&bucket=bucket&precision=ns HTTP/1.1\r\n"
+            );
+            assert_eq!(body, format!("{}\n{}", line, line));
+        }
+        assert!(sink.pending.is_empty());
+    }
+
+    #[test]
+    fn test_sink_keeps_batch_after_failure() {
+        let (url, _requests) = mock_server(vec![500, 500, 204]);
+        let mut sink = InfluxDbSink::new(&url, "org", "bucket", LineProtocolFormatter::new("m"))
+            .batch_size(1)
+            .retries(2, Duration::from_millis(1));
+
+        assert!(sink.push(&measurement()).is_err());
+        assert_eq!(sink.pending.len(), 1);
+        sink.flush().unwrap();
+        assert!(sink.pending.is_empty());
+    }
+
+    #[test]
+    fn test_sink_skips_measurement_without_fields() {
+        let mut sink = InfluxDbSink::new(
+            "http://127.0.0.1:1",
+            "org",
+            "bucket",
+            LineProtocolFormatter::new("m"),
+        )
+        .batch_size(1);
+        let mut empty = measurement();
+        for channel in Channel::ALL {
+            empty.measurement.set(channel, f32::NAN);
+        }
+
+        sink.push(&empty).unwrap();
+        assert!(sink.pending.is_empty());
+    }
+
+    #[test]
+    fn test_sink_drops_rejected_batch() {
+        let (url, _requests) = mock_server(vec![400]);
+        let mut sink = InfluxDbSink::new(&url, "org", "bucket", LineProtocolFormatter::new("m"))
+            .batch_size(1)
+            .retries(3, Duration::from_millis(1));
+
+        assert!(sink.push(&measurement()).is_err());
+        assert!(sink.pending.is_empty());
+    }
+}
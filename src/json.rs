@@ -0,0 +1,85 @@
+use std::fmt::Display;
+
+// Minimal helpers for writing JSON, we only ever need to produce small objects
+// and arrays.
+
+pub fn string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// number formats a number, JSON has no representation for NaN or infinity so
+/// those are written as null.
+pub fn number<T: Into<f64> + Display + Copy>(value: T) -> String {
+    if value.into().is_finite() {
+        value.to_string()
+    } else {
+        String::from("null")
+    }
+}
+
+pub fn array<I: IntoIterator<Item = String>>(values: I) -> String {
+    format!("[{}]", values.into_iter().collect::<Vec<_>>().join(","))
+}
+
+/// Object builds a JSON object, with fields in insertion order.
+#[derive(Default)]
+pub struct Object {
+    fields: Vec<String>,
+}
+
+impl Object {
+    pub fn new() -> Self {
+        Object::default()
+    }
+
+    /// raw adds a field whose value is already formatted as JSON.
+    pub fn raw(mut self, key: &str, value: &str) -> Self {
+        self.fields.push(format!("{}:{}", string(key), value));
+        self
+    }
+
+    pub fn string(self, key: &str, value: &str) -> Self {
+        self.raw(key, &string(value))
+    }
+
+    pub fn number<T: Into<f64> + Display + Copy>(self, key: &str, value: T) -> Self {
+        self.raw(key, &number(value))
+    }
+
+    pub fn build(&self) -> String {
+        format!("{{{}}}", self.fields.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object() {
+        assert_eq!(Object::new().build(), "{}");
+        assert_eq!(
+            Object::new()
+                .string("name", "a \"b\"\n\u{1}")
+                .number("value", 2.5f32)
+                .number("missing", f32::NAN)
+                .raw("list", &array([number(1), string("x")]))
+                .build(),
+            r#"{"name":"a \"b\"\n\u0001","value":2.5,"missing":null,"list":[1,"x"]}"#
+        );
+    }
+}
@@ -1,6 +1,35 @@
 // TODO: temporarily allow dead_code until basic implementation is done.
 #![allow(dead_code)]
 
+pub mod aggregate;
+pub mod aqi;
+pub mod bacnet;
+pub mod cadr;
+pub mod calibration;
+pub mod cleanroom;
+pub mod csv;
+pub mod device;
+pub mod distribution;
+pub mod filtration;
+pub mod fittest;
+mod http;
+pub mod humidity;
+pub mod influxdb;
+mod json;
 // TODO: temporarily make public until the real API has been determined.
 pub mod measurement;
+pub mod merge;
+pub mod modbus;
+pub mod mqtt;
+pub mod plot;
+pub mod pms5003;
+mod png;
+pub mod portacount;
+pub mod prometheus;
+pub mod purpleair;
+pub mod sensorcommunity;
 pub mod shdlc;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod tui;
+pub mod web;
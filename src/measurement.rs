@@ -3,50 +3,186 @@ use std::fmt;
 
 // See page 6 of the datasheet for more details:
 // https://sensirion.com/media/documents/8600FF88/64A3B8D6/Sensirion_PM_Sensors_Datasheet_SPS30.pdf
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Measurement {
     // ug/m3
-    mass_concentration_pm_1_0: f32,
-    mass_concentration_pm_2_5: f32,
-    mass_concentration_pm_4_0: f32,
-    mass_concentration_pm_10_0: f32,
+    pub mass_concentration_pm_1_0: f32,
+    pub mass_concentration_pm_2_5: f32,
+    pub mass_concentration_pm_4_0: f32,
+    pub mass_concentration_pm_10_0: f32,
     // #/cm3
-    number_concentration_pm_0_5: f32,
-    number_concentration_pm_1_0: f32,
-    number_concentration_pm_2_5: f32,
-    number_concentration_pm_4_0: f32,
-    number_concentration_pm_10_0: f32,
+    pub number_concentration_pm_0_5: f32,
+    pub number_concentration_pm_1_0: f32,
+    pub number_concentration_pm_2_5: f32,
+    pub number_concentration_pm_4_0: f32,
+    pub number_concentration_pm_10_0: f32,
     // um
-    typical_particle_size: f32,
+    pub typical_particle_size: f32,
+}
+
+/// Channel identifies one of the ten values contained in a Measurement, which
+/// is useful for outputs that handle every value in the same way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Channel {
+    MassPm1_0,
+    MassPm2_5,
+    MassPm4_0,
+    MassPm10_0,
+    NumberPm0_5,
+    NumberPm1_0,
+    NumberPm2_5,
+    NumberPm4_0,
+    NumberPm10_0,
+    TypicalParticleSize,
+}
+
+impl Channel {
+    /// All channels, in the same order as the ReadMeasuredValues response (and
+    /// the CSV columns).
+    pub const ALL: [Channel; 10] = [
+        Channel::MassPm1_0,
+        Channel::MassPm2_5,
+        Channel::MassPm4_0,
+        Channel::MassPm10_0,
+        Channel::NumberPm0_5,
+        Channel::NumberPm1_0,
+        Channel::NumberPm2_5,
+        Channel::NumberPm4_0,
+        Channel::NumberPm10_0,
+        Channel::TypicalParticleSize,
+    ];
+
+    /// A short machine-friendly name, e.g. for use as a field or column name.
+    pub fn key(&self) -> &'static str {
+        match self {
+            Channel::MassPm1_0 => "mass_pm1_0",
+            Channel::MassPm2_5 => "mass_pm2_5",
+            Channel::MassPm4_0 => "mass_pm4_0",
+            Channel::MassPm10_0 => "mass_pm10_0",
+            Channel::NumberPm0_5 => "number_pm0_5",
+            Channel::NumberPm1_0 => "number_pm1_0",
+            Channel::NumberPm2_5 => "number_pm2_5",
+            Channel::NumberPm4_0 => "number_pm4_0",
+            Channel::NumberPm10_0 => "number_pm10_0",
+            Channel::TypicalParticleSize => "typical_particle_size",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Channel> {
+        Channel::ALL.into_iter().find(|c| c.key() == key)
+    }
+
+    /// A human-readable name, as used in the CSV header.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Channel::MassPm1_0 => "Mass Concentration PM1",
+            Channel::MassPm2_5 => "Mass Concentration PM2.5",
+            Channel::MassPm4_0 => "Mass Concentration PM4.0",
+            Channel::MassPm10_0 => "Mass Concentration PM10.0",
+            Channel::NumberPm0_5 => "Number Concentration PM0.5",
+            Channel::NumberPm1_0 => "Number Concentration PM1.0",
+            Channel::NumberPm2_5 => "Number Concentration PM2.5",
+            Channel::NumberPm4_0 => "Number Concentration PM4.0",
+            Channel::NumberPm10_0 => "Number Concentration PM10.0",
+            Channel::TypicalParticleSize => "Typical Particle Size",
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Channel::MassPm1_0 | Channel::MassPm2_5 | Channel::MassPm4_0 | Channel::MassPm10_0 => {
+                "ug/m3"
+            }
+            Channel::NumberPm0_5
+            | Channel::NumberPm1_0
+            | Channel::NumberPm2_5
+            | Channel::NumberPm4_0
+            | Channel::NumberPm10_0 => "#/cm3",
+            Channel::TypicalParticleSize => "um",
+        }
+    }
+}
+
+/// A Measurement along with the (UTC) time at which it was read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimestampedMeasurement {
+    pub time: time::OffsetDateTime,
+    pub measurement: Measurement,
 }
 
 impl Measurement {
+    pub fn get(&self, channel: Channel) -> f32 {
+        match channel {
+            Channel::MassPm1_0 => self.mass_concentration_pm_1_0,
+            Channel::MassPm2_5 => self.mass_concentration_pm_2_5,
+            Channel::MassPm4_0 => self.mass_concentration_pm_4_0,
+            Channel::MassPm10_0 => self.mass_concentration_pm_10_0,
+            Channel::NumberPm0_5 => self.number_concentration_pm_0_5,
+            Channel::NumberPm1_0 => self.number_concentration_pm_1_0,
+            Channel::NumberPm2_5 => self.number_concentration_pm_2_5,
+            Channel::NumberPm4_0 => self.number_concentration_pm_4_0,
+            Channel::NumberPm10_0 => self.number_concentration_pm_10_0,
+            Channel::TypicalParticleSize => self.typical_particle_size,
+        }
+    }
+
+    pub fn set(&mut self, channel: Channel, value: f32) {
+        let field = match channel {
+            Channel::MassPm1_0 => &mut self.mass_concentration_pm_1_0,
+            Channel::MassPm2_5 => &mut self.mass_concentration_pm_2_5,
+            Channel::MassPm4_0 => &mut self.mass_concentration_pm_4_0,
+            Channel::MassPm10_0 => &mut self.mass_concentration_pm_10_0,
+            Channel::NumberPm0_5 => &mut self.number_concentration_pm_0_5,
+            Channel::NumberPm1_0 => &mut self.number_concentration_pm_1_0,
+            Channel::NumberPm2_5 => &mut self.number_concentration_pm_2_5,
+            Channel::NumberPm4_0 => &mut self.number_concentration_pm_4_0,
+            Channel::NumberPm10_0 => &mut self.number_concentration_pm_10_0,
+            Channel::TypicalParticleSize => &mut self.typical_particle_size,
+        };
+        *field = value;
+    }
+
     pub fn csv_header() -> String {
         String::from("Time,Mass Concentration PM1 (ug/m3),Mass Concentration PM2.5 (ug/m3),Mass Concentration PM4.0 (ug/m3),Mass Concentration PM10.0 (ug/m3),Number Concentration PM0.5 (#/cm3),Number Concentration PM1.0 (#/cm3),Number Concentration PM2.5 (#/cm3),Number Concentration PM4.0 (#/cm3),Number Concentration PM10.0 (#/cm3),Typical Particle Size (um)")
     }
 
     pub fn csv_row(&self) -> String {
-        let date_time = time::OffsetDateTime::now_utc();
-        // None of the time::format_description::well_known formats are actually well
-        // known to e.g. gnuplot or LibreOffice (translation: good luck getting them
-        // parsed).
-        let format = time::macros::format_description!(
-            version = 2,
-            "[year]-[month]-[day]T[hour]:[minute]:[second]"
-        );
-        let formatted_date_time = date_time.format(&format).unwrap();
+        TimestampedMeasurement {
+            time: time::OffsetDateTime::now_utc(),
+            measurement: *self,
+        }
+        .csv_row()
+    }
+}
+
+// None of the time::format_description::well_known formats are actually well
+// known to e.g. gnuplot or LibreOffice (translation: good luck getting them
+// parsed). Times are always UTC, despite the lack of zone.
+pub const CSV_TIME_FORMAT: &[time::format_description::BorrowedFormatItem<'static>] =
+    time::macros::format_description!(version = 2, "[year]-[month]-[day]T[hour]:[minute]:[second]");
+
+impl TimestampedMeasurement {
+    /// csv_row formats the measurement for use with Measurement::csv_header.
+    pub fn csv_row(&self) -> String {
+        let formatted_date_time = self
+            .time
+            .to_offset(time::UtcOffset::UTC)
+            .format(&CSV_TIME_FORMAT)
+            .unwrap();
+        let m = &self.measurement;
         format!(
             "{},{},{},{},{},{},{},{},{},{},{}",
             formatted_date_time,
-            self.mass_concentration_pm_1_0,
-            self.mass_concentration_pm_2_5,
-            self.mass_concentration_pm_4_0,
-            self.mass_concentration_pm_10_0,
-            self.number_concentration_pm_0_5,
-            self.number_concentration_pm_1_0,
-            self.number_concentration_pm_2_5,
-            self.number_concentration_pm_4_0,
-            self.number_concentration_pm_10_0,
-            self.typical_particle_size
+            m.mass_concentration_pm_1_0,
+            m.mass_concentration_pm_2_5,
+            m.mass_concentration_pm_4_0,
+            m.mass_concentration_pm_10_0,
+            m.number_concentration_pm_0_5,
+            m.number_concentration_pm_1_0,
+            m.number_concentration_pm_2_5,
+            m.number_concentration_pm_4_0,
+            m.number_concentration_pm_10_0,
+            m.typical_particle_size
         )
     }
 }
@@ -90,11 +226,11 @@ pub fn decode_measurement_frame(frame: &MisoFrame) -> Result<Measurement, String
     }
     if frame.data.len() != 40 {
         // TODO: len=0 indicates that no data is available yet.
-        return Result::Err(String::from(format!(
+        return Result::Err(format!(
             "ReadMeasuredValues MISO frame has unexpected length, actual={}, frame={}",
             frame.data.len(),
             frame
-        )));
+        ));
     }
 
     Result::Ok(Measurement {
@@ -110,3 +246,26 @@ pub fn decode_measurement_frame(frame: &MisoFrame) -> Result<Measurement, String
         typical_particle_size: f32::from_be_bytes(frame.data[36..40].try_into().unwrap()),
     })
 }
+
+/// Helpers shared by tests across modules.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::*;
+
+    /// measurement returns a measurement with every channel set to value.
+    pub fn measurement(time: time::OffsetDateTime, value: f32) -> TimestampedMeasurement {
+        let mut measurement = Measurement::default();
+        for channel in Channel::ALL {
+            measurement.set(channel, value);
+        }
+        TimestampedMeasurement { time, measurement }
+    }
+
+    /// temp_path returns a path in the temp directory that is unique to name
+    /// and this process, after removing anything left there by a previous run.
+    pub fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("sps30rs-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+}
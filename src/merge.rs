@@ -0,0 +1,455 @@
+use super::json;
+use super::measurement::CSV_TIME_FORMAT;
+use time::{Duration, OffsetDateTime};
+
+/// Interpolation selects how a source's value at a grid point is derived from
+/// its samples. Samples further than the tolerance from the grid point are
+/// never used, in which case the value is missing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    /// The sample nearest to the grid point.
+    Nearest,
+    /// Linear interpolation between the samples either side of the grid
+    /// point.
+    Linear,
+    /// The latest sample at or before the grid point.
+    HoldLast,
+}
+
+impl Interpolation {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nearest" => Some(Interpolation::Nearest),
+            "linear" => Some(Interpolation::Linear),
+            "hold" | "hold-last" => Some(Interpolation::HoldLast),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// One wide CSV file, with a header.
+    Csv,
+    /// One JSON object per line, missing values are null.
+    JsonLines,
+}
+
+/// Row is one point of the merged time grid, with a value (if available) for
+/// every column of every source, in the order of Merger::columns.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    pub time: OffsetDateTime,
+    pub values: Vec<Option<f32>>,
+}
+
+type Sample = (OffsetDateTime, Vec<f32>);
+
+struct Source {
+    prefix: String,
+    columns: Vec<String>,
+    // Sorted by time. Samples that can't affect any future grid point are
+    // dropped as rows are produced.
+    samples: Vec<Sample>,
+}
+
+impl Source {
+    fn value(
+        &self,
+        time: OffsetDateTime,
+        column: usize,
+        interpolation: Interpolation,
+        tolerance: Duration,
+    ) -> Option<f32> {
+        let index = self.samples.partition_point(|(t, _)| *t <= time);
+        let within = |sample: &&Sample| (sample.0 - time).abs() <= tolerance;
+        let before = index
+            .checked_sub(1)
+            .map(|i| &self.samples[i])
+            .filter(within);
+        let after = self.samples.get(index).filter(within);
+        let value = match (interpolation, before, after) {
+            (Interpolation::HoldLast, before, _) => before.map(|s| s.1[column]),
+            (Interpolation::Nearest, Some(before), Some(after)) => {
+                if time - before.0 <= after.0 - time {
+                    Some(before.1[column])
+                } else {
+                    Some(after.1[column])
+                }
+            }
+            (Interpolation::Nearest, before, after) => before.or(after).map(|s| s.1[column]),
+            (Interpolation::Linear, Some(before), _) if before.0 == time => Some(before.1[column]),
+            (Interpolation::Linear, Some(before), Some(after)) => {
+                let fraction = (time - before.0) / (after.0 - before.0);
+                Some(before.1[column] + (after.1[column] - before.1[column]) * fraction as f32)
+            }
+            (Interpolation::Linear, _, _) => None,
+        };
+        value.filter(|v| v.is_finite())
+    }
+
+    // Drops samples before the latest one at or before time.
+    fn prune(&mut self, time: OffsetDateTime) {
+        let keep_from = self
+            .samples
+            .partition_point(|(t, _)| *t <= time)
+            .saturating_sub(1);
+        self.samples.drain(..keep_from);
+    }
+}
+
+// Turns a column name into something that can be used in a CSV header or as a
+// JSON key, e.g. "Mass Concentration PM2.5" becomes mass_concentration_pm2_5.
+fn column_key(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Merger resamples several timestamped sources (e.g. the SPS30 and other
+/// instruments) onto a common time grid, with one column per source column,
+/// prefixed by the source's name.
+///
+/// Sources can be fed live, taking rows as they become ready (see
+/// Merger::ready), or from logs, taking all rows at the end (see
+/// Merger::finish). Samples must be pushed in time order per source, but
+/// sources can be pushed in any order relative to each other.
+pub struct Merger {
+    interval: Duration,
+    interpolation: Interpolation,
+    tolerance: Duration,
+    sources: Vec<Source>,
+    // The next grid point, set once the first row is produced.
+    next: Option<OffsetDateTime>,
+}
+
+impl Merger {
+    /// new creates a merger whose grid points are multiples of interval
+    /// (which is rounded to whole seconds, the resolution of CSV timestamps)
+    /// since the Unix epoch. The tolerance defaults to interval.
+    pub fn new(interval: Duration) -> Self {
+        let interval = Duration::seconds(interval.whole_seconds().max(1));
+        Merger {
+            interval,
+            interpolation: Interpolation::Nearest,
+            tolerance: interval,
+            sources: vec![],
+            next: None,
+        }
+    }
+
+    pub fn interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    /// tolerance sets the maximum distance between a grid point and the
+    /// samples used for it.
+    pub fn tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance = tolerance.abs();
+        self
+    }
+
+    /// add_source adds a source, returning the index to push its samples
+    /// with.
+    pub fn add_source(&mut self, prefix: &str, columns: &[&str]) -> usize {
+        self.sources.push(Source {
+            prefix: column_key(prefix),
+            columns: columns.iter().map(|c| column_key(c)).collect(),
+            samples: vec![],
+        });
+        self.sources.len() - 1
+    }
+
+    pub fn push(
+        &mut self,
+        source: usize,
+        time: OffsetDateTime,
+        values: &[f32],
+    ) -> Result<(), String> {
+        let source = self
+            .sources
+            .get_mut(source)
+            .ok_or_else(|| format!("unknown source {}", source))?;
+        if values.len() != source.columns.len() {
+            return Err(format!(
+                "{} has {} columns, got {} values",
+                source.prefix,
+                source.columns.len(),
+                values.len()
+            ));
+        }
+        if source.samples.last().is_some_and(|(last, _)| time < *last) {
+            return Err(format!(
+                "{} sample at {} is out of order",
+                source.prefix, time
+            ));
+        }
+        source.samples.push((time, values.to_vec()));
+        Ok(())
+    }
+
+    /// columns returns the names of the merged columns (excluding the time),
+    /// i.e. <prefix>_<column> for every source.
+    pub fn columns(&self) -> Vec<String> {
+        self.sources
+            .iter()
+            .flat_map(|source| {
+                source
+                    .columns
+                    .iter()
+                    .map(move |column| format!("{}_{}", source.prefix, column))
+            })
+            .collect()
+    }
+
+    /// ready returns the rows whose grid points are at least tolerance before
+    /// now, i.e. that can't be affected by samples that haven't been pushed
+    /// yet (assuming samples are pushed as they arrive).
+    pub fn ready(&mut self, now: OffsetDateTime) -> Vec<Row> {
+        self.rows_until(now - self.tolerance)
+    }
+
+    /// finish returns all remaining rows, up to the last sample of any source.
+    pub fn finish(&mut self) -> Vec<Row> {
+        let last = self
+            .sources
+            .iter()
+            .filter_map(|source| source.samples.last().map(|(time, _)| *time))
+            .max();
+        match last {
+            Some(last) => self.rows_until(last),
+            None => vec![],
+        }
+    }
+
+    fn rows_until(&mut self, limit: OffsetDateTime) -> Vec<Row> {
+        let next = match self.next {
+            Some(next) => next,
+            None => {
+                let first = self
+                    .sources
+                    .iter()
+                    .filter_map(|source| source.samples.first().map(|(time, _)| *time))
+                    .min();
+                let Some(first) = first else { return vec![] };
+                let seconds = self.interval.whole_seconds();
+                OffsetDateTime::from_unix_timestamp(
+                    first.unix_timestamp().div_euclid(seconds) * seconds,
+                )
+                .unwrap()
+            }
+        };
+        let (interpolation, tolerance) = (self.interpolation, self.tolerance);
+        let mut rows = vec![];
+        let mut time = next;
+        while time <= limit {
+            let values = self
+                .sources
+                .iter()
+                .flat_map(|source| {
+                    (0..source.columns.len())
+                        .map(move |column| source.value(time, column, interpolation, tolerance))
+                })
+                .collect();
+            rows.push(Row { time, values });
+            for source in &mut self.sources {
+                source.prune(time);
+            }
+            time += self.interval;
+        }
+        self.next = Some(time);
+        rows
+    }
+
+    /// header returns the header line for format, if it has one.
+    pub fn header(&self, format: OutputFormat) -> Option<String> {
+        match format {
+            OutputFormat::Csv => Some(
+                std::iter::once(String::from("time"))
+                    .chain(self.columns())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            OutputFormat::JsonLines => None,
+        }
+    }
+
+    /// format formats a row as one line of format, missing values are empty
+    /// (CSV) or null (JSON).
+    pub fn format(&self, row: &Row, format: OutputFormat) -> String {
+        let time = row
+            .time
+            .to_offset(time::UtcOffset::UTC)
+            .format(&CSV_TIME_FORMAT)
+            .unwrap();
+        match format {
+            OutputFormat::Csv => std::iter::once(time)
+                .chain(
+                    row.values
+                        .iter()
+                        .map(|value| value.map_or(String::new(), |v| v.to_string())),
+                )
+                .collect::<Vec<_>>()
+                .join(","),
+            OutputFormat::JsonLines => self
+                .columns()
+                .iter()
+                .zip(&row.values)
+                .fold(
+                    json::Object::new().string("time", &time),
+                    |object, (column, value)| object.number(column, value.unwrap_or(f32::NAN)),
+                )
+                .build(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    const START: OffsetDateTime = datetime!(2024-06-01 12:00 UTC);
+
+    // Two sources: a at 0s, 4s, 8s (values 0, 4, 8), b at 3s and 9s (values
+    // 30 and 90).
+    fn merger(interpolation: Interpolation, tolerance: i64) -> Merger {
+        let mut merger = Merger::new(Duration::seconds(2))
+            .interpolation(interpolation)
+            .tolerance(Duration::seconds(tolerance));
+        let a = merger.add_source("A", &["Value"]);
+        let b = merger.add_source("b", &["x", "y"]);
+        for seconds in [0, 4, 8] {
+            merger
+                .push(a, START + Duration::seconds(seconds), &[seconds as f32])
+                .unwrap();
+        }
+        for seconds in [3, 9] {
+            let value = seconds as f32 * 10.0;
+            merger
+                .push(b, START + Duration::seconds(seconds), &[value, f32::NAN])
+                .unwrap();
+        }
+        merger
+    }
+
+    #[test]
+    fn test_interpolation() {
+        struct TestCase {
+            interpolation: Interpolation,
+            tolerance: i64,
+            // (a, b_x) at 0s, 2s, 4s, 6s and 8s.
+            expected_output: Vec<(Option<f32>, Option<f32>)>,
+        }
+        let tests = [
+            TestCase {
+                interpolation: Interpolation::Nearest,
+                tolerance: 1,
+                expected_output: vec![
+                    (Some(0.0), None),
+                    (None, Some(30.0)),
+                    (Some(4.0), Some(30.0)),
+                    (None, None),
+                    (Some(8.0), Some(90.0)),
+                ],
+            },
+            TestCase {
+                interpolation: Interpolation::Linear,
+                tolerance: 5,
+                expected_output: vec![
+                    (Some(0.0), None),
+                    (Some(2.0), None),
+                    (Some(4.0), Some(40.0)),
+                    (Some(6.0), Some(60.0)),
+                    (Some(8.0), Some(80.0)),
+                ],
+            },
+            TestCase {
+                interpolation: Interpolation::HoldLast,
+                tolerance: 2,
+                expected_output: vec![
+                    (Some(0.0), None),
+                    (Some(0.0), None),
+                    (Some(4.0), Some(30.0)),
+                    (Some(4.0), None),
+                    (Some(8.0), None),
+                ],
+            },
+        ];
+        for case in tests {
+            let mut merger = merger(case.interpolation, case.tolerance);
+            let rows = merger.finish();
+            assert_eq!(
+                rows.iter().map(|r| r.time).collect::<Vec<_>>(),
+                (0..5)
+                    .map(|i| START + Duration::seconds(i * 2))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                rows.iter()
+                    .map(|r| (r.values[0], r.values[1]))
+                    .collect::<Vec<_>>(),
+                case.expected_output,
+                "{:?}",
+                case.interpolation
+            );
+            // b's y column is always NaN.
+            assert!(rows.iter().all(|r| r.values[2].is_none()));
+            assert!(merger.finish().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_live() {
+        let mut merger = Merger::new(Duration::seconds(5)).tolerance(Duration::seconds(2));
+        let source = merger.add_source("sps30", &["pm2_5"]);
+        assert!(merger.ready(START).is_empty());
+
+        merger
+            .push(source, START + Duration::seconds(1), &[1.0])
+            .unwrap();
+        assert!(merger.ready(START + Duration::seconds(1)).is_empty());
+        let rows = merger.ready(START + Duration::seconds(2));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].values, vec![Some(1.0)]);
+
+        merger
+            .push(source, START + Duration::seconds(6), &[6.0])
+            .unwrap();
+        assert!(merger.push(source, START, &[0.0]).is_err());
+        assert!(merger
+            .push(source, START + Duration::seconds(7), &[])
+            .is_err());
+        let rows = merger.ready(START + Duration::seconds(12));
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].values, vec![Some(6.0)]);
+        assert_eq!(rows[1].values, vec![None]);
+    }
+
+    #[test]
+    fn test_format() {
+        let mut merger = merger(Interpolation::Nearest, 1);
+        let rows = merger.finish();
+        assert_eq!(
+            merger.header(OutputFormat::Csv).unwrap(),
+            "time,a_value,b_x,b_y"
+        );
+        assert_eq!(
+            merger.format(&rows[1], OutputFormat::Csv),
+            "2024-06-01T12:00:02,,30,"
+        );
+        assert_eq!(merger.header(OutputFormat::JsonLines), None);
+        assert_eq!(
+            merger.format(&rows[0], OutputFormat::JsonLines),
+            r#"{"time":"2024-06-01T12:00:00","a_value":0,"b_x":null,"b_y":null}"#
+        );
+    }
+}
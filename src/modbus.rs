@@ -0,0 +1,594 @@
+use super::device::{DeviceStats, DeviceStatus, VersionInfo};
+use super::measurement::*;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The standard Modbus TCP port, binding to it usually requires root.
+pub const DEFAULT_PORT: u16 = 502;
+
+pub const MAX_INTERVAL_SECONDS: u16 = 3600;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+// Limits from the Modbus application protocol specification, so that
+// responses fit in 253 bytes.
+const MAX_READ_QUANTITY: usize = 125;
+const MAX_WRITE_QUANTITY: usize = 123;
+
+// Input register blocks.
+const FLOAT_REGISTERS: usize = 0;
+const SCALED_REGISTERS: usize = 100;
+const STATUS_REGISTERS: usize = 200;
+const SERIAL_REGISTERS: usize = 300;
+const FIRMWARE_REGISTER: usize = 308;
+const INPUT_REGISTERS: usize = 309;
+
+const HOLDING_INTERVAL: usize = 0;
+const HOLDING_FAN_CLEANING: usize = 1;
+const HOLDING_REGISTERS: usize = 2;
+
+const STATUS_FAN_SPEED_WARNING: u16 = 1 << 0;
+const STATUS_LASER_ERROR: u16 = 1 << 1;
+const STATUS_FAN_ERROR: u16 = 1 << 2;
+const STATUS_UNKNOWN: u16 = 1 << 15;
+
+// Connections that are idle for longer than this are closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Command is a request, written to a holding register, for the reader.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    StartFanCleaning,
+    SetInterval(Duration),
+}
+
+// The scale for each channel's uint16 register.
+fn scale(channel: Channel) -> f32 {
+    match channel {
+        Channel::TypicalParticleSize => 1000.0,
+        _ => 10.0,
+    }
+}
+
+fn scaled(value: f32) -> u16 {
+    if value.is_nan() {
+        return 0;
+    }
+    value.round().clamp(0.0, u16::MAX as f32) as u16
+}
+
+fn split_u32(value: u32) -> [u16; 2] {
+    [(value >> 16) as u16, value as u16]
+}
+
+fn status_flags(status: Option<DeviceStatus>) -> u16 {
+    match status {
+        None => STATUS_UNKNOWN,
+        Some(status) => {
+            let mut flags = 0;
+            if status.fan_speed_warning {
+                flags |= STATUS_FAN_SPEED_WARNING;
+            }
+            if status.laser_error {
+                flags |= STATUS_LASER_ERROR;
+            }
+            if status.fan_error {
+                flags |= STATUS_FAN_ERROR;
+            }
+            flags
+        }
+    }
+}
+
+fn exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
+
+struct State {
+    latest: Option<TimestampedMeasurement>,
+    stats: DeviceStats,
+    status: Option<DeviceStatus>,
+    interval_seconds: u16,
+    commands: Vec<Command>,
+}
+
+/// ModbusServer serves the latest values passed to update over Modbus TCP,
+/// e.g. for a building management system.
+///
+/// Register map (addresses are 0-based, i.e. register 30001 is input register
+/// 0). Multi-register values are big-endian, most significant word first.
+///
+/// Input registers (function 0x04):
+///
+/// | Address | Type    | Value                                                   |
+/// |---------|---------|---------------------------------------------------------|
+/// | 0-19    | float32 | every channel, in Channel::ALL order (2 registers each) |
+/// | 100-103 | uint16  | mass concentration PM1/2.5/4/10, in 0.1 ug/m3           |
+/// | 104-108 | uint16  | number concentration PM0.5/1/2.5/4/10, in 0.1 #/cm3     |
+/// | 109     | uint16  | typical particle size, in nm                            |
+/// | 200-201 | uint32  | Unix time of the latest measurement, 0 if none yet      |
+/// | 202     | uint16  | device status flags, see below                          |
+/// | 203-210 | uint32  | frame errors, checksum failures, empty responses and    |
+/// |         |         | recovery attempts                                       |
+/// | 300-307 | ASCII   | serial number, 2 characters per register, 0 padded      |
+/// | 308     | uint16  | firmware version, major in the high byte                |
+///
+/// Scaled values are clamped to 0-65535. Device status flags: bit 0 fan speed
+/// warning, bit 1 laser error, bit 2 fan error, bit 15 set if the status is
+/// unknown (e.g. the firmware doesn't support the status register). Float
+/// registers are NaN until the first measurement.
+///
+/// Holding registers (functions 0x03, 0x06 and 0x10):
+///
+/// | Address | Value                                  |
+/// |---------|----------------------------------------|
+/// | 0       | sampling interval in seconds (1-3600)  |
+/// | 1       | write 1 to start fan cleaning, reads 0 |
+///
+/// Writes are applied by the reader's main loop (see ModbusServer::commands),
+/// so they take effect after the current sampling interval.
+#[derive(Clone)]
+pub struct ModbusServer {
+    serial: Arc<String>,
+    version: VersionInfo,
+    state: Arc<Mutex<State>>,
+}
+
+impl ModbusServer {
+    /// interval is the reader's current sampling interval, as reported by
+    /// holding register 0.
+    pub fn new(serial: &str, version: VersionInfo, interval: Duration) -> Self {
+        ModbusServer {
+            serial: Arc::new(String::from(serial)),
+            version,
+            state: Arc::new(Mutex::new(State {
+                latest: None,
+                stats: DeviceStats::default(),
+                status: None,
+                interval_seconds: interval.as_secs().min(MAX_INTERVAL_SECONDS as u64) as u16,
+                commands: vec![],
+            })),
+        }
+    }
+
+    /// update records the device stats and status (None if unknown), and the
+    /// measurement if the last read was successful.
+    pub fn update(
+        &self,
+        measurement: Option<&TimestampedMeasurement>,
+        stats: DeviceStats,
+        status: Option<DeviceStatus>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(measurement) = measurement {
+            state.latest = Some(*measurement);
+        }
+        state.stats = stats;
+        state.status = status;
+    }
+
+    /// commands returns (and clears) the commands written since the last call.
+    pub fn commands(&self) -> Vec<Command> {
+        std::mem::take(&mut self.state.lock().unwrap().commands)
+    }
+
+    fn input_registers(&self) -> Vec<u16> {
+        let state = self.state.lock().unwrap();
+        let mut registers = vec![0; INPUT_REGISTERS];
+        for (i, channel) in Channel::ALL.iter().enumerate() {
+            let value = state
+                .latest
+                .map_or(f32::NAN, |m| m.measurement.get(*channel));
+            let bits = value.to_bits();
+            registers[FLOAT_REGISTERS + 2 * i..FLOAT_REGISTERS + 2 * i + 2]
+                .copy_from_slice(&split_u32(bits));
+            registers[SCALED_REGISTERS + i] = scaled(value * scale(*channel));
+        }
+
+        let time = state.latest.map_or(0, |m| {
+            m.time.unix_timestamp().clamp(0, u32::MAX as i64) as u32
+        });
+        let stats = state.stats;
+        let mut status = vec![];
+        status.extend(split_u32(time));
+        status.push(status_flags(state.status));
+        for counter in [
+            stats.frame_errors,
+            stats.checksum_failures,
+            stats.empty_responses,
+            stats.recovery_attempts,
+        ] {
+            status.extend(split_u32(counter.min(u32::MAX as u64) as u32));
+        }
+        registers[STATUS_REGISTERS..STATUS_REGISTERS + status.len()].copy_from_slice(&status);
+
+        let mut serial = self.serial.bytes().take(16).collect::<Vec<u8>>();
+        serial.resize(16, 0);
+        for (i, pair) in serial.chunks(2).enumerate() {
+            registers[SERIAL_REGISTERS + i] = u16::from_be_bytes([pair[0], pair[1]]);
+        }
+        registers[FIRMWARE_REGISTER] =
+            u16::from_be_bytes([self.version.firmware_major, self.version.firmware_minor]);
+        registers
+    }
+
+    fn holding_registers(&self) -> Vec<u16> {
+        let mut registers = vec![0; HOLDING_REGISTERS];
+        registers[HOLDING_INTERVAL] = self.state.lock().unwrap().interval_seconds;
+        registers
+    }
+
+    // Validates and applies writes to holding registers, returning an
+    // exception code if any value is invalid (in which case nothing is
+    // written).
+    fn write_holding_registers(&self, start: usize, values: &[u16]) -> Result<(), u8> {
+        if start + values.len() > HOLDING_REGISTERS {
+            return Err(ILLEGAL_DATA_ADDRESS);
+        }
+        let mut commands = vec![];
+        for (i, value) in values.iter().enumerate() {
+            match (start + i, value) {
+                (HOLDING_INTERVAL, 1..=MAX_INTERVAL_SECONDS) => {
+                    commands.push(Command::SetInterval(Duration::from_secs(*value as u64)))
+                }
+                (HOLDING_FAN_CLEANING, 0) => {}
+                (HOLDING_FAN_CLEANING, 1) => commands.push(Command::StartFanCleaning),
+                _ => return Err(ILLEGAL_DATA_VALUE),
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        for command in &commands {
+            if let Command::SetInterval(interval) = command {
+                state.interval_seconds = interval.as_secs() as u16;
+            }
+        }
+        state.commands.extend(commands);
+        Ok(())
+    }
+
+    /// handle_pdu handles a request PDU (function code and data), and returns
+    /// the response PDU.
+    pub fn handle_pdu(&self, pdu: &[u8]) -> Vec<u8> {
+        let function = match pdu.first() {
+            Some(function) => *function,
+            None => return exception(0, ILLEGAL_FUNCTION),
+        };
+        let word = |i: usize| {
+            pdu.get(1 + 2 * i..3 + 2 * i)
+                .map(|w| u16::from_be_bytes([w[0], w[1]]) as usize)
+        };
+        match function {
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                let (start, quantity) = match (word(0), word(1)) {
+                    (Some(start), Some(quantity)) if pdu.len() == 5 => (start, quantity),
+                    _ => return exception(function, ILLEGAL_DATA_VALUE),
+                };
+                if quantity == 0 || quantity > MAX_READ_QUANTITY {
+                    return exception(function, ILLEGAL_DATA_VALUE);
+                }
+                let registers = if function == READ_INPUT_REGISTERS {
+                    self.input_registers()
+                } else {
+                    self.holding_registers()
+                };
+                if start + quantity > registers.len() {
+                    return exception(function, ILLEGAL_DATA_ADDRESS);
+                }
+                let mut response = vec![function, (quantity * 2) as u8];
+                for register in &registers[start..start + quantity] {
+                    response.extend_from_slice(&register.to_be_bytes());
+                }
+                response
+            }
+            WRITE_SINGLE_REGISTER => {
+                let (start, value) = match (word(0), word(1)) {
+                    (Some(start), Some(value)) if pdu.len() == 5 => (start, value as u16),
+                    _ => return exception(function, ILLEGAL_DATA_VALUE),
+                };
+                match self.write_holding_registers(start, &[value]) {
+                    // The response echoes the request.
+                    Ok(()) => pdu.to_vec(),
+                    Err(code) => exception(function, code),
+                }
+            }
+            WRITE_MULTIPLE_REGISTERS => {
+                let start = match (word(0), word(1), pdu.get(5)) {
+                    (Some(start), Some(quantity), Some(byte_count))
+                        if (1..=MAX_WRITE_QUANTITY).contains(&quantity)
+                            && *byte_count as usize == quantity * 2
+                            && pdu.len() == 6 + quantity * 2 =>
+                    {
+                        start
+                    }
+                    _ => return exception(function, ILLEGAL_DATA_VALUE),
+                };
+                let values: Vec<u16> = pdu[6..]
+                    .chunks(2)
+                    .map(|w| u16::from_be_bytes([w[0], w[1]]))
+                    .collect();
+                match self.write_holding_registers(start, &values) {
+                    Ok(()) => pdu[..5].to_vec(),
+                    Err(code) => exception(function, code),
+                }
+            }
+            _ => exception(function, ILLEGAL_FUNCTION),
+        }
+    }
+
+    // Handles requests on one connection until the client disconnects. Each
+    // request is an MBAP header (transaction id, protocol id, length and unit
+    // id) followed by the PDU, responses echo the header.
+    fn handle_connection(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        loop {
+            let mut header = [0; 7];
+            match stream.read_exact(&mut header) {
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                result => result?,
+            }
+            let protocol = u16::from_be_bytes([header[2], header[3]]);
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if protocol != 0 || !(2..=254).contains(&length) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "invalid MBAP header",
+                ));
+            }
+            let mut pdu = vec![0; length - 1];
+            stream.read_exact(&mut pdu)?;
+
+            let response = self.handle_pdu(&pdu);
+            let mut frame = header[..4].to_vec();
+            frame.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+            frame.push(header[6]);
+            frame.extend_from_slice(&response);
+            stream.write_all(&frame)?;
+        }
+    }
+
+    /// serve starts serving on a background thread (and one thread per
+    /// connection), and returns the address that is being listened on (which
+    /// is useful when binding to port 0).
+    pub fn serve(&self, addr: &str) -> Result<SocketAddr, String> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| format!("failed to bind to {}: {}", addr, e))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| format!("failed to get local address: {}", e))?;
+        let server = self.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("failed to accept Modbus connection: {}", e);
+                        continue;
+                    }
+                };
+                let server = server.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = server.handle_connection(stream) {
+                        if e.kind() != std::io::ErrorKind::WouldBlock
+                            && e.kind() != std::io::ErrorKind::TimedOut
+                        {
+                            eprintln!("Modbus connection failed: {}", e);
+                        }
+                    }
+                });
+            }
+        });
+        Result::Ok(local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    // A minimal Modbus TCP client.
+    struct Client {
+        stream: TcpStream,
+        transaction: u16,
+    }
+
+    impl Client {
+        fn connect(addr: SocketAddr) -> Self {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            Client {
+                stream,
+                transaction: 0,
+            }
+        }
+
+        fn request(&mut self, pdu: &[u8]) -> Vec<u8> {
+            self.transaction += 1;
+            let mut frame = self.transaction.to_be_bytes().to_vec();
+            frame.extend_from_slice(&[0, 0]);
+            frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+            frame.push(17);
+            frame.extend_from_slice(pdu);
+            self.stream.write_all(&frame).unwrap();
+
+            let mut header = [0; 7];
+            self.stream.read_exact(&mut header).unwrap();
+            assert_eq!(&header[..4], &frame[..4]);
+            assert_eq!(header[6], 17);
+            let mut response = vec![0; u16::from_be_bytes([header[4], header[5]]) as usize - 1];
+            self.stream.read_exact(&mut response).unwrap();
+            response
+        }
+
+        fn read(&mut self, function: u8, start: u16, quantity: u16) -> Result<Vec<u16>, u8> {
+            let mut pdu = vec![function];
+            pdu.extend_from_slice(&start.to_be_bytes());
+            pdu.extend_from_slice(&quantity.to_be_bytes());
+            let response = self.request(&pdu);
+            if response[0] == function | 0x80 {
+                return Err(response[1]);
+            }
+            assert_eq!(response[0], function);
+            assert_eq!(response[1] as usize, response.len() - 2);
+            Ok(response[2..]
+                .chunks(2)
+                .map(|w| u16::from_be_bytes([w[0], w[1]]))
+                .collect())
+        }
+    }
+
+    fn server() -> ModbusServer {
+        let version = VersionInfo {
+            firmware_major: 2,
+            firmware_minor: 2,
+            ..Default::default()
+        };
+        ModbusServer::new("ABCDEF0123456789", version, Duration::from_secs(5))
+    }
+
+    #[test]
+    fn test_input_registers() {
+        let server = server();
+        let mut client = Client::connect(server.serve("127.0.0.1:0").unwrap());
+
+        // Nothing measured yet.
+        let floats = client.read(READ_INPUT_REGISTERS, 0, 20).unwrap();
+        assert!(f32::from_bits((floats[0] as u32) << 16 | floats[1] as u32).is_nan());
+        assert_eq!(
+            client.read(READ_INPUT_REGISTERS, 200, 3).unwrap(),
+            vec![0, 0, STATUS_UNKNOWN]
+        );
+
+        let measurement = TimestampedMeasurement {
+            time: datetime!(2024-06-01 12:00 UTC),
+            measurement: Measurement {
+                mass_concentration_pm_1_0: 1.25,
+                mass_concentration_pm_2_5: 2.5,
+                mass_concentration_pm_4_0: 3.0,
+                mass_concentration_pm_10_0: 10000.0,
+                number_concentration_pm_0_5: 10.0,
+                number_concentration_pm_1_0: 11.0,
+                number_concentration_pm_2_5: 12.0,
+                number_concentration_pm_4_0: 12.5,
+                number_concentration_pm_10_0: 13.0,
+                typical_particle_size: 0.625,
+            },
+        };
+        let stats = DeviceStats {
+            frame_errors: 1,
+            checksum_failures: 2,
+            empty_responses: 70000,
+            recovery_attempts: 4,
+        };
+        let status = DeviceStatus {
+            laser_error: true,
+            ..Default::default()
+        };
+        server.update(Some(&measurement), stats, Some(status));
+        // A failed read keeps the last measurement.
+        server.update(None, stats, Some(status));
+
+        let floats = client.read(READ_INPUT_REGISTERS, 0, 20).unwrap();
+        let values: Vec<f32> = floats
+            .chunks(2)
+            .map(|w| f32::from_bits((w[0] as u32) << 16 | w[1] as u32))
+            .collect();
+        assert_eq!(
+            values,
+            vec![1.25, 2.5, 3.0, 10000.0, 10.0, 11.0, 12.0, 12.5, 13.0, 0.625]
+        );
+        assert_eq!(
+            client.read(READ_INPUT_REGISTERS, 100, 10).unwrap(),
+            vec![13, 25, 30, 65535, 100, 110, 120, 125, 130, 625]
+        );
+        assert_eq!(
+            client.read(READ_INPUT_REGISTERS, 200, 11).unwrap(),
+            vec![
+                0x665B,
+                0x0D40,
+                STATUS_LASER_ERROR,
+                0,
+                1,
+                0,
+                2,
+                1,
+                4464,
+                0,
+                4
+            ]
+        );
+        let serial = client.read(READ_INPUT_REGISTERS, 300, 9).unwrap();
+        let bytes: Vec<u8> = serial[..8].iter().flat_map(|w| w.to_be_bytes()).collect();
+        assert_eq!(bytes, b"ABCDEF0123456789");
+        assert_eq!(serial[8], 0x0202);
+
+        assert_eq!(
+            client.read(READ_INPUT_REGISTERS, 300, 10),
+            Err(ILLEGAL_DATA_ADDRESS)
+        );
+        assert_eq!(
+            client.read(READ_INPUT_REGISTERS, 0, 126),
+            Err(ILLEGAL_DATA_VALUE)
+        );
+        assert_eq!(
+            client.request(&[0x2B, 0x0E, 0x01, 0x00]),
+            vec![0xAB, ILLEGAL_FUNCTION]
+        );
+    }
+
+    #[test]
+    fn test_holding_registers() {
+        let server = server();
+        let mut client = Client::connect(server.serve("127.0.0.1:0").unwrap());
+        assert_eq!(
+            client.read(READ_HOLDING_REGISTERS, 0, 2).unwrap(),
+            vec![5, 0]
+        );
+
+        let request = [WRITE_SINGLE_REGISTER, 0, 1, 0, 1];
+        assert_eq!(client.request(&request), request);
+        assert_eq!(server.commands(), vec![Command::StartFanCleaning]);
+        assert!(server.commands().is_empty());
+
+        let request = [WRITE_MULTIPLE_REGISTERS, 0, 0, 0, 2, 4, 0, 60, 0, 0];
+        assert_eq!(client.request(&request), request[..5]);
+        assert_eq!(
+            server.commands(),
+            vec![Command::SetInterval(Duration::from_secs(60))]
+        );
+        assert_eq!(
+            client.read(READ_HOLDING_REGISTERS, 0, 2).unwrap(),
+            vec![60, 0]
+        );
+
+        // Invalid writes change nothing.
+        for request in [
+            vec![WRITE_SINGLE_REGISTER, 0, 0, 0, 0],
+            vec![WRITE_SINGLE_REGISTER, 0, 1, 0, 2],
+            vec![WRITE_MULTIPLE_REGISTERS, 0, 0, 0, 2, 4, 0, 30, 0, 3],
+            vec![WRITE_MULTIPLE_REGISTERS, 0, 0, 0, 2, 3, 0, 30, 0],
+        ] {
+            assert_eq!(
+                client.request(&request),
+                vec![request[0] | 0x80, ILLEGAL_DATA_VALUE]
+            );
+        }
+        assert_eq!(
+            client.request(&[WRITE_SINGLE_REGISTER, 0, 2, 0, 1]),
+            vec![WRITE_SINGLE_REGISTER | 0x80, ILLEGAL_DATA_ADDRESS]
+        );
+        assert!(server.commands().is_empty());
+        assert_eq!(client.read(READ_HOLDING_REGISTERS, 0, 1).unwrap(), vec![60]);
+    }
+}
@@ -0,0 +1,757 @@
+use super::device::VersionInfo;
+use super::json;
+use super::measurement::*;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+// A minimal MQTT 3.1.1 client: we only ever publish at QoS 0, so all we need
+// is CONNECT (with a last will), PUBLISH, PINGREQ/PINGRESP and DISCONNECT. See
+// <https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html>
+
+const PACKET_CONNECT: u8 = 0x10;
+const PACKET_CONNACK: u8 = 0x20;
+const PACKET_PUBLISH: u8 = 0x30;
+const PACKET_PINGREQ: u8 = 0xC0;
+const PACKET_PINGRESP: u8 = 0xD0;
+const PACKET_DISCONNECT: u8 = 0xE0;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn push_remaining_length(out: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn push_string(out: &mut Vec<u8>, value: &[u8]) {
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![header];
+    push_remaining_length(&mut out, body.len());
+    out.extend_from_slice(body);
+    out
+}
+
+fn connect_packet(config: &MqttConfig) -> Vec<u8> {
+    let mut body = vec![];
+    push_string(&mut body, b"MQTT");
+    body.push(/* protocol level: 3.1.1 */ 4);
+    let mut flags = /* clean session */ 0x02 | /* will flag */ 0x04 | /* will retain */ 0x20;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&config.keep_alive_secs.to_be_bytes());
+    push_string(&mut body, config.client_id.as_bytes());
+    push_string(&mut body, config.availability_topic().as_bytes());
+    push_string(&mut body, b"offline");
+    if let Some(username) = &config.username {
+        push_string(&mut body, username.as_bytes());
+    }
+    if let Some(password) = &config.password {
+        push_string(&mut body, password.as_bytes());
+    }
+    packet(PACKET_CONNECT, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = vec![];
+    push_string(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload);
+    packet(PACKET_PUBLISH | retain as u8, &body)
+}
+
+/// read_packet reads one packet, returning the fixed header byte and the rest
+/// of the packet.
+pub fn read_packet<R: Read>(reader: &mut R) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8];
+    reader.read_exact(&mut header)?;
+    let mut len = 0usize;
+    for shift in 0..4 {
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7F) as usize) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body)?;
+            return Ok((header[0], body));
+        }
+    }
+    Err(std::io::Error::new(
+        ErrorKind::InvalidData,
+        "malformed remaining length",
+    ))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PayloadMode {
+    // One JSON object per measurement, published to <base topic>/state.
+    Json,
+    // One plain value per channel, published to <base topic>/<channel key>.
+    PerChannel,
+}
+
+/// MqttConfig configures an MqttSink. Topics default to sps30/<serial>, and
+/// Home Assistant discovery is published under the homeassistant prefix.
+#[derive(Clone)]
+pub struct MqttConfig {
+    addr: String,
+    serial: String,
+    version: Option<VersionInfo>,
+    client_id: String,
+    base_topic: String,
+    mode: PayloadMode,
+    discovery_prefix: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    keep_alive_secs: u16,
+}
+
+impl MqttConfig {
+    /// addr is the broker's host:port.
+    pub fn new(addr: &str, serial: &str) -> Self {
+        MqttConfig {
+            addr: String::from(addr),
+            serial: String::from(serial),
+            version: None,
+            client_id: format!("sps30rs-{}", serial),
+            base_topic: format!("sps30/{}", serial),
+            mode: PayloadMode::Json,
+            discovery_prefix: Some(String::from("homeassistant")),
+            username: None,
+            password: None,
+            keep_alive_secs: 60,
+        }
+    }
+
+    /// version is used to populate the sw_version of the Home Assistant device.
+    pub fn version(mut self, version: VersionInfo) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    pub fn base_topic(mut self, base_topic: &str) -> Self {
+        self.base_topic = String::from(base_topic.trim_end_matches('/'));
+        self
+    }
+
+    pub fn mode(mut self, mode: PayloadMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// discovery_prefix sets the Home Assistant discovery prefix, or disables
+    /// discovery entirely if None.
+    pub fn discovery_prefix(mut self, discovery_prefix: Option<&str>) -> Self {
+        self.discovery_prefix = discovery_prefix.map(String::from);
+        self
+    }
+
+    pub fn credentials(mut self, username: &str, password: &str) -> Self {
+        self.username = Some(String::from(username));
+        self.password = Some(String::from(password));
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive_secs = keep_alive.as_secs().min(u16::MAX.into()) as u16;
+        self
+    }
+
+    pub fn availability_topic(&self) -> String {
+        format!("{}/availability", self.base_topic)
+    }
+
+    pub fn state_topic(&self, channel: Channel) -> String {
+        match self.mode {
+            PayloadMode::Json => format!("{}/state", self.base_topic),
+            PayloadMode::PerChannel => format!("{}/{}", self.base_topic, channel.key()),
+        }
+    }
+
+    /// discovery_messages returns the retained Home Assistant discovery config
+    /// (topic and payload) for every channel.
+    pub fn discovery_messages(&self) -> Vec<(String, String)> {
+        let prefix = match &self.discovery_prefix {
+            Some(prefix) => prefix,
+            None => return vec![],
+        };
+        let node_id = format!("sps30_{}", self.serial);
+        let mut device = json::Object::new()
+            .raw("identifiers", &json::array([json::string(&node_id)]))
+            .string("name", &format!("SPS30 {}", self.serial))
+            .string("manufacturer", "Sensirion")
+            .string("model", "SPS30")
+            .string("serial_number", &self.serial);
+        if let Some(version) = &self.version {
+            device = device
+                .string("sw_version", &version.firmware_version())
+                .string("hw_version", &version.hardware_revision.to_string());
+        }
+        let device = device.build();
+
+        Channel::ALL
+            .iter()
+            .map(|channel| {
+                let mut config = json::Object::new()
+                    .string("name", channel.label())
+                    .string("unique_id", &format!("{}_{}", node_id, channel.key()))
+                    .string("object_id", &format!("{}_{}", node_id, channel.key()))
+                    .string("state_topic", &self.state_topic(*channel))
+                    .string("availability_topic", &self.availability_topic())
+                    .string("unit_of_measurement", ha_unit(*channel))
+                    .string("state_class", "measurement");
+                if let Some(device_class) = ha_device_class(*channel) {
+                    config = config.string("device_class", device_class);
+                }
+                if self.mode == PayloadMode::Json {
+                    config = config.string(
+                        "value_template",
+                        &format!("{{{{ value_json.{} }}}}", channel.key()),
+                    );
+                }
+                (
+                    format!("{}/sensor/{}/{}/config", prefix, node_id, channel.key()),
+                    config.raw("device", &device).build(),
+                )
+            })
+            .collect()
+    }
+
+    pub fn connect(self) -> Result<MqttSink, String> {
+        let mut sink = MqttSink {
+            config: self,
+            stream: None,
+            last_sent: Instant::now(),
+            received: Vec::new(),
+            ping_sent: None,
+            next_attempt: Instant::now(),
+        };
+        sink.reconnect()?;
+        Result::Ok(sink)
+    }
+}
+
+// Home Assistant only has device classes for PM1, PM2.5 and PM10 mass
+// concentrations.
+fn ha_device_class(channel: Channel) -> Option<&'static str> {
+    match channel {
+        Channel::MassPm1_0 => Some("pm1"),
+        Channel::MassPm2_5 => Some("pm25"),
+        Channel::MassPm10_0 => Some("pm10"),
+        _ => None,
+    }
+}
+
+fn ha_unit(channel: Channel) -> &'static str {
+    match channel.unit() {
+        "ug/m3" => "µg/m³",
+        "#/cm3" => "#/cm³",
+        "um" => "µm",
+        unit => unit,
+    }
+}
+
+/// MqttSink publishes measurements to an MQTT broker. If the connection is
+/// lost (the broker closed it, or didn't answer a ping in time), the next
+/// publish or keep_alive reconnects (and republishes discovery config). As
+/// connecting can take up to the timeout, reconnecting is only attempted once
+/// per keep alive interval.
+pub struct MqttSink {
+    config: MqttConfig,
+    stream: Option<TcpStream>,
+    last_sent: Instant,
+    // Bytes received from the broker that aren't a complete packet yet.
+    received: Vec<u8>,
+    // When the last unanswered PINGREQ was sent.
+    ping_sent: Option<Instant>,
+    // When reconnecting may next be attempted.
+    next_attempt: Instant,
+}
+
+impl MqttSink {
+    fn reconnect(&mut self) -> Result<(), String> {
+        self.stream = None;
+        self.received.clear();
+        self.ping_sent = None;
+        self.next_attempt =
+            Instant::now() + Duration::from_secs(self.config.keep_alive_secs.into());
+        let addrs = self
+            .config
+            .addr
+            .to_socket_addrs()
+            .map_err(|e| format!("failed to resolve {}: {}", self.config.addr, e))?;
+        let mut result = Err(std::io::Error::from(ErrorKind::AddrNotAvailable));
+        for addr in addrs {
+            result = TcpStream::connect_timeout(&addr, TIMEOUT);
+            if result.is_ok() {
+                break;
+            }
+        }
+        let mut stream =
+            result.map_err(|e| format!("failed to connect to {}: {}", self.config.addr, e))?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+            .map_err(|e| format!("failed to set socket timeout: {}", e))?;
+        stream
+            .write_all(&connect_packet(&self.config))
+            .map_err(|e| format!("failed to send CONNECT: {}", e))?;
+        match read_packet(&mut stream) {
+            Ok((PACKET_CONNACK, body)) if body.len() == 2 && body[1] == 0 => (),
+            Ok((PACKET_CONNACK, body)) => {
+                return Result::Err(format!(
+                    "broker refused connection, return code {:?}",
+                    body.get(1)
+                ))
+            }
+            Ok((header, _)) => {
+                return Result::Err(format!("expected CONNACK, got packet {:#04X}", header))
+            }
+            Err(e) => return Result::Err(format!("failed to read CONNACK: {}", e)),
+        }
+        self.stream = Some(stream);
+
+        for (topic, payload) in self.config.discovery_messages() {
+            self.publish(&topic, payload.as_bytes(), true)?;
+        }
+        self.publish(&self.config.availability_topic(), b"online", true)
+    }
+
+    /// check_connection reads whatever the broker has sent without blocking,
+    /// and drops the connection if the broker has closed it or hasn't answered
+    /// a ping within the timeout.
+    fn check_connection(&mut self) {
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+        let mut closed = false;
+        let mut buf = [0u8; 64];
+        stream.set_nonblocking(true).ok();
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(n) => self.received.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+        stream.set_nonblocking(false).ok();
+
+        // We only care about PINGRESPs, anything else is discarded.
+        loop {
+            let mut remaining = &self.received[..];
+            match read_packet(&mut remaining) {
+                Ok((header, _)) => {
+                    if header == PACKET_PINGRESP {
+                        self.ping_sent = None;
+                    }
+                    let consumed = self.received.len() - remaining.len();
+                    self.received.drain(..consumed);
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(_) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+
+        if closed || self.ping_sent.is_some_and(|sent| sent.elapsed() > TIMEOUT) {
+            self.stream = None;
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), String> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return Result::Err(String::from("not connected")),
+        };
+        if let Err(e) = stream.write_all(data) {
+            self.stream = None;
+            return Result::Err(format!("failed to write to broker: {}", e));
+        }
+        self.last_sent = Instant::now();
+        Result::Ok(())
+    }
+
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), String> {
+        self.send(&publish_packet(topic, payload, retain))
+    }
+
+    pub fn publish_measurement(
+        &mut self,
+        measurement: &TimestampedMeasurement,
+    ) -> Result<(), String> {
+        self.check_connection();
+        if self.stream.is_none() {
+            if Instant::now() < self.next_attempt {
+                return Result::Err(String::from("not connected"));
+            }
+            self.reconnect()?;
+        }
+        let values = &measurement.measurement;
+        match self.config.mode {
+            PayloadMode::Json => {
+                let time = measurement
+                    .time
+                    .format(&time::format_description::well_known::Rfc3339)
+                    .map_err(|e| format!("failed to format time: {}", e))?;
+                let payload = Channel::ALL
+                    .iter()
+                    .fold(json::Object::new().string("time", &time), |object, c| {
+                        object.number(c.key(), values.get(*c))
+                    })
+                    .build();
+                let topic = self.config.state_topic(Channel::MassPm1_0);
+                self.publish(&topic, payload.as_bytes(), false)
+            }
+            PayloadMode::PerChannel => {
+                for channel in Channel::ALL {
+                    let topic = self.config.state_topic(channel);
+                    self.publish(&topic, values.get(channel).to_string().as_bytes(), false)?;
+                }
+                Result::Ok(())
+            }
+        }
+    }
+
+    /// keep_alive sends a ping if nothing has been sent for a while, it should
+    /// be called regularly if measurements are published less often than the
+    /// keep alive interval (e.g. while the sensor isn't returning any data).
+    pub fn keep_alive(&mut self) -> Result<(), String> {
+        self.check_connection();
+        if self.stream.is_none() {
+            if Instant::now() < self.next_attempt {
+                return Result::Ok(());
+            }
+            return self.reconnect();
+        }
+        if self.ping_sent.is_none()
+            && self.last_sent.elapsed().as_secs() * 2 >= self.config.keep_alive_secs.into()
+        {
+            self.send(&[PACKET_PINGREQ, 0])?;
+            self.ping_sent = Some(Instant::now());
+        }
+        Result::Ok(())
+    }
+
+    /// disconnect marks the sensor as offline, and disconnects gracefully.
+    pub fn disconnect(mut self) -> Result<(), String> {
+        let availability_topic = self.config.availability_topic();
+        self.publish(&availability_topic, b"offline", true)?;
+        self.send(&[PACKET_DISCONNECT, 0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    #[derive(Debug, PartialEq)]
+    enum Received {
+        Connect(Vec<u8>),
+        Publish {
+            topic: String,
+            payload: String,
+            retain: bool,
+        },
+        PingReq,
+        Disconnect,
+    }
+
+    // A tiny broker which accepts clients one at a time, and sends everything
+    // it receives back to the test. Each client is disconnected after
+    // max_packets packets.
+    fn mock_broker(max_packets: usize) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !broker_session(stream.unwrap(), max_packets, &tx) {
+                    return;
+                }
+            }
+        });
+        (addr, rx)
+    }
+
+    // broker_session handles a single client, and returns false once the test
+    // is no longer listening.
+    fn broker_session(
+        mut stream: TcpStream,
+        max_packets: usize,
+        tx: &mpsc::Sender<Received>,
+    ) -> bool {
+        for _ in 0..max_packets {
+            let Ok((header, body)) = read_packet(&mut stream) else {
+                break;
+            };
+            let received = match header & 0xF0 {
+                PACKET_CONNECT => {
+                    stream.write_all(&[PACKET_CONNACK, 2, 0, 0]).unwrap();
+                    Received::Connect(body)
+                }
+                PACKET_PUBLISH => {
+                    let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    Received::Publish {
+                        topic: String::from_utf8(body[2..2 + len].to_vec()).unwrap(),
+                        payload: String::from_utf8(body[2 + len..].to_vec()).unwrap(),
+                        retain: header & 0x01 != 0,
+                    }
+                }
+                PACKET_PINGREQ => {
+                    stream.write_all(&[PACKET_PINGRESP, 0]).unwrap();
+                    Received::PingReq
+                }
+                PACKET_DISCONNECT => Received::Disconnect,
+                _ => continue,
+            };
+            if tx.send(received).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn measurement() -> TimestampedMeasurement {
+        TimestampedMeasurement {
+            time: time::macros::datetime!(2024-06-01 12:00:00 UTC),
+            measurement: Measurement {
+                mass_concentration_pm_2_5: 2.5,
+                typical_particle_size: 0.5,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_remaining_length() {
+        for (len, expected) in [
+            (0, vec![0x00]),
+            (127, vec![0x7F]),
+            (128, vec![0x80, 0x01]),
+            (16_383, vec![0xFF, 0x7F]),
+            (2_097_152, vec![0x80, 0x80, 0x80, 0x01]),
+        ] {
+            let mut out = vec![];
+            push_remaining_length(&mut out, len);
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn test_connect_packet() {
+        let config = MqttConfig::new("localhost:1883", "ABC").credentials("user", "pw");
+        let packet = connect_packet(&config);
+        let (header, body) = read_packet(&mut &packet[..]).unwrap();
+        assert_eq!(header, PACKET_CONNECT);
+        let mut expected = vec![0, 4, b'M', b'Q', b'T', b'T', 4, 0xE6, 0, 60];
+        for value in [
+            &b"sps30rs-ABC"[..],
+            b"sps30/ABC/availability",
+            b"offline",
+            b"user",
+            b"pw",
+        ] {
+            push_string(&mut expected, value);
+        }
+        assert_eq!(body, expected);
+    }
+
+    #[test]
+    fn test_json_mode() {
+        let (addr, received) = mock_broker(usize::MAX);
+        let version = VersionInfo {
+            firmware_major: 2,
+            firmware_minor: 3,
+            ..Default::default()
+        };
+        let mut sink = MqttConfig::new(&addr, "ABC")
+            .version(version)
+            .connect()
+            .unwrap();
+        sink.publish_measurement(&measurement()).unwrap();
+        sink.disconnect().unwrap();
+
+        assert!(matches!(received.recv().unwrap(), Received::Connect(_)));
+        let mut discovery = vec![];
+        for _ in Channel::ALL {
+            discovery.push(received.recv().unwrap());
+        }
+        assert_eq!(
+            discovery[1],
+            Received::Publish {
+                topic: String::from("homeassistant/sensor/sps30_ABC/mass_pm2_5/config"),
+                payload: String::from(
+                    r#"{"name":"Mass Concentration PM2.5","unique_id":"sps30_ABC_mass_pm2_5","object_id":"sps30_ABC_mass_pm2_5","state_topic":"sps30/ABC/state","availability_topic":"sps30/ABC/availability","unit_of_measurement":"µg/m³","state_class":"measurement","device_class":"pm25","value_template":"{{ value_json.mass_pm2_5 }}","device":{"identifiers":["sps30_ABC"],"name":"SPS30 ABC","manufacturer":"Sensirion","model":"SPS30","serial_number":"ABC","sw_version":"2.3","hw_version":"0"}}"#
+                ),
+                retain: true,
+            }
+        );
+        assert_eq!(
+            received.recv().unwrap(),
+            Received::Publish {
+                topic: String::from("sps30/ABC/availability"),
+                payload: String::from("online"),
+                retain: true,
+            }
+        );
+        assert_eq!(
+            received.recv().unwrap(),
+            Received::Publish {
+                topic: String::from("sps30/ABC/state"),
+                payload: String::from(
+                    r#"{"time":"2024-06-01T12:00:00Z","mass_pm1_0":0,"mass_pm2_5":2.5,"mass_pm4_0":0,"mass_pm10_0":0,"number_pm0_5":0,"number_pm1_0":0,"number_pm2_5":0,"number_pm4_0":0,"number_pm10_0":0,"typical_particle_size":0.5}"#
+                ),
+                retain: false,
+            }
+        );
+        assert_eq!(
+            received.recv().unwrap(),
+            Received::Publish {
+                topic: String::from("sps30/ABC/availability"),
+                payload: String::from("offline"),
+                retain: true,
+            }
+        );
+        assert_eq!(received.recv().unwrap(), Received::Disconnect);
+    }
+
+    #[test]
+    fn test_per_channel_mode() {
+        let (addr, received) = mock_broker(usize::MAX);
+        let mut sink = MqttConfig::new(&addr, "ABC")
+            .base_topic("home/office/")
+            .mode(PayloadMode::PerChannel)
+            .discovery_prefix(None)
+            .connect()
+            .unwrap();
+        sink.publish_measurement(&measurement()).unwrap();
+
+        assert!(matches!(received.recv().unwrap(), Received::Connect(_)));
+        assert_eq!(
+            received.recv().unwrap(),
+            Received::Publish {
+                topic: String::from("home/office/availability"),
+                payload: String::from("online"),
+                retain: true,
+            }
+        );
+        let published: Vec<Received> = (0..Channel::ALL.len())
+            .map(|_| received.recv().unwrap())
+            .collect();
+        assert_eq!(
+            published[1],
+            Received::Publish {
+                topic: String::from("home/office/mass_pm2_5"),
+                payload: String::from("2.5"),
+                retain: false,
+            }
+        );
+        assert_eq!(
+            published[9],
+            Received::Publish {
+                topic: String::from("home/office/typical_particle_size"),
+                payload: String::from("0.5"),
+                retain: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let (addr, received) = mock_broker(usize::MAX);
+        let mut sink = MqttConfig::new(&addr, "ABC")
+            .discovery_prefix(None)
+            .keep_alive(Duration::ZERO)
+            .connect()
+            .unwrap();
+        assert!(matches!(received.recv().unwrap(), Received::Connect(_)));
+        assert!(matches!(received.recv().unwrap(), Received::Publish { .. }));
+
+        sink.keep_alive().unwrap();
+        assert_eq!(received.recv().unwrap(), Received::PingReq);
+        assert!(sink.ping_sent.is_some());
+        // Wait for the PINGRESP to arrive.
+        while sink.ping_sent.is_some() {
+            std::thread::sleep(Duration::from_millis(1));
+            sink.check_connection();
+        }
+        assert!(sink.stream.is_some());
+    }
+
+    #[test]
+    fn test_reconnect_after_eof() {
+        // The broker closes the connection after CONNECT and the availability
+        // message.
+        let (addr, received) = mock_broker(2);
+        let mut sink = MqttConfig::new(&addr, "ABC")
+            .discovery_prefix(None)
+            .connect()
+            .unwrap();
+        assert!(matches!(received.recv().unwrap(), Received::Connect(_)));
+        assert!(matches!(received.recv().unwrap(), Received::Publish { .. }));
+        while sink.stream.is_some() {
+            std::thread::sleep(Duration::from_millis(1));
+            sink.check_connection();
+        }
+
+        // Reconnecting waits for the keep alive interval.
+        sink.keep_alive().unwrap();
+        assert!(sink.stream.is_none());
+        assert_eq!(
+            sink.publish_measurement(&measurement()),
+            Result::Err(String::from("not connected"))
+        );
+        assert!(received.try_recv().is_err());
+
+        sink.next_attempt = Instant::now();
+        sink.keep_alive().unwrap();
+        assert!(matches!(received.recv().unwrap(), Received::Connect(_)));
+        assert!(sink.stream.is_some());
+    }
+
+    #[test]
+    fn test_reconnect_without_pingresp() {
+        let (addr, received) = mock_broker(usize::MAX);
+        let mut sink = MqttConfig::new(&addr, "ABC")
+            .discovery_prefix(None)
+            .connect()
+            .unwrap();
+        assert!(matches!(received.recv().unwrap(), Received::Connect(_)));
+        assert!(matches!(received.recv().unwrap(), Received::Publish { .. }));
+
+        // Pretend a ping went unanswered for longer than the timeout.
+        sink.ping_sent = Some(Instant::now() - TIMEOUT - Duration::from_secs(1));
+        sink.next_attempt = Instant::now();
+        sink.publish_measurement(&measurement()).unwrap();
+        assert!(matches!(received.recv().unwrap(), Received::Connect(_)));
+        assert!(sink.ping_sent.is_none());
+    }
+}
@@ -0,0 +1,835 @@
+use super::aqi::{self, Pollutant, Standard};
+use super::measurement::*;
+use super::png::Raster;
+use std::fmt::Write;
+use time::{Duration, OffsetDateTime};
+
+// gnuplot's default line colours, so that charts look familiar next to
+// plot.gnu's. Yellow is left out, as it doesn't show on the AQI bands.
+const PALETTE: [&str; 7] = [
+    "#9400D3", "#009E73", "#56B4E9", "#E69F00", "#0072B2", "#E51E10", "#000000",
+];
+
+const MARGIN_LEFT: f64 = 80.0;
+const MARGIN_RIGHT: f64 = 30.0;
+// Used instead of MARGIN_RIGHT when there is a right axis.
+const MARGIN_RIGHT_AXIS: f64 = 80.0;
+const MARGIN_TOP: f64 = 60.0;
+const MARGIN_BOTTOM: f64 = 50.0;
+const FONT_SIZE: f64 = 14.0;
+// Opacity of the AQI bands, which are drawn behind everything else.
+const BAND_OPACITY: f64 = 0.25;
+
+const TIME_STEPS: [i64; 20] = [
+    1, 2, 5, 10, 15, 30, 60, 120, 300, 600, 900, 1800, 3600, 7200, 10800, 21600, 43200, 86400,
+    172800, 604800,
+];
+const MAX_TICKS: f64 = 8.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Axis {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Svg,
+    Png,
+}
+
+impl ImageFormat {
+    /// from_path picks the format matching the extension of path.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+        match extension.as_str() {
+            "svg" => Some(ImageFormat::Svg),
+            "png" => Some(ImageFormat::Png),
+            _ => None,
+        }
+    }
+}
+
+/// Quantity selects a group of channels that share a unit, and can therefore
+/// share an axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quantity {
+    Mass,
+    Number,
+    Size,
+}
+
+impl Quantity {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mass" => Some(Quantity::Mass),
+            "number" => Some(Quantity::Number),
+            "size" => Some(Quantity::Size),
+            _ => None,
+        }
+    }
+
+    pub fn channels(&self) -> &'static [Channel] {
+        match self {
+            Quantity::Mass => &Channel::ALL[0..4],
+            Quantity::Number => &Channel::ALL[4..9],
+            Quantity::Size => &Channel::ALL[9..],
+        }
+    }
+
+    pub fn axis_label(&self) -> &'static str {
+        match self {
+            Quantity::Mass => "Mass concentration (ug/m3)",
+            Quantity::Number => "Number concentration (#/cm3)",
+            Quantity::Size => "Typical particle size (um)",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Series {
+    pub label: String,
+    pub axis: Axis,
+    /// Non-finite values leave a gap in the line.
+    pub points: Vec<(OffsetDateTime, f32)>,
+}
+
+impl Series {
+    pub fn new(label: &str, points: Vec<(OffsetDateTime, f32)>) -> Self {
+        Series {
+            label: String::from(label),
+            axis: Axis::Left,
+            points,
+        }
+    }
+
+    pub fn from_measurements(measurements: &[TimestampedMeasurement], channel: Channel) -> Self {
+        Series::new(
+            channel.label(),
+            measurements
+                .iter()
+                .map(|m| (m.time, m.measurement.get(channel)))
+                .collect(),
+        )
+    }
+
+    pub fn axis(mut self, axis: Axis) -> Self {
+        self.axis = axis;
+        self
+    }
+}
+
+/// Chart is a time series line chart, with up to two y axes (both starting at
+/// zero), which can be rendered to SVG or PNG.
+pub struct Chart {
+    title: String,
+    width: usize,
+    height: usize,
+    left_label: String,
+    right_label: String,
+    start: Option<OffsetDateTime>,
+    end: Option<OffsetDateTime>,
+    last: Option<Duration>,
+    aqi: Option<(Standard, Pollutant)>,
+    series: Vec<Series>,
+}
+
+impl Chart {
+    pub fn new(title: &str) -> Self {
+        Chart {
+            title: String::from(title),
+            width: 960,
+            height: 540,
+            left_label: String::new(),
+            right_label: String::new(),
+            start: None,
+            end: None,
+            last: None,
+            aqi: None,
+            series: vec![],
+        }
+    }
+
+    pub fn size(mut self, width: usize, height: usize) -> Self {
+        self.width = width.max(320);
+        self.height = height.max(240);
+        self
+    }
+
+    pub fn y_label(mut self, axis: Axis, label: &str) -> Self {
+        match axis {
+            Axis::Left => self.left_label = String::from(label),
+            Axis::Right => self.right_label = String::from(label),
+        }
+        self
+    }
+
+    /// start hides data before start, by default the chart starts at the
+    /// first sample.
+    pub fn start(mut self, start: OffsetDateTime) -> Self {
+        self.start = Some(start);
+        self
+    }
+
+    /// end hides data after end, by default the chart ends at the last
+    /// sample.
+    pub fn end(mut self, end: OffsetDateTime) -> Self {
+        self.end = Some(end);
+        self
+    }
+
+    /// last only shows the given duration before the end, unless a start is
+    /// set.
+    pub fn last(mut self, duration: Duration) -> Self {
+        self.last = Some(duration);
+        self
+    }
+
+    /// aqi_bands colours the background of the left axis with the index's
+    /// categories for pollutant, which only makes sense if the left axis shows
+    /// mass concentrations.
+    pub fn aqi_bands(mut self, standard: Standard, pollutant: Pollutant) -> Self {
+        self.aqi = Some((standard, pollutant));
+        self
+    }
+
+    pub fn series(mut self, series: Series) -> Self {
+        self.series.push(series);
+        self
+    }
+
+    /// measurements adds a series for every channel of quantity, on the left
+    /// axis.
+    pub fn measurements(
+        mut self,
+        quantity: Quantity,
+        measurements: &[TimestampedMeasurement],
+    ) -> Self {
+        for channel in quantity.channels() {
+            self.series
+                .push(Series::from_measurements(measurements, *channel));
+        }
+        self.y_label(Axis::Left, quantity.axis_label())
+    }
+
+    pub fn render(&self, format: ImageFormat) -> Result<Vec<u8>, String> {
+        match format {
+            ImageFormat::Svg => self.to_svg().map(String::into_bytes),
+            ImageFormat::Png => self.to_png(),
+        }
+    }
+
+    pub fn to_svg(&self) -> Result<String, String> {
+        let layout = self.layout()?;
+        let mut canvas = Svg {
+            out: format!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n",
+                self.width, self.height
+            ),
+        };
+        self.draw(&mut canvas, &layout);
+        canvas.out.push_str("</svg>\n");
+        Ok(canvas.out)
+    }
+
+    pub fn to_png(&self) -> Result<Vec<u8>, String> {
+        let layout = self.layout()?;
+        let mut raster = Raster::new(self.width, self.height, [255, 255, 255]);
+        self.draw(&mut raster, &layout);
+        Ok(raster.encode())
+    }
+
+    // The points of every series that are inside the time range.
+    fn visible(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+    ) -> Vec<Vec<(OffsetDateTime, f32)>> {
+        self.series
+            .iter()
+            .map(|series| {
+                series
+                    .points
+                    .iter()
+                    .filter(|(time, _)| *time >= start && *time <= end)
+                    .copied()
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn layout(&self) -> Result<Layout, String> {
+        let times = self
+            .series
+            .iter()
+            .flat_map(|series| series.points.iter())
+            .filter(|(time, value)| {
+                value.is_finite()
+                    && self.start.is_none_or(|start| *time >= start)
+                    && self.end.is_none_or(|end| *time <= end)
+            })
+            .map(|(time, _)| *time);
+        let (first, last) = times
+            .fold(
+                None,
+                |range: Option<(OffsetDateTime, OffsetDateTime)>, time| {
+                    Some(range.map_or((time, time), |(first, last)| {
+                        (first.min(time), last.max(time))
+                    }))
+                },
+            )
+            .ok_or_else(|| String::from("no data in the time range"))?;
+        let end = self.end.unwrap_or(last);
+        let start = match (self.start, self.last) {
+            (Some(start), _) => start,
+            (None, Some(last)) => first.max(end - last),
+            (None, None) => first,
+        };
+        let end = if end > start {
+            end
+        } else {
+            start + Duration::MINUTE
+        };
+
+        let visible = self.visible(start, end);
+        let axis = |axis: Axis| {
+            let values: Vec<f32> = self
+                .series
+                .iter()
+                .zip(&visible)
+                .filter(|(series, _)| series.axis == axis)
+                .flat_map(|(_, points)| points.iter().map(|(_, value)| *value))
+                .filter(|value| value.is_finite())
+                .collect();
+            if values.is_empty() {
+                return None;
+            }
+            let max = values.into_iter().fold(0f32, f32::max) as f64;
+            Some(ValueAxis::new(max))
+        };
+        let axes = [axis(Axis::Left), axis(Axis::Right)];
+        if self.aqi.is_some() && axes[0].is_none() {
+            return Err(String::from("AQI bands need a series on the left axis"));
+        }
+        let right_margin = if axes[1].is_some() {
+            MARGIN_RIGHT_AXIS
+        } else {
+            MARGIN_RIGHT
+        };
+        Ok(Layout {
+            left: MARGIN_LEFT,
+            right: self.width as f64 - right_margin,
+            top: MARGIN_TOP,
+            bottom: self.height as f64 - MARGIN_BOTTOM,
+            start,
+            end,
+            axes,
+            visible,
+        })
+    }
+
+    fn draw(&self, canvas: &mut dyn Canvas, layout: &Layout) {
+        let width = self.width as f64;
+        let plot_width = layout.right - layout.left;
+
+        if let (Some((standard, pollutant)), Some(axis)) = (self.aqi, &layout.axes[0]) {
+            for band in aqi::concentration_bands(standard, pollutant) {
+                let lower = band.lower as f64;
+                if lower >= axis.top {
+                    break;
+                }
+                let y_upper = layout.y(axis, (band.upper as f64).min(axis.top));
+                let y_lower = layout.y(axis, lower);
+                canvas.rect(
+                    layout.left,
+                    y_upper,
+                    plot_width,
+                    y_lower - y_upper,
+                    band.colour,
+                    BAND_OPACITY,
+                );
+            }
+        }
+
+        // Value grid and ticks, the grid follows the left axis if there is
+        // one.
+        for (index, axis) in layout.axes.iter().enumerate() {
+            let Some(axis) = axis else { continue };
+            let grid = index == 0 || layout.axes[0].is_none();
+            for value in axis.ticks() {
+                let y = layout.y(axis, value);
+                if grid {
+                    canvas.line(&[(layout.left, y), (layout.right, y)], "#DDDDDD", 1.0);
+                }
+                let label = axis.format(value);
+                if index == 0 {
+                    canvas.text(layout.left - 8.0, y + 5.0, &label, Anchor::End, "#000000");
+                } else {
+                    canvas.text(
+                        layout.right + 8.0,
+                        y + 5.0,
+                        &label,
+                        Anchor::Start,
+                        "#000000",
+                    );
+                }
+            }
+        }
+
+        // Time grid and ticks.
+        let (step, format) = time_ticks(layout.end - layout.start);
+        let mut tick = layout.start.unix_timestamp().div_euclid(step) * step;
+        if tick < layout.start.unix_timestamp() {
+            tick += step;
+        }
+        while let Ok(time) = OffsetDateTime::from_unix_timestamp(tick) {
+            if time > layout.end {
+                break;
+            }
+            let x = layout.x(time);
+            canvas.line(&[(x, layout.top), (x, layout.bottom)], "#DDDDDD", 1.0);
+            canvas.line(
+                &[(x, layout.bottom), (x, layout.bottom + 5.0)],
+                "#000000",
+                1.0,
+            );
+            canvas.text(
+                x,
+                layout.bottom + 20.0,
+                &time.format(format).unwrap(),
+                Anchor::Middle,
+                "#000000",
+            );
+            tick += step;
+        }
+
+        canvas.line(
+            &[
+                (layout.left, layout.top),
+                (layout.right, layout.top),
+                (layout.right, layout.bottom),
+                (layout.left, layout.bottom),
+                (layout.left, layout.top),
+            ],
+            "#000000",
+            1.0,
+        );
+
+        for (index, (series, points)) in self.series.iter().zip(&layout.visible).enumerate() {
+            let axis = match series.axis {
+                Axis::Left => &layout.axes[0],
+                Axis::Right => &layout.axes[1],
+            };
+            let Some(axis) = axis else { continue };
+            let colour = PALETTE[index % PALETTE.len()];
+            for segment in points.split(|(_, value)| !value.is_finite()) {
+                let line: Vec<(f64, f64)> = segment
+                    .iter()
+                    .map(|(time, value)| (layout.x(*time), layout.y(axis, *value as f64)))
+                    .collect();
+                if !line.is_empty() {
+                    canvas.line(&line, colour, 2.0);
+                }
+            }
+        }
+
+        // Legend, in the top left corner of the plot.
+        let both_axes = layout.axes.iter().all(|axis| axis.is_some());
+        let labels: Vec<String> = self
+            .series
+            .iter()
+            .map(|series| match series.axis {
+                Axis::Right if both_axes => format!("{} (right)", series.label),
+                _ => series.label.clone(),
+            })
+            .collect();
+        if !labels.is_empty() {
+            let legend_width = labels
+                .iter()
+                .map(|label| canvas.text_width(label))
+                .fold(0.0, f64::max)
+                + 46.0;
+            canvas.rect(
+                layout.left + 4.0,
+                layout.top + 4.0,
+                legend_width,
+                labels.len() as f64 * 18.0 + 8.0,
+                "#FFFFFF",
+                0.8,
+            );
+            for (index, label) in labels.iter().enumerate() {
+                let y = layout.top + 24.0 + index as f64 * 18.0;
+                let colour = PALETTE[index % PALETTE.len()];
+                canvas.line(
+                    &[(layout.left + 10.0, y - 5.0), (layout.left + 34.0, y - 5.0)],
+                    colour,
+                    2.0,
+                );
+                canvas.text(layout.left + 40.0, y, label, Anchor::Start, "#000000");
+            }
+        }
+
+        canvas.text(width / 2.0, 24.0, &self.title, Anchor::Middle, "#000000");
+        canvas.text(10.0, 48.0, &self.left_label, Anchor::Start, "#000000");
+        canvas.text(
+            width - 10.0,
+            48.0,
+            &self.right_label,
+            Anchor::End,
+            "#000000",
+        );
+        canvas.text(
+            layout.left + plot_width / 2.0,
+            self.height as f64 - 8.0,
+            "Time (UTC)",
+            Anchor::Middle,
+            "#000000",
+        );
+    }
+}
+
+// ValueAxis is a y axis from 0 to top, with ticks every step.
+struct ValueAxis {
+    top: f64,
+    step: f64,
+}
+
+impl ValueAxis {
+    fn new(max: f64) -> Self {
+        let max = if max > 0.0 { max } else { 1.0 };
+        let raw = max / MAX_TICKS;
+        let magnitude = 10f64.powf(raw.log10().floor());
+        let step = [1.0, 2.0, 2.5, 5.0, 10.0]
+            .iter()
+            .map(|m| m * magnitude)
+            .find(|step| *step >= raw)
+            .unwrap();
+        ValueAxis {
+            top: (max / step).ceil() * step,
+            step,
+        }
+    }
+
+    fn ticks(&self) -> impl Iterator<Item = f64> + '_ {
+        (0..)
+            .map(|i| i as f64 * self.step)
+            .take_while(|value| *value <= self.top * (1.0 + 1e-9))
+    }
+
+    // Formats a tick with as many decimals as the step needs.
+    fn format(&self, value: f64) -> String {
+        let decimals = (0..6)
+            .find(|d| {
+                let scaled = self.step * 10f64.powi(*d);
+                (scaled - scaled.round()).abs() < 1e-6
+            })
+            .unwrap_or(6) as usize;
+        format!("{:.*}", decimals, value)
+    }
+}
+
+// Layout is the position of the plot area, and the ranges it shows.
+struct Layout {
+    left: f64,
+    right: f64,
+    top: f64,
+    bottom: f64,
+    start: OffsetDateTime,
+    end: OffsetDateTime,
+    axes: [Option<ValueAxis>; 2],
+    visible: Vec<Vec<(OffsetDateTime, f32)>>,
+}
+
+impl Layout {
+    fn x(&self, time: OffsetDateTime) -> f64 {
+        self.left + (time - self.start) / (self.end - self.start) * (self.right - self.left)
+    }
+
+    fn y(&self, axis: &ValueAxis, value: f64) -> f64 {
+        self.bottom - value / axis.top * (self.bottom - self.top)
+    }
+}
+
+type TimeFormat = &'static [time::format_description::BorrowedFormatItem<'static>];
+
+// Picks the time between ticks (in seconds) for a time range, and a matching
+// label format.
+fn time_ticks(range: Duration) -> (i64, TimeFormat) {
+    let seconds = range.as_seconds_f64();
+    let step = TIME_STEPS
+        .iter()
+        .copied()
+        .find(|step| seconds / *step as f64 <= MAX_TICKS)
+        .unwrap_or_else(|| (seconds / MAX_TICKS / 604800.0).ceil() as i64 * 604800);
+    let format: TimeFormat = if step < 60 {
+        time::macros::format_description!("[hour]:[minute]:[second]")
+    } else if step >= 86400 {
+        time::macros::format_description!("[year]-[month]-[day]")
+    } else if seconds > 86400.0 {
+        time::macros::format_description!("[month]-[day] [hour]:[minute]")
+    } else {
+        time::macros::format_description!("[hour]:[minute]")
+    };
+    (step, format)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Anchor {
+    Start,
+    Middle,
+    End,
+}
+
+// Canvas is what charts are drawn on. Coordinates are in pixels from the top
+// left, text is positioned by its baseline, and colours are #RRGGBB.
+trait Canvas {
+    fn rect(&mut self, x: f64, y: f64, width: f64, height: f64, colour: &str, opacity: f64);
+    fn line(&mut self, points: &[(f64, f64)], colour: &str, width: f64);
+    fn text(&mut self, x: f64, y: f64, text: &str, anchor: Anchor, colour: &str);
+    // An estimate is good enough, this is only used to size the legend.
+    fn text_width(&self, text: &str) -> f64;
+}
+
+struct Svg {
+    out: String,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl Canvas for Svg {
+    fn rect(&mut self, x: f64, y: f64, width: f64, height: f64, colour: &str, opacity: f64) {
+        writeln!(
+            self.out,
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\" fill-opacity=\"{}\"/>",
+            x, y, width, height, colour, opacity
+        )
+        .unwrap();
+    }
+
+    fn line(&mut self, points: &[(f64, f64)], colour: &str, width: f64) {
+        let points: Vec<String> = points
+            .iter()
+            .map(|(x, y)| format!("{:.1},{:.1}", x, y))
+            .collect();
+        writeln!(
+            self.out,
+            "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\"/>",
+            points.join(" "),
+            colour,
+            width
+        )
+        .unwrap();
+    }
+
+    fn text(&mut self, x: f64, y: f64, text: &str, anchor: Anchor, colour: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let anchor = match anchor {
+            Anchor::Start => "start",
+            Anchor::Middle => "middle",
+            Anchor::End => "end",
+        };
+        writeln!(
+            self.out,
+            "<text x=\"{:.1}\" y=\"{:.1}\" font-family=\"sans-serif\" font-size=\"{}\" text-anchor=\"{}\" fill=\"{}\">{}</text>",
+            x, y, FONT_SIZE, anchor, colour, escape(text)
+        )
+        .unwrap();
+    }
+
+    fn text_width(&self, text: &str) -> f64 {
+        text.chars().count() as f64 * FONT_SIZE * 0.6
+    }
+}
+
+// Parses #RRGGBB, anything else is drawn in black.
+fn rgb(colour: &str) -> [u8; 3] {
+    let channel = |i: usize| {
+        colour
+            .get(1 + 2 * i..3 + 2 * i)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .unwrap_or(0)
+    };
+    [channel(0), channel(1), channel(2)]
+}
+
+// The bitmap font is 7 pixels high, and drawn at twice its size.
+const PNG_TEXT_SCALE: usize = 2;
+
+impl Canvas for Raster {
+    fn rect(&mut self, x: f64, y: f64, width: f64, height: f64, colour: &str, opacity: f64) {
+        self.fill_rect(x, y, width, height, rgb(colour), opacity);
+    }
+
+    fn line(&mut self, points: &[(f64, f64)], colour: &str, width: f64) {
+        let colour = rgb(colour);
+        if let [point] = points {
+            Raster::line(self, *point, *point, colour, width);
+        }
+        for pair in points.windows(2) {
+            Raster::line(self, pair[0], pair[1], colour, width);
+        }
+    }
+
+    fn text(&mut self, x: f64, y: f64, text: &str, anchor: Anchor, colour: &str) {
+        let width = Canvas::text_width(self, text);
+        let x = match anchor {
+            Anchor::Start => x,
+            Anchor::Middle => x - width / 2.0,
+            Anchor::End => x - width,
+        };
+        Raster::text(
+            self,
+            x,
+            y - 7.0 * PNG_TEXT_SCALE as f64,
+            text,
+            rgb(colour),
+            PNG_TEXT_SCALE,
+        );
+    }
+
+    fn text_width(&self, text: &str) -> f64 {
+        Raster::text_width(text, PNG_TEXT_SCALE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    const START: OffsetDateTime = datetime!(2024-06-01 12:00 UTC);
+
+    fn series(label: &str, values: &[f32]) -> Series {
+        Series::new(
+            label,
+            values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| (START + Duration::minutes(i as i64), *value))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_value_axis() {
+        struct TestCase {
+            max: f64,
+            top: f64,
+            step: f64,
+            label: &'static str,
+        }
+        let tests = [
+            TestCase {
+                max: 37.0,
+                top: 40.0,
+                step: 5.0,
+                label: "5",
+            },
+            TestCase {
+                max: 2.0,
+                top: 2.0,
+                step: 0.25,
+                label: "0.25",
+            },
+            TestCase {
+                max: 1500.0,
+                top: 1600.0,
+                step: 200.0,
+                label: "200",
+            },
+            TestCase {
+                max: 0.9,
+                top: 1.0,
+                step: 0.2,
+                label: "0.2",
+            },
+        ];
+        for case in tests {
+            let axis = ValueAxis::new(case.max);
+            assert!((axis.top - case.top).abs() < 1e-9, "{}", case.max);
+            assert!((axis.step - case.step).abs() < 1e-9, "{}", case.max);
+            assert_eq!(axis.format(axis.step), case.label);
+            assert_eq!(axis.ticks().last(), Some(axis.top));
+        }
+        assert_eq!(ValueAxis::new(0.0).top, 1.0);
+    }
+
+    #[test]
+    fn test_time_ticks() {
+        assert_eq!(time_ticks(Duration::seconds(30)).0, 5);
+        assert_eq!(time_ticks(Duration::minutes(10)).0, 120);
+        assert_eq!(time_ticks(Duration::hours(8)).0, 3600);
+        assert_eq!(time_ticks(Duration::days(3)).0, 43200);
+        assert_eq!(time_ticks(Duration::days(365)).0, 7 * 604800);
+    }
+
+    #[test]
+    fn test_svg() {
+        let chart = Chart::new("PM <& co>")
+            .y_label(Axis::Left, "ug/m3")
+            .y_label(Axis::Right, "#/cm3")
+            .aqi_bands(Standard::UsEpa, Pollutant::Pm2_5)
+            .series(series("PM2.5", &[5.0, 12.0, f32::NAN, 20.0, 30.0]))
+            .series(series("8020A", &[1000.0, 1200.0, 1100.0]).axis(Axis::Right));
+        let svg = chart.to_svg().unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.ends_with("</svg>\n"));
+        assert!(svg.contains(">PM &lt;&amp; co&gt;</text>"));
+        assert!(svg.contains(">8020A (right)</text>"));
+        // The axis goes up to 30ug/m3, so only the first two bands show.
+        assert!(svg.contains("fill=\"#00E400\""));
+        assert!(svg.contains("fill=\"#FFFF00\""));
+        assert!(!svg.contains("fill=\"#FF7E00\""));
+        // The gap splits the PM2.5 line in two.
+        assert_eq!(svg.matches("stroke=\"#9400D3\"").count(), 3);
+        assert!(svg.contains(">12:00:00</text>"));
+
+        let svg = chart.last(Duration::minutes(1)).to_svg().unwrap();
+        assert!(svg.contains(">12:03:00</text>"));
+        assert!(!svg.contains(">12:02:00</text>"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Chart::new("").to_svg().is_err());
+        assert!(Chart::new("")
+            .series(series("PM2.5", &[1.0]))
+            .start(START + Duration::hours(1))
+            .to_png()
+            .is_err());
+        assert!(Chart::new("")
+            .series(series("8020A", &[1.0]).axis(Axis::Right))
+            .aqi_bands(Standard::UsEpa, Pollutant::Pm2_5)
+            .to_svg()
+            .is_err());
+    }
+
+    #[test]
+    fn test_png() {
+        let measurements: Vec<TimestampedMeasurement> = (0..60)
+            .map(|i| TimestampedMeasurement {
+                time: START + Duration::seconds(i * 10),
+                measurement: Measurement {
+                    mass_concentration_pm_2_5: i as f32,
+                    ..Default::default()
+                },
+            })
+            .collect();
+        let png = Chart::new("Mass")
+            .size(400, 300)
+            .measurements(Quantity::Mass, &measurements)
+            .render(ImageFormat::Png)
+            .unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1A\n");
+        assert_eq!(&png[16..24], &[0, 0, 1, 144, 0, 0, 1, 44]);
+        assert_eq!(ImageFormat::from_path("out.PNG"), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::from_path("out"), None);
+    }
+}
@@ -0,0 +1,202 @@
+use super::measurement::*;
+use std::io::Write;
+
+/// The PMS5003's UART runs at 9600 baud, 8N1.
+pub const BAUD_RATE: u32 = 9600;
+
+pub const FRAME_LENGTH: usize = 32;
+
+/// How often the PMS5003 sends a frame in active mode, when concentrations are
+/// stable (it sends them more often, down to every 200ms, while they change).
+pub const ACTIVE_MODE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(2300);
+
+const START_CHARACTERS: [u8; 2] = [0x42, 0x4D];
+
+// The length field counts the data words and the checksum.
+const DATA_WORDS: usize = 13;
+
+/// counts_per_deciliter returns the number of particles per deciliter (0.1L)
+/// larger than 0.3, 0.5, 1.0, 2.5, 5.0 and 10um, which is how Plantower sensors
+/// (and so PurpleAir) report counts.
+///
+/// The SPS30's number concentrations are of particles smaller than each size
+/// (in #/cm3), so these are differences from the PM10 number concentration,
+/// which covers everything the SPS30 counts. As the SPS30 has no 0.3um or 5um
+/// bins, >0.3um is the total (the SPS30 counts from 0.3um) and >5um is
+/// approximated by >4um. Nothing is counted above 10um.
+pub fn counts_per_deciliter(measurement: &Measurement) -> [f64; 6] {
+    let total = measurement.number_concentration_pm_10_0 as f64;
+    let above = |channel: Channel| (total - measurement.get(channel) as f64).max(0.0) * 100.0;
+    [
+        total * 100.0,
+        above(Channel::NumberPm0_5),
+        above(Channel::NumberPm1_0),
+        above(Channel::NumberPm2_5),
+        above(Channel::NumberPm4_0),
+        0.0,
+    ]
+}
+
+// Rounds to the nearest representable value, the PMS5003 only reports whole
+// ug/m3 and counts.
+fn word(value: f64) -> u16 {
+    if value.is_nan() {
+        return 0;
+    }
+    value.round().clamp(0.0, u16::MAX as f64) as u16
+}
+
+/// encode_frame encodes a measurement as a PMS5003 active mode frame.
+///
+/// The SPS30 doesn't distinguish between CF=1 ("standard particle", i.e. the
+/// factory calibration) and atmospheric environment mass concentrations, so
+/// both are the SPS30's mass concentrations. PM4 and the typical particle size
+/// have no equivalent and aren't sent. See counts_per_deciliter for the
+/// counts. The reserved word (firmware version and error code) is 0.
+pub fn encode_frame(measurement: &Measurement) -> [u8; FRAME_LENGTH] {
+    let pm1_0 = word(measurement.mass_concentration_pm_1_0 as f64);
+    let pm2_5 = word(measurement.mass_concentration_pm_2_5 as f64);
+    let pm10_0 = word(measurement.mass_concentration_pm_10_0 as f64);
+    let mut words = vec![pm1_0, pm2_5, pm10_0, pm1_0, pm2_5, pm10_0];
+    words.extend(counts_per_deciliter(measurement).map(word));
+    words.push(0);
+
+    let mut frame = [0; FRAME_LENGTH];
+    frame[..2].copy_from_slice(&START_CHARACTERS);
+    frame[2..4].copy_from_slice(&((DATA_WORDS as u16 + 1) * 2).to_be_bytes());
+    for (i, word) in words.iter().enumerate() {
+        frame[4 + 2 * i..6 + 2 * i].copy_from_slice(&word.to_be_bytes());
+    }
+    let checksum = frame[..FRAME_LENGTH - 2]
+        .iter()
+        .fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+    frame[FRAME_LENGTH - 2..].copy_from_slice(&checksum.to_be_bytes());
+    frame
+}
+
+/// Pms5003Emulator writes SPS30 measurements as PMS5003 frames, for software
+/// that only understands Plantower sensors.
+///
+/// Like Sps30 it only needs a writer, so that it can be tested without a
+/// serial port: a real serial port (see Pms5003Emulator::open), or the
+/// other end of a pty (e.g. created with
+/// `socat pty,raw,echo=0,link=/tmp/pms5003 pty,raw,echo=0,link=/tmp/pms5003-app`).
+pub struct Pms5003Emulator<W: Write> {
+    writer: W,
+    frames: u64,
+}
+
+pub type SerialPms5003Emulator = Pms5003Emulator<Box<dyn serialport::SerialPort>>;
+
+impl SerialPms5003Emulator {
+    pub fn open(path: &str) -> Result<SerialPms5003Emulator, String> {
+        let port = serialport::new(path, BAUD_RATE)
+            .data_bits(serialport::DataBits::Eight)
+            .parity(serialport::Parity::None)
+            .stop_bits(serialport::StopBits::One)
+            .timeout(core::time::Duration::new(5, 0))
+            .open()
+            .map_err(|e| format!("unable to open serial port {}: {}", path, e))?;
+        Ok(Pms5003Emulator::new(port))
+    }
+}
+
+impl<W: Write> Pms5003Emulator<W> {
+    pub fn new(writer: W) -> Self {
+        Pms5003Emulator { writer, frames: 0 }
+    }
+
+    /// frames returns the number of frames written so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn write(&mut self, measurement: &Measurement) -> Result<(), String> {
+        self.writer
+            .write_all(&encode_frame(measurement))
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| format!("failed to write PMS5003 frame: {}", e))?;
+        self.frames += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decodes a frame the way a PMS5003 driver would, returning the data
+    // words.
+    fn decode_frame(frame: &[u8]) -> Result<Vec<u16>, String> {
+        if frame.len() != FRAME_LENGTH || frame[..2] != START_CHARACTERS {
+            return Err(String::from("not a frame"));
+        }
+        let words: Vec<u16> = frame[2..]
+            .chunks(2)
+            .map(|w| u16::from_be_bytes([w[0], w[1]]))
+            .collect();
+        let checksum = frame[..30].iter().map(|b| *b as u16).sum::<u16>();
+        if words[0] != 28 || words[14] != checksum {
+            return Err(String::from("invalid length or checksum"));
+        }
+        Ok(words[1..14].to_vec())
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        let measurement = Measurement {
+            mass_concentration_pm_1_0: 5.4,
+            mass_concentration_pm_2_5: 10.5,
+            mass_concentration_pm_4_0: 12.0,
+            mass_concentration_pm_10_0: 1000.0,
+            number_concentration_pm_0_5: 30.0,
+            number_concentration_pm_1_0: 36.0,
+            number_concentration_pm_2_5: 37.5,
+            number_concentration_pm_4_0: 37.75,
+            number_concentration_pm_10_0: 38.0,
+            typical_particle_size: 0.5,
+        };
+        let frame = encode_frame(&measurement);
+        assert_eq!(&frame[..4], &[0x42, 0x4D, 0x00, 0x1C]);
+        assert_eq!(
+            decode_frame(&frame).unwrap(),
+            vec![5, 11, 1000, 5, 11, 1000, 3800, 800, 200, 50, 25, 0, 0]
+        );
+
+        // Out of range values are clamped.
+        let frame = encode_frame(&Measurement {
+            mass_concentration_pm_10_0: 1e6,
+            number_concentration_pm_10_0: f32::NAN,
+            mass_concentration_pm_1_0: -1.0,
+            ..Default::default()
+        });
+        assert_eq!(
+            decode_frame(&frame).unwrap()[..7],
+            [0, 0, 65535, 0, 0, 65535, 0]
+        );
+    }
+
+    #[test]
+    fn test_emulator() {
+        let mut emulator = Pms5003Emulator::new(Vec::new());
+        emulator.write(&Measurement::default()).unwrap();
+        emulator
+            .write(&Measurement {
+                mass_concentration_pm_2_5: 2.0,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(emulator.frames(), 2);
+        assert_eq!(emulator.writer.len(), 2 * FRAME_LENGTH);
+        let frames: Vec<Vec<u16>> = emulator
+            .writer
+            .chunks(FRAME_LENGTH)
+            .map(|frame| decode_frame(frame).unwrap())
+            .collect();
+        assert_eq!(frames[0], vec![0; 13]);
+        assert_eq!(frames[1][1], 2);
+        // The checksum of an all-zero frame is just the start characters and
+        // the length.
+        assert_eq!(&emulator.writer[30..32], &[0x00, 0xAB]);
+    }
+}
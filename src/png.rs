@@ -0,0 +1,423 @@
+// Minimal raster drawing and PNG encoding, just enough to render charts
+// without depending on an image library.
+
+/// Raster is an RGB image, with (0, 0) at the top left.
+pub struct Raster {
+    width: usize,
+    height: usize,
+    pixels: Vec<[u8; 3]>,
+}
+
+// 5x7 glyphs, one row per byte (top to bottom), with the most significant of
+// the low 5 bits on the left. Only uppercase letters are included, lowercase
+// letters are drawn as uppercase, and unknown characters as '?'.
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '<' => [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02],
+        '>' => [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+impl Raster {
+    pub fn new(width: usize, height: usize, background: [u8; 3]) -> Self {
+        Raster {
+            width,
+            height,
+            pixels: vec![background; width * height],
+        }
+    }
+
+    // Blends colour into the pixel at (x, y), if it's inside the image.
+    fn blend(&mut self, x: i64, y: i64, colour: [u8; 3], alpha: f64) {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return;
+        }
+        let pixel = &mut self.pixels[y as usize * self.width + x as usize];
+        for (p, c) in pixel.iter_mut().zip(colour) {
+            *p = (*p as f64 * (1.0 - alpha) + c as f64 * alpha).round() as u8;
+        }
+    }
+
+    /// fill_rect fills the pixels whose centres are inside the rectangle.
+    pub fn fill_rect(
+        &mut self,
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+        colour: [u8; 3],
+        alpha: f64,
+    ) {
+        let (x0, x1) = ((x - 0.5).ceil() as i64, (x + width - 0.5).ceil() as i64);
+        let (y0, y1) = ((y - 0.5).ceil() as i64, (y + height - 0.5).ceil() as i64);
+        for py in y0..y1 {
+            for px in x0..x1 {
+                self.blend(px, py, colour, alpha);
+            }
+        }
+    }
+
+    /// line draws a line of (roughly) the given width, by stamping squares
+    /// along it.
+    pub fn line(&mut self, from: (f64, f64), to: (f64, f64), colour: [u8; 3], width: f64) {
+        let steps = (to.0 - from.0)
+            .abs()
+            .max((to.1 - from.1).abs())
+            .ceil()
+            .max(1.0) as usize;
+        let half = (width / 2.0).max(0.5);
+        for step in 0..=steps {
+            let t = step as f64 / steps as f64;
+            let x = from.0 + (to.0 - from.0) * t;
+            let y = from.1 + (to.1 - from.1) * t;
+            self.fill_rect(x - half, y - half, 2.0 * half, 2.0 * half, colour, 1.0);
+        }
+    }
+
+    /// text_width returns the width of text drawn at scale.
+    pub fn text_width(text: &str, scale: usize) -> f64 {
+        let chars = text.chars().count();
+        (chars * (GLYPH_WIDTH + 1) * scale).saturating_sub(scale) as f64
+    }
+
+    /// text draws text with its top left corner at (x, y), with every glyph
+    /// pixel drawn as a scale x scale square.
+    pub fn text(&mut self, x: f64, y: f64, text: &str, colour: [u8; 3], scale: usize) {
+        let (x, y) = (x.round() as i64, y.round() as i64);
+        let scale = scale as i64;
+        for (i, c) in text.chars().enumerate() {
+            let left = x + i as i64 * (GLYPH_WIDTH as i64 + 1) * scale;
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for column in 0..GLYPH_WIDTH {
+                    if bits & (0x10 >> column) == 0 {
+                        continue;
+                    }
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            self.blend(
+                                left + column as i64 * scale + dx,
+                                y + row as i64 * scale + dy,
+                                colour,
+                                1.0,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// encode encodes the image as a PNG.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity((self.width * 3 + 1) * self.height);
+        for row in self.pixels.chunks(self.width) {
+            // Filter type 0 (None).
+            data.push(0);
+            data.extend(row.iter().flatten());
+        }
+
+        let mut header = vec![];
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per channel, RGB, default compression, filtering and no
+        // interlacing.
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        chunk(&mut png, b"IHDR", &header);
+        chunk(&mut png, b"IDAT", &zlib(&data));
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(0xFFFF_FFFFu32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+// Bits are packed starting from the least significant bit of each byte, as
+// required by deflate.
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        for i in 0..bits {
+            if self.bit == 0 {
+                self.bytes.push(0);
+            }
+            *self.bytes.last_mut().unwrap() |= (((value >> i) & 1) as u8) << self.bit;
+            self.bit = (self.bit + 1) % 8;
+        }
+    }
+
+    // Huffman codes are written starting from their most significant bit.
+    fn write_code(&mut self, code: u32, bits: u32) {
+        let reversed = (0..bits).fold(0, |r, i| (r << 1) | ((code >> i) & 1));
+        self.write(reversed, bits);
+    }
+
+    // Writes a literal/length symbol, using the fixed Huffman code.
+    fn write_symbol(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xC0 + symbol - 280, 8),
+        }
+    }
+}
+
+const LENGTH_BASES: [u32; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u32; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+const WINDOW: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+// Compresses data as a zlib stream, with a single fixed Huffman block. Matches
+// are found using the most recent occurrence of each 3 byte sequence, which
+// is crude but works well for charts (which are mostly runs of the same
+// colour, and rows similar to the previous one).
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter {
+        bytes: vec![0x78, 0x01],
+        bit: 0,
+    };
+    // BFINAL, and BTYPE 01 (fixed Huffman codes).
+    out.write(1, 1);
+    out.write(1, 2);
+
+    let mut last_seen = std::collections::HashMap::new();
+    let mut i = 0;
+    while i < data.len() {
+        let candidate = data
+            .get(i..i + MIN_MATCH)
+            .and_then(|key| last_seen.insert(key, i))
+            .filter(|start| i - start <= WINDOW);
+        let length = candidate.map_or(0, |start| {
+            (0..MAX_MATCH.min(data.len() - i))
+                .take_while(|k| data[start + k] == data[i + k])
+                .count()
+        });
+        if length < MIN_MATCH {
+            out.write_symbol(data[i] as u32);
+            i += 1;
+            continue;
+        }
+
+        let distance = (i - candidate.unwrap()) as u32;
+        let code = LENGTH_BASES
+            .iter()
+            .rposition(|b| *b <= length as u32)
+            .unwrap();
+        out.write_symbol(257 + code as u32);
+        out.write(length as u32 - LENGTH_BASES[code], LENGTH_EXTRA[code]);
+        let code = DISTANCE_BASES.iter().rposition(|b| *b <= distance).unwrap();
+        out.write_code(code as u32, 5);
+        out.write(distance - DISTANCE_BASES[code], DISTANCE_EXTRA[code]);
+        // Index the sequences inside the match too, so that later matches can
+        // refer to them.
+        for j in i + 1..i + length {
+            if let Some(key) = data.get(j..j + MIN_MATCH) {
+                last_seen.insert(key, j);
+            }
+        }
+        i += length;
+    }
+    out.write_symbol(256);
+
+    let mut bytes = out.bytes;
+    bytes.extend_from_slice(&adler32(data).to_be_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decompresses the output of zlib, i.e. a single fixed Huffman block.
+    fn inflate(stream: &[u8]) -> Vec<u8> {
+        let mut position = 16;
+        let mut bit = |count: u32| -> u32 {
+            (0..count).fold(0, |value, i| {
+                let b = (stream[position / 8] >> (position % 8)) & 1;
+                position += 1;
+                value | ((b as u32) << i)
+            })
+        };
+        assert_eq!((bit(1), bit(2)), (1, 1));
+        let code = |bit: &mut dyn FnMut(u32) -> u32, bits: u32| {
+            (0..bits).fold(0, |code, _| (code << 1) | bit(1))
+        };
+        let mut out: Vec<u8> = vec![];
+        loop {
+            let mut symbol = code(&mut bit, 7);
+            symbol = if symbol <= 0x17 {
+                symbol + 256
+            } else {
+                let symbol = (symbol << 1) | bit(1);
+                match symbol {
+                    0x30..=0xBF => symbol - 0x30,
+                    0xC0..=0xC7 => symbol - 0xC0 + 280,
+                    _ => ((symbol << 1) | bit(1)) - 0x190 + 144,
+                }
+            };
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => break,
+                _ => {
+                    let index = (symbol - 257) as usize;
+                    let length = LENGTH_BASES[index] + bit(LENGTH_EXTRA[index]);
+                    let index = code(&mut bit, 5) as usize;
+                    let distance = DISTANCE_BASES[index] + bit(DISTANCE_EXTRA[index]);
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance as usize]);
+                    }
+                }
+            }
+        }
+        let checksum = &stream[position.div_ceil(8)..];
+        assert_eq!(checksum, adler32(&out).to_be_bytes());
+        out
+    }
+
+    #[test]
+    fn test_zlib() {
+        let tests: [Vec<u8>; 5] = [
+            vec![],
+            b"a".to_vec(),
+            b"abcabcabcabcabcabc, abc!".to_vec(),
+            vec![0xFF; 100_000],
+            (0..70_000u32).map(|i| (i * 7 % 251) as u8).collect(),
+        ];
+        for data in tests {
+            let compressed = zlib(&data);
+            assert_eq!(&compressed[..2], &[0x78, 0x01]);
+            assert_eq!(inflate(&compressed), data);
+        }
+        assert!(zlib(&[0xFF; 100_000]).len() < 2000);
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_raster() {
+        let mut raster = Raster::new(4, 3, [255, 255, 255]);
+        raster.fill_rect(1.0, 1.0, 2.0, 5.0, [0, 0, 0], 1.0);
+        raster.fill_rect(0.0, 0.0, 1.0, 1.0, [0, 0, 255], 0.5);
+        assert_eq!(raster.pixels[0], [128, 128, 255]);
+        assert_eq!(raster.pixels[5], [0, 0, 0]);
+        assert_eq!(raster.pixels[4], [255, 255, 255]);
+        assert_eq!(raster.pixels[11], [255, 255, 255]);
+        assert_eq!(Raster::text_width("AB", 2), 22.0);
+
+        let png = raster.encode();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1A\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 4, 0, 0, 0, 3]);
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]));
+        let idat_length = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let data = inflate(&png[41..41 + idat_length]);
+        assert_eq!(data.len(), 3 * (1 + 4 * 3));
+        assert_eq!(&data[1 + 3 * 5 + 1..1 + 3 * 5 + 4], &[0, 0, 0]);
+    }
+}
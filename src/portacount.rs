@@ -0,0 +1,289 @@
+use std::io::{BufRead, BufReader, Read};
+use time::OffsetDateTime;
+
+/// The 8020A's RS-232 port defaults to 1200 baud, 8N1 (it can be changed via
+/// the instrument's setup menu).
+pub const DEFAULT_BAUD_RATE: u32 = 1200;
+
+/// PortaCountStats counts what was received from the instrument.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PortaCountStats {
+    pub samples: u64,
+    // Lines that aren't a concentration, e.g. echoed commands or status
+    // messages.
+    pub invalid_lines: u64,
+}
+
+/// Sample is one concentration reading from the 8020A, timestamped when it
+/// was received.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pub time: OffsetDateTime,
+    /// Particles per cm3.
+    pub concentration: f32,
+}
+
+impl Sample {
+    pub fn csv_header() -> &'static str {
+        "Time,Particles/cm3"
+    }
+
+    /// csv_row formats the sample like TimestampedMeasurement::csv_row, so
+    /// that logs from both instruments can be plotted together.
+    pub fn csv_row(&self) -> String {
+        format!(
+            "{},{}",
+            self.time
+                .to_offset(time::UtcOffset::UTC)
+                .format(&super::measurement::CSV_TIME_FORMAT)
+                .unwrap(),
+            self.concentration
+        )
+    }
+}
+
+/// parse_line parses one line of the 8020A's concentration output, which is
+/// the concentration in particles/cm3 (possibly zero-padded, and possibly
+/// followed by a unit).
+pub fn parse_line(line: &str) -> Result<f32, String> {
+    let value = line
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| String::from("empty line"))?;
+    match value.parse::<f32>() {
+        Ok(concentration) if concentration.is_finite() && concentration >= 0.0 => Ok(concentration),
+        _ => Err(format!("not a concentration: {:?}", line)),
+    }
+}
+
+/// PortaCount reads the concentration output of a TSI PortaCount 8020A
+/// (which sends one reading per second while counting), so that it can be
+/// logged alongside an SPS30.
+///
+/// Only reading is needed, so unlike Sps30 this only takes a reader: either a
+/// serial port (see PortaCount::open), or a file containing previously
+/// captured output (see PortaCount::replay).
+pub struct PortaCount<R: Read> {
+    reader: BufReader<R>,
+    // The part of the current line that has been read so far.
+    line: Vec<u8>,
+    stats: PortaCountStats,
+}
+
+pub type SerialPortaCount = PortaCount<Box<dyn serialport::SerialPort>>;
+
+impl SerialPortaCount {
+    pub fn open(path: &str, baud_rate: u32) -> Result<SerialPortaCount, String> {
+        let port = serialport::new(path, baud_rate)
+            .data_bits(serialport::DataBits::Eight)
+            .parity(serialport::Parity::None)
+            .stop_bits(serialport::StopBits::One)
+            .timeout(core::time::Duration::new(5, 0))
+            .open()
+            .map_err(|e| format!("unable to open serial port {}: {}", path, e))?;
+        Ok(PortaCount::new(port))
+    }
+}
+
+impl PortaCount<std::fs::File> {
+    /// replay reads output that was captured from the serial port, e.g. with
+    /// `cat /dev/ttyUSB1 > capture.txt`. Samples are timestamped as they are
+    /// read, i.e. all at (roughly) the same time.
+    pub fn replay(path: &str) -> Result<Self, String> {
+        let file =
+            std::fs::File::open(path).map_err(|e| format!("unable to open {}: {}", path, e))?;
+        Ok(PortaCount::new(file))
+    }
+}
+
+impl<R: Read> PortaCount<R> {
+    pub fn new(reader: R) -> Self {
+        PortaCount {
+            reader: BufReader::new(reader),
+            line: vec![],
+            stats: PortaCountStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> PortaCountStats {
+        self.stats
+    }
+
+    // read_line reads up to the next CR or LF, returning None at the end of
+    // the data. Depending on its settings the 8020A terminates lines with CR
+    // only, or with CR LF. The 8020A only sends data while counting, so read
+    // timeouts are retried, and on other errors the partial line is kept for
+    // the next call.
+    fn read_line(&mut self) -> Result<Option<String>, String> {
+        loop {
+            let buffer = match self.reader.fill_buf() {
+                Ok(buffer) => buffer,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::TimedOut
+                        || e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::Interrupted =>
+                {
+                    continue
+                }
+                Err(e) => return Err(format!("failure reading data: {}", e)),
+            };
+            if buffer.is_empty() {
+                if self.line.is_empty() {
+                    return Ok(None);
+                }
+                let line = std::mem::take(&mut self.line);
+                return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+            }
+            match buffer.iter().position(|b| *b == b'\r' || *b == b'\n') {
+                Some(end) => {
+                    self.line.extend_from_slice(&buffer[..end]);
+                    self.reader.consume(end + 1);
+                    let line = std::mem::take(&mut self.line);
+                    return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+                }
+                None => {
+                    let n = buffer.len();
+                    self.line.extend_from_slice(buffer);
+                    self.reader.consume(n);
+                }
+            }
+        }
+    }
+
+    /// read_sample returns the next concentration reading, skipping anything
+    /// else the instrument sends, or None at the end of a replay file.
+    pub fn read_sample(&mut self) -> Result<Option<Sample>, String> {
+        while let Some(line) = self.read_line()? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_line(&line) {
+                Ok(concentration) => {
+                    self.stats.samples += 1;
+                    return Ok(Some(Sample {
+                        time: OffsetDateTime::now_utc(),
+                        concentration,
+                    }));
+                }
+                Err(_) => self.stats.invalid_lines += 1,
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurement::test_util::temp_path;
+
+    #[test]
+    fn test_parse_line() {
+        struct TestCase {
+            line: &'static str,
+            expected_output: Option<f32>,
+        }
+        let tests = [
+            TestCase {
+                line: "1234",
+                expected_output: Some(1234.0),
+            },
+            TestCase {
+                line: "  001234.5",
+                expected_output: Some(1234.5),
+            },
+            TestCase {
+                line: "0.12 #/cc",
+                expected_output: Some(0.12),
+            },
+            TestCase {
+                line: "OK",
+                expected_output: None,
+            },
+            TestCase {
+                line: "-1",
+                expected_output: None,
+            },
+            TestCase {
+                line: "",
+                expected_output: None,
+            },
+        ];
+        for case in tests {
+            assert_eq!(
+                parse_line(case.line).ok(),
+                case.expected_output,
+                "{:?}",
+                case.line
+            );
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let path = temp_path("8020a.txt");
+        std::fs::write(&path, "VN\r\n001523\r\n001498.5\r\r\nLOW PARTICLE\r\n1502").unwrap();
+        let mut portacount = PortaCount::replay(&path).unwrap();
+
+        let mut samples = vec![];
+        while let Some(sample) = portacount.read_sample().unwrap() {
+            samples.push(sample);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            samples.iter().map(|s| s.concentration).collect::<Vec<_>>(),
+            vec![1523.0, 1498.5, 1502.0]
+        );
+        assert!(samples.windows(2).all(|s| s[0].time <= s[1].time));
+        assert_eq!(
+            portacount.stats(),
+            PortaCountStats {
+                samples: 3,
+                invalid_lines: 2,
+            }
+        );
+        assert!(samples[0].csv_row().ends_with(",1523"));
+    }
+
+    // ChunkedReader returns each of its chunks (or errors) from a separate
+    // read, like a serial port receiving data in bursts.
+    struct ChunkedReader(std::collections::VecDeque<Result<&'static [u8], std::io::ErrorKind>>);
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Ok(chunk)) => {
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    Ok(chunk.len())
+                }
+                Some(Err(kind)) => Err(kind.into()),
+                None => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let mut portacount = PortaCount::new(ChunkedReader(
+            [
+                Ok(&b"0015"[..]),
+                Err(std::io::ErrorKind::TimedOut),
+                Ok(&b"23\r\n14"[..]),
+                Err(std::io::ErrorKind::BrokenPipe),
+                Ok(&b"98\r"[..]),
+            ]
+            .into(),
+        ));
+        assert_eq!(
+            portacount.read_sample().unwrap().map(|s| s.concentration),
+            Some(1523.0)
+        );
+        assert!(portacount.read_sample().is_err());
+        assert_eq!(
+            portacount.read_sample().unwrap().map(|s| s.concentration),
+            Some(1498.0)
+        );
+        assert_eq!(portacount.read_sample(), Result::Ok(None));
+    }
+}
@@ -0,0 +1,353 @@
+use super::device::DeviceStats;
+use super::http;
+use super::measurement::*;
+use std::fmt::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// The size label for each channel, typical particle size doesn't have one.
+fn size_label(channel: Channel) -> Option<&'static str> {
+    match channel {
+        Channel::MassPm1_0 | Channel::NumberPm1_0 => Some("pm1.0"),
+        Channel::MassPm2_5 | Channel::NumberPm2_5 => Some("pm2.5"),
+        Channel::MassPm4_0 | Channel::NumberPm4_0 => Some("pm4.0"),
+        Channel::MassPm10_0 | Channel::NumberPm10_0 => Some("pm10.0"),
+        Channel::NumberPm0_5 => Some("pm0.5"),
+        Channel::TypicalParticleSize => None,
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// format_value formats a sample value, using the spelling of non-finite values
+// that Prometheus expects (Rust would write "inf").
+fn format_value(value: f32) -> String {
+    if value.is_nan() {
+        String::from("NaN")
+    } else if value == f32::INFINITY {
+        String::from("+Inf")
+    } else if value == f32::NEG_INFINITY {
+        String::from("-Inf")
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// render formats the latest measurement and device stats in the Prometheus
+/// text exposition format. Measurement gauges are omitted until the first
+/// successful read.
+pub fn render(
+    serial: &str,
+    latest: Option<&TimestampedMeasurement>,
+    stats: &DeviceStats,
+) -> String {
+    let serial = escape_label(serial);
+    let mut out = String::new();
+
+    if let Some(latest) = latest {
+        let gauges = [
+            (
+                "sps30_mass_concentration_ugm3",
+                "Mass concentration of particles up to the given size, in ug/m3.",
+                &Channel::ALL[0..4],
+            ),
+            (
+                "sps30_number_concentration_per_cm3",
+                "Number concentration of particles up to the given size, in #/cm3.",
+                &Channel::ALL[4..9],
+            ),
+        ];
+        for (name, help, channels) in gauges {
+            write_header(&mut out, name, "gauge", help);
+            for channel in channels {
+                writeln!(
+                    out,
+                    "{}{{serial=\"{}\",size=\"{}\"}} {}",
+                    name,
+                    serial,
+                    size_label(*channel).unwrap(),
+                    format_value(latest.measurement.get(*channel))
+                )
+                .unwrap();
+            }
+        }
+        write_header(
+            &mut out,
+            "sps30_typical_particle_size_um",
+            "gauge",
+            "Typical particle size, in um.",
+        );
+        writeln!(
+            out,
+            "sps30_typical_particle_size_um{{serial=\"{}\"}} {}",
+            serial,
+            format_value(latest.measurement.typical_particle_size)
+        )
+        .unwrap();
+        write_header(
+            &mut out,
+            "sps30_last_read_timestamp_seconds",
+            "gauge",
+            "Unix time of the last successful measurement read.",
+        );
+        writeln!(
+            out,
+            "sps30_last_read_timestamp_seconds{{serial=\"{}\"}} {}",
+            serial,
+            latest.time.unix_timestamp_nanos() as f64 / 1e9
+        )
+        .unwrap();
+    }
+
+    let counters = [
+        (
+            "sps30_frame_errors_total",
+            "Frames from the sensor that could not be decoded.",
+            stats.frame_errors,
+        ),
+        (
+            "sps30_checksum_failures_total",
+            "Frames from the sensor with an invalid checksum.",
+            stats.checksum_failures,
+        ),
+        (
+            "sps30_empty_responses_total",
+            "Measurement reads that returned no data.",
+            stats.empty_responses,
+        ),
+        (
+            "sps30_recovery_attempts_total",
+            "Attempts to recover the sensor by resetting it.",
+            stats.recovery_attempts,
+        ),
+    ];
+    for (name, help, value) in counters {
+        write_header(&mut out, name, "counter", help);
+        writeln!(out, "{}{{serial=\"{}\"}} {}", name, serial, value).unwrap();
+    }
+    out
+}
+
+struct State {
+    latest: Option<TimestampedMeasurement>,
+    stats: DeviceStats,
+}
+
+/// Exporter serves the latest values passed to update on /metrics.
+#[derive(Clone)]
+pub struct Exporter {
+    serial: Arc<String>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Exporter {
+    pub fn new(serial: &str) -> Self {
+        Exporter {
+            serial: Arc::new(String::from(serial)),
+            state: Arc::new(Mutex::new(State {
+                latest: None,
+                stats: DeviceStats::default(),
+            })),
+        }
+    }
+
+    /// update records the device stats, and the measurement if the last read
+    /// was successful.
+    pub fn update(&self, measurement: Option<&TimestampedMeasurement>, stats: DeviceStats) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(measurement) = measurement {
+            state.latest = Some(*measurement);
+        }
+        state.stats = stats;
+    }
+
+    pub fn metrics(&self) -> String {
+        let state = self.state.lock().unwrap();
+        render(&self.serial, state.latest.as_ref(), &state.stats)
+    }
+
+    /// serve starts serving /metrics on a background thread, and returns the
+    /// address that is being listened on (which is useful when binding to
+    /// port 0).
+    pub fn serve(&self, addr: &str) -> Result<SocketAddr, String> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| format!("failed to bind to {}: {}", addr, e))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| format!("failed to get local address: {}", e))?;
+        let exporter = self.clone();
+        http::serve(listener, move |request| match request.path.as_str() {
+            "/metrics" => http::Response::new(200, CONTENT_TYPE, exporter.metrics().into_bytes()),
+            _ => http::Response::not_found(),
+        });
+        Result::Ok(local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_value() {
+        struct TestCase {
+            input: f32,
+            expected_output: &'static str,
+        }
+        let tests = [
+            TestCase {
+                input: 2.5,
+                expected_output: "2.5",
+            },
+            TestCase {
+                input: 12.0,
+                expected_output: "12",
+            },
+            TestCase {
+                input: f32::NAN,
+                expected_output: "NaN",
+            },
+            TestCase {
+                input: f32::INFINITY,
+                expected_output: "+Inf",
+            },
+            TestCase {
+                input: f32::NEG_INFINITY,
+                expected_output: "-Inf",
+            },
+        ];
+        for case in tests {
+            assert_eq!(case.expected_output, format_value(case.input));
+        }
+    }
+
+    #[test]
+    fn test_escape_label() {
+        struct TestCase {
+            input: &'static str,
+            expected_output: &'static str,
+        }
+        let tests = [
+            TestCase {
+                input: "ABCD1234",
+                expected_output: "ABCD1234",
+            },
+            TestCase {
+                input: "a\"b",
+                expected_output: "a\\\"b",
+            },
+            TestCase {
+                input: "a\\b",
+                expected_output: "a\\\\b",
+            },
+            TestCase {
+                input: "a\nb",
+                expected_output: "a\\nb",
+            },
+            TestCase {
+                input: "\\\"\n",
+                expected_output: "\\\\\\\"\\n",
+            },
+        ];
+        for case in tests {
+            assert_eq!(case.expected_output, escape_label(case.input));
+        }
+    }
+
+    #[test]
+    fn test_render_non_finite() {
+        let measurement = TimestampedMeasurement {
+            time: time::macros::datetime!(2024-06-01 12:00:00 UTC),
+            measurement: Measurement {
+                mass_concentration_pm_1_0: f32::NAN,
+                mass_concentration_pm_2_5: f32::INFINITY,
+                number_concentration_pm_0_5: f32::NEG_INFINITY,
+                typical_particle_size: f32::NAN,
+                ..Default::default()
+            },
+        };
+        let body = render("s\n1", Some(&measurement), &DeviceStats::default());
+        for expected in [
+            "sps30_mass_concentration_ugm3{serial=\"s\\n1\",size=\"pm1.0\"} NaN\n",
+            "sps30_mass_concentration_ugm3{serial=\"s\\n1\",size=\"pm2.5\"} +Inf\n",
+            "sps30_number_concentration_per_cm3{serial=\"s\\n1\",size=\"pm0.5\"} -Inf\n",
+            "sps30_typical_particle_size_um{serial=\"s\\n1\"} NaN\n",
+        ] {
+            assert!(
+                body.contains(expected),
+                "missing {:?} in {}",
+                expected,
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn test_exporter() {
+        let exporter = Exporter::new("ABC\"123");
+        let addr = exporter.serve("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/metrics", addr);
+
+        // Nothing but counters prior to the first measurement.
+        let response = http::get(&url).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, CONTENT_TYPE);
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains("sps30_empty_responses_total{serial=\"ABC\\\"123\"} 0\n"));
+        assert!(!body.contains("sps30_mass_concentration_ugm3"));
+
+        let measurement = TimestampedMeasurement {
+            time: time::macros::datetime!(2024-06-01 12:00:00.5 UTC),
+            measurement: Measurement {
+                mass_concentration_pm_2_5: 2.5,
+                number_concentration_pm_0_5: 12.0,
+                typical_particle_size: 0.75,
+                ..Default::default()
+            },
+        };
+        let stats = DeviceStats {
+            frame_errors: 1,
+            checksum_failures: 2,
+            empty_responses: 3,
+            recovery_attempts: 4,
+        };
+        exporter.update(Some(&measurement), stats);
+        // A failed read shouldn't clear the last measurement.
+        exporter.update(None, stats);
+
+        let body = String::from_utf8(http::get(&url).unwrap().body).unwrap();
+        for expected in [
+            "# TYPE sps30_mass_concentration_ugm3 gauge\n",
+            "sps30_mass_concentration_ugm3{serial=\"ABC\\\"123\",size=\"pm2.5\"} 2.5\n",
+            "sps30_number_concentration_per_cm3{serial=\"ABC\\\"123\",size=\"pm0.5\"} 12\n",
+            "sps30_typical_particle_size_um{serial=\"ABC\\\"123\"} 0.75\n",
+            "sps30_last_read_timestamp_seconds{serial=\"ABC\\\"123\"} 1717243200.5\n",
+            "# TYPE sps30_frame_errors_total counter\n",
+            "sps30_frame_errors_total{serial=\"ABC\\\"123\"} 1\n",
+            "sps30_checksum_failures_total{serial=\"ABC\\\"123\"} 2\n",
+            "sps30_empty_responses_total{serial=\"ABC\\\"123\"} 3\n",
+            "sps30_recovery_attempts_total{serial=\"ABC\\\"123\"} 4\n",
+        ] {
+            assert!(
+                body.contains(expected),
+                "missing {:?} in {}",
+                expected,
+                body
+            );
+        }
+
+        assert_eq!(http::get(&format!("http://{}/", addr)).unwrap().status, 404);
+    }
+}
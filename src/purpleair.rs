@@ -0,0 +1,286 @@
+use super::aggregate::Aggregator;
+use super::aqi;
+use super::http;
+use super::json;
+use super::measurement::*;
+use super::pms5003;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use time::{Duration, OffsetDateTime};
+
+/// PurpleAir sensors report 2 minute averages on /json, and the latest
+/// reading on /json?live=true.
+const AVERAGING_PERIOD: Duration = Duration::minutes(2);
+
+// The reader's default interval, for the Aggregator (see set_expected_interval).
+const EXPECTED_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+const DATE_TIME_FORMAT: &[time::format_description::BorrowedFormatItem<'static>] =
+    time::macros::format_description!("[year]/[month]/[day]T[hour]:[minute]:[second]z");
+
+// PurpleAir's p_*_um fields are what its Plantower sensors report.
+const COUNT_KEYS: [&str; 6] = [
+    "p_0_3_um",
+    "p_0_5_um",
+    "p_1_0_um",
+    "p_2_5_um",
+    "p_5_0_um",
+    "p_10_0_um",
+];
+
+fn round2(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// Reading is what /json reports: either the latest measurement, or the
+/// average over the last 2 minutes.
+pub struct Reading<'a> {
+    pub serial: &'a str,
+    pub time: OffsetDateTime,
+    pub measurement: Measurement,
+    /// The averaging period, in seconds.
+    pub period: i64,
+    /// The number of measurements since startup.
+    pub id: u64,
+    pub uptime: std::time::Duration,
+}
+
+/// render formats a reading in the schema of a PurpleAir sensor's local /json
+/// endpoint, see pms5003::counts_per_deciliter for the counts. Only what the
+/// SPS30 measures is included: there are no temperature, humidity or pressure
+/// fields, and no second (_b) channel. The SPS30 doesn't distinguish between
+/// CF=1 and ATM mass concentrations, so both have the same values.
+pub fn render(reading: &Reading) -> String {
+    let measurement = &reading.measurement;
+    let mut object = json::Object::new()
+        .string("SensorId", reading.serial)
+        .string(
+            "DateTime",
+            &reading
+                .time
+                .to_offset(time::UtcOffset::UTC)
+                .format(DATE_TIME_FORMAT)
+                .unwrap_or_default(),
+        )
+        .string("Geo", &format!("SPS30-{}", reading.serial))
+        .number("Id", reading.id as f64)
+        .number("uptime", reading.uptime.as_secs() as f64)
+        .number("period", reading.period as f64)
+        .string("version", env!("CARGO_PKG_VERSION"))
+        .string("hardwareversion", "SPS30")
+        .string("hardwarediscovered", "SPS30");
+
+    // The AQI and its colour are from PM2.5 alone.
+    if let Ok(aqi) = aqi::compute(
+        aqi::Standard::UsEpa,
+        measurement.mass_concentration_pm_2_5,
+        0.0,
+    ) {
+        if let Some((r, g, b)) = aqi::rgb(aqi.colour) {
+            object = object.string("p25aqic", &format!("rgb({},{},{})", r, g, b));
+        }
+        object = object.number("pm2.5_aqi", aqi.pm2_5_index);
+    }
+
+    for (suffix, channel) in [
+        ("1_0", Channel::MassPm1_0),
+        ("2_5", Channel::MassPm2_5),
+        ("10_0", Channel::MassPm10_0),
+    ] {
+        let value = round2(measurement.get(channel) as f64);
+        object = object
+            .number(&format!("pm{}_cf_1", suffix), value)
+            .number(&format!("pm{}_atm", suffix), value);
+    }
+    for (key, value) in COUNT_KEYS
+        .iter()
+        .zip(pms5003::counts_per_deciliter(measurement))
+    {
+        object = object.number(key, round2(value));
+    }
+    object.build()
+}
+
+struct State {
+    aggregator: Aggregator,
+    count: u64,
+}
+
+/// PurpleAirEndpoint serves the latest and averaged measurements on /json, in
+/// the format of a PurpleAir sensor's local API (see render), so that tools
+/// written for PurpleAir sensors can be pointed at the reader.
+#[derive(Clone)]
+pub struct PurpleAirEndpoint {
+    serial: Arc<String>,
+    started: Instant,
+    state: Arc<Mutex<State>>,
+}
+
+impl PurpleAirEndpoint {
+    pub fn new(serial: &str) -> Self {
+        PurpleAirEndpoint {
+            serial: Arc::new(String::from(serial)),
+            started: Instant::now(),
+            // Like a PurpleAir sensor, averages are reported as soon as there
+            // is any data.
+            state: Arc::new(Mutex::new(State {
+                aggregator: Aggregator::new(EXPECTED_INTERVAL).min_completeness(0.0),
+                count: 0,
+            })),
+        }
+    }
+
+    /// set_expected_interval changes the normal time between measurements, e.g.
+    /// after the sampling interval was changed over Modbus.
+    pub fn set_expected_interval(&self, expected_interval: std::time::Duration) {
+        self.state
+            .lock()
+            .unwrap()
+            .aggregator
+            .set_expected_interval(expected_interval);
+    }
+
+    pub fn update(&self, measurement: &TimestampedMeasurement) {
+        let mut state = self.state.lock().unwrap();
+        state.aggregator.push(measurement);
+        state.count += 1;
+    }
+
+    /// json returns the reading at now, or None if there are no (recent
+    /// enough) measurements.
+    pub fn json(&self, live: bool, now: OffsetDateTime) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        let latest = state.aggregator.latest()?;
+        let (measurement, period) = if live {
+            (latest.measurement, 0)
+        } else {
+            (
+                state.aggregator.period_mean(now - AVERAGING_PERIOD, now)?,
+                AVERAGING_PERIOD.whole_seconds(),
+            )
+        };
+        Some(render(&Reading {
+            serial: &self.serial,
+            time: if live { latest.time } else { now },
+            measurement,
+            period,
+            id: state.count,
+            uptime: self.started.elapsed(),
+        }))
+    }
+
+    /// serve starts serving /json on a background thread, and returns the
+    /// address that is being listened on (which is useful when binding to
+    /// port 0).
+    pub fn serve(&self, addr: &str) -> Result<SocketAddr, String> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| format!("failed to bind to {}: {}", addr, e))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| format!("failed to get local address: {}", e))?;
+        let endpoint = self.clone();
+        http::serve(listener, move |request| match request.path.as_str() {
+            "/json" => {
+                let live = request.query.split('&').any(|p| p == "live=true");
+                match endpoint.json(live, OffsetDateTime::now_utc()) {
+                    Some(body) => http::Response::new(200, "application/json", body.into_bytes()),
+                    None => http::Response::new(
+                        503,
+                        "text/plain; charset=utf-8",
+                        b"no measurements yet\n".to_vec(),
+                    ),
+                }
+            }
+            _ => http::Response::not_found(),
+        });
+        Result::Ok(local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    const START: OffsetDateTime = datetime!(2024-06-01 12:00 UTC);
+
+    fn measurement(time: OffsetDateTime, scale: f32) -> TimestampedMeasurement {
+        TimestampedMeasurement {
+            time,
+            measurement: Measurement {
+                mass_concentration_pm_1_0: 5.0 * scale,
+                mass_concentration_pm_2_5: 10.0 * scale,
+                mass_concentration_pm_4_0: 12.0 * scale,
+                mass_concentration_pm_10_0: 13.0 * scale,
+                number_concentration_pm_0_5: 30.0 * scale,
+                number_concentration_pm_1_0: 36.0 * scale,
+                number_concentration_pm_2_5: 37.5 * scale,
+                number_concentration_pm_4_0: 37.75 * scale,
+                number_concentration_pm_10_0: 38.0 * scale,
+                typical_particle_size: 0.5,
+            },
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let json = render(&Reading {
+            serial: "ABCDEF0123456789",
+            time: START,
+            measurement: measurement(START, 1.0).measurement,
+            period: 120,
+            id: 42,
+            uptime: std::time::Duration::from_secs(3600),
+        });
+        assert_eq!(
+            json,
+            format!(
+                "{{\"SensorId\":\"ABCDEF0123456789\",\"DateTime\":\"2024/06/01T12:00:00z\",\
+                \"Geo\":\"SPS30-ABCDEF0123456789\",\"Id\":42,\"uptime\":3600,\"period\":120,\
+                \"version\":\"{}\",\"hardwareversion\":\"SPS30\",\"hardwarediscovered\":\"SPS30\",\
+                \"p25aqic\":\"rgb(255,255,0)\",\"pm2.5_aqi\":53,\
+                \"pm1_0_cf_1\":5,\"pm1_0_atm\":5,\"pm2_5_cf_1\":10,\"pm2_5_atm\":10,\
+                \"pm10_0_cf_1\":13,\"pm10_0_atm\":13,\
+                \"p_0_3_um\":3800,\"p_0_5_um\":800,\"p_1_0_um\":200,\"p_2_5_um\":50,\
+                \"p_5_0_um\":25,\"p_10_0_um\":0}}",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let endpoint = PurpleAirEndpoint::new("ABC");
+        let addr = endpoint.serve("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/json", addr);
+        assert_eq!(http::get(&url).unwrap().status, 503);
+
+        // One sample per 5 seconds, 1x for the first minute and 3x for the
+        // second: the average is 2x.
+        let now = OffsetDateTime::now_utc();
+        for i in 0..24 {
+            let time = now - Duration::minutes(2) + Duration::seconds(5 * i);
+            endpoint.update(&measurement(time, if i < 12 { 1.0 } else { 3.0 }));
+        }
+
+        // The server averages over the 2 minutes before it handles the
+        // request, so the values are checked at a fixed time instead.
+        let response = http::get(&url).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, "application/json");
+        let average = endpoint.json(false, now).unwrap();
+        assert!(average.contains("\"pm2_5_atm\":20,"), "{}", average);
+        assert!(average.contains("\"period\":120,"), "{}", average);
+        assert!(average.contains("\"Id\":24,"), "{}", average);
+
+        let live =
+            String::from_utf8(http::get(&format!("{}?live=true", url)).unwrap().body).unwrap();
+        assert!(live.contains("\"pm2_5_atm\":30,"), "{}", live);
+        assert!(live.contains("\"period\":0,"), "{}", live);
+
+        // Nothing recent enough to average.
+        assert_eq!(endpoint.json(false, now + Duration::minutes(5)), None);
+        assert!(endpoint.json(true, now + Duration::minutes(5)).is_some());
+    }
+}
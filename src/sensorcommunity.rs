@@ -0,0 +1,395 @@
+use super::http;
+use super::json;
+use super::measurement::*;
+use std::io::Write;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+pub const DEFAULT_URL: &str = "http://api.sensor.community/v1/push-sensor-data/";
+
+/// The X-Pin that Sensor.Community expects for particulate matter sensors
+/// (it is the pin the sensor is connected to on their reference hardware).
+pub const PM_PIN: u32 = 1;
+
+/// The airrohr firmware's default sending interval, uploading much more often
+/// than this is discouraged.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(145);
+
+/// value_type returns the Sensor.Community value type for a channel, as used
+/// by the airrohr firmware for the SPS30. Note that P1 is PM10 and P2 is
+/// PM2.5, for historical reasons.
+pub fn value_type(channel: Channel) -> &'static str {
+    match channel {
+        Channel::MassPm1_0 => "P0",
+        Channel::MassPm2_5 => "P2",
+        Channel::MassPm4_0 => "P4",
+        Channel::MassPm10_0 => "P1",
+        Channel::NumberPm0_5 => "N05",
+        Channel::NumberPm1_0 => "N1",
+        Channel::NumberPm2_5 => "N25",
+        Channel::NumberPm4_0 => "N4",
+        Channel::NumberPm10_0 => "N10",
+        Channel::TypicalParticleSize => "TS",
+    }
+}
+
+/// payload formats a measurement for /v1/push-sensor-data/. Values are sent as
+/// strings, like the airrohr firmware does.
+pub fn payload(measurement: &Measurement) -> String {
+    let values = Channel::ALL.iter().map(|c| {
+        let precision = match c {
+            Channel::TypicalParticleSize => 3,
+            _ => 2,
+        };
+        json::Object::new()
+            .string("value_type", value_type(*c))
+            .string("value", &format!("{:.*}", precision, measurement.get(*c)))
+            .build()
+    });
+    json::Object::new()
+        .string(
+            "software_version",
+            concat!("sps30rs-", env!("CARGO_PKG_VERSION")),
+        )
+        .raw("sensordatavalues", &json::array(values))
+        .build()
+}
+
+/// SensorCommunitySink uploads measurements to Sensor.Community (formerly
+/// Luftdaten), so that a station shows up on their map.
+///
+/// The API timestamps data when it is received, so rather than uploading
+/// every measurement, measurements are batched for an interval and their mean
+/// is uploaded (like the airrohr firmware does). For the same reason uploads
+/// that still fail after all attempts are dropped rather than kept for later,
+/// as they would be recorded at the wrong time.
+///
+/// In dry-run mode payloads are written to a file as JSON Lines instead, with
+/// the headers that would have been sent.
+///
+/// Uploads (including retries) block the caller, so push is best called from
+/// a thread that isn't also reading the sensor.
+pub struct SensorCommunitySink {
+    url: String,
+    sensor_id: String,
+    pin: u32,
+    interval: time::Duration,
+    max_attempts: u32,
+    retry_delay: Duration,
+    dry_run: Option<Box<dyn Write + Send>>,
+    batch: Vec<TimestampedMeasurement>,
+}
+
+impl SensorCommunitySink {
+    /// sensor_id is the X-Sensor header the station was registered with, e.g.
+    /// raspi-00000000abcdef12.
+    pub fn new(sensor_id: &str) -> Self {
+        SensorCommunitySink {
+            url: String::from(DEFAULT_URL),
+            sensor_id: String::from(sensor_id),
+            pin: PM_PIN,
+            interval: time::Duration::try_from(DEFAULT_INTERVAL).unwrap(),
+            max_attempts: 3,
+            retry_delay: Duration::from_secs(1),
+            dry_run: None,
+            batch: Vec::new(),
+        }
+    }
+
+    /// url overrides the API endpoint, e.g. for testing.
+    pub fn url(mut self, url: &str) -> Self {
+        self.url = String::from(url);
+        self
+    }
+
+    pub fn pin(mut self, pin: u32) -> Self {
+        self.pin = pin;
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = time::Duration::try_from(interval).unwrap_or(time::Duration::MAX);
+        self
+    }
+
+    pub fn retries(mut self, max_attempts: u32, retry_delay: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// dry_run writes payloads to output instead of uploading them.
+    pub fn dry_run<W: Write + Send + 'static>(mut self, output: W) -> Self {
+        self.dry_run = Some(Box::new(output));
+        self
+    }
+
+    /// push adds a measurement to the current batch, and uploads the batch
+    /// once it covers the interval.
+    pub fn push(&mut self, measurement: &TimestampedMeasurement) -> Result<(), String> {
+        self.batch.push(*measurement);
+        if measurement.time - self.batch[0].time >= self.interval {
+            return self.flush();
+        }
+        Result::Ok(())
+    }
+
+    // Returns the mean of every channel over the batch.
+    fn mean(&self) -> Measurement {
+        let mut mean = Measurement::default();
+        for channel in Channel::ALL {
+            let sum: f64 = self
+                .batch
+                .iter()
+                .map(|m| m.measurement.get(channel) as f64)
+                .sum();
+            mean.set(channel, (sum / self.batch.len() as f64) as f32);
+        }
+        mean
+    }
+
+    /// flush uploads the mean of the current batch (if any).
+    pub fn flush(&mut self) -> Result<(), String> {
+        if self.batch.is_empty() {
+            return Result::Ok(());
+        }
+        let body = payload(&self.mean());
+        let time = self.batch.last().unwrap().time;
+        self.batch.clear();
+        let pin = self.pin.to_string();
+        let headers = [
+            ("Content-Type", "application/json"),
+            ("X-Sensor", self.sensor_id.as_str()),
+            ("X-Pin", pin.as_str()),
+        ];
+
+        if let Some(output) = &mut self.dry_run {
+            let line = json::Object::new()
+                .string("time", &format_time(time))
+                .raw(
+                    "headers",
+                    &headers
+                        .iter()
+                        .fold(json::Object::new(), |object, (name, value)| {
+                            object.string(name, value)
+                        })
+                        .build(),
+                )
+                .raw("body", &body)
+                .build();
+            return writeln!(output, "{}", line)
+                .and_then(|_| output.flush())
+                .map_err(|e| format!("failed to write dry-run payload: {}", e));
+        }
+
+        let mut last_error = String::new();
+        for attempt in 0..self.max_attempts {
+            if attempt > 0 {
+                std::thread::sleep(self.retry_delay);
+            }
+            match http::post(&self.url, &headers, body.as_bytes()) {
+                Ok(response) if response.is_success() => return Result::Ok(()),
+                Ok(response) if response.status == 429 || response.status >= 500 => {
+                    last_error = format!(
+                        "Sensor.Community upload failed with status {}",
+                        response.status
+                    );
+                }
+                Ok(response) => {
+                    return Result::Err(format!(
+                        "Sensor.Community rejected upload with status {}: {}",
+                        response.status,
+                        String::from_utf8_lossy(&response.body)
+                    ));
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Result::Err(format!(
+            "Sensor.Community upload failed after {} attempts (dropping it), last error: {}",
+            self.max_attempts, last_error
+        ))
+    }
+}
+
+fn format_time(time: OffsetDateTime) -> String {
+    time.format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurement::test_util::{measurement, temp_path};
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use time::macros::datetime;
+
+    const START: OffsetDateTime = datetime!(2024-06-01 12:00 UTC);
+
+    struct Request {
+        request_line: String,
+        headers: Vec<String>,
+        body: String,
+    }
+
+    // Accepts one connection per entry in statuses, responding with that status,
+    // and sends each request back to the test.
+    fn mock_server(statuses: Vec<u16>) -> (String, mpsc::Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/v1/push-sensor-data/",
+            listener.local_addr().unwrap()
+        );
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut headers = vec![];
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                    headers.push(String::from(line.trim_end()));
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                tx.send(Request {
+                    request_line,
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                })
+                .unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_payload() {
+        let measurement = Measurement {
+            mass_concentration_pm_1_0: 1.0,
+            mass_concentration_pm_2_5: 2.5,
+            mass_concentration_pm_4_0: 4.0,
+            mass_concentration_pm_10_0: 10.0,
+            number_concentration_pm_0_5: 0.5,
+            number_concentration_pm_1_0: 1.25,
+            number_concentration_pm_2_5: 2.125,
+            number_concentration_pm_4_0: 4.0,
+            number_concentration_pm_10_0: 10.0,
+            typical_particle_size: 0.5625,
+        };
+        assert_eq!(
+            payload(&measurement),
+            format!(
+                "{{\"software_version\":\"sps30rs-{}\",\"sensordatavalues\":[\
+                {{\"value_type\":\"P0\",\"value\":\"1.00\"}},\
+                {{\"value_type\":\"P2\",\"value\":\"2.50\"}},\
+                {{\"value_type\":\"P4\",\"value\":\"4.00\"}},\
+                {{\"value_type\":\"P1\",\"value\":\"10.00\"}},\
+                {{\"value_type\":\"N05\",\"value\":\"0.50\"}},\
+                {{\"value_type\":\"N1\",\"value\":\"1.25\"}},\
+                {{\"value_type\":\"N25\",\"value\":\"2.12\"}},\
+                {{\"value_type\":\"N4\",\"value\":\"4.00\"}},\
+                {{\"value_type\":\"N10\",\"value\":\"10.00\"}},\
+                {{\"value_type\":\"TS\",\"value\":\"0.562\"}}]}}",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+
+    #[test]
+    fn test_sink_batches_and_retries() {
+        let (url, requests) = mock_server(vec![503, 201]);
+        let mut sink = SensorCommunitySink::new("raspi-00000000abcdef12")
+            .url(&url)
+            .interval(Duration::from_secs(10))
+            .retries(2, Duration::from_millis(1));
+
+        sink.push(&measurement(START, 1.0)).unwrap();
+        sink.push(&measurement(START + time::Duration::seconds(5), 2.0))
+            .unwrap();
+        assert!(requests.try_recv().is_err());
+        sink.push(&measurement(START + time::Duration::seconds(10), 6.0))
+            .unwrap();
+
+        for _ in 0..2 {
+            let request = requests.recv().unwrap();
+            assert_eq!(
+                request.request_line,
+                "POST /v1/push-sensor-data/ HTTP/1.1\r\n"
+            );
+            for header in [
+                "Content-Type: application/json",
+                "X-Sensor: raspi-00000000abcdef12",
+                "X-Pin: 1",
+            ] {
+                assert!(
+                    request.headers.contains(&String::from(header)),
+                    "missing {} in {:?}",
+                    header,
+                    request.headers
+                );
+            }
+            assert_eq!(request.body, payload(&measurement(START, 3.0).measurement));
+        }
+        assert!(sink.batch.is_empty());
+    }
+
+    #[test]
+    fn test_sink_errors() {
+        let (url, requests) = mock_server(vec![500, 500, 400]);
+        let mut sink = SensorCommunitySink::new("raspi-1")
+            .url(&url)
+            .interval(Duration::ZERO)
+            .retries(2, Duration::from_millis(1));
+
+        assert!(sink.push(&measurement(START, 1.0)).is_err());
+        assert!(sink.push(&measurement(START, 1.0)).is_err());
+        assert_eq!(requests.iter().take(3).count(), 3);
+        // Failed uploads aren't retried later.
+        assert!(sink.batch.is_empty());
+        assert!(sink.flush().is_ok());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let path = temp_path("sensor-community.jsonl");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut sink = SensorCommunitySink::new("raspi-1")
+            .url("http://127.0.0.1:1/")
+            .interval(Duration::from_secs(60))
+            .dry_run(file);
+
+        sink.push(&measurement(START, 1.0)).unwrap();
+        sink.flush().unwrap();
+        sink.flush().unwrap();
+        let output = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            output,
+            format!(
+                "{{\"time\":\"2024-06-01T12:00:00Z\",\"headers\":{{\"Content-Type\":\"application/json\",\
+                \"X-Sensor\":\"raspi-1\",\"X-Pin\":\"1\"}},\"body\":{}}}\n",
+                payload(&measurement(START, 1.0).measurement)
+            )
+        );
+    }
+}
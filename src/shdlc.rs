@@ -90,7 +90,7 @@ fn unstuff_data(data: &[u8]) -> Result<Vec<u8>, String> {
 pub struct MisoFrame {
     adr: u8,
     pub cmd: u8,
-    state: u8,
+    pub state: u8,
     pub data: Vec<u8>,
 }
 
@@ -119,6 +119,16 @@ impl PartialEq for MisoFrame {
     }
 }
 
+/// has_valid_checksum checks only the checksum of an entire (stuffed) miso
+/// frame, which is useful to distinguish corrupted frames from other decoding
+/// failures.
+pub fn has_valid_checksum(data_stuffed: &[u8]) -> bool {
+    match unstuff_data(data_stuffed) {
+        Ok(data) if data.len() >= 7 => checksum(&data[1..data.len() - 2]) == data[data.len() - 2],
+        _ => false,
+    }
+}
+
 // Decode an entire miso_frame, including start/stop bytes.
 pub fn decode_miso_frame(data_stuffed: &[u8]) -> Result<MisoFrame, String> {
     if data_stuffed.len() < 7 {
@@ -131,7 +141,11 @@ pub fn decode_miso_frame(data_stuffed: &[u8]) -> Result<MisoFrame, String> {
         ));
     }
 
-    let data = &(unstuff_data(&data_stuffed).unwrap());
+    let data = &(unstuff_data(data_stuffed)?);
+    // unstuffing can only shrink the frame, so recheck the minimum length.
+    if data.len() < 7 {
+        return Result::Err(String::from("invalid miso frame length"));
+    }
 
     let state = data[3];
     if state != 0 {
@@ -163,7 +177,10 @@ pub fn decode_miso_frame(data_stuffed: &[u8]) -> Result<MisoFrame, String> {
         ));
     }
 
-    // TODO: check checksum.
+    // Checksum is on adr + cmd + state + len + data.
+    if checksum(&data[1..data.len() - 2]) != data[data.len() - 2] {
+        return Result::Err(String::from("invalid miso frame checksum"));
+    }
 
     Result::Ok(MisoFrame {
         adr: data[1],
@@ -339,8 +356,7 @@ mod tests {
                 expected_result: Result::Err(String::from("invalid miso frame length")),
             },
             TestCase {
-                // TODO: fix CHK (2nd last byte) once checksum checks are implemented.
-                input: &[0x7E, 0, 0, 0, 0, 0, 0x7E],
+                input: &[0x7E, 0, 0, 0, 0, 0xFF, 0x7E],
                 expected_result: Result::Ok(MisoFrame {
                     adr: 0,
                     cmd: 0,
@@ -349,8 +365,7 @@ mod tests {
                 }),
             },
             TestCase {
-                // TODO: fix CHK (2nd last byte) once checksum checks are implemented.
-                input: &[0x7E, 0, 0, 0, 1, 0xFF, 0, 0x7E],
+                input: &[0x7E, 0, 0, 0, 1, 0xFF, 0xFF, 0x7E],
                 expected_result: Result::Ok(MisoFrame {
                     adr: 0,
                     cmd: 0,
@@ -359,8 +374,10 @@ mod tests {
                 }),
             },
             TestCase {
-                // TODO: fix CHK (2nd last byte) once checksum checks are implemented.
-                input: &[0x7E, 0, 0, 0, 4, 0xFF, 0x7D, 0x5E, 1, 0xFF, 0, 0x7E],
+                // CHK is 0x7E, and is therefore stuffed too.
+                input: &[
+                    0x7E, 0, 0, 0, 4, 0xFF, 0x7D, 0x5E, 1, 0xFF, 0x7D, 0x5E, 0x7E,
+                ],
                 expected_result: Result::Ok(MisoFrame {
                     adr: 0,
                     cmd: 0,
@@ -369,8 +386,7 @@ mod tests {
                 }),
             },
             TestCase {
-                // TODO: fix CHK (2nd last byte) once checksum checks are implemented.
-                input: &[0x7E, 1, 2, 3, 1, 0xFF, 0, 0x7E],
+                input: &[0x7E, 1, 2, 3, 1, 0xFF, 0xF9, 0x7E],
                 expected_result: Result::Ok(MisoFrame {
                     adr: 1,
                     cmd: 2,
@@ -379,25 +395,22 @@ mod tests {
                 }),
             },
             TestCase {
-                // TODO: fix CHK (2nd last byte) once checksum checks are implemented.
                 // L=2, but RX Data contains only 1 byte.
-                input: &[0x7E, 1, 2, 3, 2, 0xFF, 0, 0x7E],
+                input: &[0x7E, 1, 2, 3, 2, 0xFF, 0xF8, 0x7E],
                 expected_result: Result::Err(String::from(
                     "actual received data does not match expected length",
                 )),
             },
             TestCase {
-                // TODO: fix CHK (2nd last byte) once checksum checks are implemented.
                 // L=0, but RX Data contains 1 byte.
-                input: &[0x7E, 1, 2, 3, 0, 0xFF, 0, 0x7E],
+                input: &[0x7E, 1, 2, 3, 0, 0xFF, 0xFA, 0x7E],
                 expected_result: Result::Err(String::from(
                     "actual received data does not match expected length",
                 )),
             },
             TestCase {
-                // TODO: fix CHK (2nd last byte) once checksum checks are implemented.
                 // L=2, but RX Data contains 1 normal and 1 stuffed byte (i.e. 3 prior to unstuffing).
-                input: &[0x7E, 1, 2, 3, 2, 0xFF, 0x7D, 0x5D, 0, 0x7E],
+                input: &[0x7E, 1, 2, 3, 2, 0xFF, 0x7D, 0x5D, 0x7B, 0x7E],
                 expected_result: Result::Ok(MisoFrame {
                     adr: 1,
                     cmd: 2,
@@ -405,10 +418,26 @@ mod tests {
                     data: vec![0xFF, 0x7D],
                 }),
             },
+            TestCase {
+                // Same as above, but with an incorrect CHK.
+                input: &[0x7E, 1, 2, 3, 2, 0xFF, 0x7D, 0x5D, 0x7C, 0x7E],
+                expected_result: Result::Err(String::from("invalid miso frame checksum")),
+            },
+            TestCase {
+                input: &[0x7E, 1, 2, 3, 0, 0x7D, 0x00, 0x7E],
+                expected_result: Result::Err(String::from("invalid/unsupported stuffed byte")),
+            },
         ];
         for case in tests {
             let out = decode_miso_frame(case.input);
             assert_eq!(case.expected_result, out)
         }
     }
+
+    #[test]
+    fn test_has_valid_checksum() {
+        assert!(has_valid_checksum(&[0x7E, 1, 2, 3, 1, 0xFF, 0xF9, 0x7E]));
+        assert!(!has_valid_checksum(&[0x7E, 1, 2, 3, 1, 0xFF, 0xF8, 0x7E]));
+        assert!(!has_valid_checksum(&[0x7E, 0x7E]));
+    }
 }
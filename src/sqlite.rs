@@ -0,0 +1,408 @@
+use super::measurement::*;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+
+// Bump this (and add a migration to migrate()) whenever the schema changes.
+const SCHEMA_VERSION: i64 = 1;
+
+/// SensorInfo is the metadata stored alongside measurements.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SensorInfo {
+    pub serial: String,
+    pub product_type: Option<String>,
+    pub firmware_version: Option<String>,
+    pub location: Option<String>,
+}
+
+fn sql_error(context: &str) -> impl Fn(rusqlite::Error) -> String + '_ {
+    move |e| format!("{}: {}", context, e)
+}
+
+fn channel_columns() -> String {
+    Channel::ALL
+        .iter()
+        .map(|c| c.key())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn migrate(conn: &Connection) -> Result<(), String> {
+    conn.execute_batch("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL);")
+        .map_err(sql_error("failed to create schema_version table"))?;
+    let version: Option<i64> = conn
+        .query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+            row.get(0)
+        })
+        .map_err(sql_error("failed to read schema version"))?;
+    match version {
+        Some(SCHEMA_VERSION) => Result::Ok(()),
+        Some(version) => Result::Err(format!(
+            "unsupported schema version {} (expected {})",
+            version, SCHEMA_VERSION
+        )),
+        None => {
+            let columns: Vec<String> = Channel::ALL
+                .iter()
+                .map(|c| format!("{} REAL", c.key()))
+                .collect();
+            conn.execute_batch(&format!(
+                "BEGIN;
+                CREATE TABLE sensors (
+                    id INTEGER PRIMARY KEY,
+                    serial TEXT NOT NULL UNIQUE,
+                    product_type TEXT,
+                    firmware_version TEXT,
+                    location TEXT
+                );
+                CREATE TABLE measurements (
+                    sensor_id INTEGER NOT NULL REFERENCES sensors(id),
+                    -- Unix time in milliseconds.
+                    time_ms INTEGER NOT NULL,
+                    {}
+                );
+                CREATE INDEX measurements_time ON measurements(time_ms);
+                CREATE INDEX measurements_sensor_time ON measurements(sensor_id, time_ms);
+                INSERT INTO schema_version (version) VALUES ({});
+                COMMIT;",
+                columns.join(",\n"),
+                SCHEMA_VERSION
+            ))
+            .map_err(sql_error("failed to create schema"))
+        }
+    }
+}
+
+fn to_unix_ms(time: time::OffsetDateTime) -> i64 {
+    (time.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+fn from_unix_ms(time_ms: i64) -> Result<time::OffsetDateTime, String> {
+    time::OffsetDateTime::from_unix_timestamp_nanos(time_ms as i128 * 1_000_000)
+        .map_err(|e| format!("invalid timestamp {}: {}", time_ms, e))
+}
+
+/// SqliteSink writes measurements to a SQLite database, in batches to avoid
+/// excessive writes (e.g. to SD cards): measurements are only written once
+/// batch_size measurements are pending, or on flush (or drop).
+///
+/// Timestamps are stored with millisecond precision.
+pub struct SqliteSink {
+    conn: Connection,
+    sensor_id: i64,
+    batch_size: usize,
+    pending: Vec<TimestampedMeasurement>,
+}
+
+impl SqliteSink {
+    /// open opens (or creates) the database at path, and records (or
+    /// updates) the metadata for the given sensor.
+    pub fn open(path: &str, sensor: &SensorInfo) -> Result<Self, String> {
+        let conn = Connection::open(path)
+            .map_err(|e| format!("failed to open database {}: {}", path, e))?;
+        migrate(&conn)?;
+        conn.execute(
+            "INSERT INTO sensors (serial, product_type, firmware_version, location)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (serial) DO UPDATE SET
+                product_type = excluded.product_type,
+                firmware_version = excluded.firmware_version,
+                location = excluded.location",
+            params![
+                sensor.serial,
+                sensor.product_type,
+                sensor.firmware_version,
+                sensor.location
+            ],
+        )
+        .map_err(sql_error("failed to store sensor"))?;
+        let sensor_id = conn
+            .query_row(
+                "SELECT id FROM sensors WHERE serial = ?1",
+                [&sensor.serial],
+                |row| row.get(0),
+            )
+            .map_err(sql_error("failed to look up sensor"))?;
+        Result::Ok(SqliteSink {
+            conn,
+            sensor_id,
+            batch_size: 60,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn push(&mut self, measurement: &TimestampedMeasurement) -> Result<(), String> {
+        self.pending.push(*measurement);
+        if self.pending.len() >= self.batch_size {
+            return self.flush();
+        }
+        Result::Ok(())
+    }
+
+    /// flush writes all pending measurements in a single transaction.
+    pub fn flush(&mut self) -> Result<(), String> {
+        if self.pending.is_empty() {
+            return Result::Ok(());
+        }
+        let tx = self
+            .conn
+            .transaction()
+            .map_err(sql_error("failed to start transaction"))?;
+        {
+            let placeholders = vec!["?"; Channel::ALL.len() + 2].join(", ");
+            let mut statement = tx
+                .prepare_cached(&format!(
+                    "INSERT INTO measurements (sensor_id, time_ms, {}) VALUES ({})",
+                    channel_columns(),
+                    placeholders
+                ))
+                .map_err(sql_error("failed to prepare insert"))?;
+            for measurement in &self.pending {
+                let values = Channel::ALL
+                    .iter()
+                    .map(|c| rusqlite::types::Value::Real(measurement.measurement.get(*c).into()));
+                let row = [
+                    rusqlite::types::Value::Integer(self.sensor_id),
+                    rusqlite::types::Value::Integer(to_unix_ms(measurement.time)),
+                ]
+                .into_iter()
+                .chain(values);
+                statement
+                    .execute(params_from_iter(row))
+                    .map_err(sql_error("failed to insert measurement"))?;
+            }
+        }
+        tx.commit().map_err(sql_error("failed to commit"))?;
+        self.pending.clear();
+        Result::Ok(())
+    }
+}
+
+impl Drop for SqliteSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("failed to flush measurements: {}", e);
+        }
+    }
+}
+
+/// SqliteReader loads measurements written by SqliteSink.
+pub struct SqliteReader {
+    conn: Connection,
+}
+
+impl SqliteReader {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let conn = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| format!("failed to open database {}: {}", path, e))?;
+        let version: Option<i64> = conn
+            .query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(sql_error("failed to read schema version"))?
+            .flatten();
+        if version != Some(SCHEMA_VERSION) {
+            return Result::Err(format!(
+                "unsupported schema version {:?} (expected {})",
+                version, SCHEMA_VERSION
+            ));
+        }
+        Result::Ok(SqliteReader { conn })
+    }
+
+    pub fn sensors(&self) -> Result<Vec<SensorInfo>, String> {
+        let mut statement = self
+            .conn
+            .prepare(
+                "SELECT serial, product_type, firmware_version, location FROM sensors ORDER BY id",
+            )
+            .map_err(sql_error("failed to prepare query"))?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok(SensorInfo {
+                    serial: row.get(0)?,
+                    product_type: row.get(1)?,
+                    firmware_version: row.get(2)?,
+                    location: row.get(3)?,
+                })
+            })
+            .map_err(sql_error("failed to query sensors"))?;
+        rows.collect::<Result<_, _>>()
+            .map_err(sql_error("failed to read sensor"))
+    }
+
+    /// read_range loads all measurements in [from, to) for the given sensor
+    /// (or all sensors if serial is None), ordered by time.
+    pub fn read_range(
+        &self,
+        serial: Option<&str>,
+        from: time::OffsetDateTime,
+        to: time::OffsetDateTime,
+    ) -> Result<Vec<TimestampedMeasurement>, String> {
+        let mut statement = self
+            .conn
+            .prepare(&format!(
+                "SELECT time_ms, {}
+                FROM measurements JOIN sensors ON sensors.id = measurements.sensor_id
+                WHERE time_ms >= ?1 AND time_ms < ?2 AND (?3 IS NULL OR serial = ?3)
+                ORDER BY time_ms",
+                channel_columns()
+            ))
+            .map_err(sql_error("failed to prepare query"))?;
+        let rows = statement
+            .query_map(params![to_unix_ms(from), to_unix_ms(to), serial], |row| {
+                let mut measurement = Measurement::default();
+                for (i, channel) in Channel::ALL.iter().enumerate() {
+                    // SQLite stores NaN as NULL.
+                    let value: Option<f64> = row.get(i + 1)?;
+                    measurement.set(*channel, value.map_or(f32::NAN, |v| v as f32));
+                }
+                Ok((row.get::<_, i64>(0)?, measurement))
+            })
+            .map_err(sql_error("failed to query measurements"))?;
+
+        let mut out = Vec::new();
+        for row in rows {
+            let (time_ms, measurement) = row.map_err(sql_error("failed to read measurement"))?;
+            out.push(TimestampedMeasurement {
+                time: from_unix_ms(time_ms)?,
+                measurement,
+            });
+        }
+        Result::Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurement::test_util::{measurement, temp_path};
+    use time::macros::datetime;
+
+    #[test]
+    fn test_round_trip() {
+        let path = temp_path("round-trip.db");
+        let sensor = SensorInfo {
+            serial: String::from("ABC"),
+            firmware_version: Some(String::from("2.2")),
+            ..Default::default()
+        };
+        let mut sink = SqliteSink::open(&path, &sensor).unwrap().batch_size(2);
+        let mut other = SqliteSink::open(
+            &path,
+            &SensorInfo {
+                serial: String::from("DEF"),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        sink.push(&measurement(datetime!(2024-06-01 12:00:00 UTC), 1.0))
+            .unwrap();
+        other
+            .push(&measurement(datetime!(2024-06-01 12:00:01 UTC), 9.0))
+            .unwrap();
+        let reader = SqliteReader::open(&path).unwrap();
+        let all_time = (
+            datetime!(2000-01-01 0:00 UTC),
+            datetime!(2100-01-01 0:00 UTC),
+        );
+        // Nothing is written until the batch is full.
+        assert!(reader
+            .read_range(None, all_time.0, all_time.1)
+            .unwrap()
+            .is_empty());
+
+        sink.push(&measurement(datetime!(2024-06-01 12:00:05.25 UTC), 2.5))
+            .unwrap();
+        sink.push(&measurement(datetime!(2024-06-01 12:00:10 UTC), 3.0))
+            .unwrap();
+        drop(sink);
+        drop(other);
+
+        assert_eq!(
+            reader
+                .read_range(
+                    Some("ABC"),
+                    datetime!(2024-06-01 12:00:00 UTC),
+                    datetime!(2024-06-01 12:00:10 UTC)
+                )
+                .unwrap(),
+            vec![
+                measurement(datetime!(2024-06-01 12:00:00 UTC), 1.0),
+                measurement(datetime!(2024-06-01 12:00:05.25 UTC), 2.5),
+            ]
+        );
+        assert_eq!(
+            reader
+                .read_range(None, all_time.0, all_time.1)
+                .unwrap()
+                .len(),
+            4
+        );
+        assert_eq!(
+            reader.sensors().unwrap(),
+            vec![
+                sensor,
+                SensorInfo {
+                    serial: String::from("DEF"),
+                    ..Default::default()
+                }
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_nan_round_trip() {
+        let path = temp_path("nan-round-trip.db");
+        let mut sink = SqliteSink::open(&path, &SensorInfo::default()).unwrap();
+        let mut nan = measurement(datetime!(2024-06-01 12:00:00 UTC), 1.0);
+        nan.measurement.mass_concentration_pm_2_5 = f32::NAN;
+        sink.push(&nan).unwrap();
+        sink.push(&measurement(datetime!(2024-06-01 12:00:05 UTC), 2.0))
+            .unwrap();
+        drop(sink);
+
+        let read = SqliteReader::open(&path)
+            .unwrap()
+            .read_range(
+                None,
+                datetime!(2024-06-01 12:00:00 UTC),
+                datetime!(2024-06-01 12:01:00 UTC),
+            )
+            .unwrap();
+        assert_eq!(read.len(), 2);
+        assert!(read[0].measurement.mass_concentration_pm_2_5.is_nan());
+        assert_eq!(read[0].measurement.mass_concentration_pm_1_0, 1.0);
+        assert_eq!(
+            read[1],
+            measurement(datetime!(2024-06-01 12:00:05 UTC), 2.0)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let path = temp_path("schema-version.db");
+        drop(SqliteSink::open(&path, &SensorInfo::default()).unwrap());
+        // Reopening an existing database is fine...
+        drop(SqliteSink::open(&path, &SensorInfo::default()).unwrap());
+
+        // ... but not if it was created by a newer version.
+        Connection::open(&path)
+            .unwrap()
+            .execute("INSERT INTO schema_version (version) VALUES (2)", [])
+            .unwrap();
+        assert_eq!(
+            SqliteSink::open(&path, &SensorInfo::default()).err(),
+            Some(String::from("unsupported schema version 2 (expected 1)"))
+        );
+        assert!(SqliteReader::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+use super::aggregate::Aggregator;
+use super::aqi::{self, Standard};
+use super::device::{DeviceStats, DeviceStatus};
+use super::measurement::*;
+use std::collections::VecDeque;
+use std::io::Write;
+use time::{Duration, OffsetDateTime};
+
+// From lowest to highest.
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+const RESET: &str = "\x1b[0m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+
+/// Dashboard renders a live overview of the sensor for a terminal: the latest
+/// value of every channel, with rolling means and sparklines over the last
+/// window, the AQI category, and the health of the sensor and its link.
+///
+/// Rendering (see Dashboard::render) is separate from drawing (see draw), so
+/// that frames can be checked without a terminal.
+pub struct Dashboard {
+    title: String,
+    window: Duration,
+    standard: Standard,
+    colour: bool,
+    sparkline_width: usize,
+    aggregator: Aggregator,
+    history: VecDeque<TimestampedMeasurement>,
+}
+
+impl Dashboard {
+    /// window is the period covered by the rolling means and sparklines, and
+    /// expected_interval the normal time between measurements (see
+    /// Aggregator::new).
+    pub fn new(window: std::time::Duration, expected_interval: std::time::Duration) -> Self {
+        Dashboard {
+            title: String::from("SPS30"),
+            window: Duration::try_from(window).unwrap_or(Duration::DAY),
+            standard: Standard::UsEpa,
+            colour: true,
+            sparkline_width: 30,
+            // Partial windows are still useful while watching a sensor, e.g.
+            // right after starting the reader.
+            aggregator: Aggregator::new(expected_interval).min_completeness(0.0),
+            history: VecDeque::new(),
+        }
+    }
+
+    /// set_expected_interval changes the normal time between measurements, e.g.
+    /// after the sampling interval was changed over Modbus.
+    pub fn set_expected_interval(&mut self, expected_interval: std::time::Duration) {
+        self.aggregator.set_expected_interval(expected_interval);
+    }
+
+    pub fn title(mut self, title: &str) -> Self {
+        self.title = String::from(title);
+        self
+    }
+
+    /// standard selects the AQI used for the category colour, the default is
+    /// the US EPA AQI.
+    pub fn standard(mut self, standard: Standard) -> Self {
+        self.standard = standard;
+        self
+    }
+
+    /// colour enables ANSI colours (the default), which should be disabled if
+    /// e.g. NO_COLOR is set.
+    pub fn colour(mut self, colour: bool) -> Self {
+        self.colour = colour;
+        self
+    }
+
+    pub fn sparkline_width(mut self, width: usize) -> Self {
+        self.sparkline_width = width.max(1);
+        self
+    }
+
+    /// push adds a measurement, measurements that are older than the latest
+    /// measurement are ignored.
+    pub fn push(&mut self, measurement: &TimestampedMeasurement) {
+        if self
+            .history
+            .back()
+            .is_some_and(|latest| measurement.time <= latest.time)
+        {
+            return;
+        }
+        self.aggregator.push(measurement);
+        self.history.push_back(*measurement);
+        let cutoff = measurement.time - self.window;
+        while self.history.front().is_some_and(|m| m.time < cutoff) {
+            self.history.pop_front();
+        }
+    }
+
+    // Returns the mean of each of width equal slots of the window ending at
+    // now, or None for slots without any samples.
+    fn slots(&self, channel: Channel, now: OffsetDateTime) -> Vec<Option<f32>> {
+        let width = self.sparkline_width;
+        let start = now - self.window;
+        let mut sums = vec![(0.0, 0); width];
+        for sample in self
+            .history
+            .iter()
+            .filter(|m| m.time >= start && m.time <= now)
+        {
+            let offset = (sample.time - start) / self.window;
+            let slot = ((offset * width as f64) as usize).min(width - 1);
+            sums[slot].0 += sample.measurement.get(channel) as f64;
+            sums[slot].1 += 1;
+        }
+        sums.iter()
+            .map(|(sum, n)| (*n > 0).then(|| (sum / *n as f64) as f32))
+            .collect()
+    }
+
+    fn paint(&self, text: &str, style: &str) -> String {
+        if self.colour {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            String::from(text)
+        }
+    }
+
+    fn aqi_line(&self, mean: Option<&Measurement>) -> String {
+        let (measurement, basis) = match (mean, self.history.back()) {
+            (Some(mean), _) => (mean, format!("{} mean", self.window_label())),
+            (None, Some(latest)) => (&latest.measurement, String::from("latest")),
+            (None, None) => return String::from("AQI: waiting for measurements"),
+        };
+        match aqi::from_measurement(self.standard, measurement) {
+            Ok(aqi) => {
+                let swatch = match (self.colour, aqi::rgb(aqi.colour)) {
+                    (true, Some((r, g, b))) => format!("\x1b[48;2;{};{};{}m  {} ", r, g, b, RESET),
+                    _ => String::new(),
+                };
+                format!(
+                    "AQI ({}, {}): {}{} {} ({})",
+                    standard_name(self.standard),
+                    basis,
+                    swatch,
+                    aqi.index,
+                    aqi.category,
+                    aqi.dominant_pollutant
+                )
+            }
+            Err(e) => format!("AQI: {}", e),
+        }
+    }
+
+    fn status_line(&self, status: Option<DeviceStatus>) -> String {
+        let status = match status {
+            None => return format!("Device status: {}", self.paint("unknown", YELLOW)),
+            Some(status) if status.is_healthy() => {
+                return format!("Device status: {}", self.paint("ok", GREEN))
+            }
+            Some(status) => status,
+        };
+        let mut flags = vec![];
+        if status.fan_speed_warning {
+            flags.push(self.paint("fan speed warning", YELLOW));
+        }
+        if status.laser_error {
+            flags.push(self.paint("laser error", RED));
+        }
+        if status.fan_error {
+            flags.push(self.paint("fan error", RED));
+        }
+        format!("Device status: {}", flags.join(", "))
+    }
+
+    fn window_label(&self) -> String {
+        format!("{} min", self.window.whole_minutes())
+    }
+
+    /// render returns the dashboard for now as lines of text. status is None
+    /// if the device status register couldn't be read, and error is the most
+    /// recent error (if any), which would otherwise be drawn over.
+    pub fn render(
+        &self,
+        now: OffsetDateTime,
+        stats: DeviceStats,
+        status: Option<DeviceStatus>,
+        error: Option<&str>,
+    ) -> String {
+        let mean = self.aggregator.period_mean(now - self.window, now);
+        let latest = self.history.back();
+        let mut lines = vec![];
+
+        let age = match latest {
+            Some(latest) => format!("{}s ago", (now - latest.time).whole_seconds().max(0)),
+            None => String::from("none yet"),
+        };
+        lines.push(format!("{} - latest measurement {}", self.title, age));
+        lines.push(self.aqi_line(mean.as_ref()));
+        lines.push(String::new());
+        lines.push(format!(
+            "{:<28}{:>8}{:>12}{:>12}  last {}",
+            "",
+            "unit",
+            "latest",
+            format!("{} mean", self.window_label()),
+            self.window_label()
+        ));
+        let format_value = |value: Option<f32>| match value {
+            Some(value) => format!("{:.2}", value),
+            None => String::from("-"),
+        };
+        for channel in Channel::ALL {
+            lines.push(format!(
+                "{:<28}{:>8}{:>12}{:>12}  {}",
+                channel.label(),
+                channel.unit(),
+                format_value(latest.map(|m| m.measurement.get(channel))),
+                format_value(mean.as_ref().map(|m| m.get(channel))),
+                sparkline(&self.slots(channel, now))
+            ));
+        }
+        lines.push(String::new());
+        lines.push(self.status_line(status));
+        let link = format!(
+            "Link: {} frame errors, {} checksum failures, {} empty responses, {} recovery attempts",
+            stats.frame_errors,
+            stats.checksum_failures,
+            stats.empty_responses,
+            stats.recovery_attempts
+        );
+        if stats.frame_errors + stats.checksum_failures + stats.recovery_attempts > 0 {
+            lines.push(self.paint(&link, YELLOW));
+        } else {
+            lines.push(link);
+        }
+        if let Some(error) = error {
+            lines.push(self.paint(&format!("Last error: {}", error), RED));
+        }
+        lines.join("\n")
+    }
+}
+
+fn standard_name(standard: Standard) -> &'static str {
+    match standard {
+        Standard::UsEpa => "US EPA",
+        Standard::EuCaqi => "CAQI",
+        Standard::UkDaqi => "DAQI",
+    }
+}
+
+// Scales values between their minimum and maximum, leaving gaps for None.
+fn sparkline(values: &[Option<f32>]) -> String {
+    let present = values.iter().flatten();
+    let min = present.clone().cloned().fold(f32::INFINITY, f32::min);
+    let max = present.cloned().fold(f32::NEG_INFINITY, f32::max);
+    values
+        .iter()
+        .map(|value| match value {
+            Some(value) if max > min => {
+                let level = ((value - min) / (max - min) * (SPARKS.len() - 1) as f32).round();
+                SPARKS[level as usize]
+            }
+            Some(_) => SPARKS[0],
+            None => ' ',
+        })
+        .collect()
+}
+
+/// clear_screen clears the terminal, before drawing the first frame.
+pub fn clear_screen<W: Write>(out: &mut W) -> Result<(), String> {
+    write!(out, "\x1b[2J")
+        .and_then(|_| out.flush())
+        .map_err(|e| format!("failed to write to terminal: {}", e))
+}
+
+/// draw replaces what's on the terminal with frame. Rather than clearing the
+/// screen (which flickers), each line is overwritten and then cleared to the
+/// end of the line, and anything below the frame is cleared.
+pub fn draw<W: Write>(out: &mut W, frame: &str) -> Result<(), String> {
+    let mut buffer = String::from("\x1b[H");
+    for line in frame.lines() {
+        buffer.push_str(line);
+        buffer.push_str("\x1b[K\n");
+    }
+    buffer.push_str("\x1b[J");
+    out.write_all(buffer.as_bytes())
+        .and_then(|_| out.flush())
+        .map_err(|e| format!("failed to write to terminal: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurement::test_util::measurement;
+    use time::macros::datetime;
+
+    const START: OffsetDateTime = datetime!(2024-06-01 00:00 UTC);
+
+    #[test]
+    fn test_sparkline() {
+        struct TestCase {
+            values: Vec<Option<f32>>,
+            expected_output: &'static str,
+        }
+        let tests = [
+            TestCase {
+                values: vec![],
+                expected_output: "",
+            },
+            TestCase {
+                values: vec![Some(0.0), Some(7.0), Some(3.5), None, Some(1.0)],
+                expected_output: "▁█▅ ▂",
+            },
+            TestCase {
+                values: vec![Some(5.0), None, Some(5.0)],
+                expected_output: "▁ ▁",
+            },
+            TestCase {
+                values: vec![None, None],
+                expected_output: "  ",
+            },
+        ];
+        for case in tests {
+            assert_eq!(
+                sparkline(&case.values),
+                case.expected_output,
+                "{:?}",
+                case.values
+            );
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let mut dashboard = Dashboard::new(
+            std::time::Duration::from_secs(60),
+            std::time::Duration::from_secs(5),
+        )
+        .title("SPS30 ABCDEF0123456789")
+        .colour(false)
+        .sparkline_width(6);
+        let now = START + Duration::seconds(120);
+
+        let frame = dashboard.render(now, DeviceStats::default(), None, None);
+        assert!(frame.starts_with("SPS30 ABCDEF0123456789 - latest measurement none yet\n"));
+        assert!(frame.contains("AQI: waiting for measurements"));
+        assert!(frame.contains("Device status: unknown"));
+
+        // Samples from before the window only count towards the latest value.
+        dashboard.push(&measurement(START, 100.0));
+        for i in 0..6 {
+            dashboard.push(&measurement(now - Duration::seconds(55 - i * 10), i as f32));
+        }
+        // Out of order.
+        dashboard.push(&measurement(START, 100.0));
+
+        let stats = DeviceStats {
+            empty_responses: 3,
+            ..Default::default()
+        };
+        let status = DeviceStatus {
+            fan_speed_warning: true,
+            ..Default::default()
+        };
+        let frame = dashboard.render(now, stats, Some(status), Some("timed out"));
+        let lines: Vec<&str> = frame.lines().collect();
+        assert_eq!(
+            lines[0],
+            "SPS30 ABCDEF0123456789 - latest measurement 5s ago"
+        );
+        assert_eq!(lines[1], "AQI (US EPA, 1 min mean): 14 Good (PM2.5)");
+        assert_eq!(
+            lines[3],
+            "                                unit      latest  1 min mean  last 1 min"
+        );
+        assert_eq!(
+            lines[5],
+            "Mass Concentration PM2.5       ug/m3        5.00        2.50  ▁▂▄▅▇█"
+        );
+        assert_eq!(lines[15], "Device status: fan speed warning");
+        assert_eq!(
+            lines[16],
+            "Link: 0 frame errors, 0 checksum failures, 3 empty responses, 0 recovery attempts"
+        );
+        assert_eq!(lines[17], "Last error: timed out");
+
+        let frame = dashboard.colour(true).render(
+            now,
+            DeviceStats::default(),
+            Some(DeviceStatus::default()),
+            None,
+        );
+        assert!(frame.contains("\x1b[48;2;0;228;0m  \x1b[0m 14 Good"));
+        assert!(frame.contains("Device status: \x1b[32mok\x1b[0m"));
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut out = vec![];
+        draw(&mut out, "a\nb").unwrap();
+        assert_eq!(out, b"\x1b[Ha\x1b[K\nb\x1b[K\n\x1b[J");
+    }
+}
@@ -0,0 +1,503 @@
+use super::device::DeviceStats;
+use super::http;
+use super::json;
+use super::measurement::*;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use time::{Duration, OffsetDateTime};
+
+const INDEX_HTML: &str = include_str!("web.html");
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// 24 hours at the reader's 5 second interval.
+pub const DEFAULT_CAPACITY: usize = 24 * 60 * 12;
+
+// How many updates may be queued for an /events subscriber that isn't keeping
+// up, before it's disconnected.
+const SUBSCRIBER_BACKLOG: usize = 16;
+
+// Defaults and limits for /api/history?minutes=N.
+const DEFAULT_HISTORY_MINUTES: i64 = 60;
+const MAX_HISTORY_MINUTES: i64 = 7 * 24 * 60;
+
+/// DeviceInfo describes the sensor on the dashboard.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceInfo {
+    pub serial: String,
+    pub product_type: String,
+    pub firmware_version: String,
+}
+
+// Loads measurements in [from, to), e.g. from the SQLite log.
+type Archive = dyn Fn(OffsetDateTime, OffsetDateTime) -> Result<Vec<TimestampedMeasurement>, String>
+    + Send
+    + Sync;
+
+fn format_time(time: OffsetDateTime) -> String {
+    time.format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_default()
+}
+
+/// measurement_json formats a measurement like the MQTT JSON payload: the
+/// RFC 3339 time, and every channel by key.
+pub fn measurement_json(measurement: &TimestampedMeasurement) -> String {
+    Channel::ALL
+        .iter()
+        .fold(
+            json::Object::new().string("time", &format_time(measurement.time)),
+            |object, c| object.number(c.key(), measurement.measurement.get(*c)),
+        )
+        .build()
+}
+
+fn stats_json(stats: &DeviceStats) -> String {
+    json::Object::new()
+        .number("frame_errors", stats.frame_errors as f64)
+        .number("checksum_failures", stats.checksum_failures as f64)
+        .number("empty_responses", stats.empty_responses as f64)
+        .number("recovery_attempts", stats.recovery_attempts as f64)
+        .build()
+}
+
+// Formats a Server-Sent Event, data must not contain newlines (which JSON
+// produced by json::Object never does).
+fn event(name: &str, data: &str) -> String {
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+fn json_response(body: String) -> http::Response {
+    http::Response::new(200, JSON_CONTENT_TYPE, body.into_bytes())
+}
+
+fn error_response(status: u16, message: &str) -> http::Response {
+    http::Response::new(
+        status,
+        "text/plain; charset=utf-8",
+        format!("{}\n", message).into_bytes(),
+    )
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+struct State {
+    history: VecDeque<TimestampedMeasurement>,
+    stats: DeviceStats,
+    // Queues of events for the threads writing to /events connections, so
+    // that update never waits for a (slow) client.
+    subscribers: Vec<SyncSender<String>>,
+}
+
+/// WebDashboard serves a small web UI with a live chart, the latest values
+/// and device info. The page is embedded in the binary, and is fed by:
+///
+/// - /events: Server-Sent Events, a `measurement` event per measurement and
+///   a `stats` event per update.
+/// - /api/device: device info, link statistics and the channels.
+/// - /api/latest: the latest measurement, or null.
+/// - /api/history?minutes=N: measurements from the last N minutes (default
+///   60), from the in-memory ring buffer and, for anything older, the archive
+///   (if any).
+#[derive(Clone)]
+pub struct WebDashboard {
+    info: Arc<DeviceInfo>,
+    capacity: usize,
+    archive: Option<Arc<Archive>>,
+    state: Arc<Mutex<State>>,
+}
+
+impl WebDashboard {
+    pub fn new(info: DeviceInfo) -> Self {
+        WebDashboard {
+            info: Arc::new(info),
+            capacity: DEFAULT_CAPACITY,
+            archive: None,
+            state: Arc::new(Mutex::new(State {
+                history: VecDeque::new(),
+                stats: DeviceStats::default(),
+                subscribers: vec![],
+            })),
+        }
+    }
+
+    /// capacity is the number of measurements kept in memory, the default is
+    /// DEFAULT_CAPACITY.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// archive supplies history that is older than the ring buffer, e.g. by
+    /// reading the SQLite log. It's called with [from, to).
+    pub fn archive<F>(mut self, archive: F) -> Self
+    where
+        F: Fn(OffsetDateTime, OffsetDateTime) -> Result<Vec<TimestampedMeasurement>, String>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.archive = Some(Arc::new(archive));
+        self
+    }
+
+    /// update records the device stats, and the measurement if the last read
+    /// was successful, and sends them to every /events subscriber.
+    pub fn update(&self, measurement: Option<&TimestampedMeasurement>, stats: DeviceStats) {
+        let mut state = self.state.lock().unwrap();
+        let mut events = String::new();
+        if let Some(measurement) = measurement {
+            if state
+                .history
+                .back()
+                .is_none_or(|latest| measurement.time > latest.time)
+            {
+                state.history.push_back(*measurement);
+                while state.history.len() > self.capacity {
+                    state.history.pop_front();
+                }
+            }
+            events.push_str(&event("measurement", &measurement_json(measurement)));
+        }
+        state.stats = stats;
+        events.push_str(&event("stats", &stats_json(&stats)));
+        // Subscribers that have gone away (or have fallen too far behind) are
+        // dropped, which ends their connection.
+        state
+            .subscribers
+            .retain(|subscriber| subscriber.try_send(events.clone()).is_ok());
+    }
+
+    /// history returns the measurements in [from, to), ordered by time.
+    pub fn history(
+        &self,
+        from: OffsetDateTime,
+        to: OffsetDateTime,
+    ) -> Result<Vec<TimestampedMeasurement>, String> {
+        let (oldest, recent): (_, Vec<TimestampedMeasurement>) = {
+            let state = self.state.lock().unwrap();
+            (
+                state.history.front().map(|m| m.time),
+                state
+                    .history
+                    .iter()
+                    .filter(|m| m.time >= from && m.time < to)
+                    .copied()
+                    .collect(),
+            )
+        };
+        // The archive may be slow (e.g. a database query), so it's read
+        // without holding the lock.
+        let mut out = vec![];
+        if let Some(archive) = &self.archive {
+            let archive_to = oldest.map_or(to, |oldest| oldest.min(to));
+            if from < archive_to {
+                out = archive(from, archive_to)?;
+            }
+        }
+        out.extend(recent);
+        Ok(out)
+    }
+
+    fn device(&self) -> String {
+        let channels = Channel::ALL.iter().map(|c| {
+            json::Object::new()
+                .string("key", c.key())
+                .string("label", c.label())
+                .string("unit", c.unit())
+                .build()
+        });
+        json::Object::new()
+            .string("serial", &self.info.serial)
+            .string("product_type", &self.info.product_type)
+            .string("firmware_version", &self.info.firmware_version)
+            .raw("stats", &stats_json(&self.state.lock().unwrap().stats))
+            .raw("channels", &json::array(channels))
+            .build()
+    }
+
+    fn handle(&self, request: &http::Request) -> http::Response {
+        match request.path.as_str() {
+            "/" => http::Response::new(
+                200,
+                "text/html; charset=utf-8",
+                INDEX_HTML.as_bytes().to_vec(),
+            ),
+            "/api/device" => json_response(self.device()),
+            "/api/latest" => json_response(
+                self.state
+                    .lock()
+                    .unwrap()
+                    .history
+                    .back()
+                    .map_or_else(|| String::from("null"), measurement_json),
+            ),
+            "/api/history" => {
+                let minutes = match query_param(&request.query, "minutes") {
+                    None => DEFAULT_HISTORY_MINUTES,
+                    Some(value) => match value.parse::<i64>() {
+                        Ok(minutes) if (1..=MAX_HISTORY_MINUTES).contains(&minutes) => minutes,
+                        _ => {
+                            return error_response(
+                                400,
+                                &format!("minutes must be between 1 and {}", MAX_HISTORY_MINUTES),
+                            )
+                        }
+                    },
+                };
+                let now = OffsetDateTime::now_utc();
+                // Include measurements made right now.
+                match self.history(now - Duration::minutes(minutes), now + Duration::SECOND) {
+                    Ok(history) => json_response(json::array(history.iter().map(measurement_json))),
+                    Err(e) => error_response(500, &e),
+                }
+            }
+            _ => http::Response::not_found(),
+        }
+    }
+
+    // subscribe streams events to a client until it disconnects, on the
+    // connection's own thread.
+    fn subscribe(&self, mut stream: TcpStream) {
+        // Clients reconnect after 5 seconds if the connection drops, and get
+        // the latest measurement straight away.
+        let mut initial = String::from("retry: 5000\n\n");
+        let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_BACKLOG);
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(latest) = state.history.back() {
+                initial.push_str(&event("measurement", &measurement_json(latest)));
+            }
+            initial.push_str(&event("stats", &stats_json(&state.stats)));
+            state.subscribers.push(tx);
+        }
+        if let Err(e) = http::write_stream_head(&mut stream, "text/event-stream")
+            .and_then(|_| stream.write_all(initial.as_bytes()))
+        {
+            eprintln!("failed to start event stream: {}", e);
+            return;
+        }
+        for events in rx {
+            if stream.write_all(events.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// serve starts serving the dashboard on a background thread, and returns
+    /// the address that is being listened on (which is useful when binding to
+    /// port 0).
+    pub fn serve(&self, addr: &str) -> Result<SocketAddr, String> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| format!("failed to bind to {}: {}", addr, e))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| format!("failed to get local address: {}", e))?;
+        let dashboard = self.clone();
+        http::serve_connections(listener, move |request, mut stream| {
+            if request.path == "/events" {
+                if request.method == "HEAD" {
+                    let _ = http::write_stream_head(&mut stream, "text/event-stream");
+                    return;
+                }
+                return dashboard.subscribe(stream);
+            }
+            if let Err(e) = http::write_response(&mut stream, request, &dashboard.handle(request)) {
+                eprintln!("failed to write http response: {}", e);
+            }
+        });
+        Result::Ok(local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurement::test_util::measurement;
+    use std::io::{BufRead, BufReader};
+    use time::macros::datetime;
+
+    const START: OffsetDateTime = datetime!(2024-06-01 00:00 UTC);
+
+    fn info() -> DeviceInfo {
+        DeviceInfo {
+            serial: String::from("ABCDEF0123456789"),
+            product_type: String::from("00080000"),
+            firmware_version: String::from("2.2"),
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        // The archive has everything, at a 1 minute interval.
+        let dashboard = WebDashboard::new(info()).capacity(3).archive(|from, to| {
+            let mut out = vec![];
+            let mut time = START;
+            while time < START + Duration::minutes(10) {
+                if time >= from && time < to {
+                    out.push(measurement(time, 1.0));
+                }
+                time += Duration::minutes(1);
+            }
+            Ok(out)
+        });
+        for i in 5..10 {
+            dashboard.update(
+                Some(&measurement(START + Duration::minutes(i), 2.0)),
+                DeviceStats::default(),
+            );
+        }
+        // Out of order.
+        dashboard.update(Some(&measurement(START, 3.0)), DeviceStats::default());
+
+        let history = dashboard
+            .history(START + Duration::minutes(2), START + Duration::minutes(20))
+            .unwrap();
+        assert_eq!(
+            history
+                .iter()
+                .map(|m| (
+                    (m.time - START).whole_minutes(),
+                    m.measurement.mass_concentration_pm_1_0
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (2, 1.0),
+                (3, 1.0),
+                (4, 1.0),
+                (5, 1.0),
+                (6, 1.0),
+                (7, 2.0),
+                (8, 2.0),
+                (9, 2.0)
+            ]
+        );
+
+        // Without an archive only the ring buffer is available.
+        let dashboard = WebDashboard::new(info()).capacity(3);
+        dashboard.update(Some(&measurement(START, 2.0)), DeviceStats::default());
+        assert_eq!(
+            dashboard
+                .history(START - Duration::HOUR, START + Duration::HOUR)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_serve() {
+        let dashboard = WebDashboard::new(info());
+        let addr = dashboard.serve("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", addr);
+
+        let response = http::get(&format!("{}/", url)).unwrap();
+        assert_eq!(response.status, 200);
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("EventSource"));
+
+        let device =
+            String::from_utf8(http::get(&format!("{}/api/device", url)).unwrap().body).unwrap();
+        assert!(device.starts_with(
+            r#"{"serial":"ABCDEF0123456789","product_type":"00080000","firmware_version":"2.2","stats":{"frame_errors":0,"#
+        ));
+        assert!(device
+            .contains(r#"{"key":"mass_pm2_5","label":"Mass Concentration PM2.5","unit":"ug/m3"}"#));
+        assert_eq!(
+            http::get(&format!("{}/api/latest", url)).unwrap().body,
+            b"null"
+        );
+
+        // Subscribe before the first measurement.
+        let mut events = TcpStream::connect(addr).unwrap();
+        events
+            .set_read_timeout(Some(std::time::Duration::from_secs(10)))
+            .unwrap();
+        write!(events, "GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut events = BufReader::new(events);
+        let mut lines = vec![];
+        while lines.last().map(String::as_str) != Some("retry: 5000") {
+            let mut line = String::new();
+            events.read_line(&mut line).unwrap();
+            lines.push(String::from(line.trim_end()));
+        }
+        assert_eq!(lines[0], "HTTP/1.1 200 OK");
+        assert!(lines.contains(&String::from("Content-Type: text/event-stream")));
+
+        let now = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap();
+        let stats = DeviceStats {
+            empty_responses: 2,
+            ..Default::default()
+        };
+        dashboard.update(Some(&measurement(now, 1.5)), stats);
+
+        let mut data = vec![];
+        while data.len() < 3 {
+            let mut line = String::new();
+            events.read_line(&mut line).unwrap();
+            if let Some(value) = line.trim_end().strip_prefix("data: ") {
+                data.push(String::from(value));
+            }
+        }
+        let expected = measurement_json(&measurement(now, 1.5));
+        assert!(expected.contains(r#""mass_pm1_0":1.5"#));
+        assert_eq!(data[0], stats_json(&DeviceStats::default()));
+        assert_eq!(data[1], expected);
+        assert_eq!(data[2], stats_json(&stats));
+
+        assert_eq!(
+            http::get(&format!("{}/api/latest", url)).unwrap().body,
+            expected.as_bytes()
+        );
+        assert_eq!(
+            http::get(&format!("{}/api/history?minutes=5", url))
+                .unwrap()
+                .body,
+            format!("[{}]", expected).as_bytes()
+        );
+        assert_eq!(
+            http::get(&format!("{}/api/history?minutes=0", url))
+                .unwrap()
+                .status,
+            400
+        );
+        assert_eq!(http::get(&format!("{}/nope", url)).unwrap().status, 404);
+    }
+    #[test]
+    fn test_stalled_subscriber() {
+        let dashboard = WebDashboard::new(info());
+        let addr = dashboard.serve("127.0.0.1:0").unwrap();
+        let mut events = TcpStream::connect(addr).unwrap();
+        write!(events, "GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        while dashboard.state.lock().unwrap().subscribers.is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        // The client never reads, so eventually its socket buffers fill up
+        // and then its backlog, but updates carry on regardless.
+        let start = std::time::Instant::now();
+        let mut updates = 0;
+        while !dashboard.state.lock().unwrap().subscribers.is_empty() {
+            dashboard.update(
+                Some(&measurement(START + Duration::seconds(updates), 1.0)),
+                DeviceStats::default(),
+            );
+            updates += 1;
+            assert!(
+                start.elapsed() < std::time::Duration::from_secs(5),
+                "still subscribed after {} updates",
+                updates
+            );
+        }
+        drop(events);
+    }
+}